@@ -11,12 +11,15 @@
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Event {
     /// Unique identifier for this event
+    #[serde(default = "generate_event_id")]
     pub id: String,
     /// When the event occurred
+    #[serde(default = "Utc::now")]
     pub timestamp: DateTime<Utc>,
     /// The type of event
     pub kind: EventKind,
     /// Source of the event (e.g., path, URL, etc.)
+    #[serde(default)]
     pub source: String,
     /// Additional metadata
     #[serde(default)]
@@ -58,6 +61,8 @@ pub enum EventKind {
     Manual,
     /// Scheduled trigger
     Scheduled { schedule: String },
+    /// Named event from a plugin source
+    Custom { name: String },
 }
 
 fn generate_event_id() -> String {
@@ -83,4 +88,13 @@ fn test_event_creation() {
         assert!(event.id.starts_with("evt_"));
         assert_eq!(event.source, "/tmp");
     }
+
+    #[test]
+    fn test_event_defaults_when_deserializing() {
+        let event: Event =
+            serde_json::from_str(r#"{"kind": {"type": "custom", "name": "row_added"}}"#).unwrap();
+        assert!(event.id.starts_with("evt_"));
+        assert_eq!(event.kind, EventKind::Custom { name: "row_added".to_string() });
+        assert!(event.source.is_empty());
+    }
 }
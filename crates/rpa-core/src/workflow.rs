@@ -64,6 +64,9 @@ pub struct WorkflowState {
     pub actions_executed: u64,
     /// Number of errors encountered
     pub error_count: u64,
+    /// Whether the last shutdown finished all in-flight work
+    #[serde(default)]
+    pub clean_shutdown: Option<bool>,
 }
 
 /// Status of a workflow
@@ -93,6 +96,7 @@ pub fn new(workflow_name: impl Into<String>) -> Self {
             events_processed: 0,
             actions_executed: 0,
             error_count: 0,
+            clean_shutdown: None,
         }
     }
 
@@ -102,10 +106,11 @@ pub fn start(&mut self) {
         self.started_at = Some(Utc::now());
     }
 
-    /// Mark as stopped
-    pub fn stop(&mut self) {
+    /// Mark as stopped, recording whether in-flight work drained cleanly
+    pub fn stop(&mut self, clean: bool) {
         self.status = WorkflowStatus::Stopped;
         self.completed_at = Some(Utc::now());
+        self.clean_shutdown = Some(clean);
     }
 
     /// Increment events processed
@@ -11,7 +11,7 @@
 use rpa_core::{Action, Event, EventKind, Result, action::ActionResult, Error};
 use std::fs::File;
 use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tracing::{debug, info};
 
 /// Action that archives files
@@ -31,7 +31,7 @@ pub fn new(destination: PathBuf, format: ArchiveFormat, delete_source: bool) ->
         }
     }
 
-    fn generate_archive_name(&self, source: &PathBuf) -> PathBuf {
+    fn generate_archive_name(&self, source: &Path) -> PathBuf {
         let stem = source
             .file_stem()
             .map(|s| s.to_string_lossy().to_string())
@@ -46,7 +46,7 @@ fn generate_archive_name(&self, source: &PathBuf) -> PathBuf {
         self.destination.join(format!("{}_{}.{}", stem, timestamp, extension))
     }
 
-    fn create_tar_gz(&self, source: &PathBuf, archive_path: &PathBuf) -> Result<()> {
+    fn create_tar_gz(&self, source: &Path, archive_path: &Path) -> Result<()> {
         let file = File::create(archive_path)?;
         let encoder = GzEncoder::new(file, Compression::default());
         let mut tar = tar::Builder::new(encoder);
@@ -62,7 +62,7 @@ fn create_tar_gz(&self, source: &PathBuf, archive_path: &PathBuf) -> Result<()>
         Ok(())
     }
 
-    fn create_zip(&self, source: &PathBuf, archive_path: &PathBuf) -> Result<()> {
+    fn create_zip(&self, source: &Path, archive_path: &Path) -> Result<()> {
         let file = File::create(archive_path)?;
         let mut zip = zip::ZipWriter::new(file);
 
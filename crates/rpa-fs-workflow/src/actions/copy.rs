@@ -5,7 +5,7 @@
 
 use async_trait::async_trait;
 use rpa_core::{Action, Event, EventKind, Result, action::ActionResult, Error};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tracing::{debug, info};
 
 /// Action that copies files to a destination
@@ -25,7 +25,7 @@ pub fn new(destination: PathBuf, overwrite: bool, preserve_structure: bool) -> S
         }
     }
 
-    fn get_dest_path(&self, source: &PathBuf) -> PathBuf {
+    fn get_dest_path(&self, source: &Path) -> PathBuf {
         if self.preserve_structure {
             // Preserve directory structure under destination
             self.destination.join(source.file_name().unwrap_or_default())
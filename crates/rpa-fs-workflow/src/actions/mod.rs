@@ -17,10 +17,15 @@
 pub use rename::RenameAction;
 pub use plugin::PluginActionWrapper;
 
-use rpa_core::{Action, Event, Result, action::ActionResult};
+use crate::pipeline::BranchCondition;
+use crate::retry::RetryPolicy;
+use crate::template::{self, TemplateContext};
+use rpa_core::{Action, Error, Event, Result, action::ActionResult};
+use rpa_plugin::PluginHost;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::path::PathBuf;
+use std::sync::Arc;
 use async_trait::async_trait;
 
 /// Configuration for filesystem actions
@@ -62,12 +67,218 @@ pub enum ActionConfig {
     Plugin {
         /// Plugin ID
         plugin: String,
+        /// Semver requirement for the plugin version (e.g. `^1.2`)
+        #[serde(default)]
+        version: Option<String>,
         /// Action name
         action: String,
         /// Plugin configuration
         #[serde(default)]
         config: HashMap<String, serde_json::Value>,
+        /// Fill templates in the configuration's strings
+        #[serde(default)]
+        template_config: bool,
+    },
+    /// Run `then` if the condition holds, otherwise `else`
+    If {
+        condition: BranchCondition,
+        #[serde(default)]
+        then: Vec<ActionStep>,
+        #[serde(default, rename = "else")]
+        otherwise: Vec<ActionStep>,
     },
+    /// Run independent steps concurrently
+    Parallel {
+        steps: Vec<ActionStep>,
+    },
+}
+
+/// An action in a rule, with its own retry policy
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActionStep {
+    /// Step ID under which later steps find this step's results (default:
+    /// the action name, numbered from its second use on, e.g. `copy#2`)
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(flatten)]
+    pub action: ActionConfig,
+    /// Retry policy for this action (overrides the rule's)
+    #[serde(default)]
+    pub retry: Option<RetryPolicy>,
+    /// Keep going with the next step if this one fails
+    #[serde(default)]
+    pub continue_on_error: bool,
+    /// Steps to run if this one fails; if they succeed the rule continues
+    #[serde(default)]
+    pub on_error: Vec<ActionStep>,
+}
+
+impl ActionStep {
+    /// The step's ID
+    pub fn id(&self) -> &str {
+        self.id.as_deref().unwrap_or_else(|| self.action.name())
+    }
+
+    /// Steps nested in this one: branches, parallel groups and error handlers
+    pub fn children(&self) -> impl Iterator<Item = &ActionStep> {
+        let nested: &[ActionStep] = match &self.action {
+            ActionConfig::If { then, .. } => then,
+            ActionConfig::Parallel { steps } => steps,
+            _ => &[],
+        };
+        let otherwise: &[ActionStep] = match &self.action {
+            ActionConfig::If { otherwise, .. } => otherwise,
+            _ => &[],
+        };
+        nested.iter().chain(otherwise).chain(&self.on_error)
+    }
+
+    /// This step and every step nested in it, depth first
+    pub fn flatten(&self) -> Vec<&ActionStep> {
+        std::iter::once(self).chain(self.children().flat_map(ActionStep::flatten)).collect()
+    }
+
+    /// Call `f` on this step and every nested one, in the order of `flatten`
+    pub fn visit_mut(&mut self, f: &mut dyn FnMut(&mut ActionStep)) {
+        f(self);
+        let (nested, otherwise): (&mut [ActionStep], &mut [ActionStep]) = match &mut self.action {
+            ActionConfig::If { then, otherwise, .. } => (then, otherwise),
+            ActionConfig::Parallel { steps } => (steps, &mut []),
+            _ => (&mut [], &mut []),
+        };
+        for step in nested.iter_mut().chain(otherwise).chain(&mut self.on_error) {
+            step.visit_mut(f);
+        }
+    }
+}
+
+impl From<ActionConfig> for ActionStep {
+    fn from(action: ActionConfig) -> Self {
+        Self {
+            id: None,
+            action,
+            retry: None,
+            continue_on_error: false,
+            on_error: Vec::new(),
+        }
+    }
+}
+
+impl ActionConfig {
+    /// Name of the action this config creates
+    pub fn name(&self) -> &str {
+        match self {
+            ActionConfig::Copy { .. } => "copy",
+            ActionConfig::Move { .. } => "move",
+            ActionConfig::Archive { .. } => "archive",
+            ActionConfig::Delete { .. } => "delete",
+            ActionConfig::Rename { .. } => "rename",
+            ActionConfig::Plugin { action, .. } => action,
+            ActionConfig::If { .. } => "if",
+            ActionConfig::Parallel { .. } => "parallel",
+        }
+    }
+
+    /// Whether the action takes the file away from its path
+    pub fn relocates(&self) -> bool {
+        matches!(
+            self,
+            ActionConfig::Move { .. }
+                | ActionConfig::Rename { .. }
+                | ActionConfig::Delete { .. }
+                | ActionConfig::Archive { delete_source: true, .. }
+        )
+    }
+
+    /// Whether the step reads or changes the file itself, not counting nested steps
+    pub fn uses_file(&self) -> bool {
+        match self {
+            ActionConfig::If { condition, .. } => matches!(condition, BranchCondition::File { .. }),
+            ActionConfig::Parallel { .. } => false,
+            _ => true,
+        }
+    }
+
+    /// Fill templates in destinations and, if `template_config` is set,
+    /// plugin config strings
+    ///
+    /// Rename patterns are filled by the rename action itself, and nested
+    /// steps of `if` and `parallel` when they run.
+    pub fn render(&self, context: &TemplateContext) -> Result<ActionConfig> {
+        self.map_templates(&mut |template, path| match path {
+            true => template::render_path(template, context),
+            false => template::render(template, context),
+        })
+    }
+
+    /// Every templated field of the action
+    pub fn templates(&self) -> Vec<String> {
+        let mut templates = match self {
+            ActionConfig::Rename { pattern } => vec![pattern.clone()],
+            _ => Vec::new(),
+        };
+        let _ = self.map_templates(&mut |template, _| {
+            templates.push(template.to_string());
+            Ok(template.to_string())
+        });
+        templates
+    }
+
+    /// Apply `f` to each templated field, telling it whether the field is a path
+    fn map_templates(&self, f: &mut dyn FnMut(&str, bool) -> Result<String>) -> Result<ActionConfig> {
+        let mut path = |p: &PathBuf| f(&p.to_string_lossy(), true).map(PathBuf::from);
+        Ok(match self.clone() {
+            ActionConfig::Copy { destination, overwrite, preserve_structure } => ActionConfig::Copy {
+                destination: path(&destination)?,
+                overwrite,
+                preserve_structure,
+            },
+            ActionConfig::Move { destination, overwrite } => ActionConfig::Move {
+                destination: path(&destination)?,
+                overwrite,
+            },
+            ActionConfig::Archive { destination, format, delete_source } => ActionConfig::Archive {
+                destination: path(&destination)?,
+                format,
+                delete_source,
+            },
+            ActionConfig::Delete { to_trash } => ActionConfig::Delete { to_trash },
+            ActionConfig::Rename { pattern } => ActionConfig::Rename { pattern },
+            ActionConfig::Plugin { plugin, version, action, config, template_config: false } => ActionConfig::Plugin {
+                plugin,
+                version,
+                action,
+                config,
+                template_config: false,
+            },
+            ActionConfig::Plugin { plugin, version, action, config, template_config: true } => ActionConfig::Plugin {
+                plugin,
+                version,
+                action,
+                config: config
+                    .into_iter()
+                    .map(|(key, value)| Ok((key, map_value(value, &mut |s| f(s, false))?)))
+                    .collect::<Result<_>>()?,
+                template_config: true,
+            },
+            control @ (ActionConfig::If { .. } | ActionConfig::Parallel { .. }) => control,
+        })
+    }
+}
+
+/// Apply `f` to every string inside a JSON value
+fn map_value(value: serde_json::Value, f: &mut dyn FnMut(&str) -> Result<String>) -> Result<serde_json::Value> {
+    use serde_json::Value;
+    Ok(match value {
+        Value::String(s) => Value::String(f(&s)?),
+        Value::Array(items) => Value::Array(items.into_iter().map(|v| map_value(v, f)).collect::<Result<_>>()?),
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .map(|(k, v)| Ok((k, map_value(v, f)?)))
+                .collect::<Result<_>>()?,
+        ),
+        other => other,
+    })
 }
 
 fn default_archive_format() -> ArchiveFormat {
@@ -90,7 +301,9 @@ pub struct DynamicAction {
 
 impl DynamicAction {
     /// Create a new dynamic action from config
-    pub fn from_config(config: ActionConfig) -> Self {
+    ///
+    /// Plugin actions are bound to `plugins` when a host is given.
+    pub fn from_config(config: ActionConfig, plugins: Option<&Arc<PluginHost>>) -> Self {
         let inner: Box<dyn Action> = match config {
             ActionConfig::Copy { destination, overwrite, preserve_structure } => {
                 Box::new(CopyAction::new(destination, overwrite, preserve_structure))
@@ -107,12 +320,48 @@ pub fn from_config(config: ActionConfig) -> Self {
             ActionConfig::Rename { pattern } => {
                 Box::new(RenameAction::new(pattern))
             }
-            ActionConfig::Plugin { plugin, action, config } => {
-                Box::new(PluginActionWrapper::new(plugin, action, config))
+            ActionConfig::Plugin { plugin, version, action, config, .. } => {
+                let mut wrapper = PluginActionWrapper::new(plugin, action, config);
+                if let Some(version) = version {
+                    wrapper = wrapper.with_version(version);
+                }
+                if let Some(host) = plugins {
+                    wrapper = wrapper.with_host(host.clone());
+                }
+                Box::new(wrapper)
+            }
+            control @ (ActionConfig::If { .. } | ActionConfig::Parallel { .. }) => {
+                Box::new(ControlStep(control.name().to_string()))
             }
         };
         Self { inner }
     }
+
+    /// Execute the action on a blocking thread
+    ///
+    /// The built-in actions use `std::fs` and plugins run synchronously, so
+    /// they are kept off the async workers, and the branches of a
+    /// `parallel` step can run at the same time.
+    pub async fn execute_blocking(self, event: Event) -> Result<ActionResult> {
+        let runtime = tokio::runtime::Handle::current();
+        tokio::task::spawn_blocking(move || runtime.block_on(self.execute(&event)))
+            .await
+            .unwrap_or_else(|e| Err(Error::Workflow(format!("Action panicked: {}", e))))
+    }
+}
+
+/// Stand-in for `if` and `parallel`, whose steps the runner executes itself
+struct ControlStep(String);
+
+#[async_trait]
+impl Action for ControlStep {
+    async fn execute(&self, _event: &Event) -> Result<ActionResult> {
+        Err(Error::Workflow(format!("'{}' steps can only run inside a rule", self.0)))
+    }
+
+    fn name(&self) -> &str {
+        &self.0
+    }
 }
 
 #[async_trait]
@@ -6,6 +6,7 @@
 use async_trait::async_trait;
 use rpa_core::{Action, Event, Result, action::ActionResult, Error};
 use rpa_plugin::{PluginContext, PluginHost};
+use semver::VersionReq;
 use std::collections::HashMap;
 use std::sync::Arc;
 use tracing::debug;
@@ -13,6 +14,7 @@
 /// Wrapper that executes plugin actions
 pub struct PluginActionWrapper {
     plugin_id: String,
+    version: Option<String>,
     action_name: String,
     config: HashMap<String, serde_json::Value>,
     host: Option<Arc<PluginHost>>,
@@ -27,12 +29,30 @@ pub fn new(
     ) -> Self {
         Self {
             plugin_id,
+            version: None,
             action_name,
             config,
             host: None,
         }
     }
 
+    /// Require a plugin version matching a semver requirement
+    pub fn with_version(mut self, requirement: impl Into<String>) -> Self {
+        self.version = Some(requirement.into());
+        self
+    }
+
+    fn version_req(&self) -> Result<Option<VersionReq>> {
+        self.version
+            .as_deref()
+            .map(VersionReq::parse)
+            .transpose()
+            .map_err(|e| Error::Config(format!(
+                "Invalid version requirement for plugin '{}': {}",
+                self.plugin_id, e
+            )))
+    }
+
     /// Set the plugin host
     pub fn with_host(mut self, host: Arc<PluginHost>) -> Self {
         self.host = Some(host);
@@ -50,6 +70,8 @@ async fn execute(&self, event: &Event) -> Result<ActionResult> {
             }
         })?;
 
+        let requirement = self.version_req()?;
+
         // Create plugin context from event
         let mut ctx = PluginContext::new(event.clone());
         for (key, value) in &self.config {
@@ -62,7 +84,7 @@ async fn execute(&self, event: &Event) -> Result<ActionResult> {
         );
 
         // Execute the plugin action
-        match host.execute_action(&self.plugin_id, &self.action_name, &ctx) {
+        match host.execute_action(&self.plugin_id, requirement.as_ref(), &self.action_name, &ctx) {
             Ok(result) => Ok(result.into_action_result()),
             Err(e) => Err(Error::ActionFailed {
                 action: self.name().to_string(),
@@ -82,6 +104,7 @@ fn validate(&self) -> Result<()> {
         if self.action_name.is_empty() {
             return Err(Error::Config("Action name cannot be empty".into()));
         }
+        self.version_req()?;
         Ok(())
     }
 }
@@ -4,14 +4,14 @@
 //! Rename action implementation with pattern support
 
 use async_trait::async_trait;
-use chrono::Utc;
+use crate::template::{self, TemplateContext};
 use rpa_core::{Action, Event, EventKind, Result, action::ActionResult, Error};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tracing::info;
 
 /// Action that renames files using a pattern
 ///
-/// Supported pattern variables:
+/// The pattern is a template (see [`crate::template`]), for example:
 /// - `{name}` - Original filename without extension
 /// - `{ext}` - Original extension
 /// - `{date}` - Current date (YYYY-MM-DD)
@@ -28,44 +28,23 @@ pub fn new(pattern: String) -> Self {
         Self { pattern }
     }
 
-    fn apply_pattern(&self, source: &PathBuf) -> PathBuf {
-        let name = source
-            .file_stem()
-            .map(|s| s.to_string_lossy().to_string())
-            .unwrap_or_default();
-        let ext = source
-            .extension()
-            .map(|s| s.to_string_lossy().to_string())
-            .unwrap_or_default();
-
-        let now = Utc::now();
-        let date = now.format("%Y-%m-%d").to_string();
-        let time = now.format("%H-%M-%S").to_string();
-        let datetime = now.format("%Y%m%d_%H%M%S").to_string();
-
-        let mut new_name = self.pattern.clone();
-        new_name = new_name.replace("{name}", &name);
-        new_name = new_name.replace("{ext}", &ext);
-        new_name = new_name.replace("{date}", &date);
-        new_name = new_name.replace("{time}", &time);
-        new_name = new_name.replace("{datetime}", &datetime);
+    fn apply_pattern(&self, event: &Event, source: &Path) -> Result<PathBuf> {
+        let parent = source.parent().unwrap_or(std::path::Path::new("."));
+        if !template::references(&self.pattern)?.contains(&"counter") {
+            let new_name = template::render_path(&self.pattern, &TemplateContext::new(event))?;
+            return Ok(parent.join(new_name));
+        }
 
         // Handle counter for uniqueness
-        if new_name.contains("{counter}") {
-            let parent = source.parent().unwrap_or(std::path::Path::new("."));
-            let mut counter = 1;
-            loop {
-                let candidate = new_name.replace("{counter}", &counter.to_string());
-                let candidate_path = parent.join(&candidate);
-                if !candidate_path.exists() || counter > 9999 {
-                    new_name = candidate;
-                    break;
-                }
-                counter += 1;
+        let mut counter = 1;
+        loop {
+            let context = TemplateContext::new(event).with_value("counter", counter);
+            let candidate_path = parent.join(template::render_path(&self.pattern, &context)?);
+            if !candidate_path.exists() || counter > 9999 {
+                return Ok(candidate_path);
             }
+            counter += 1;
         }
-
-        source.parent().unwrap_or(std::path::Path::new(".")).join(new_name)
     }
 }
 
@@ -86,7 +65,7 @@ async fn execute(&self, event: &Event) -> Result<ActionResult> {
             )));
         }
 
-        let dest = self.apply_pattern(source);
+        let dest = self.apply_pattern(event, source)?;
 
         if dest == *source {
             return Ok(ActionResult::success("No rename needed (same name)"));
@@ -117,7 +96,7 @@ fn validate(&self) -> Result<()> {
         if self.pattern.is_empty() {
             return Err(Error::Config("Rename pattern cannot be empty".into()));
         }
-        Ok(())
+        template::check(&self.pattern)
     }
 }
 
@@ -129,7 +108,8 @@ mod tests {
     fn test_pattern_name_ext() {
         let action = RenameAction::new("{name}_backup.{ext}".to_string());
         let source = PathBuf::from("/tmp/document.pdf");
-        let result = action.apply_pattern(&source);
+        let event = Event::new(EventKind::FileCreated { path: source.clone() }, "test");
+        let result = action.apply_pattern(&event, &source).unwrap();
         assert!(result.to_string_lossy().contains("document_backup.pdf"));
     }
 }
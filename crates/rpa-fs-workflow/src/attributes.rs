@@ -0,0 +1,256 @@
+// SPDX-License-Identifier: MIT OR AGPL-3.0-or-later
+// SPDX-FileCopyrightText: 2024 Hyperpolymath <hyperpolymath@proton.me>
+
+//! File attribute checks used by rule conditions
+//!
+//! Content types are detected from magic bytes rather than the file
+//! extension, so a renamed file is reported as what it really is.
+
+use rpa_core::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::Metadata;
+use std::io::Read;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// Bytes read from the start of a file for content type detection
+const SNIFF_LEN: usize = 512;
+
+/// Timestamp an age condition measures
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeField {
+    /// Last content modification
+    #[default]
+    Mtime,
+    /// Last status change (creation time on non-Unix platforms)
+    Ctime,
+}
+
+/// Whether the file size lies in `[min, max]`
+pub fn size_in_range(metadata: &Metadata, min: Option<u64>, max: Option<u64>) -> bool {
+    let size = metadata.len();
+    min.is_none_or(|min| size >= min) && max.is_none_or(|max| size <= max)
+}
+
+/// Time elapsed since the chosen timestamp
+pub fn age(metadata: &Metadata, field: TimeField) -> Option<Duration> {
+    let time = file_time(metadata, field)?;
+    Some(SystemTime::now().duration_since(time).unwrap_or_default())
+}
+
+/// The chosen timestamp of a file
+pub fn file_time(metadata: &Metadata, field: TimeField) -> Option<SystemTime> {
+    match field {
+        TimeField::Mtime => metadata.modified().ok(),
+        TimeField::Ctime => ctime(metadata),
+    }
+}
+
+#[cfg(unix)]
+fn ctime(metadata: &Metadata) -> Option<SystemTime> {
+    use std::os::unix::fs::MetadataExt;
+    let since_epoch = Duration::new(metadata.ctime().try_into().ok()?, metadata.ctime_nsec().try_into().ok()?);
+    Some(SystemTime::UNIX_EPOCH + since_epoch)
+}
+
+#[cfg(not(unix))]
+fn ctime(metadata: &Metadata) -> Option<SystemTime> {
+    metadata.created().ok()
+}
+
+/// Whether the file is owned by `uid` and `gid` (where given)
+#[cfg(unix)]
+pub fn owner_matches(metadata: &Metadata, uid: Option<u32>, gid: Option<u32>) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    uid.is_none_or(|uid| metadata.uid() == uid) && gid.is_none_or(|gid| metadata.gid() == gid)
+}
+
+#[cfg(not(unix))]
+pub fn owner_matches(_metadata: &Metadata, _uid: Option<u32>, _gid: Option<u32>) -> bool {
+    tracing::warn!("Owner conditions are only supported on Unix");
+    false
+}
+
+/// Parse permission bits written in octal (`"0644"`, `"755"`, `"0o600"`)
+pub fn parse_mode(mode: &str) -> Result<u32> {
+    let digits = mode.strip_prefix("0o").unwrap_or(mode);
+    u32::from_str_radix(digits, 8)
+        .ok()
+        .filter(|bits| *bits <= 0o7777)
+        .ok_or_else(|| Error::Config(format!("Invalid permission bits '{}': expected octal like \"0644\"", mode)))
+}
+
+/// Whether all `set` bits are set and all `unset` bits are clear
+#[cfg(unix)]
+pub fn mode_matches(metadata: &Metadata, set: u32, unset: u32) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = metadata.permissions().mode();
+    mode & set == set && mode & unset == 0
+}
+
+#[cfg(not(unix))]
+pub fn mode_matches(metadata: &Metadata, set: u32, unset: u32) -> bool {
+    // Only the owner write bit has a meaning here
+    let mode = if metadata.permissions().readonly() { 0o444 } else { 0o666 };
+    mode & set == set && mode & unset == 0
+}
+
+/// Detect a file's content type from its first bytes
+pub fn detect_mime(path: &Path) -> Option<&'static str> {
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    std::fs::File::open(path)
+        .ok()?
+        .take(SNIFF_LEN as u64)
+        .read_to_end(&mut head)
+        .ok()?;
+    Some(sniff(&head))
+}
+
+/// Content type of a buffer holding the start of a file
+pub fn sniff(head: &[u8]) -> &'static str {
+    const MAGIC: &[(&[u8], &str)] = &[
+        (b"%PDF-", "application/pdf"),
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"II*\0", "image/tiff"),
+        (b"MM\0*", "image/tiff"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"PK\x05\x06", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"BZh", "application/x-bzip2"),
+        (b"\xfd7zXZ\0", "application/x-xz"),
+        (b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+        (b"\x28\xb5\x2f\xfd", "application/zstd"),
+        (b"Rar!\x1a\x07", "application/vnd.rar"),
+        (b"\x7fELF", "application/x-executable"),
+        (b"\0asm", "application/wasm"),
+        (b"SQLite format 3\0", "application/vnd.sqlite3"),
+        (b"ID3", "audio/mpeg"),
+        (b"OggS", "audio/ogg"),
+        (b"fLaC", "audio/flac"),
+        (b"\x1a\x45\xdf\xa3", "video/webm"),
+        (b"{\\rtf", "application/rtf"),
+        (b"<?xml", "application/xml"),
+    ];
+
+    if let Some((_, mime)) = MAGIC.iter().find(|(magic, _)| head.starts_with(magic)) {
+        return mime;
+    }
+
+    match head {
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => return "image/webp",
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => return "audio/wav",
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => return "video/mp4",
+        _ => {}
+    }
+    if head.len() > 262 && &head[257..262] == b"ustar" {
+        return "application/x-tar";
+    }
+    // Two-byte magics also start plain text, so check the headers behind them
+    if is_pe(head) {
+        return "application/vnd.microsoft.portable-executable";
+    }
+    if is_bmp(head) {
+        return "image/bmp";
+    }
+
+    // Text if it has no NUL bytes and is UTF-8 (allowing a cut-off final character)
+    let text = !head.contains(&0)
+        && match std::str::from_utf8(head) {
+            Ok(_) => true,
+            Err(e) => e.error_len().is_none(),
+        };
+    if text {
+        "text/plain"
+    } else {
+        "application/octet-stream"
+    }
+}
+
+/// `MZ` followed by a `PE\0\0` signature at the offset stored at 0x3c
+fn is_pe(head: &[u8]) -> bool {
+    if !head.starts_with(b"MZ") || head.len() < 0x40 {
+        return false;
+    }
+    let offset = u32::from_le_bytes([head[0x3c], head[0x3d], head[0x3e], head[0x3f]]) as usize;
+    head.get(offset..offset + 4) == Some(b"PE\0\0")
+}
+
+/// `BM` followed by a known DIB header size and one colour plane
+fn is_bmp(head: &[u8]) -> bool {
+    if !head.starts_with(b"BM") || head.len() < 28 {
+        return false;
+    }
+    let header_size = u32::from_le_bytes([head[14], head[15], head[16], head[17]]);
+    let planes = u16::from_le_bytes([head[26], head[27]]);
+    matches!(header_size, 12 | 40 | 52 | 56 | 64 | 108 | 124) && planes == 1
+}
+
+/// Whether a content type matches a pattern such as `image/png` or `image/*`
+pub fn mime_matches(mime: &str, pattern: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(family) => mime.split('/').next() == Some(family),
+        None => pattern == "*" || pattern.eq_ignore_ascii_case(mime),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sniff() {
+        assert_eq!(sniff(b"%PDF-1.7\n"), "application/pdf");
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0"), "image/png");
+        assert_eq!(sniff(b"RIFF\0\0\0\0WEBPVP8 "), "image/webp");
+        assert_eq!(sniff(b"hello, world\n"), "text/plain");
+        assert_eq!(sniff(b"\0\x01\x02\x03"), "application/octet-stream");
+        assert_eq!(sniff(b""), "text/plain");
+
+        // Text that happens to start like a PE or BMP header
+        assert_eq!(sniff(b"MZ-1234 shipment notice\n"), "text/plain");
+        assert_eq!(sniff(b"BMW service invoice\n"), "text/plain");
+        let mut pe = vec![0u8; 0x84];
+        pe[..2].copy_from_slice(b"MZ");
+        pe[0x3c] = 0x80;
+        pe[0x80..].copy_from_slice(b"PE\0\0");
+        assert_eq!(sniff(&pe), "application/vnd.microsoft.portable-executable");
+        let mut bmp = vec![0u8; 54];
+        bmp[..2].copy_from_slice(b"BM");
+        bmp[14] = 40;
+        bmp[26] = 1;
+        assert_eq!(sniff(&bmp), "image/bmp");
+    }
+
+    #[test]
+    fn test_mime_patterns() {
+        assert!(mime_matches("image/png", "image/*"));
+        assert!(mime_matches("application/pdf", "application/pdf"));
+        assert!(!mime_matches("application/pdf", "image/*"));
+        assert!(mime_matches("text/plain", "*"));
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(parse_mode("0644").unwrap(), 0o644);
+        assert_eq!(parse_mode("0o755").unwrap(), 0o755);
+        assert!(parse_mode("0999").is_err());
+        assert!(parse_mode("rw-r--r--").is_err());
+    }
+
+    #[test]
+    fn test_size_and_age() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("placeholder.pdf");
+        std::fs::write(&file, b"").unwrap();
+        let metadata = std::fs::metadata(&file).unwrap();
+
+        assert!(!size_in_range(&metadata, Some(1), None));
+        assert!(size_in_range(&metadata, None, Some(0)));
+        assert!(age(&metadata, TimeField::Mtime).unwrap() < Duration::from_secs(60));
+        assert!(age(&metadata, TimeField::Ctime).is_some());
+    }
+}
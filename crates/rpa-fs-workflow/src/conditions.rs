@@ -0,0 +1,841 @@
+// SPDX-License-Identifier: MIT OR AGPL-3.0-or-later
+// SPDX-FileCopyrightText: 2024 Hyperpolymath <hyperpolymath@proton.me>
+
+//! Rule conditions evaluated before a rule's actions run
+//!
+//! A plugin condition calls a plugin action as a predicate. The action sets
+//! its output to either a boolean or an object of the form
+//! `{"matched": bool, "variables": {...}}`; extracted variables are merged
+//! into `event.metadata["variables"]` for the rule's actions.
+//!
+//! Attribute conditions (size, age, owner, mode, mime) and content
+//! conditions (content, json, csv_header) check the file an event refers
+//! to and never match events without an existing file. Content conditions
+//! read a bounded prefix of the file (see [`crate::content`]) and expose
+//! named regex captures as variables. All of them combine with `all`,
+//! `any` and `not`.
+
+use crate::attributes::{self, TimeField};
+use crate::content::{self, DEFAULT_READ_BYTES, MAX_READ_BYTES};
+use regex::Regex;
+use rpa_core::{Error, Event, EventKind, Result};
+use rpa_plugin::{PluginContext, PluginHost};
+use semver::VersionReq;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tracing::{debug, warn};
+
+/// Default fuel budget for predicate plugins (a tenth of an action's)
+pub const DEFAULT_PREDICATE_FUEL: u64 = 10_000_000;
+
+/// Maximum number of cached predicate results
+const CACHE_CAPACITY: usize = 4096;
+
+/// A condition that must hold for a rule to run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ConditionConfig {
+    /// Call a plugin action as a predicate
+    Plugin {
+        plugin: String,
+        /// Semver requirement for the plugin version
+        #[serde(default)]
+        version: Option<String>,
+        action: String,
+        #[serde(default)]
+        config: HashMap<String, Value>,
+        /// Fuel budget (default: DEFAULT_PREDICATE_FUEL)
+        #[serde(default)]
+        fuel_limit: Option<u64>,
+    },
+    /// File size in bytes within `[min, max]`
+    Size {
+        #[serde(default)]
+        min: Option<u64>,
+        #[serde(default)]
+        max: Option<u64>,
+    },
+    /// Time since the file was modified (`mtime`) or changed (`ctime`)
+    Age {
+        #[serde(default)]
+        time: TimeField,
+        #[serde(default)]
+        min_ms: Option<u64>,
+        #[serde(default)]
+        max_ms: Option<u64>,
+    },
+    /// Owning user and/or group ID (Unix only)
+    Owner {
+        #[serde(default)]
+        uid: Option<u32>,
+        #[serde(default)]
+        gid: Option<u32>,
+    },
+    /// Permission bits, in octal, that must be set and that must be clear
+    Mode {
+        #[serde(default)]
+        set: Option<String>,
+        #[serde(default)]
+        unset: Option<String>,
+    },
+    /// Content type detected from magic bytes (`image/*` allowed)
+    Mime { types: Vec<String> },
+    /// Regex over the start of the file
+    Content {
+        pattern: String,
+        /// Bytes to read (default: 64 KiB)
+        #[serde(default = "default_read_bytes")]
+        max_bytes: u64,
+        /// Only read this many lines
+        #[serde(default)]
+        max_lines: Option<usize>,
+    },
+    /// Value at a JSON pointer in a JSON file
+    Json {
+        pointer: String,
+        /// Value that must be present
+        #[serde(default)]
+        equals: Option<Value>,
+        /// Regex the value must match
+        #[serde(default)]
+        pattern: Option<String>,
+        /// Expose the value as this variable
+        #[serde(default)]
+        variable: Option<String>,
+        /// Largest document to parse (default: 1 MiB)
+        #[serde(default = "default_json_bytes")]
+        max_bytes: u64,
+    },
+    /// Column headers of a CSV file
+    CsvHeader {
+        /// Columns that must all be present
+        #[serde(default)]
+        columns: Vec<String>,
+        /// Regex some column header must match
+        #[serde(default)]
+        pattern: Option<String>,
+        #[serde(default = "default_delimiter")]
+        delimiter: char,
+        #[serde(default = "default_read_bytes")]
+        max_bytes: u64,
+    },
+    /// Every condition holds
+    All { conditions: Vec<ConditionConfig> },
+    /// At least one condition holds
+    Any { conditions: Vec<ConditionConfig> },
+    /// The condition does not hold
+    Not { condition: Box<ConditionConfig> },
+}
+
+fn default_read_bytes() -> u64 {
+    DEFAULT_READ_BYTES
+}
+
+fn default_json_bytes() -> u64 {
+    1024 * 1024 // 1MB
+}
+
+fn default_delimiter() -> char {
+    ','
+}
+
+impl ConditionConfig {
+    /// Plugin references (id, version requirement, action), including nested ones
+    pub fn plugin_refs(&self) -> Vec<(&str, Option<&str>, &str)> {
+        match self {
+            ConditionConfig::Plugin { plugin, version, action, .. } => {
+                vec![(plugin.as_str(), version.as_deref(), action.as_str())]
+            }
+            ConditionConfig::All { conditions } | ConditionConfig::Any { conditions } => {
+                conditions.iter().flat_map(|c| c.plugin_refs()).collect()
+            }
+            ConditionConfig::Not { condition } => condition.plugin_refs(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Check settings that would make the condition fail at runtime
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            ConditionConfig::Mode { set, unset } => {
+                for bits in [set, unset].into_iter().flatten() {
+                    attributes::parse_mode(bits)?;
+                }
+            }
+            ConditionConfig::All { conditions } | ConditionConfig::Any { conditions } => {
+                for condition in conditions {
+                    condition.validate()?;
+                }
+            }
+            ConditionConfig::Not { condition } => condition.validate()?,
+            ConditionConfig::Content { pattern, max_bytes, .. } => {
+                compile_regex(pattern)?;
+                check_read_limit(*max_bytes)?;
+            }
+            ConditionConfig::Json { pointer, pattern, max_bytes, .. } => {
+                if !pointer.is_empty() && !pointer.starts_with('/') {
+                    return Err(Error::Config(format!("Invalid JSON pointer '{}': must start with '/'", pointer)));
+                }
+                if let Some(pattern) = pattern {
+                    compile_regex(pattern)?;
+                }
+                check_read_limit(*max_bytes)?;
+            }
+            ConditionConfig::CsvHeader { pattern, max_bytes, .. } => {
+                if let Some(pattern) = pattern {
+                    compile_regex(pattern)?;
+                }
+                check_read_limit(*max_bytes)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Why the condition can never hold, if it cannot
+    pub fn never_true(&self) -> Option<String> {
+        match self {
+            ConditionConfig::Size { min: Some(min), max: Some(max) } if min > max => {
+                Some(format!("size min {} exceeds max {}", min, max))
+            }
+            ConditionConfig::Age { min_ms: Some(min), max_ms: Some(max), .. } if min > max => {
+                Some(format!("age min {}ms exceeds max {}ms", min, max))
+            }
+            ConditionConfig::Mode { set: Some(set), unset: Some(unset) } => {
+                let (set, unset) = (attributes::parse_mode(set).ok()?, attributes::parse_mode(unset).ok()?);
+                (set & unset != 0).then(|| format!("mode bits {:o} must be both set and clear", set & unset))
+            }
+            ConditionConfig::Mime { types } if types.is_empty() => Some("mime lists no types".to_string()),
+            ConditionConfig::Any { conditions } if conditions.is_empty() => Some("any of no conditions".to_string()),
+            ConditionConfig::Any { conditions } => {
+                let reasons: Option<Vec<_>> = conditions.iter().map(|c| c.never_true()).collect();
+                reasons.map(|r| r.join("; "))
+            }
+            ConditionConfig::All { conditions } => never_all(conditions),
+            ConditionConfig::Not { condition } => condition
+                .always_true()
+                .then(|| "negates a condition that always holds".to_string()),
+            _ => None,
+        }
+    }
+
+    /// Whether the condition holds for every file
+    fn always_true(&self) -> bool {
+        match self {
+            ConditionConfig::Size { min, max } => min.unwrap_or(0) == 0 && max.is_none(),
+            ConditionConfig::All { conditions } => conditions.iter().all(|c| c.always_true()),
+            ConditionConfig::Any { conditions } => conditions.iter().any(|c| c.always_true()),
+            ConditionConfig::Not { condition } => condition.never_true().is_some(),
+            _ => false,
+        }
+    }
+}
+
+fn compile_regex(pattern: &str) -> Result<Regex> {
+    Regex::new(pattern).map_err(|e| Error::InvalidPattern(format!("'{}': {}", pattern, e)))
+}
+
+fn check_read_limit(max_bytes: u64) -> Result<()> {
+    if max_bytes > MAX_READ_BYTES {
+        return Err(Error::Config(format!(
+            "Content conditions read at most {} bytes, not {}",
+            MAX_READ_BYTES, max_bytes
+        )));
+    }
+    Ok(())
+}
+
+/// Why a list of conditions that must all hold never does, if it cannot
+pub fn never_all(conditions: &[ConditionConfig]) -> Option<String> {
+    if let Some(reason) = conditions.iter().find_map(|c| c.never_true()) {
+        return Some(reason);
+    }
+
+    // Size ranges must overlap
+    let (mut low, mut high) = (0, u64::MAX);
+    for condition in conditions {
+        if let ConditionConfig::Size { min, max } = condition {
+            low = low.max(min.unwrap_or(0));
+            high = high.min(max.unwrap_or(u64::MAX));
+        }
+    }
+    (low > high).then(|| format!("size ranges do not overlap (min {} > max {})", low, high))
+}
+
+/// Result of evaluating a predicate
+#[derive(Debug, Clone, PartialEq)]
+pub struct PredicateOutcome {
+    /// Whether the predicate matched
+    pub matched: bool,
+    /// Variables extracted by the predicate
+    pub variables: Map<String, Value>,
+}
+
+impl PredicateOutcome {
+    /// A non-matching outcome
+    pub fn no_match() -> Self {
+        Self::from_bool(false)
+    }
+
+    /// An outcome without variables
+    pub fn from_bool(matched: bool) -> Self {
+        Self {
+            matched,
+            variables: Map::new(),
+        }
+    }
+
+    /// Interpret a predicate plugin's output
+    pub fn from_output(output: &Value) -> Option<Self> {
+        match output {
+            Value::Bool(matched) => Some(Self {
+                matched: *matched,
+                variables: Map::new(),
+            }),
+            Value::Object(obj) => {
+                let matched = obj.get("matched")?.as_bool()?;
+                let variables = match obj.get("variables") {
+                    Some(Value::Object(vars)) => vars.clone(),
+                    Some(Value::Null) | None => Map::new(),
+                    Some(_) => return None,
+                };
+                Some(Self { matched, variables })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Cache key: condition, predicate call, file path and modification time
+type CacheKey = (String, String, PathBuf, SystemTime);
+
+/// Evaluates rule conditions, caching plugin results by path and mtime
+///
+/// Safe to share between workers; the cache lock is not held while a
+/// predicate runs. The cache lives as long as the evaluator, which is
+/// created with the plugin host it calls.
+pub struct ConditionEvaluator {
+    plugins: Option<Arc<PluginHost>>,
+    cache: Mutex<HashMap<CacheKey, PredicateOutcome>>,
+    /// Compiled content regexes by source
+    regexes: Mutex<HashMap<String, Regex>>,
+}
+
+impl ConditionEvaluator {
+    /// Create a new evaluator
+    pub fn new(plugins: Option<Arc<PluginHost>>) -> Self {
+        Self {
+            plugins,
+            cache: Mutex::new(HashMap::new()),
+            regexes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Number of cached predicate results
+    pub fn cached(&self) -> usize {
+        self.cache.lock().unwrap().len()
+    }
+
+    /// Evaluate conditions on the blocking pool
+    ///
+    /// Predicates and content conditions run plugins and read files, so
+    /// they are kept off the async workers.
+    pub async fn evaluate_blocking(
+        self: &Arc<Self>,
+        key: &str,
+        conditions: &[ConditionConfig],
+        event: &Event,
+    ) -> Option<Map<String, Value>> {
+        if conditions.is_empty() {
+            return Some(Map::new());
+        }
+        let (evaluator, key, conditions, event) = (self.clone(), key.to_string(), conditions.to_vec(), event.clone());
+        tokio::task::spawn_blocking(move || evaluator.evaluate(&key, &conditions, &event))
+            .await
+            .unwrap_or_else(|e| {
+                warn!("Condition evaluation panicked: {}", e);
+                None
+            })
+    }
+
+    /// Evaluate all conditions of a rule against an event
+    ///
+    /// Returns the merged variables if every condition matched.
+    pub fn evaluate(
+        &self,
+        rule: &str,
+        conditions: &[ConditionConfig],
+        event: &Event,
+    ) -> Option<Map<String, Value>> {
+        let mut variables = Map::new();
+
+        for (index, condition) in conditions.iter().enumerate() {
+            let key = format!("{}#{}", rule, index);
+            let outcome = self.evaluate_one(&key, condition, event);
+            if !outcome.matched {
+                debug!("Rule '{}': condition {} did not match", rule, index);
+                return None;
+            }
+            variables.extend(outcome.variables);
+        }
+
+        Some(variables)
+    }
+
+    fn evaluate_one(&self, key: &str, condition: &ConditionConfig, event: &Event) -> PredicateOutcome {
+        match condition {
+            ConditionConfig::Plugin { .. } => self.evaluate_cached(key, condition, event),
+            ConditionConfig::All { conditions } => {
+                let mut variables = Map::new();
+                for (index, condition) in conditions.iter().enumerate() {
+                    let outcome = self.evaluate_one(&format!("{}.{}", key, index), condition, event);
+                    if !outcome.matched {
+                        return PredicateOutcome::no_match();
+                    }
+                    variables.extend(outcome.variables);
+                }
+                PredicateOutcome { matched: true, variables }
+            }
+            ConditionConfig::Any { conditions } => conditions
+                .iter()
+                .enumerate()
+                .map(|(index, condition)| self.evaluate_one(&format!("{}.{}", key, index), condition, event))
+                .find(|outcome| outcome.matched)
+                .unwrap_or_else(PredicateOutcome::no_match),
+            ConditionConfig::Not { condition } => {
+                let outcome = self.evaluate_one(&format!("{}!", key), condition, event);
+                PredicateOutcome::from_bool(!outcome.matched)
+            }
+            ConditionConfig::Content { .. } | ConditionConfig::Json { .. } | ConditionConfig::CsvHeader { .. } => {
+                event_path(event).map_or_else(PredicateOutcome::no_match, |path| self.check_content(condition, path))
+            }
+            _ => PredicateOutcome::from_bool(event_path(event).is_some_and(|path| check_attributes(condition, path))),
+        }
+    }
+
+    fn regex(&self, pattern: &str) -> Option<Regex> {
+        let mut regexes = self.regexes.lock().unwrap();
+        if let Some(regex) = regexes.get(pattern) {
+            return Some(regex.clone());
+        }
+        match compile_regex(pattern) {
+            Ok(regex) => Some(regexes.entry(pattern.to_string()).or_insert(regex).clone()),
+            Err(e) => {
+                warn!("{}", e);
+                None
+            }
+        }
+    }
+
+    /// Check a content condition against the file at `path`
+    fn check_content(&self, condition: &ConditionConfig, path: &Path) -> PredicateOutcome {
+        let captured = |variables: Option<Map<String, Value>>| match variables {
+            Some(variables) => PredicateOutcome { matched: true, variables },
+            None => PredicateOutcome::no_match(),
+        };
+
+        match condition {
+            ConditionConfig::Content { pattern, max_bytes, max_lines } => {
+                let (Some(regex), Ok(head)) = (self.regex(pattern), content::read_head(path, *max_bytes, *max_lines)) else {
+                    return PredicateOutcome::no_match();
+                };
+                captured(content::captures(&regex, &head))
+            }
+            ConditionConfig::Json { pointer, equals, pattern, variable, max_bytes } => {
+                let Some(value) = content::json_pointer(path, pointer, *max_bytes) else {
+                    return PredicateOutcome::no_match();
+                };
+                if equals.as_ref().is_some_and(|expected| *expected != value) {
+                    return PredicateOutcome::no_match();
+                }
+                let mut variables = match pattern {
+                    Some(pattern) => {
+                        let Some(regex) = self.regex(pattern) else {
+                            return PredicateOutcome::no_match();
+                        };
+                        match content::captures(&regex, &content::value_text(&value)) {
+                            Some(variables) => variables,
+                            None => return PredicateOutcome::no_match(),
+                        }
+                    }
+                    None => Map::new(),
+                };
+                if let Some(name) = variable {
+                    variables.insert(name.clone(), value);
+                }
+                PredicateOutcome { matched: true, variables }
+            }
+            ConditionConfig::CsvHeader { columns, pattern, delimiter, max_bytes } => {
+                let Some(header) = content::csv_header(path, *delimiter, *max_bytes) else {
+                    return PredicateOutcome::no_match();
+                };
+                if !columns.iter().all(|column| header.contains(column)) {
+                    return PredicateOutcome::no_match();
+                }
+                match pattern {
+                    Some(pattern) => match self.regex(pattern) {
+                        Some(regex) => captured(header.iter().find_map(|cell| content::captures(&regex, cell))),
+                        None => PredicateOutcome::no_match(),
+                    },
+                    None => PredicateOutcome::from_bool(true),
+                }
+            }
+            _ => PredicateOutcome::no_match(),
+        }
+    }
+
+    /// Evaluate a plugin predicate, reusing the result while the file is unchanged
+    fn evaluate_cached(&self, key: &str, condition: &ConditionConfig, event: &Event) -> PredicateOutcome {
+        // The same key may name a different predicate after a config reload
+        let call = serde_json::to_value(condition).unwrap_or_default().to_string();
+        let cache_key = event_path(event)
+            .and_then(|path| {
+                let mtime = std::fs::metadata(path).and_then(|m| m.modified()).ok()?;
+                Some((key.to_string(), call, path.to_path_buf(), mtime))
+            });
+
+        if let Some(outcome) = cache_key.as_ref().and_then(|k| self.cache.lock().unwrap().get(k).cloned()) {
+            debug!("Condition {}: using cached result", key);
+            return outcome;
+        }
+
+        let outcome = match condition {
+            ConditionConfig::Plugin { plugin, version, action, config, fuel_limit } => {
+                self.run_plugin(plugin, version.as_deref(), action, config, *fuel_limit, event)
+            }
+            _ => unreachable!("only plugin conditions are cached"),
+        };
+
+        if let Some(cache_key) = cache_key {
+            let mut cache = self.cache.lock().unwrap();
+            if cache.len() >= CACHE_CAPACITY {
+                cache.clear();
+            }
+            cache.insert(cache_key, outcome.clone());
+        }
+
+        outcome
+    }
+
+    fn run_plugin(
+        &self,
+        plugin: &str,
+        version: Option<&str>,
+        action: &str,
+        config: &HashMap<String, Value>,
+        fuel_limit: Option<u64>,
+        event: &Event,
+    ) -> PredicateOutcome {
+        let Some(host) = &self.plugins else {
+            warn!("Condition uses plugin '{}' but no plugins are loaded", plugin);
+            return PredicateOutcome::no_match();
+        };
+
+        let requirement = match version.map(VersionReq::parse).transpose() {
+            Ok(req) => req,
+            Err(e) => {
+                warn!("Invalid version requirement for plugin '{}': {}", plugin, e);
+                return PredicateOutcome::no_match();
+            }
+        };
+
+        let mut ctx = PluginContext::new(event.clone());
+        for (key, value) in config {
+            ctx = ctx.with_config(key.clone(), value.clone());
+        }
+
+        let result = host
+            .resolve(plugin, requirement.as_ref())
+            .and_then(|instance| {
+                instance.execute_with_fuel(action, &ctx, fuel_limit.unwrap_or(DEFAULT_PREDICATE_FUEL))
+            });
+
+        match result {
+            Ok(result) if result.success => PredicateOutcome::from_output(&result.output)
+                .unwrap_or_else(|| {
+                    warn!(
+                        "Predicate {}::{} returned invalid output: {}",
+                        plugin, action, result.output
+                    );
+                    PredicateOutcome::no_match()
+                }),
+            Ok(result) => {
+                warn!("Predicate {}::{} failed: {}", plugin, action, result.message);
+                PredicateOutcome::no_match()
+            }
+            Err(e) => {
+                warn!("Predicate {}::{} error: {}", plugin, action, e);
+                PredicateOutcome::no_match()
+            }
+        }
+    }
+}
+
+/// Check an attribute condition against the file at `path`
+fn check_attributes(condition: &ConditionConfig, path: &Path) -> bool {
+    let Ok(metadata) = std::fs::metadata(path) else {
+        return false;
+    };
+
+    match condition {
+        ConditionConfig::Size { min, max } => attributes::size_in_range(&metadata, *min, *max),
+        ConditionConfig::Age { time, min_ms, max_ms } => attributes::age(&metadata, *time).is_some_and(|age| {
+            let ms = age.as_millis() as u64;
+            min_ms.is_none_or(|min| ms >= min) && max_ms.is_none_or(|max| ms <= max)
+        }),
+        ConditionConfig::Owner { uid, gid } => attributes::owner_matches(&metadata, *uid, *gid),
+        ConditionConfig::Mode { set, unset } => {
+            let bits = |mode: &Option<String>| mode.as_deref().map_or(Ok(0), attributes::parse_mode);
+            match (bits(set), bits(unset)) {
+                (Ok(set), Ok(unset)) => attributes::mode_matches(&metadata, set, unset),
+                (Err(e), _) | (_, Err(e)) => {
+                    warn!("{}", e);
+                    false
+                }
+            }
+        }
+        ConditionConfig::Mime { types } => {
+            let Some(mime) = attributes::detect_mime(path) else {
+                return false;
+            };
+            debug!("Detected {} for {}", mime, path.display());
+            types.iter().any(|pattern| attributes::mime_matches(mime, pattern))
+        }
+        _ => false,
+    }
+}
+
+/// Merge extracted variables into `event.metadata["variables"]`
+pub fn with_variables(event: &Event, variables: Map<String, Value>) -> Event {
+    let mut event = event.clone();
+    if variables.is_empty() {
+        return event;
+    }
+
+    if !event.metadata.is_object() {
+        event.metadata = Value::Object(Map::new());
+    }
+    let metadata = event.metadata.as_object_mut().expect("metadata is an object");
+    match metadata.get_mut("variables") {
+        Some(Value::Object(existing)) => existing.extend(variables),
+        _ => {
+            metadata.insert("variables".to_string(), Value::Object(variables));
+        }
+    }
+    event
+}
+
+/// Path an event refers to (the destination for renames)
+pub fn event_path(event: &Event) -> Option<&Path> {
+    match &event.kind {
+        EventKind::FileCreated { path }
+        | EventKind::FileModified { path }
+        | EventKind::FileDeleted { path } => Some(path),
+        EventKind::FileRenamed { to, .. } => Some(to),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rpa_plugin::host::PluginConfig;
+    use serde_json::json;
+
+    /// Always matches and extracts `kind = invoice`
+    const PREDICATE_PLUGIN: &str = r#"
+        (module
+          (import "host" "set_output" (func $set_output (param i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 0) "{\22matched\22:true,\22variables\22:{\22kind\22:\22invoice\22}}")
+          (func (export "is_invoice") (result i32)
+            (call $set_output (i32.const 0) (i32.const 47))))
+    "#;
+
+    #[test]
+    fn test_outcome_from_output() {
+        assert_eq!(PredicateOutcome::from_output(&json!(false)), Some(PredicateOutcome::no_match()));
+        let outcome = PredicateOutcome::from_output(&json!({"matched": true, "variables": {"n": 1}})).unwrap();
+        assert!(outcome.matched);
+        assert_eq!(outcome.variables["n"], 1);
+        assert!(PredicateOutcome::from_output(&json!("yes")).is_none());
+    }
+
+    #[test]
+    fn test_plugin_condition_cached_by_mtime() {
+        let dir = tempfile::tempdir().unwrap();
+        let wasm = dir.path().join("predicates.wasm");
+        std::fs::write(&wasm, wat::parse_str(PREDICATE_PLUGIN).unwrap()).unwrap();
+        let file = dir.path().join("scan.pdf");
+        std::fs::write(&file, b"%PDF").unwrap();
+
+        let mut host = PluginHost::new().unwrap();
+        host.load_plugin(PluginConfig::new(&wasm)).unwrap();
+        let mut evaluator = ConditionEvaluator::new(Some(Arc::new(host)));
+
+        let conditions = vec![ConditionConfig::Plugin {
+            plugin: "predicates".to_string(),
+            version: None,
+            action: "is_invoice".to_string(),
+            config: HashMap::new(),
+            fuel_limit: None,
+        }];
+        let event = Event::new(EventKind::FileModified { path: file.clone() }, "test");
+
+        let variables = evaluator.evaluate("invoices", &conditions, &event).unwrap();
+        assert_eq!(variables["kind"], "invoice");
+        assert_eq!(evaluator.cached(), 1);
+
+        // Without a host, only a cached result can still match
+        evaluator.plugins = None;
+        assert!(evaluator.evaluate("invoices", &conditions, &event).is_some());
+
+        let later = SystemTime::now() + std::time::Duration::from_secs(60);
+        std::fs::File::options().write(true).open(&file).unwrap().set_modified(later).unwrap();
+        assert!(evaluator.evaluate("invoices", &conditions, &event).is_none());
+
+        let event = with_variables(&event, variables);
+        assert_eq!(event.metadata["variables"]["kind"], "invoice");
+    }
+
+    #[test]
+    fn test_plugin_condition_cached_per_call() {
+        let dir = tempfile::tempdir().unwrap();
+        let wasm = dir.path().join("predicates.wasm");
+        std::fs::write(&wasm, wat::parse_str(PREDICATE_PLUGIN).unwrap()).unwrap();
+        let file = dir.path().join("scan.pdf");
+        std::fs::write(&file, b"%PDF").unwrap();
+
+        let mut host = PluginHost::new().unwrap();
+        host.load_plugin(PluginConfig::new(&wasm)).unwrap();
+        let evaluator = Arc::new(ConditionEvaluator::new(Some(Arc::new(host))));
+
+        let mut conditions = vec![ConditionConfig::Plugin {
+            plugin: "predicates".to_string(),
+            version: None,
+            action: "is_invoice".to_string(),
+            config: HashMap::new(),
+            fuel_limit: None,
+        }];
+        let event = Event::new(EventKind::FileModified { path: file }, "test");
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        assert!(runtime.block_on(evaluator.evaluate_blocking("invoices", &conditions, &event)).is_some());
+
+        if let ConditionConfig::Plugin { config, .. } = &mut conditions[0] {
+            config.insert("strict".to_string(), Value::Bool(true));
+        }
+        assert!(runtime.block_on(evaluator.evaluate_blocking("invoices", &conditions, &event)).is_some());
+        assert_eq!(evaluator.cached(), 2);
+    }
+
+    #[test]
+    fn test_attribute_conditions() {
+        let dir = tempfile::tempdir().unwrap();
+        let placeholder = dir.path().join("empty.pdf");
+        std::fs::write(&placeholder, b"").unwrap();
+        let disguised = dir.path().join("photo.pdf");
+        std::fs::write(&disguised, b"\x89PNG\r\n\x1a\n").unwrap();
+        let real = dir.path().join("real.pdf");
+        std::fs::write(&real, b"%PDF-1.7\n").unwrap();
+
+        let evaluator = ConditionEvaluator::new(None);
+        let conditions: Vec<ConditionConfig> = serde_json::from_value(json!([
+            {"type": "size", "min": 1},
+            {"type": "any", "conditions": [
+                {"type": "mime", "types": ["application/pdf"]},
+                {"type": "not", "condition": {"type": "age", "max_ms": 3_600_000}}
+            ]}
+        ])).unwrap();
+        let matches = |path: &Path| {
+            let event = Event::new(EventKind::FileCreated { path: path.to_path_buf() }, "test");
+            evaluator.evaluate("pdfs", &conditions, &event).is_some()
+        };
+
+        assert!(!matches(&placeholder));
+        assert!(!matches(&disguised));
+        assert!(matches(&real));
+        assert!(!matches(&dir.path().join("missing.pdf")));
+    }
+
+    #[test]
+    fn test_content_conditions_extract_variables() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv = dir.path().join("export.csv");
+        std::fs::write(&csv, "date,amount,cust_4711\n2024-01-01,10,x\n").unwrap();
+        let json = dir.path().join("order.json");
+        std::fs::write(&json, r#"{"customer": {"id": "C-42", "tier": "gold"}}"#).unwrap();
+        let text = dir.path().join("note.txt");
+        std::fs::write(&text, "Subject: invoice\nRef: INV-2024-007\n").unwrap();
+
+        let evaluator = ConditionEvaluator::new(None);
+        let evaluate = |path: &Path, conditions: Value| {
+            let conditions: Vec<ConditionConfig> = serde_json::from_value(conditions).unwrap();
+            let event = Event::new(EventKind::FileCreated { path: path.to_path_buf() }, "test");
+            evaluator.evaluate("content", &conditions, &event)
+        };
+
+        let vars = evaluate(&csv, json!([
+            {"type": "csv_header", "columns": ["amount"], "pattern": "^cust_(?P<customer>\\d+)$"}
+        ])).unwrap();
+        assert_eq!(vars["customer"], "4711");
+        assert!(evaluate(&csv, json!([{"type": "csv_header", "columns": ["vat"]}])).is_none());
+
+        let vars = evaluate(&json, json!([
+            {"type": "json", "pointer": "/customer/tier", "equals": "gold"},
+            {"type": "json", "pointer": "/customer/id", "pattern": "^C-(?P<number>\\d+)$", "variable": "customer"}
+        ])).unwrap();
+        assert_eq!(vars["customer"], "C-42");
+        assert_eq!(vars["number"], "42");
+
+        let vars = evaluate(&text, json!([
+            {"type": "content", "pattern": "Ref: (?P<reference>INV-[0-9-]+)", "max_lines": 2}
+        ])).unwrap();
+        assert_eq!(vars["reference"], "INV-2024-007");
+        assert!(evaluate(&text, json!([{"type": "content", "pattern": "Ref:", "max_lines": 1}])).is_none());
+    }
+
+    #[test]
+    fn test_never_true() {
+        let conditions: Vec<ConditionConfig> = serde_json::from_value(json!([
+            {"type": "size", "min": 100},
+            {"type": "size", "max": 10}
+        ])).unwrap();
+        assert!(conditions.iter().all(|c| c.never_true().is_none()));
+        assert!(never_all(&conditions).is_some());
+
+        let contradiction: ConditionConfig = serde_json::from_value(json!(
+            {"type": "mode", "set": "0644", "unset": "0200"}
+        )).unwrap();
+        assert!(contradiction.never_true().is_some());
+
+        let negated: ConditionConfig = serde_json::from_value(json!(
+            {"type": "not", "condition": {"type": "all", "conditions": []}}
+        )).unwrap();
+        assert!(negated.never_true().is_some());
+
+        let invalid: ConditionConfig = serde_json::from_value(json!({"type": "mode", "set": "rwx"})).unwrap();
+        assert!(invalid.validate().is_err());
+        let invalid: ConditionConfig = serde_json::from_value(json!({"type": "content", "pattern": "("})).unwrap();
+        assert!(invalid.validate().is_err());
+        let invalid: ConditionConfig = serde_json::from_value(json!({"type": "json", "pointer": "customer"})).unwrap();
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn test_missing_plugin_does_not_match() {
+        let evaluator = ConditionEvaluator::new(None);
+        let conditions = vec![ConditionConfig::Plugin {
+            plugin: "absent".to_string(),
+            version: None,
+            action: "check".to_string(),
+            config: HashMap::new(),
+            fuel_limit: None,
+        }];
+        let event = Event::new(EventKind::FileCreated { path: PathBuf::from("/nonexistent") }, "test");
+        assert!(evaluator.evaluate("rule", &conditions, &event).is_none());
+    }
+}
@@ -6,10 +6,25 @@
 //! Supports both JSON configuration and Nickel configuration files.
 //! Nickel files are evaluated and converted to JSON for parsing.
 
-use crate::actions::ActionConfig;
+use crate::actions::{ActionConfig, ActionStep};
+use crate::conditions::{self, ConditionConfig};
+use crate::debounce::DebounceConfig;
+use crate::filter::WatchFilter;
+use crate::patterns::PatternSet;
+use crate::pipeline::{BranchCondition, StepStatus};
+use crate::retry::RetryPolicy;
+use crate::scan::InitialScanConfig;
+use crate::template;
+use crate::stability::{StabilityConfig, StabilityPolicy};
+use crate::sources::SourceConfig;
+use crate::watcher::WatchOptions;
 use rpa_core::{Error, Result, Workflow};
-use rpa_plugin::{Permission, PermissionSet, SandboxConfig};
+use rpa_plugin::host::PluginConfig;
+use rpa_plugin::sandbox::DeterministicConfig;
+use rpa_plugin::{Permission, PermissionSet, PluginHost, SandboxConfig};
+use semver::VersionReq;
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 use tracing::debug;
 
@@ -26,9 +41,88 @@ pub struct WorkflowConfig {
     /// Rules that match events to actions
     pub rules: Vec<RuleConfig>,
 
+    /// Whether every matching rule runs or only the first
+    #[serde(default)]
+    pub match_mode: MatchMode,
+
     /// Plugin configurations
     #[serde(default)]
     pub plugins: Vec<PluginLoadConfig>,
+
+    /// Plugin-defined event sources
+    #[serde(default)]
+    pub sources: Vec<SourceConfig>,
+
+    /// Event processing settings
+    #[serde(default)]
+    pub runner: RunnerConfig,
+}
+
+/// Which of the matching rules run for an event
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchMode {
+    /// Every matching rule, in priority order, until one with `stop` runs
+    #[default]
+    All,
+    /// Only the first matching rule in priority order
+    First,
+}
+
+/// Event processing settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunnerConfig {
+    /// Number of concurrent workers (default: available CPUs)
+    #[serde(default = "default_workers")]
+    pub workers: usize,
+    /// Maximum queued events before the watcher waits (default: 1024)
+    #[serde(default = "default_queue_capacity")]
+    pub queue_capacity: usize,
+    /// Time in-flight actions get to finish after a stop (default: 10s)
+    #[serde(default = "default_shutdown_grace")]
+    pub shutdown_grace_ms: u64,
+    /// File where queued events are saved on stop and replayed on start
+    #[serde(default)]
+    pub pending_file: Option<PathBuf>,
+    /// File recording processed files, to skip unchanged ones and catch up on restart
+    #[serde(default)]
+    pub index_file: Option<PathBuf>,
+    /// Directory receiving files whose actions failed after all retries
+    #[serde(default)]
+    pub dead_letter_dir: Option<PathBuf>,
+    /// How long events for paths the runner itself wrote are ignored (default: 5s, 0 = never)
+    #[serde(default = "default_self_write_window")]
+    pub self_write_window_ms: u64,
+}
+
+impl Default for RunnerConfig {
+    fn default() -> Self {
+        Self {
+            workers: default_workers(),
+            queue_capacity: default_queue_capacity(),
+            shutdown_grace_ms: default_shutdown_grace(),
+            pending_file: None,
+            index_file: None,
+            dead_letter_dir: None,
+            self_write_window_ms: default_self_write_window(),
+        }
+    }
+}
+
+fn default_workers() -> usize {
+    std::thread::available_parallelism().map_or(4, |n| n.get())
+}
+
+fn default_queue_capacity() -> usize {
+    1024
+}
+
+fn default_shutdown_grace() -> u64 {
+    10_000 // 10 seconds
+}
+
+fn default_self_write_window() -> u64 {
+    5_000 // 5 seconds
 }
 
 /// Configuration for loading a plugin
@@ -36,7 +130,7 @@ pub struct WorkflowConfig {
 pub struct PluginLoadConfig {
     /// Path to the plugin WASM file
     pub path: PathBuf,
-    /// Optional plugin ID (defaults to filename)
+    /// Optional plugin ID (defaults to the id in the plugin metadata, then the filename)
     pub id: Option<String>,
     /// Whether plugin is enabled
     #[serde(default = "default_enabled")]
@@ -64,6 +158,21 @@ pub struct PluginSandboxConfig {
     /// Environment variables the plugin can access
     #[serde(default)]
     pub env_vars: Vec<String>,
+    /// Run with a virtual clock and seeded randomness
+    #[serde(default)]
+    pub deterministic: Option<DeterministicConfig>,
+}
+
+impl PluginLoadConfig {
+    /// Convert to rpa_plugin::host::PluginConfig
+    pub fn to_plugin_config(&self) -> PluginConfig {
+        let mut config = PluginConfig::new(&self.path).with_enabled(self.enabled);
+        if let Some(id) = &self.id {
+            config = config.with_id(id.clone());
+        }
+        config.sandbox = self.sandbox.to_sandbox_config();
+        config
+    }
 }
 
 fn default_memory_limit() -> u64 {
@@ -97,6 +206,7 @@ pub fn to_sandbox_config(&self) -> SandboxConfig {
             fuel_limit: Some(100_000_000),
             permissions,
             work_dir: None,
+            deterministic: self.deterministic.clone(),
         }
     }
 }
@@ -104,35 +214,276 @@ pub fn to_sandbox_config(&self) -> SandboxConfig {
 /// Configuration for a watched directory
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WatchConfig {
+    /// Name that rules can scope themselves to
+    #[serde(default)]
+    pub name: Option<String>,
     /// Path to watch
     pub path: PathBuf,
     /// Whether to watch recursively
     #[serde(default = "default_recursive")]
     pub recursive: bool,
+    /// Only deliver events for paths matching one of these globs
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Never deliver events for paths matching these globs
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Deepest level below the root to deliver (1 = direct children)
+    #[serde(default)]
+    pub max_depth: Option<usize>,
+    /// Deliver events from symlinked subdirectories
+    #[serde(default)]
+    pub follow_symlinks: bool,
+    /// Debounce and coalescing settings
+    #[serde(default)]
+    pub debounce: DebounceConfig,
+    /// Hold events until files are completely written
+    #[serde(default)]
+    pub stability: Option<StabilityConfig>,
+    /// Emit events for files already present when the runner starts, in
+    /// order and paced (requires `runner.index_file`, which alone reports
+    /// them unpaced)
+    #[serde(default)]
+    pub initial_scan: Option<InitialScanConfig>,
 }
 
 fn default_recursive() -> bool {
     true
 }
 
+impl WatchConfig {
+    /// Watcher settings for this watch
+    pub fn options(&self) -> Result<WatchOptions> {
+        Ok(WatchOptions {
+            recursive: self.recursive,
+            debounce: self.debounce,
+            filter: WatchFilter::new(&self.include, &self.exclude, self.max_depth, self.follow_symlinks)?,
+        })
+    }
+}
+
 /// A rule that matches events and triggers actions
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RuleConfig {
     /// Name of this rule
     pub name: String,
-    /// File patterns to match (glob patterns)
+    /// Patterns to match: globs, `regex:` patterns and `!` negations
     #[serde(default)]
     pub patterns: Vec<String>,
     /// Event types to match
     #[serde(default = "default_events")]
     pub events: Vec<EventType>,
+    /// Conditions that must all hold before actions run
+    #[serde(default)]
+    pub conditions: Vec<ConditionConfig>,
+    /// Hold events until files are completely written (overrides the watch's)
+    #[serde(default)]
+    pub stability: Option<StabilityConfig>,
+    /// Only match events from these named watches (default: all)
+    #[serde(default)]
+    pub watches: Vec<String>,
+    /// Rules with higher priority are tried first; ties keep config order
+    #[serde(default)]
+    pub priority: i32,
+    /// No further rules run for an event once this one has
+    #[serde(default)]
+    pub stop: bool,
     /// Actions to execute when rule matches
-    pub actions: Vec<ActionConfig>,
+    pub actions: Vec<ActionStep>,
+    /// Retry policy for the rule's actions (default: no retries)
+    #[serde(default)]
+    pub retry: Option<RetryPolicy>,
+    /// Steps to run when an action failure stops the rule
+    #[serde(default)]
+    pub on_error: Vec<ActionStep>,
+    /// Let files this rule writes trigger rules again, including itself
+    #[serde(default)]
+    pub allow_retrigger: bool,
     /// Whether this rule is enabled
     #[serde(default = "default_enabled")]
     pub enabled: bool,
 }
 
+impl RuleConfig {
+    /// Compile the rule's patterns
+    pub fn pattern_set(&self) -> Result<PatternSet> {
+        PatternSet::compile(&self.patterns)
+            .map_err(|e| Error::Config(format!("Rule '{}': {}", self.name, e)))
+    }
+
+    /// Plugin references (id, version requirement, action) from actions and conditions
+    pub fn plugin_refs(&self) -> Vec<(&str, Option<&str>, &str)> {
+        let steps = self.steps();
+        let actions = steps.iter().filter_map(|a| match &a.action {
+            ActionConfig::Plugin { plugin, version, action, .. } => {
+                Some((plugin.as_str(), version.as_deref(), action.as_str()))
+            }
+            _ => None,
+        });
+        let conditions = self.conditions.iter().chain(self.branch_conditions()).flat_map(ConditionConfig::plugin_refs);
+        conditions.chain(actions).collect()
+    }
+
+    /// Every step of the rule, nested ones and error handlers included
+    pub fn steps(&self) -> Vec<&ActionStep> {
+        self.actions.iter().chain(&self.on_error).flat_map(ActionStep::flatten).collect()
+    }
+
+    /// Number the default IDs of steps sharing an action (`copy`, `copy#2`, ...)
+    /// so each step's results are kept apart; explicit IDs are left alone
+    pub fn number_steps(&mut self) {
+        let mut taken: HashSet<String> = self.steps().iter().filter_map(|s| s.id.clone()).collect();
+        let mut number = |step: &mut ActionStep| {
+            if step.id.is_some() {
+                return;
+            }
+            let name = step.action.name();
+            let id = (1..)
+                .map(|n| if n == 1 { name.to_string() } else { format!("{}#{}", name, n) })
+                .find(|id| !taken.contains(id))
+                .unwrap();
+            if id != name {
+                step.id = Some(id.clone());
+            }
+            taken.insert(id);
+        };
+        for step in self.actions.iter_mut().chain(&mut self.on_error) {
+            step.visit_mut(&mut number);
+        }
+    }
+
+    /// File conditions of `if` steps
+    pub fn branch_conditions(&self) -> Vec<&ConditionConfig> {
+        self.steps()
+            .into_iter()
+            .filter_map(|step| match &step.action {
+                ActionConfig::If { condition: BranchCondition::File { conditions }, .. } => Some(conditions),
+                _ => None,
+            })
+            .flatten()
+            .collect()
+    }
+
+    /// Check that `if` steps only refer to results they can see
+    fn check_step_references(&self) -> Result<()> {
+        let mut visible = Vec::new();
+        self.check_references(&self.actions, &mut visible)?;
+        let mut handlers: Vec<_> = visible.iter().map(|&(id, _)| (id, true)).collect();
+        self.check_references(&self.on_error, &mut handlers)
+    }
+
+    /// Check that a branch of a parallel step that moves or deletes the file
+    /// runs alone, as the other branches would race it for the file
+    fn check_parallel_branches(&self) -> Result<()> {
+        for step in self.steps() {
+            let ActionConfig::Parallel { steps } = &step.action else {
+                continue;
+            };
+            let moving = steps.iter().filter(|branch| branch.flatten().iter().any(|s| s.action.relocates())).count();
+            let using = steps.iter().filter(|branch| branch.flatten().iter().any(|s| s.action.uses_file())).count();
+            if moving > 0 && using > 1 {
+                return Err(Error::Config(format!(
+                    "Rule '{}': a branch of parallel step '{}' moves or deletes the file while other branches use it",
+                    self.name,
+                    step.id()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// `visible` holds the IDs of steps that ran before, with whether a
+    /// failure of that step lets the rule go on
+    fn check_references<'a>(&self, steps: &'a [ActionStep], visible: &mut Vec<(&'a str, bool)>) -> Result<()> {
+        for step in steps {
+            let before = visible.len();
+            match &step.action {
+                ActionConfig::If { condition, then, otherwise } => {
+                    if let BranchCondition::Step { step: target, status, .. } = condition {
+                        match visible.iter().rev().find(|(id, _)| id == target) {
+                            None => {
+                                return Err(Error::Config(format!(
+                                    "Rule '{}': step '{}' refers to step '{}', which does not run before it",
+                                    self.name, step.id(), target
+                                )));
+                            }
+                            Some((_, false)) if *status == StepStatus::Failed => {
+                                return Err(Error::Config(format!(
+                                    "Rule '{}': the 'then' branch of step '{}' is unreachable: a failure of step '{}' stops the rule (set continue_on_error or on_error)",
+                                    self.name, step.id(), target
+                                )));
+                            }
+                            _ => {}
+                        }
+                    }
+                    for branch in [then, otherwise] {
+                        let mut inner = visible[..before].to_vec();
+                        self.check_references(branch, &mut inner)?;
+                        visible.extend_from_slice(&inner[before..]);
+                    }
+                }
+                ActionConfig::Parallel { steps } => {
+                    for branch in steps {
+                        let mut inner = visible[..before].to_vec();
+                        self.check_references(std::slice::from_ref(branch), &mut inner)?;
+                        visible.extend_from_slice(&inner[before..]);
+                    }
+                }
+                _ => {}
+            }
+
+            let mut handlers = visible.clone();
+            handlers.push((step.id(), true));
+            self.check_references(&step.on_error, &mut handlers)?;
+            visible.push((step.id(), step.continue_on_error || !step.on_error.is_empty()));
+        }
+        Ok(())
+    }
+
+    /// Steps that can never run or never see the file
+    fn step_warnings(&self, steps: &[ActionStep], warnings: &mut Vec<String>) {
+        let mut deleted_by = None;
+        for step in steps {
+            if let Some(delete) = deleted_by {
+                warnings.push(format!(
+                    "Rule '{}': step '{}' runs after step '{}' deleted the file",
+                    self.name, step.id(), delete
+                ));
+            }
+            if let ActionConfig::If { condition: BranchCondition::File { conditions }, .. } = &step.action {
+                if let Some(reason) = conditions::never_all(conditions) {
+                    warnings.push(format!(
+                        "Rule '{}': the 'then' branch of step '{}' never runs: {}",
+                        self.name, step.id(), reason
+                    ));
+                }
+            }
+            if matches!(step.action, ActionConfig::Delete { .. }) && !step.continue_on_error && deleted_by.is_none() {
+                deleted_by = Some(step.id());
+            }
+
+            match &step.action {
+                ActionConfig::If { then, otherwise, .. } => {
+                    self.step_warnings(then, warnings);
+                    self.step_warnings(otherwise, warnings);
+                }
+                ActionConfig::Parallel { steps } => {
+                    for branch in steps {
+                        self.step_warnings(std::slice::from_ref(branch), warnings);
+                    }
+                }
+                _ => {}
+            }
+            self.step_warnings(&step.on_error, warnings);
+        }
+    }
+}
+
+/// Whether `rule` sees events from `watch`
+fn in_scope(rule: &RuleConfig, watch: &WatchConfig) -> bool {
+    rule.watches.is_empty() || watch.name.as_ref().is_some_and(|n| rule.watches.contains(n))
+}
+
 fn default_events() -> Vec<EventType> {
     vec![EventType::Created, EventType::Modified]
 }
@@ -149,6 +500,8 @@ pub enum EventType {
     Modified,
     Deleted,
     Renamed,
+    /// Named events from plugin sources
+    Custom,
 }
 
 impl WorkflowConfig {
@@ -171,7 +524,8 @@ pub fn load(path: impl AsRef<Path>) -> Result<Self> {
     fn load_json(path: &Path) -> Result<Self> {
         debug!("Loading JSON config from {}", path.display());
         let content = std::fs::read_to_string(path)?;
-        let config: Self = serde_json::from_str(&content)?;
+        let mut config: Self = serde_json::from_str(&content)?;
+        config.number_steps();
         config.validate()?;
         Ok(config)
     }
@@ -197,21 +551,48 @@ fn load_nickel(path: &Path) -> Result<Self> {
         }
 
         let json = String::from_utf8_lossy(&output.stdout);
-        let config: Self = serde_json::from_str(&json)?;
+        let mut config: Self = serde_json::from_str(&json)?;
+        config.number_steps();
         config.validate()?;
         Ok(config)
     }
 
+    /// Number the default step IDs of every rule; see [`RuleConfig::number_steps`]
+    pub fn number_steps(&mut self) {
+        for rule in &mut self.rules {
+            rule.number_steps();
+        }
+    }
+
     /// Validate the configuration
     pub fn validate(&self) -> Result<()> {
-        if self.watch.is_empty() {
-            return Err(Error::Config("At least one watch path is required".into()));
+        if self.watch.is_empty() && self.sources.is_empty() {
+            return Err(Error::Config("At least one watch path or source is required".into()));
         }
 
         if self.rules.is_empty() {
             return Err(Error::Config("At least one rule is required".into()));
         }
 
+        for (i, watch) in self.watch.iter().enumerate() {
+            watch.options().map_err(|e| Error::Config(format!(
+                "Watch '{}': {}",
+                watch.path.display(),
+                e
+            )))?;
+            if watch.initial_scan.is_some() && self.runner.index_file.is_none() {
+                return Err(Error::Config(format!(
+                    "Watch '{}': initial_scan needs runner.index_file, or every start would process all files again",
+                    watch.path.display()
+                )));
+            }
+            if let Some(name) = &watch.name {
+                if self.watch[..i].iter().any(|w| w.name.as_ref() == Some(name)) {
+                    return Err(Error::Config(format!("Duplicate watch name '{}'", name)));
+                }
+            }
+        }
+
         for (i, rule) in self.rules.iter().enumerate() {
             if rule.name.is_empty() {
                 return Err(Error::Config(format!("Rule {} has no name", i)));
@@ -222,36 +603,328 @@ pub fn validate(&self) -> Result<()> {
                     rule.name
                 )));
             }
+            rule.pattern_set()?;
+            let steps = rule.steps();
+            for (i, step) in steps.iter().enumerate() {
+                if step.id.is_some() && steps[..i].iter().any(|s| s.id() == step.id()) {
+                    return Err(Error::Config(format!(
+                        "Rule '{}' has two actions with ID '{}'",
+                        rule.name, step.id()
+                    )));
+                }
+                for template in step.action.templates() {
+                    template::check(&template).map_err(|e| Error::Config(format!("Rule '{}': {}", rule.name, e)))?;
+                }
+            }
+            rule.check_step_references()?;
+            rule.check_parallel_branches()?;
+            if !rule.allow_retrigger {
+                for step in &steps {
+                    if let Some((dir, watch)) = self.watched_destination(rule, step) {
+                        return Err(Error::Config(format!(
+                            "Rule '{}': step '{}' writes to {}, inside watch {}, so its output would trigger the workflow again (set allow_retrigger to allow this)",
+                            rule.name,
+                            step.id(),
+                            dir.display(),
+                            watch.name.as_deref().unwrap_or(&watch.path.to_string_lossy())
+                        )));
+                    }
+                }
+            }
+            let policies = steps.iter().filter_map(|a| a.retry.as_ref()).chain(&rule.retry);
+            for policy in policies {
+                policy.validate().map_err(|e| Error::Config(format!("Rule '{}': {}", rule.name, e)))?;
+            }
+            for condition in rule.conditions.iter().chain(rule.branch_conditions()) {
+                condition.validate().map_err(|e| Error::Config(format!("Rule '{}': {}", rule.name, e)))?;
+            }
+            for name in &rule.watches {
+                if !self.watch.iter().any(|w| w.name.as_ref() == Some(name)) {
+                    return Err(Error::Config(format!(
+                        "Rule '{}' refers to unknown watch '{}'",
+                        rule.name, name
+                    )));
+                }
+            }
+            for (plugin, version, _) in rule.plugin_refs() {
+                if let Some(version) = version {
+                    VersionReq::parse(version).map_err(|e| Error::Config(format!(
+                        "Rule '{}': invalid version requirement '{}' for plugin '{}': {}",
+                        rule.name, version, plugin, e
+                    )))?;
+                }
+            }
+        }
+
+        if self.runner.workers == 0 {
+            return Err(Error::Config("Runner needs at least one worker".into()));
+        }
+        if self.runner.queue_capacity == 0 {
+            return Err(Error::Config("Runner queue capacity must be non-zero".into()));
+        }
+
+        for source in &self.sources {
+            if source.name.is_empty() {
+                return Err(Error::Config("Source has no name".into()));
+            }
+            if source.interval_ms == 0 {
+                return Err(Error::Config(format!(
+                    "Source '{}' has a zero interval",
+                    source.name
+                )));
+            }
+            source.version_req()?;
         }
 
         Ok(())
     }
 
+    /// Load the enabled plugins into a new plugin host (see [`plugin_host`])
+    pub fn plugin_host(&self) -> Result<PluginHost> {
+        plugin_host(&self.plugins)
+    }
+
+    /// Check that every plugin action in the rules resolves against `host`
+    ///
+    /// Returns one message per unresolvable action.
+    pub fn check_plugin_actions(&self, host: &PluginHost) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        for rule in self.rules.iter().filter(|r| r.enabled) {
+            for (plugin, version, action) in rule.plugin_refs() {
+                let requirement = match version.map(VersionReq::parse).transpose() {
+                    Ok(req) => req,
+                    Err(e) => {
+                        problems.push(format!("Rule '{}': {}", rule.name, e));
+                        continue;
+                    }
+                };
+
+                match host.resolve(plugin, requirement.as_ref()) {
+                    Ok(instance) if !instance.has_action(action) => problems.push(format!(
+                        "Rule '{}': plugin '{}@{}' has no action '{}'",
+                        rule.name,
+                        plugin,
+                        instance.version(),
+                        action
+                    )),
+                    Ok(_) => {}
+                    Err(e) => problems.push(format!("Rule '{}': {}", rule.name, e)),
+                }
+            }
+        }
+
+        for source in self.sources.iter().filter(|s| s.enabled) {
+            let requirement = match source.version_req() {
+                Ok(req) => req,
+                Err(e) => {
+                    problems.push(e.to_string());
+                    continue;
+                }
+            };
+
+            match host.resolve(&source.plugin, requirement.as_ref()) {
+                Ok(instance) if !instance.has_action(source.entry_point()) => problems.push(format!(
+                    "Source '{}': plugin '{}@{}' has no entry point '{}'",
+                    source.name,
+                    source.plugin,
+                    instance.version(),
+                    source.entry_point()
+                )),
+                Ok(_) => {}
+                Err(e) => problems.push(format!("Source '{}': {}", source.name, e)),
+            }
+        }
+
+        problems
+    }
+
+    /// Non-fatal problems: rules whose conditions can never hold
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings: Vec<String> = self
+            .rules
+            .iter()
+            .filter(|r| r.enabled)
+            .filter_map(|rule| {
+                conditions::never_all(&rule.conditions)
+                    .map(|reason| format!("Rule '{}' can never match: {}", rule.name, reason))
+            })
+            .collect();
+
+        for rule in self.rules.iter().filter(|r| r.enabled) {
+            rule.step_warnings(&rule.actions, &mut warnings);
+        }
+
+        let order = self.rule_order();
+        for (position, &later) in order.iter().enumerate() {
+            let rule = &self.rules[later];
+            let shadowing = order[..position]
+                .iter()
+                .map(|&earlier| &self.rules[earlier])
+                .find(|earlier| self.shadows(earlier, rule));
+            if let Some(earlier) = shadowing {
+                warnings.push(format!(
+                    "Rule '{}' never runs: rule '{}' matches every event it does and stops",
+                    rule.name, earlier.name
+                ));
+            }
+        }
+
+        warnings
+    }
+
+    /// Indices of enabled rules in the order they are tried
+    pub fn rule_order(&self) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.rules.len()).filter(|&i| self.rules[i].enabled).collect();
+        order.sort_by_key(|&i| std::cmp::Reverse(self.rules[i].priority));
+        order
+    }
+
+    /// Whether `earlier` always runs and stops where `later` would match
+    fn shadows(&self, earlier: &RuleConfig, later: &RuleConfig) -> bool {
+        let stops = earlier.stop || self.match_mode == MatchMode::First;
+        let scoped = earlier.watches.is_empty()
+            || (!later.watches.is_empty() && later.watches.iter().all(|w| earlier.watches.contains(w)));
+        let events = later.events.iter().all(|e| earlier.events.contains(e));
+        // Patterns are compared conservatively: a catch-all or a superset,
+        // and only without negations, which may exclude what `later` matches
+        let patterns = earlier.patterns.is_empty()
+            || (!earlier.patterns.iter().any(|p| p.starts_with('!'))
+                && (earlier.patterns.iter().any(|p| p == "**" || p == "*")
+                    || (!later.patterns.is_empty() && later.patterns.iter().all(|p| earlier.patterns.contains(p)))));
+
+        stops && earlier.conditions.is_empty() && earlier.stability.is_none() && scoped && events && patterns
+    }
+
+    /// Directory a step writes to and a watch of the rule that sees files written there
+    fn watched_destination(&self, rule: &RuleConfig, step: &ActionStep) -> Option<(PathBuf, &WatchConfig)> {
+        let destination = match &step.action {
+            ActionConfig::Copy { destination, .. }
+            | ActionConfig::Move { destination, .. }
+            | ActionConfig::Archive { destination, .. } => destination,
+            // Renames stay inside the rule's watches; they matter to rules that see renames
+            ActionConfig::Rename { .. } => {
+                return self
+                    .watch
+                    .iter()
+                    .filter(|w| in_scope(rule, w))
+                    .find(|w| self.rules.iter().any(|r| r.events.contains(&EventType::Renamed) && in_scope(r, w)))
+                    .map(|w| (w.path.clone(), w));
+            }
+            _ => return None,
+        };
+
+        // Up to the first placeholder; templated parts add at least one level
+        let text = destination.to_string_lossy();
+        let (dir, extra) = match text.find('{') {
+            None => (destination.clone(), 0),
+            Some(0) => return None,
+            Some(i) if text[..i].ends_with(std::path::MAIN_SEPARATOR) => (PathBuf::from(&text[..i]), 1),
+            Some(i) => (Path::new(&text[..i]).parent()?.to_path_buf(), 1),
+        };
+
+        self.watch
+            .iter()
+            .filter(|w| in_scope(rule, w))
+            .find(|w| {
+                let Ok(relative) = dir.strip_prefix(&w.path) else {
+                    return false;
+                };
+                let depth = relative.components().count() + extra + 1;
+                (w.recursive || depth == 1) && w.max_depth.is_none_or(|max| depth <= max)
+            })
+            .map(|w| (dir, w))
+    }
+
+    /// Watch whose root contains `path` (the most specific one)
+    pub fn watch_for(&self, path: &Path) -> Option<&WatchConfig> {
+        self.watch
+            .iter()
+            .filter(|w| {
+                path.starts_with(&w.path)
+                    || w.path.canonicalize().is_ok_and(|root| path.starts_with(root))
+            })
+            .max_by_key(|w| w.path.as_os_str().len())
+    }
+
+    /// Whether any watch or rule waits for close-write notifications
+    pub fn uses_close_write(&self) -> bool {
+        let uses = |s: &Option<StabilityConfig>| {
+            s.as_ref().is_some_and(|s| s.policy == StabilityPolicy::CloseWrite)
+        };
+        self.watch.iter().any(|w| uses(&w.stability)) || self.rules.iter().any(|r| uses(&r.stability))
+    }
+
     /// Create a minimal example configuration
     pub fn example() -> Self {
         Self {
             workflow: Workflow::new("example-workflow")
                 .with_description("Example filesystem workflow"),
             watch: vec![WatchConfig {
+                name: Some("inbox".to_string()),
                 path: PathBuf::from("/tmp/watch"),
                 recursive: true,
+                include: Vec::new(),
+                exclude: vec!["*.part".to_string()],
+                max_depth: None,
+                follow_symlinks: false,
+                debounce: DebounceConfig::default(),
+                stability: None,
+                initial_scan: None,
             }],
+            match_mode: MatchMode::All,
             rules: vec![RuleConfig {
                 name: "backup-pdfs".to_string(),
                 patterns: vec!["*.pdf".to_string()],
                 events: vec![EventType::Created],
+                conditions: Vec::new(),
+                stability: None,
+                watches: Vec::new(),
+                priority: 0,
+                stop: false,
                 actions: vec![ActionConfig::Copy {
                     destination: PathBuf::from("/tmp/backup"),
                     overwrite: false,
                     preserve_structure: false,
-                }],
+                }
+                .into()],
+                retry: None,
+                on_error: Vec::new(),
+                allow_retrigger: false,
                 enabled: true,
             }],
             plugins: Vec::new(),
+            sources: Vec::new(),
+            runner: RunnerConfig::default(),
         }
     }
 }
 
+/// Load every enabled plugin of one or more workflows into a new plugin host
+///
+/// All load failures (missing files, invalid modules, two entries
+/// providing the same plugin version) are collected into one error.
+pub fn plugin_host(plugins: &[PluginLoadConfig]) -> Result<PluginHost> {
+    let mut host = PluginHost::new()
+        .map_err(|e| Error::Config(format!("Failed to create plugin host: {}", e)))?;
+    let mut errors = Vec::new();
+
+    for plugin in plugins.iter().filter(|p| p.enabled) {
+        if let Err(e) = host.load_plugin(plugin.to_plugin_config()) {
+            errors.push(format!("{}: {}", plugin.path.display(), e));
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(host)
+    } else {
+        Err(Error::Config(format!(
+            "Failed to load plugins:\n  {}",
+            errors.join("\n  ")
+        )))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -270,4 +943,242 @@ fn test_json_roundtrip() {
         let parsed: WorkflowConfig = serde_json::from_str(&json).unwrap();
         assert_eq!(parsed.workflow.name, config.workflow.name);
     }
+
+    #[test]
+    fn test_invalid_plugin_version_requirement() {
+        let mut config = WorkflowConfig::example();
+        config.rules[0].actions.push(ActionConfig::Plugin {
+            plugin: "tagger".to_string(),
+            version: Some("not-a-version".to_string()),
+            action: "tag".to_string(),
+            config: Default::default(),
+            template_config: false,
+        }.into());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_condition_plugins_checked() {
+        let mut config = WorkflowConfig::example();
+        config.rules[0].conditions.push(ConditionConfig::Plugin {
+            plugin: "classifier".to_string(),
+            version: None,
+            action: "is_invoice".to_string(),
+            config: Default::default(),
+            fuel_limit: None,
+        });
+        let host = PluginHost::new().unwrap();
+        let problems = config.check_plugin_actions(&host);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("classifier"));
+    }
+
+    #[test]
+    fn test_unresolved_plugin_action_reported() {
+        let mut config = WorkflowConfig::example();
+        config.rules[0].actions.push(ActionConfig::Plugin {
+            plugin: "tagger".to_string(),
+            version: Some("^1.2".to_string()),
+            action: "tag".to_string(),
+            config: Default::default(),
+            template_config: false,
+        }.into());
+        let host = PluginHost::new().unwrap();
+        let problems = config.check_plugin_actions(&host);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("tagger"));
+    }
+
+    #[test]
+    fn test_watch_scoping_validated() {
+        let mut config = WorkflowConfig::example();
+        config.rules[0].watches = vec!["inbox".to_string()];
+        assert!(config.validate().is_ok());
+
+        config.rules[0].watches = vec!["outbox".to_string()];
+        assert!(config.validate().is_err());
+
+        config.rules[0].watches.clear();
+        config.watch[0].include = vec!["[".to_string()];
+        assert!(config.validate().is_err());
+
+        config.watch[0].include.clear();
+        config.watch[0].initial_scan = Some(Default::default());
+        assert!(config.validate().unwrap_err().to_string().contains("index_file"));
+        config.runner.index_file = Some(PathBuf::from("/var/lib/rpa/index.json"));
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_invalid_patterns_rejected() {
+        let mut config = WorkflowConfig::example();
+        config.rules[0].patterns = vec!["invoices/**/*.pdf".to_string(), "!regex:(".to_string()];
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("backup-pdfs"));
+    }
+
+    #[test]
+    fn test_impossible_conditions_warned() {
+        let mut config = WorkflowConfig::example();
+        assert!(config.warnings().is_empty());
+        config.rules[0].conditions = vec![
+            ConditionConfig::Size { min: Some(1024), max: None },
+            ConditionConfig::Size { min: None, max: Some(0) },
+        ];
+        assert!(config.validate().is_ok());
+        let warnings = config.warnings();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("backup-pdfs"));
+    }
+
+    #[test]
+    fn test_rule_order_and_shadowing() {
+        let mut config = WorkflowConfig::example();
+        let mut archive = config.rules[0].clone();
+        archive.name = "archive-everything".to_string();
+        archive.patterns.clear();
+        archive.priority = -1;
+        config.rules.insert(0, archive);
+        assert_eq!(config.rule_order(), vec![1, 0]);
+        assert!(config.warnings().is_empty());
+
+        // A catch-all that runs first and stops hides the PDF rule
+        config.rules[0].priority = 10;
+        config.rules[0].stop = true;
+        assert_eq!(config.rule_order(), vec![0, 1]);
+        let warnings = config.warnings();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("'backup-pdfs' never runs"));
+
+        // Neither a negation nor a condition leaves it a catch-all
+        config.rules[0].patterns = vec!["*".to_string(), "!*.pdf".to_string()];
+        assert!(config.warnings().is_empty());
+        config.rules[0].patterns = vec!["*".to_string()];
+        config.rules[0].conditions = vec![ConditionConfig::Size { min: Some(1), max: None }];
+        assert!(config.warnings().is_empty());
+        config.rules[0].conditions.clear();
+        assert_eq!(config.warnings().len(), 1);
+
+        config.rules[0].stop = false;
+        config.match_mode = MatchMode::First;
+        assert_eq!(config.warnings().len(), 1);
+    }
+
+    #[test]
+    fn test_default_step_ids_numbered() {
+        let mut config = WorkflowConfig::example();
+        config.rules[0].actions = serde_json::from_value(serde_json::json!([
+            { "type": "copy", "destination": "/tmp/a" },
+            { "type": "parallel", "steps": [
+                { "type": "copy", "destination": "/tmp/b" },
+                { "type": "copy", "id": "copy#3", "destination": "/tmp/c" },
+            ] },
+            { "type": "if", "condition": { "type": "step", "step": "copy#2", "status": "succeeded" },
+              "then": [{ "type": "copy", "destination": "/tmp/d" }] },
+        ])).unwrap();
+        config.number_steps();
+        let ids: Vec<_> = config.rules[0].steps().iter().map(|s| s.id()).collect();
+        assert_eq!(ids, vec!["copy", "parallel", "copy#2", "copy#3", "if", "copy#4"]);
+        assert_eq!(config.rules[0].actions[0].id, None);
+        assert!(config.validate().is_ok());
+
+        // Numbering again changes nothing
+        let before = serde_json::to_value(&config.rules[0].actions).unwrap();
+        config.number_steps();
+        assert_eq!(serde_json::to_value(&config.rules[0].actions).unwrap(), before);
+    }
+
+    #[test]
+    fn test_moving_branch_runs_alone_in_parallel_step() {
+        let mut config = WorkflowConfig::example();
+        config.rules[0].actions = serde_json::from_value(serde_json::json!([
+            { "type": "copy", "id": "backup", "destination": "/tmp/a", "continue_on_error": true },
+            { "type": "parallel", "steps": [
+                { "type": "copy", "destination": "/tmp/b" },
+                { "type": "copy", "destination": "/tmp/c" },
+            ] },
+            { "type": "parallel", "steps": [
+                { "type": "move", "destination": "/tmp/d" },
+                { "type": "if", "condition": { "type": "step", "step": "backup", "status": "failed" }, "then": [] },
+            ] },
+        ])).unwrap();
+        assert!(config.validate().is_ok());
+
+        let ActionConfig::Parallel { steps } = &mut config.rules[0].actions[1].action else {
+            unreachable!()
+        };
+        steps[1] = ActionConfig::Move { destination: "/tmp/e".into(), overwrite: false }.into();
+        assert!(config.validate().unwrap_err().to_string().contains("while other branches use it"));
+        let ActionConfig::Parallel { steps } = &mut config.rules[0].actions[1].action else {
+            unreachable!()
+        };
+        steps[0] = ActionConfig::Delete { to_trash: false }.into();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_step_references_validated() {
+        let mut config = WorkflowConfig::example();
+        let steps = |value: serde_json::Value| serde_json::from_value::<Vec<ActionStep>>(value).unwrap();
+        config.rules[0].actions = steps(serde_json::json!([
+            { "type": "copy", "id": "backup", "destination": "/tmp/backup", "continue_on_error": true },
+            { "type": "if", "condition": { "type": "step", "step": "backup", "status": "failed" },
+              "then": [{ "type": "move", "destination": "/tmp/failed" }],
+              "else": [{ "type": "delete" }] },
+            { "type": "rename", "pattern": "{name}.done" },
+        ]));
+        assert!(config.validate().is_ok());
+        assert!(config.warnings().is_empty());
+
+        // A failure without continue_on_error never reaches the branch
+        config.rules[0].actions[0].continue_on_error = false;
+        assert!(config.validate().unwrap_err().to_string().contains("unreachable"));
+
+        // Steps can only see results of steps before them
+        config.rules[0].actions.swap(0, 1);
+        assert!(config.validate().unwrap_err().to_string().contains("does not run before it"));
+
+        config.rules[0].actions = steps(serde_json::json!([
+            { "type": "delete" },
+            { "type": "copy", "destination": "/tmp/backup" },
+        ]));
+        assert!(config.validate().is_ok());
+        assert!(config.warnings()[0].contains("deleted the file"));
+    }
+
+    #[test]
+    fn test_destinations_inside_watches_rejected() {
+        let mut config = WorkflowConfig::example();
+        config.watch[0].path = PathBuf::from("/srv/inbox");
+        config.watch[0].recursive = false;
+        let destination = |config: &mut WorkflowConfig, path: &str| {
+            config.rules[0].actions = vec![ActionConfig::Copy {
+                destination: PathBuf::from(path),
+                overwrite: false,
+                preserve_structure: false,
+            }.into()];
+        };
+
+        destination(&mut config, "/srv/inbox");
+        assert!(config.validate().unwrap_err().to_string().contains("allow_retrigger"));
+
+        // Subdirectories of a non-recursive watch are not seen
+        destination(&mut config, "/srv/inbox/{mtime:%Y}");
+        assert!(config.validate().is_ok());
+        config.watch[0].recursive = true;
+        assert!(config.validate().is_err());
+        config.watch[0].max_depth = Some(1);
+        assert!(config.validate().is_ok());
+
+        config.watch[0].max_depth = None;
+        config.rules[0].allow_retrigger = true;
+        assert!(config.validate().is_ok());
+
+        // Renaming in place is seen by rules that match renames
+        config.rules[0].allow_retrigger = false;
+        config.rules[0].actions = vec![ActionConfig::Rename { pattern: "{name}.done".to_string() }.into()];
+        assert!(config.validate().is_ok());
+        config.rules[0].events.push(EventType::Renamed);
+        assert!(config.validate().unwrap_err().to_string().contains("allow_retrigger"));
+    }
 }
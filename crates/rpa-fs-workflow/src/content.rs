@@ -0,0 +1,139 @@
+// SPDX-License-Identifier: MIT OR AGPL-3.0-or-later
+// SPDX-FileCopyrightText: 2024 Hyperpolymath <hyperpolymath@proton.me>
+
+//! Bounded reads of file contents used by rule conditions
+//!
+//! Every read stops after a byte limit, capped at [`MAX_READ_BYTES`], so a
+//! huge file costs no more than a small one.
+
+use regex::Regex;
+use serde_json::{Map, Value};
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+/// Default number of bytes a content condition reads
+pub const DEFAULT_READ_BYTES: u64 = 64 * 1024;
+
+/// Upper bound on any content read
+pub const MAX_READ_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Read the start of a file as text: at most `max_bytes`, and at most `max_lines` lines
+pub fn read_head(path: &Path, max_bytes: u64, max_lines: Option<usize>) -> std::io::Result<String> {
+    let file = std::fs::File::open(path)?.take(max_bytes.min(MAX_READ_BYTES));
+    let mut reader = BufReader::new(file);
+    let mut head = Vec::new();
+
+    match max_lines {
+        Some(lines) => {
+            for _ in 0..lines {
+                if reader.read_until(b'\n', &mut head)? == 0 {
+                    break;
+                }
+            }
+        }
+        None => {
+            reader.read_to_end(&mut head)?;
+        }
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+/// Named captures of the first match, or `None` if the regex does not match
+pub fn captures(regex: &Regex, text: &str) -> Option<Map<String, Value>> {
+    let captures = regex.captures(text)?;
+    Some(
+        regex
+            .capture_names()
+            .flatten()
+            .filter_map(|name| {
+                let value = captures.name(name)?.as_str();
+                Some((name.to_string(), Value::String(value.to_string())))
+            })
+            .collect(),
+    )
+}
+
+/// Value at a JSON pointer; `None` if the file is not JSON within `max_bytes`
+pub fn json_pointer(path: &Path, pointer: &str, max_bytes: u64) -> Option<Value> {
+    let text = read_head(path, max_bytes, None).ok()?;
+    let document: Value = serde_json::from_str(&text).ok()?;
+    document.pointer(pointer).cloned()
+}
+
+/// Column names from the first line of a CSV file
+pub fn csv_header(path: &Path, delimiter: char, max_bytes: u64) -> Option<Vec<String>> {
+    let head = read_head(path, max_bytes, Some(1)).ok()?;
+    let line = head.trim_start_matches('\u{feff}').trim_end_matches(['\r', '\n']);
+    if line.is_empty() {
+        return None;
+    }
+    Some(split_csv_line(line, delimiter))
+}
+
+/// Split one CSV line, honouring double-quoted fields
+fn split_csv_line(line: &str, delimiter: char) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            c if c == delimiter && !quoted => fields.push(std::mem::take(&mut field).trim().to_string()),
+            c => field.push(c),
+        }
+    }
+    fields.push(field.trim().to_string());
+    fields
+}
+
+/// Text form of a JSON value for regex matching (strings without quotes)
+pub fn value_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_head_is_bounded() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("big.txt");
+        std::fs::write(&file, "line one\nline two\nline three\n".repeat(1000)).unwrap();
+
+        assert_eq!(read_head(&file, 8, None).unwrap(), "line one");
+        assert_eq!(read_head(&file, 1024, Some(2)).unwrap(), "line one\nline two\n");
+    }
+
+    #[test]
+    fn test_csv_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("export.csv");
+        std::fs::write(&file, "\u{feff}\"Customer, ID\",amount;x,\"say \"\"hi\"\"\"\r\n1,2,3\n").unwrap();
+
+        let header = csv_header(&file, ',', DEFAULT_READ_BYTES).unwrap();
+        assert_eq!(header, vec!["Customer, ID", "amount;x", "say \"hi\""]);
+    }
+
+    #[test]
+    fn test_json_pointer() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("order.json");
+        std::fs::write(&file, r#"{"customer": {"id": "C-42"}, "lines": [1, 2]}"#).unwrap();
+
+        assert_eq!(json_pointer(&file, "/customer/id", DEFAULT_READ_BYTES), Some(Value::from("C-42")));
+        assert_eq!(json_pointer(&file, "/lines/1", DEFAULT_READ_BYTES), Some(Value::from(2)));
+        assert_eq!(json_pointer(&file, "/missing", DEFAULT_READ_BYTES), None);
+        // Truncated documents are not parsed
+        assert_eq!(json_pointer(&file, "/customer/id", 10), None);
+    }
+}
@@ -0,0 +1,692 @@
+// SPDX-License-Identifier: MIT OR AGPL-3.0-or-later
+// SPDX-FileCopyrightText: 2024 Hyperpolymath <hyperpolymath@proton.me>
+
+//! Several workflows in one process
+//!
+//! A [`Daemon`] runs every workflow file (`.json` or `.ncl`) in a
+//! directory. The workflows share one filesystem watcher, one plugin host
+//! and one worker pool, while each keeps its own state, processed index,
+//! pending events and dead letters:
+//!
+//! - Workflows naming the same dead-letter directory share its claim,
+//!   which is released when the last of them has stopped.
+//! - Each watched path is registered once. When workflows watch the same
+//!   path with different settings it is registered as the narrowest watch
+//!   covering all of them, without globs, and each workflow's own filter,
+//!   depth and recursion are applied when events are dispatched.
+//! - The plugin host holds the enabled plugins of all workflows, one per
+//!   id and version, and is rebuilt when a workflow brings plugins it does
+//!   not have. Workflows must configure a shared plugin version alike.
+//! - Events from a workflow's sources and initial scans only reach that
+//!   workflow; filesystem events reach every workflow watching the path.
+//!
+//! The directory is polled: new files start workflows, removed files stop
+//! them (their queued events are cancelled and saved as on a stop) and
+//! changed files are reloaded as by `rpa-fs run`. The pool settings
+//! (`workers`, `queue_capacity`, `shutdown_grace_ms`) are the daemon's;
+//! the other runner settings apply per workflow.
+
+use crate::config::{PluginLoadConfig, RunnerConfig, WatchConfig, WorkflowConfig};
+use crate::deadletter;
+use crate::reload::{json, ConfigDiff, RELOAD_INTERVAL};
+use crate::runner::{self, Active, Shared, StopHandle, WorkerPool};
+use crate::scan;
+use crate::sources;
+use crate::stability::CloseWriteTracker;
+use crate::watcher::{EventInjector, FsWatcher};
+use rpa_core::{Event, Result, WorkflowState};
+use rpa_plugin::PluginHost;
+use semver::Version;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+use tracing::{debug, error, info, warn};
+
+/// Runs the workflows of a directory on shared resources
+pub struct Daemon {
+    dir: PathBuf,
+    settings: RunnerConfig,
+    stop: StopHandle,
+    /// Running workflows by config file
+    workflows: BTreeMap<PathBuf, Running>,
+    /// Config files of `workflows`, for other threads
+    running: Arc<Mutex<BTreeSet<PathBuf>>>,
+    /// Stopped workflows whose queued events have not drained yet
+    retired: Vec<Running>,
+    plugins: Option<Arc<PluginHost>>,
+    /// Plugin entries loaded into `plugins`
+    plugin_configs: Vec<PluginLoadConfig>,
+    /// Settings each watched path is registered with
+    registered: BTreeMap<PathBuf, WatchConfig>,
+    /// Dead-letter directories claimed for running and retired workflows
+    dead_letters: HashMap<PathBuf, Weak<deadletter::Owner>>,
+    close_writes: CloseWriteTracker,
+}
+
+/// A workflow started by the daemon
+struct Running {
+    config: WorkflowConfig,
+    shared: Arc<Shared>,
+    /// Cleared to stop the workflow's sources and scans
+    running: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+    /// Keeps dead letters from being replayed while the workflow runs
+    _dead_letters: Option<Arc<deadletter::Owner>>,
+}
+
+/// A workflow file that appeared, changed or disappeared
+enum Change {
+    Loaded(PathBuf, Box<WorkflowConfig>),
+    Removed(PathBuf),
+}
+
+impl Daemon {
+    /// Run the workflows in `dir`
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            settings: RunnerConfig::default(),
+            stop: StopHandle::new(),
+            workflows: BTreeMap::new(),
+            running: Arc::new(Mutex::new(BTreeSet::new())),
+            retired: Vec::new(),
+            plugins: None,
+            plugin_configs: Vec::new(),
+            registered: BTreeMap::new(),
+            dead_letters: HashMap::new(),
+            close_writes: CloseWriteTracker::new(),
+        }
+    }
+
+    /// Size the worker pool with `settings` instead of the defaults
+    pub fn with_settings(mut self, settings: RunnerConfig) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    /// Get a handle to stop the daemon
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop.clone()
+    }
+
+    /// Get a handle to the config files of the running workflows
+    ///
+    /// A workflow is listed once it watches its paths, and unlisted when it
+    /// is stopped.
+    pub fn running_handle(&self) -> Arc<Mutex<BTreeSet<PathBuf>>> {
+        self.running.clone()
+    }
+
+    /// Snapshots of the running workflows' states by config file
+    pub fn states(&self) -> Vec<(PathBuf, WorkflowState)> {
+        self.workflows
+            .iter()
+            .map(|(path, w)| (path.clone(), w.shared.state.lock().unwrap().clone()))
+            .collect()
+    }
+
+    /// Run until stopped (blocking)
+    pub fn run(&mut self) -> Result<()> {
+        if !self.stop.is_running() {
+            return Ok(());
+        }
+        info!("Starting daemon for workflows in {}", self.dir.display());
+
+        let pool = WorkerPool::new(&self.settings)?;
+        // Recursion is set per watch in `watch_with`
+        let mut watcher = FsWatcher::new(false)?;
+        *self.stop.waker.lock().unwrap() = Some(watcher.injector());
+        let (changes, poll_thread) = spawn_poller(self.dir.clone(), watcher.injector(), self.stop.running.clone());
+
+        while self.stop.is_running() {
+            if let Some(event) = watcher.next_event() {
+                self.dispatch(&pool, event);
+            }
+            for change in changes.try_iter() {
+                match change {
+                    Change::Loaded(path, config) if self.workflows.contains_key(&path) => {
+                        self.reload(path, *config, &mut watcher);
+                    }
+                    Change::Loaded(path, config) => {
+                        if let Err(e) = self.start(path.clone(), *config, &pool, &mut watcher) {
+                            error!("Failed to start workflow {}: {}", path.display(), e);
+                        }
+                    }
+                    Change::Removed(path) => self.remove(&path, &mut watcher),
+                }
+            }
+            self.reap();
+        }
+
+        info!("Stopping daemon");
+        *self.stop.waker.lock().unwrap() = None;
+
+        // Cancel queued events and give in-flight actions the grace period
+        self.running.lock().unwrap().clear();
+        let stopping: Vec<_> = std::mem::take(&mut self.workflows).into_values().chain(self.retired.drain(..)).collect();
+        for workflow in &stopping {
+            workflow.shared.cancelled.store(true, Ordering::SeqCst);
+            workflow.running.store(false, Ordering::SeqCst);
+        }
+        let clean = pool.shut_down(Duration::from_millis(self.settings.shutdown_grace_ms));
+
+        let _ = poll_thread.join();
+        for workflow in stopping {
+            for thread in workflow.threads {
+                let _ = thread.join();
+            }
+            if let Err(e) = workflow.shared.finish(clean) {
+                error!("Failed to save state of workflow '{}': {}", workflow.config.workflow.name, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Queue an event for every workflow it belongs to
+    fn dispatch(&self, pool: &WorkerPool, event: Event) {
+        let targets: Vec<_> = match event.metadata.get("workflow").and_then(|w| w.as_str()) {
+            Some(origin) => self.workflows.get(Path::new(origin)).into_iter().collect(),
+            None => match crate::conditions::event_path(&event) {
+                Some(path) => self.workflows.values().filter(|w| w.shared.active().watches(path)).collect(),
+                None => Vec::new(),
+            },
+        };
+        if targets.is_empty() {
+            debug!("No workflow for event: {:?}", event.kind);
+        }
+        for workflow in targets {
+            workflow.shared.state.lock().unwrap().record_event();
+            pool.enqueue(&workflow.shared, event.clone(), &self.stop);
+        }
+    }
+
+    /// Start a workflow loaded from `path`
+    fn start(&mut self, path: PathBuf, config: WorkflowConfig, pool: &WorkerPool, watcher: &mut FsWatcher) -> Result<()> {
+        let name = config.workflow.name.clone();
+        info!("Starting workflow '{}' from {}", name, path.display());
+        let plugins = self.plugins_for(&path, &config)?;
+
+        // Files modified from here on are reported by the watcher
+        let started = SystemTime::now();
+        let state = Arc::new(Mutex::new(WorkflowState::new(&name)));
+        state.lock().unwrap().start();
+        let index = runner::load_index(&config)?;
+        let (catch_up, skip) = runner::catch_up(&config, index.as_ref())?;
+        let shared = Arc::new(Shared::new(&config, plugins.clone(), state, self.close_writes.clone(), index)?);
+        let dead_letters = config.runner.dead_letter_dir.as_deref().map(|dir| self.claim_dead_letters(dir)).transpose()?;
+
+        let running = Arc::new(AtomicBool::new(true));
+        self.workflows.insert(path.clone(), Running {
+            config: config.clone(),
+            shared: shared.clone(),
+            running: running.clone(),
+            threads: Vec::new(),
+            _dead_letters: dead_letters,
+        });
+        if let Err(e) = self.sync_watches(watcher) {
+            self.workflows.remove(&path);
+            return Err(e);
+        }
+
+        let injector = watcher.injector().for_workflow(path.display().to_string());
+        let mut threads = match &plugins {
+            Some(host) if !config.sources.is_empty() => {
+                sources::spawn_sources(&config.sources, host.clone(), injector.clone(), running.clone())
+            }
+            _ => {
+                if !config.sources.is_empty() {
+                    warn!("Workflow '{}' has sources but no plugins", name);
+                }
+                Vec::new()
+            }
+        };
+
+        for event in catch_up {
+            pool.enqueue(&shared, event, &self.stop);
+        }
+        if config.watch.iter().any(|w| w.initial_scan.is_some()) {
+            threads.push(scan::spawn_scan(&config.watch, started, skip, injector, running, shared.scan_dedup.clone()));
+        }
+        if let Some(workflow) = self.workflows.get_mut(&path) {
+            workflow.threads = threads;
+        }
+        self.running.lock().unwrap().insert(path);
+        Ok(())
+    }
+
+    /// Swap in a changed workflow file, keeping the old config if it cannot be applied
+    fn reload(&mut self, path: PathBuf, mut config: WorkflowConfig, watcher: &mut FsWatcher) {
+        let current = &self.workflows[&path].config;
+        let diff = ConfigDiff::between(current, &config);
+        if diff.is_empty() {
+            debug!("Workflow file saved without changes: {}", path.display());
+            return;
+        }
+
+        // Runner settings and sources stay as started
+        config.runner = current.runner.clone();
+        config.sources = current.sources.clone();
+        let active = self
+            .plugins_for(&path, &config)
+            .and_then(|plugins| Active::new(config.clone(), plugins));
+        let active = match active {
+            Ok(active) => active,
+            Err(e) => {
+                error!("Keeping the running config of {}: {}", path.display(), e);
+                return;
+            }
+        };
+
+        // Watches are registered before the rules change, and kept if they cannot be
+        let workflow = self.workflows.get_mut(&path).expect("workflow is running");
+        let previous = std::mem::replace(&mut workflow.config, config.clone());
+        if let Err(e) = self.sync_watches(watcher) {
+            error!("Keeping the running config of {}: {}", path.display(), e);
+            self.workflows.get_mut(&path).expect("workflow is running").config = previous;
+            return;
+        }
+
+        let workflow = self.workflows.get_mut(&path).expect("workflow is running");
+        *workflow.shared.active.write().unwrap() = Arc::new(active);
+        info!("Reloaded workflow '{}': {}", config.workflow.name, diff);
+
+        let added: Vec<_> = config.watch.iter().filter(|w| diff.watches_added.contains(&w.path)).cloned().collect();
+        if added.iter().any(|w| w.initial_scan.is_some()) {
+            let injector = watcher.injector().for_workflow(path.display().to_string());
+            let dedup = workflow.shared.scan_dedup.clone();
+            let scan = scan::spawn_scan(&added, SystemTime::now(), HashSet::new(), injector, workflow.running.clone(), dedup);
+            workflow.threads.push(scan);
+        }
+    }
+
+    /// Stop the workflow loaded from `path`
+    fn remove(&mut self, path: &Path, watcher: &mut FsWatcher) {
+        let Some(workflow) = self.workflows.remove(path) else {
+            return;
+        };
+        info!("Stopping workflow '{}': {} was removed", workflow.config.workflow.name, path.display());
+        workflow.shared.cancelled.store(true, Ordering::SeqCst);
+        workflow.running.store(false, Ordering::SeqCst);
+        self.retired.push(workflow);
+        if let Err(e) = self.sync_watches(watcher) {
+            error!("{}", e);
+        }
+        self.running.lock().unwrap().remove(path);
+    }
+
+    /// Finish stopped workflows once no worker holds their events
+    fn reap(&mut self) {
+        let (drained, waiting) = std::mem::take(&mut self.retired).into_iter().partition(|w: &Running| {
+            w.shared.is_idle() && w.threads.iter().all(JoinHandle::is_finished)
+        });
+        self.retired = waiting;
+        for workflow in drained {
+            if let Err(e) = workflow.shared.finish(true) {
+                error!("Failed to save state of workflow '{}': {}", workflow.config.workflow.name, e);
+            }
+        }
+    }
+
+    /// The plugin host for a workflow, rebuilt if it lacks the workflow's plugins
+    ///
+    /// Plugins are shared by id and version. A workflow that configures a
+    /// plugin version differently from a running one is rejected, as is one
+    /// whose plugins would leave a running workflow unable to switch to the
+    /// rebuilt host; a rebuilt host replaces the old one in every running
+    /// workflow.
+    fn plugins_for(&mut self, path: &Path, config: &WorkflowConfig) -> Result<Option<Arc<PluginHost>>> {
+        let mut wanted: Vec<PluginLoadConfig> = Vec::new();
+        let mut owners: HashMap<(String, Version), (&str, &PluginLoadConfig)> = HashMap::new();
+        let others = self.workflows.iter().filter(|(p, _)| *p != path).map(|(_, w)| &w.config);
+        for workflow in others.chain([config]) {
+            for plugin in workflow.plugins.iter().filter(|p| p.enabled) {
+                let (id, version) = PluginHost::identify(&plugin.to_plugin_config())
+                    .map_err(|e| rpa_core::Error::Config(format!("{}: {}", plugin.path.display(), e)))?;
+                match owners.get(&(id.clone(), version.clone())) {
+                    Some((owner, loaded)) if json(&loaded.sandbox) != json(&plugin.sandbox) => {
+                        return Err(rpa_core::Error::Config(format!(
+                            "Plugin '{}@{}' is configured differently by workflows '{}' and '{}'",
+                            id, version, owner, workflow.workflow.name
+                        )));
+                    }
+                    Some(_) => {}
+                    None => {
+                        owners.insert((id, version), (&workflow.workflow.name, plugin));
+                        wanted.push(plugin.clone());
+                    }
+                }
+            }
+        }
+
+        let missing = wanted.iter().any(|w| !self.plugin_configs.iter().any(|p| json(p) == json(w)));
+        if !missing {
+            if let Some(host) = &self.plugins {
+                check_plugin_actions(config, host)?;
+            }
+            return Ok(self.plugins.clone());
+        }
+
+        let host = Arc::new(crate::config::plugin_host(&wanted)?);
+        check_plugin_actions(config, &host)?;
+        let mut switched = Vec::new();
+        for (_, workflow) in self.workflows.iter().filter(|(p, _)| *p != path) {
+            let config = workflow.shared.active().config.clone();
+            let name = config.workflow.name.clone();
+            let active = Active::new(config, Some(host.clone())).map_err(|e| {
+                rpa_core::Error::Config(format!("Workflow '{}' cannot use the new plugins: {}", name, e))
+            })?;
+            switched.push((workflow, active));
+        }
+        for (workflow, active) in switched {
+            *workflow.shared.active.write().unwrap() = Arc::new(active);
+        }
+        info!("Loaded {} plugin(s)", host.plugin_count());
+        self.plugins = Some(host.clone());
+        self.plugin_configs = wanted;
+        Ok(Some(host))
+    }
+
+    /// Share the claim on a dead-letter directory between workflows using it
+    fn claim_dead_letters(&mut self, dir: &Path) -> Result<Arc<deadletter::Owner>> {
+        std::fs::create_dir_all(dir)?;
+        let key = dir.canonicalize()?;
+        if let Some(owner) = self.dead_letters.get(&key).and_then(Weak::upgrade) {
+            return Ok(owner);
+        }
+        let owner = Arc::new(deadletter::Owner::claim(&key)?);
+        self.dead_letters.retain(|_, owner| owner.strong_count() > 0);
+        self.dead_letters.insert(key, Arc::downgrade(&owner));
+        Ok(owner)
+    }
+
+    /// Register and unregister watch paths to match the running workflows
+    ///
+    /// New and changed registrations are made first. If one fails, the
+    /// previous registrations are restored and the error is returned.
+    fn sync_watches(&mut self, watcher: &mut FsWatcher) -> Result<()> {
+        let mut wanted: BTreeMap<PathBuf, WatchConfig> = BTreeMap::new();
+        for watch in self.workflows.values().flat_map(|w| &w.config.watch) {
+            wanted
+                .entry(watch.path.clone())
+                .and_modify(|registered| *registered = merge(registered, watch))
+                .or_insert_with(|| watch.clone());
+        }
+
+        let paths: BTreeSet<_> = self.registered.keys().chain(wanted.keys()).collect();
+        let changes: Vec<_> = paths
+            .into_iter()
+            .map(|path| (self.registered.get(path), wanted.get(path)))
+            .filter(|(before, after)| before.map(json) != after.map(json))
+            .collect();
+        runner::swap_watches(watcher, &changes)?;
+        self.registered = wanted;
+
+        if self.workflows.values().any(|w| w.config.uses_close_write()) {
+            watcher.track_close_writes(self.close_writes.clone());
+        }
+        Ok(())
+    }
+}
+
+/// Registration for a path watched by two workflows
+///
+/// Differing settings fall back to the narrowest watch covering both,
+/// without include or exclude globs; each workflow's own filter, depth and
+/// recursion are applied on dispatch.
+fn merge(registered: &WatchConfig, watch: &WatchConfig) -> WatchConfig {
+    if json(registered) == json(watch) {
+        return registered.clone();
+    }
+    // Levels below the root a watch reaches; `None` is unlimited
+    let reach = |w: &WatchConfig| if w.recursive { w.max_depth } else { Some(1) };
+    let recursive = registered.recursive || watch.recursive;
+    WatchConfig {
+        recursive,
+        include: Vec::new(),
+        exclude: Vec::new(),
+        max_depth: reach(registered).zip(reach(watch)).map(|(a, b)| a.max(b)).filter(|_| recursive),
+        follow_symlinks: registered.follow_symlinks || watch.follow_symlinks,
+        ..registered.clone()
+    }
+}
+
+/// Fail if a plugin action of `config` does not resolve against `host`
+fn check_plugin_actions(config: &WorkflowConfig, host: &PluginHost) -> Result<()> {
+    let problems = config.check_plugin_actions(host);
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(rpa_core::Error::Config(problems.join("; ")))
+    }
+}
+
+/// Workflow files in `dir`, sorted
+fn workflow_files(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut files: Vec<_> = entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_file())
+        .filter(|path| matches!(path.extension().and_then(|e| e.to_str()), Some("json" | "ncl")))
+        .collect();
+    files.sort();
+    files
+}
+
+/// Poll `dir` and report workflow files that appear, change or disappear
+///
+/// Files that fail to load are logged and not reported. The event loop
+/// is woken after every round, so stopped workflows get reaped.
+fn spawn_poller(dir: PathBuf, injector: EventInjector, running: Arc<AtomicBool>) -> (Receiver<Change>, JoinHandle<()>) {
+    let (tx, rx) = channel();
+    let thread = std::thread::spawn(move || {
+        let mut seen: HashMap<PathBuf, Vec<u8>> = HashMap::new();
+        while running.load(Ordering::SeqCst) {
+            let files = workflow_files(&dir);
+            let removed: Vec<_> = seen.keys().filter(|path| !files.contains(path)).cloned().collect();
+            for path in removed {
+                seen.remove(&path);
+                if tx.send(Change::Removed(path)).is_err() {
+                    return;
+                }
+            }
+
+            for path in files {
+                let Ok(content) = std::fs::read(&path) else {
+                    continue;
+                };
+                if seen.get(&path) == Some(&content) {
+                    continue;
+                }
+                seen.insert(path.clone(), content);
+                match WorkflowConfig::load(&path) {
+                    Ok(config) => {
+                        if tx.send(Change::Loaded(path, Box::new(config))).is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => error!("Ignoring invalid workflow file {}: {}", path.display(), e),
+                }
+            }
+
+            injector.wake();
+            std::thread::sleep(RELOAD_INTERVAL);
+        }
+    });
+    (rx, thread)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actions::ActionConfig;
+    use std::time::Instant;
+
+    fn workflow(name: &str, watch: &Path, pattern: &str, destination: &Path) -> WorkflowConfig {
+        let mut config = WorkflowConfig::example();
+        config.workflow.name = name.to_string();
+        config.watch[0].path = watch.to_path_buf();
+        config.rules[0].patterns = vec![pattern.to_string()];
+        config.rules[0].actions = vec![ActionConfig::Copy {
+            destination: destination.to_path_buf(),
+            overwrite: true,
+            preserve_structure: false,
+        }.into()];
+        config
+    }
+
+    fn wait_for(what: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            if what() {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        false
+    }
+
+    #[test]
+    fn test_workflows_share_watch_and_start_and_stop_live() {
+        let dir = tempfile::tempdir().unwrap();
+        let workflows = dir.path().join("workflows");
+        let inbox = dir.path().join("inbox");
+        std::fs::create_dir_all(&workflows).unwrap();
+        std::fs::create_dir_all(&inbox).unwrap();
+
+        let pdfs = workflow("pdfs", &inbox, "*.pdf", &dir.path().join("pdfs"));
+        let mut all = workflow("all", &inbox, "*", &dir.path().join("all"));
+        all.watch[0].exclude = vec!["*.tmp".to_string()];
+        std::fs::write(workflows.join("pdfs.json"), serde_json::to_vec(&pdfs).unwrap()).unwrap();
+        std::fs::write(workflows.join("all.json"), serde_json::to_vec(&all).unwrap()).unwrap();
+
+        let mut daemon = Daemon::new(&workflows).with_settings(RunnerConfig { workers: 2, ..Default::default() });
+        let stop = daemon.stop_handle();
+        let running = daemon.running_handle();
+        let handle = std::thread::spawn(move || {
+            daemon.run().unwrap();
+            daemon
+        });
+        assert!(wait_for(|| running.lock().unwrap().len() == 2));
+
+        std::fs::write(inbox.join("a.pdf"), b"pdf").unwrap();
+        std::fs::write(inbox.join("b.tmp"), b"tmp").unwrap();
+        assert!(wait_for(|| dir.path().join("pdfs/a.pdf").exists() && dir.path().join("all/a.pdf").exists()));
+        assert!(!dir.path().join("all/b.tmp").exists());
+
+        // Removing a file stops its workflow; the other keeps running
+        std::fs::remove_file(workflows.join("pdfs.json")).unwrap();
+        assert!(wait_for(|| !running.lock().unwrap().contains(&workflows.join("pdfs.json"))));
+        std::fs::write(inbox.join("c.pdf"), b"pdf").unwrap();
+        assert!(wait_for(|| dir.path().join("all/c.pdf").exists()));
+        assert!(!dir.path().join("pdfs/c.pdf").exists());
+
+        stop.stop();
+        let daemon = handle.join().unwrap();
+        assert!(daemon.states().is_empty());
+        assert!(running.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_merge_registers_narrowest_covering_watch() {
+        let mut flat = workflow("flat", Path::new("/in"), "*", Path::new("/out")).watch.remove(0);
+        flat.recursive = false;
+        let mut deep = flat.clone();
+        deep.recursive = true;
+        deep.max_depth = Some(3);
+        deep.include = vec!["*.pdf".to_string()];
+
+        let merged = merge(&flat, &deep);
+        assert!(merged.recursive);
+        assert_eq!(merged.max_depth, Some(3));
+        assert!(merged.include.is_empty());
+
+        deep.max_depth = None;
+        assert_eq!(merge(&flat, &deep).max_depth, None);
+        flat.include = vec!["*.txt".to_string()];
+        assert!(!merge(&flat, &flat.clone()).include.is_empty());
+    }
+
+    #[test]
+    fn test_non_recursive_workflow_ignores_nested_events_of_shared_watch() {
+        let mut config = workflow("flat", Path::new("/in"), "*", Path::new("/out"));
+        config.watch[0].recursive = false;
+        let active = Active::new(config, None).unwrap();
+        assert!(active.watches(Path::new("/in/a.pdf")));
+        assert!(!active.watches(Path::new("/in/sub/a.pdf")));
+    }
+
+    #[test]
+    fn test_conflicting_plugin_configs_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let wasm = dir.path().join("plugin.wasm");
+        std::fs::write(&wasm, wat::parse_str(r#"(module (memory (export "memory") 1))"#).unwrap()).unwrap();
+        let with_plugin = |name: &str, memory_limit: u64| {
+            let mut config = workflow(name, &dir.path().join("in"), "*", &dir.path().join("out"));
+            let plugin = serde_json::json!({ "path": wasm, "sandbox": { "memory_limit": memory_limit } });
+            config.plugins = vec![serde_json::from_value(plugin).unwrap()];
+            config
+        };
+
+        let pool = WorkerPool::new(&RunnerConfig::default()).unwrap();
+        let mut watcher = FsWatcher::new(false).unwrap();
+        let mut daemon = Daemon::new(dir.path());
+        daemon.start(dir.path().join("a.json"), with_plugin("a", 1 << 24), &pool, &mut watcher).unwrap();
+        daemon.start(dir.path().join("b.json"), with_plugin("b", 1 << 24), &pool, &mut watcher).unwrap();
+        let err = daemon.start(dir.path().join("c.json"), with_plugin("c", 1 << 25), &pool, &mut watcher).unwrap_err();
+        assert!(err.to_string().contains("configured differently by workflows 'a' and 'c'"), "{}", err);
+        assert_eq!(daemon.plugin_configs.len(), 1);
+        assert_eq!(daemon.running_handle().lock().unwrap().len(), 2);
+        pool.shut_down(Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_failed_reload_keeps_watches_and_shared_dead_letters() {
+        let dir = tempfile::tempdir().unwrap();
+        let (inbox, other) = (dir.path().join("inbox"), dir.path().join("other"));
+        std::fs::create_dir_all(&inbox).unwrap();
+        std::fs::create_dir_all(&other).unwrap();
+        std::fs::write(dir.path().join("file"), b"").unwrap();
+        let dead = dir.path().join("dead");
+        let with_dead_letters = |name: &str, watch: &Path| {
+            let mut config = workflow(name, watch, "*", &dir.path().join("out"));
+            config.runner.dead_letter_dir = Some(dead.clone());
+            config
+        };
+
+        let pool = WorkerPool::new(&RunnerConfig::default()).unwrap();
+        let mut watcher = FsWatcher::new(false).unwrap();
+        let mut daemon = Daemon::new(dir.path());
+        let (a, b) = (dir.path().join("a.json"), dir.path().join("b.json"));
+        daemon.start(a.clone(), with_dead_letters("a", &inbox), &pool, &mut watcher).unwrap();
+        daemon.start(b.clone(), with_dead_letters("b", &other), &pool, &mut watcher).unwrap();
+
+        // The unwatchable path is rejected after the inbox watch moved
+        let mut changed = with_dead_letters("a", &dir.path().join("moved"));
+        std::fs::create_dir_all(dir.path().join("moved")).unwrap();
+        let mut broken = changed.watch[0].clone();
+        broken.path = dir.path().join("file/sub");
+        changed.watch.push(broken);
+        daemon.reload(a.clone(), changed, &mut watcher);
+        assert_eq!(daemon.workflows[&a].config.watch[0].path, inbox);
+        let mut watched = watcher.watched_paths().to_vec();
+        watched.sort();
+        assert_eq!(watched, [inbox.clone(), other.clone()]);
+        assert_eq!(daemon.registered.keys().collect::<Vec<_>>(), [&inbox, &other]);
+
+        // Both workflows fill the directory; it stays claimed until the last one stops
+        daemon.remove(&a, &mut watcher);
+        daemon.retired.clear();
+        assert_eq!(deadletter::live_owner(&dead), Some(std::process::id()));
+        daemon.remove(&b, &mut watcher);
+        daemon.retired.clear();
+        assert_eq!(deadletter::live_owner(&dead), None);
+        assert!(watcher.watched_paths().is_empty());
+        pool.shut_down(Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,265 @@
+// SPDX-License-Identifier: MIT OR AGPL-3.0-or-later
+// SPDX-FileCopyrightText: 2024 Hyperpolymath <hyperpolymath@proton.me>
+
+//! Dead-letter directory for events whose actions kept failing
+//!
+//! The event's file is moved into the directory under a unique name and a
+//! JSON sidecar (`<name>.deadletter.json`) records the event, the rule and
+//! action that failed, the number of attempts and the error chain of each
+//! failure. Restoring a dead letter moves the file back and returns the
+//! original event so it can be processed again.
+//!
+//! A running workflow records its process id in `.runner.pid` inside the
+//! directory. Replaying while it runs would handle restored files twice,
+//! once by the replay and once by the runner watching their original path.
+
+use crate::actions::ActionConfig;
+use chrono::{DateTime, Utc};
+use rpa_core::{Error, Event, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Suffix of sidecar files
+pub const SIDECAR_SUFFIX: &str = ".deadletter.json";
+
+/// File naming the process of the workflow filling the directory
+const OWNER_FILE: &str = ".runner.pid";
+
+/// Everything known about a failed event
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    /// The event as it was handled
+    pub event: Event,
+    /// Rule whose action failed
+    pub rule: String,
+    /// The failed action
+    pub action: ActionConfig,
+    /// Attempts made
+    pub attempts: u32,
+    /// Error chain of each failed attempt, outermost error first
+    pub errors: Vec<Vec<String>>,
+    /// Where the file was before it was moved here
+    pub original_path: Option<PathBuf>,
+    /// Name of the file inside the dead-letter directory
+    pub stored_as: Option<String>,
+    pub failed_at: DateTime<Utc>,
+}
+
+impl DeadLetter {
+    /// Move `original_path` (if it still exists) into `dir` and write the sidecar
+    ///
+    /// Returns the sidecar path.
+    pub fn store(mut self, dir: &Path) -> Result<PathBuf> {
+        std::fs::create_dir_all(dir)?;
+
+        let name = match self.original_path.as_deref().and_then(Path::file_name) {
+            Some(file_name) => format!(
+                "{}-{}-{}",
+                self.failed_at.format("%Y%m%dT%H%M%S"),
+                short_id(&self.event.id),
+                file_name.to_string_lossy()
+            ),
+            None => format!("{}-{}", self.failed_at.format("%Y%m%dT%H%M%S"), short_id(&self.event.id)),
+        };
+
+        if let Some(original) = self.original_path.as_deref().filter(|p| p.is_file()) {
+            move_file(original, &dir.join(&name))?;
+            self.stored_as = Some(name.clone());
+        }
+
+        let sidecar = dir.join(format!("{}{}", name, SIDECAR_SUFFIX));
+        std::fs::write(&sidecar, serde_json::to_vec_pretty(&self)?)?;
+        Ok(sidecar)
+    }
+
+    /// Move the stored file back, delete the sidecar and return the event
+    pub fn restore(self, sidecar: &Path) -> Result<Event> {
+        let dir = sidecar.parent().unwrap_or(Path::new("."));
+        if let (Some(stored), Some(original)) = (&self.stored_as, &self.original_path) {
+            if original.exists() {
+                return Err(Error::Workflow(format!(
+                    "Cannot restore {}: the original path exists again",
+                    original.display()
+                )));
+            }
+            if let Some(parent) = original.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            move_file(&dir.join(stored), original)?;
+        }
+        std::fs::remove_file(sidecar)?;
+        Ok(self.event)
+    }
+}
+
+/// Dead letters in `dir`, oldest first, with their sidecar paths
+pub fn list(dir: &Path) -> Result<Vec<(PathBuf, DeadLetter)>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut letters = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.to_string_lossy().ends_with(SIDECAR_SUFFIX) {
+            continue;
+        }
+        let letter: DeadLetter = serde_json::from_slice(&std::fs::read(&path)?)?;
+        letters.push((path, letter));
+    }
+    letters.sort_by_key(|(_, letter)| letter.failed_at);
+    Ok(letters)
+}
+
+/// Directories claimed by this process
+static CLAIMED: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// Marks a dead-letter directory as used by a running workflow until dropped
+///
+/// A directory has one owner at a time. Workflows sharing a directory in
+/// one process share the owner.
+#[derive(Debug)]
+pub struct Owner {
+    dir: PathBuf,
+}
+
+impl Owner {
+    /// Record this process as the one filling `dir`
+    ///
+    /// Fails if `dir` is already claimed, by this process or a running one.
+    pub fn claim(dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let dir = dir.canonicalize()?;
+        let mut claimed = CLAIMED.lock().unwrap();
+        if claimed.contains(&dir) {
+            return Err(Error::Workflow(format!("Dead-letter directory {} is already in use", dir.display())));
+        }
+        if let Some(pid) = live_owner(&dir).filter(|pid| *pid != std::process::id()) {
+            return Err(Error::Workflow(format!(
+                "Dead-letter directory {} is in use by process {}",
+                dir.display(),
+                pid
+            )));
+        }
+        std::fs::write(dir.join(OWNER_FILE), std::process::id().to_string())?;
+        claimed.insert(dir.clone());
+        Ok(Self { dir })
+    }
+}
+
+impl Drop for Owner {
+    fn drop(&mut self) {
+        let mut claimed = CLAIMED.lock().unwrap_or_else(|e| e.into_inner());
+        claimed.remove(&self.dir);
+        let _ = std::fs::remove_file(self.dir.join(OWNER_FILE));
+    }
+}
+
+/// Process id of a running workflow filling `dir`, if any
+///
+/// Outside Linux a recorded process is assumed to be running; remove
+/// `.runner.pid` by hand if it was left behind by a crash.
+pub fn live_owner(dir: &Path) -> Option<u32> {
+    let pid: u32 = std::fs::read_to_string(dir.join(OWNER_FILE)).ok()?.trim().parse().ok()?;
+    let alive = !cfg!(target_os = "linux") || Path::new("/proc").join(pid.to_string()).exists();
+    alive.then_some(pid)
+}
+
+/// Messages of an error and each of its sources
+pub fn error_chain(error: &dyn std::error::Error) -> Vec<String> {
+    let mut chain = vec![error.to_string()];
+    let mut source = error.source();
+    while let Some(error) = source {
+        chain.push(error.to_string());
+        source = error.source();
+    }
+    chain
+}
+
+fn short_id(id: &str) -> &str {
+    &id[..id.len().min(8)]
+}
+
+/// Rename, falling back to copy and delete across filesystems
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if std::fs::rename(from, to).is_err() {
+        std::fs::copy(from, to)?;
+        std::fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rpa_core::EventKind;
+
+    #[test]
+    fn test_store_and_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let inbox = dir.path().join("inbox");
+        std::fs::create_dir(&inbox).unwrap();
+        let file = inbox.join("report.pdf");
+        std::fs::write(&file, b"%PDF").unwrap();
+
+        let event = Event::new(EventKind::FileCreated { path: file.clone() }, "inbox");
+        let io = std::io::Error::new(std::io::ErrorKind::NotConnected, "share unavailable");
+        let letter = DeadLetter {
+            event: event.clone(),
+            rule: "archive".to_string(),
+            action: ActionConfig::Copy {
+                destination: PathBuf::from("/mnt/nfs"),
+                overwrite: false,
+                preserve_structure: false,
+            },
+            attempts: 3,
+            errors: vec![error_chain(&Error::from(io))],
+            original_path: Some(file.clone()),
+            stored_as: None,
+            failed_at: Utc::now(),
+        };
+
+        let dead = dir.path().join("dead");
+        let sidecar = letter.store(&dead).unwrap();
+        assert!(!file.exists());
+
+        let letters = list(&dead).unwrap();
+        assert_eq!(letters.len(), 1);
+        let (path, letter) = letters.into_iter().next().unwrap();
+        assert_eq!(path, sidecar);
+        assert_eq!(letter.errors[0], vec!["IO error: share unavailable", "share unavailable"]);
+        assert!(dead.join(letter.stored_as.as_ref().unwrap()).exists());
+
+        let restored = letter.restore(&path).unwrap();
+        assert_eq!(restored.id, event.id);
+        assert!(file.exists());
+        assert!(list(&dead).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_live_owner() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(live_owner(dir.path()), None);
+
+        let owner = Owner::claim(dir.path()).unwrap();
+        assert_eq!(live_owner(dir.path()), Some(std::process::id()));
+        assert!(list(dir.path()).unwrap().is_empty());
+        assert!(Owner::claim(dir.path()).is_err());
+        drop(owner);
+        assert_eq!(live_owner(dir.path()), None);
+
+        // A crashed runner leaves its file behind; a running one keeps the directory
+        if cfg!(target_os = "linux") {
+            std::fs::write(dir.path().join(OWNER_FILE), u32::MAX.to_string()).unwrap();
+            assert_eq!(live_owner(dir.path()), None);
+            drop(Owner::claim(dir.path()).unwrap());
+
+            std::fs::write(dir.path().join(OWNER_FILE), "1").unwrap();
+            let err = Owner::claim(dir.path()).unwrap_err();
+            assert!(err.to_string().contains("in use by process 1"), "{}", err);
+            assert!(dir.path().join(OWNER_FILE).exists());
+        }
+    }
+}
@@ -0,0 +1,391 @@
+// SPDX-License-Identifier: MIT OR AGPL-3.0-or-later
+// SPDX-FileCopyrightText: 2024 Hyperpolymath <hyperpolymath@proton.me>
+
+//! Debouncing and coalescing of filesystem events
+//!
+//! Events for a path are held until no further event for that path has
+//! arrived for the watch's debounce window, or at most `max_wait_ms` after
+//! the first of them for a file that keeps changing. With coalescing
+//! enabled, the
+//! held events are reduced to the net change:
+//!
+//! | Pending   | Incoming  | Result       |
+//! |-----------|-----------|--------------|
+//! | created   | modified  | created      |
+//! | created   | deleted   | (nothing)    |
+//! | modified  | modified  | modified     |
+//! | modified  | deleted   | deleted      |
+//! | deleted   | created   | modified     |
+//! | created   | renamed   | created (at the new path) |
+//!
+//! A creation carried over by a rename merges with events already held
+//! for the new path like any other incoming event.
+//!
+//! Without coalescing, only repeated events of the same kind are merged.
+//! The debouncer takes explicit timestamps so it can be driven by a
+//! simulated event stream.
+
+use crate::conditions::event_path;
+use rpa_core::{Event, EventKind};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::mem::discriminant;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Debounce settings for a watch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DebounceConfig {
+    /// Quiet period before events for a path are delivered (0 disables)
+    #[serde(default = "default_window")]
+    pub window_ms: u64,
+    /// Reduce a burst of events to its net change
+    #[serde(default = "default_coalesce")]
+    pub coalesce: bool,
+    /// Longest time events for a path are held (0 for no limit)
+    #[serde(default = "default_max_wait")]
+    pub max_wait_ms: u64,
+}
+
+impl Default for DebounceConfig {
+    fn default() -> Self {
+        Self {
+            window_ms: default_window(),
+            coalesce: default_coalesce(),
+            max_wait_ms: default_max_wait(),
+        }
+    }
+}
+
+fn default_window() -> u64 {
+    250
+}
+
+fn default_coalesce() -> bool {
+    true
+}
+
+fn default_max_wait() -> u64 {
+    5_000
+}
+
+impl DebounceConfig {
+    /// Deliver events immediately
+    pub fn disabled() -> Self {
+        Self {
+            window_ms: 0,
+            coalesce: false,
+            max_wait_ms: 0,
+        }
+    }
+
+    /// Debounce window as a duration
+    pub fn window(&self) -> Duration {
+        Duration::from_millis(self.window_ms)
+    }
+}
+
+/// Events held for one path
+#[derive(Debug)]
+struct Pending {
+    events: Vec<Event>,
+    /// Order of the first event, used to deliver paths in arrival order
+    seq: u64,
+    deadline: Instant,
+    /// Latest deadline, set by `max_wait_ms`
+    flush_by: Option<Instant>,
+}
+
+/// Holds events until their path has been quiet for the debounce window
+#[derive(Debug, Default)]
+pub struct Debouncer {
+    pending: HashMap<PathBuf, Pending>,
+    ready: VecDeque<Event>,
+    seq: u64,
+}
+
+impl Debouncer {
+    /// Create an empty debouncer
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an event observed at `now`
+    pub fn push(&mut self, event: Event, config: DebounceConfig, now: Instant) {
+        let Some(path) = event_path(&event).map(Path::to_path_buf) else {
+            self.ready.push_back(event);
+            return;
+        };
+
+        if config.window_ms == 0 {
+            self.ready.push_back(event);
+            return;
+        }
+
+        let deadline = now + config.window();
+
+        // A rename carries a pending creation over to the new path
+        let mut event = event;
+        let mut carried = None;
+        if let (EventKind::FileRenamed { from, to }, true) = (&event.kind, config.coalesce) {
+            let (from, to) = (from.clone(), to.clone());
+            if let Some(previous) = self.pending.remove(&from) {
+                if previous.events.iter().all(|e| matches!(e.kind, EventKind::FileCreated { .. })) {
+                    event.kind = EventKind::FileCreated { path: to };
+                    carried = Some(previous.seq);
+                } else {
+                    self.pending.insert(from, previous);
+                }
+            }
+        }
+
+        match self.pending.get_mut(&path) {
+            Some(pending) => {
+                pending.deadline = pending.flush_by.map_or(deadline, |flush_by| deadline.min(flush_by));
+                pending.seq = carried.map_or(pending.seq, |seq| seq.min(pending.seq));
+                if config.coalesce {
+                    let last = pending.events.pop().expect("pending entries hold an event");
+                    pending.events.extend(coalesce(last, event));
+                    if pending.events.is_empty() {
+                        self.pending.remove(&path);
+                    }
+                } else if pending
+                    .events
+                    .last()
+                    .is_some_and(|last| discriminant(&last.kind) == discriminant(&event.kind))
+                {
+                    *pending.events.last_mut().unwrap() = event;
+                } else {
+                    pending.events.push(event);
+                }
+            }
+            None => {
+                let seq = carried.unwrap_or_else(|| {
+                    self.seq += 1;
+                    self.seq
+                });
+                let flush_by = (config.max_wait_ms > 0).then(|| now + Duration::from_millis(config.max_wait_ms));
+                self.pending.insert(
+                    path,
+                    Pending {
+                        events: vec![event],
+                        seq,
+                        deadline: flush_by.map_or(deadline, |flush_by| deadline.min(flush_by)),
+                        flush_by,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Take the next event that is ready at `now`
+    pub fn pop_ready(&mut self, now: Instant) -> Option<Event> {
+        if self.ready.is_empty() {
+            self.flush_due(now);
+        }
+        self.ready.pop_front()
+    }
+
+    /// Move every path whose window has elapsed to the ready queue
+    fn flush_due(&mut self, now: Instant) {
+        let mut due: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|(_, p)| p.deadline <= now)
+            .map(|(path, p)| (p.seq, path.clone()))
+            .collect();
+        due.sort();
+
+        for (_, path) in due {
+            if let Some(pending) = self.pending.remove(&path) {
+                self.ready.extend(pending.events);
+            }
+        }
+    }
+
+    /// Earliest time a held event becomes ready
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|p| p.deadline).min()
+    }
+
+    /// Whether no events are held or ready
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty() && self.ready.is_empty()
+    }
+}
+
+/// Reduce two consecutive events for one path to their net effect
+fn coalesce(previous: Event, next: Event) -> Option<Event> {
+    use EventKind::*;
+
+    let kind = match (&previous.kind, &next.kind) {
+        (FileCreated { path }, FileModified { .. }) => FileCreated { path: path.clone() },
+        (FileCreated { .. }, FileDeleted { .. }) => return None,
+        (FileDeleted { path }, FileCreated { .. }) => FileModified { path: path.clone() },
+        (FileRenamed { from, to }, FileModified { .. }) => FileRenamed {
+            from: from.clone(),
+            to: to.clone(),
+        },
+        _ => return Some(next),
+    };
+
+    Some(Event {
+        kind,
+        metadata: merge_metadata(previous.metadata, next.metadata),
+        ..next
+    })
+}
+
+/// Combine metadata of coalesced events; later keys win
+fn merge_metadata(previous: serde_json::Value, next: serde_json::Value) -> serde_json::Value {
+    match (previous, next) {
+        (serde_json::Value::Object(mut merged), serde_json::Value::Object(next)) => {
+            merged.extend(next);
+            serde_json::Value::Object(merged)
+        }
+        (previous, serde_json::Value::Null) => previous,
+        (_, next) => next,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(kind: fn(PathBuf) -> EventKind, path: &str) -> Event {
+        Event::new(kind(PathBuf::from(path)), "test")
+    }
+
+    fn created(path: PathBuf) -> EventKind {
+        EventKind::FileCreated { path }
+    }
+
+    fn modified(path: PathBuf) -> EventKind {
+        EventKind::FileModified { path }
+    }
+
+    fn deleted(path: PathBuf) -> EventKind {
+        EventKind::FileDeleted { path }
+    }
+
+    /// Feed `(offset_ms, event)` pairs and collect everything delivered
+    fn simulate(config: DebounceConfig, stream: Vec<(u64, Event)>) -> Vec<EventKind> {
+        let start = Instant::now();
+        let mut debouncer = Debouncer::new();
+        let mut delivered = Vec::new();
+
+        for (offset, event) in stream {
+            let now = start + Duration::from_millis(offset);
+            while let Some(ready) = debouncer.pop_ready(now) {
+                delivered.push(ready.kind);
+            }
+            debouncer.push(event, config, now);
+        }
+
+        while let Some(deadline) = debouncer.next_deadline() {
+            while let Some(ready) = debouncer.pop_ready(deadline) {
+                delivered.push(ready.kind);
+            }
+        }
+        delivered
+    }
+
+    #[test]
+    fn test_create_then_modify_is_one_create() {
+        let delivered = simulate(DebounceConfig::default(), vec![
+            (0, event(created, "/in/a.txt")),
+            (10, event(modified, "/in/a.txt")),
+            (20, event(modified, "/in/a.txt")),
+        ]);
+        assert_eq!(delivered, vec![created(PathBuf::from("/in/a.txt"))]);
+    }
+
+    #[test]
+    fn test_create_then_delete_is_nothing() {
+        let delivered = simulate(DebounceConfig::default(), vec![
+            (0, event(created, "/in/tmp.part")),
+            (5, event(modified, "/in/tmp.part")),
+            (30, event(deleted, "/in/tmp.part")),
+        ]);
+        assert!(delivered.is_empty());
+    }
+
+    #[test]
+    fn test_window_separates_bursts_and_keeps_path_order() {
+        let delivered = simulate(DebounceConfig::default(), vec![
+            (0, event(modified, "/in/a.txt")),
+            (1, event(modified, "/in/b.txt")),
+            (100, event(modified, "/in/a.txt")),
+            (1000, event(modified, "/in/a.txt")),
+        ]);
+        assert_eq!(delivered, vec![
+            modified(PathBuf::from("/in/a.txt")),
+            modified(PathBuf::from("/in/b.txt")),
+            modified(PathBuf::from("/in/a.txt")),
+        ]);
+    }
+
+    #[test]
+    fn test_rename_carries_creation() {
+        let delivered = simulate(DebounceConfig::default(), vec![
+            (0, event(created, "/in/a.tmp")),
+            (5, Event::new(EventKind::FileRenamed {
+                from: PathBuf::from("/in/a.tmp"),
+                to: PathBuf::from("/in/a.pdf"),
+            }, "test")),
+        ]);
+        assert_eq!(delivered, vec![created(PathBuf::from("/in/a.pdf"))]);
+    }
+
+    #[test]
+    fn test_rename_merges_with_events_held_for_new_path() {
+        let delivered = simulate(DebounceConfig::default(), vec![
+            (0, event(deleted, "/in/a.pdf")),
+            (1, event(created, "/in/a.tmp")),
+            (5, Event::new(EventKind::FileRenamed {
+                from: PathBuf::from("/in/a.tmp"),
+                to: PathBuf::from("/in/a.pdf"),
+            }, "test")),
+        ]);
+        assert_eq!(delivered, vec![modified(PathBuf::from("/in/a.pdf"))]);
+    }
+
+    #[test]
+    fn test_max_wait_flushes_continuous_writes() {
+        let config = DebounceConfig { max_wait_ms: 1000, ..Default::default() };
+        let stream = (0..20).map(|i| (i * 100, event(modified, "/in/growing.log"))).collect();
+        let delivered = simulate(config, stream);
+        assert_eq!(delivered, vec![modified(PathBuf::from("/in/growing.log")); 2]);
+    }
+
+    #[test]
+    fn test_without_coalescing_only_repeats_merge() {
+        let config = DebounceConfig { window_ms: 50, coalesce: false, ..Default::default() };
+        let delivered = simulate(config, vec![
+            (0, event(created, "/in/a.txt")),
+            (1, event(modified, "/in/a.txt")),
+            (2, event(modified, "/in/a.txt")),
+        ]);
+        assert_eq!(delivered, vec![
+            created(PathBuf::from("/in/a.txt")),
+            modified(PathBuf::from("/in/a.txt")),
+        ]);
+    }
+
+    #[test]
+    fn test_coalescing_keeps_move_flags() {
+        let moved_in = event(created, "/in/a.pdf").with_metadata(serde_json::json!({ "moved_from_outside": true }));
+        let merged = coalesce(moved_in, event(modified, "/in/a.pdf")).unwrap();
+        assert_eq!(merged.kind, created(PathBuf::from("/in/a.pdf")));
+        assert_eq!(merged.metadata["moved_from_outside"], true);
+    }
+
+    #[test]
+    fn test_disabled_delivers_immediately() {
+        let mut debouncer = Debouncer::new();
+        let now = Instant::now();
+        debouncer.push(event(modified, "/in/a.txt"), DebounceConfig::disabled(), now);
+        assert!(debouncer.pop_ready(now).is_some());
+        assert!(debouncer.is_empty());
+    }
+}
@@ -0,0 +1,113 @@
+// SPDX-License-Identifier: MIT OR AGPL-3.0-or-later
+// SPDX-FileCopyrightText: 2024 Hyperpolymath <hyperpolymath@proton.me>
+
+//! Suppression of events caused by the runner's own writes
+//!
+//! Paths an action reports in `ActionResult.affected_paths` are remembered
+//! for a window, with the size and modification time they had then.
+//! Events for those paths arriving within the window, while the file is
+//! unchanged, are the watcher seeing the runner's own work and are
+//! dropped, so a rule writing into a watched directory (or renaming in
+//! place) does not trigger itself again. A file changed by someone else in
+//! the meantime is handled as usual. Rules with `allow_retrigger` set are
+//! not tracked.
+
+use crate::conditions::event_path;
+use rpa_core::Event;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::debug;
+
+/// Size and modification time of a file, `None` if it does not exist
+type Signature = Option<(u64, SystemTime)>;
+
+/// Recently written paths, when they were written and what they looked like
+#[derive(Debug)]
+pub struct SelfWrites {
+    window: Duration,
+    written: Mutex<HashMap<PathBuf, (Instant, Signature)>>,
+}
+
+impl SelfWrites {
+    /// Track writes for `window` (zero disables tracking)
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            written: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Remember paths an action produced
+    pub fn record(&self, paths: &[PathBuf]) {
+        if self.window.is_zero() || paths.is_empty() {
+            return;
+        }
+        let now = Instant::now();
+        let mut written = self.written.lock().unwrap();
+        written.retain(|_, (at, _)| now.duration_since(*at) < self.window);
+        written.extend(paths.iter().map(|path| (path.clone(), (now, signature(path)))));
+    }
+
+    /// Whether an event is the echo of a recent write
+    pub fn suppresses(&self, event: &Event) -> bool {
+        let Some(path) = event_path(event) else {
+            return false;
+        };
+        let written = self.written.lock().unwrap();
+        let echo = written
+            .get(path)
+            .is_some_and(|(at, recorded)| at.elapsed() < self.window && *recorded == signature(path));
+        if echo {
+            debug!("Ignoring event caused by our own write: {}", path.display());
+        }
+        echo
+    }
+}
+
+fn signature(path: &Path) -> Signature {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.len(), metadata.modified().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rpa_core::EventKind;
+
+    #[test]
+    fn test_own_writes_suppressed_for_window() {
+        let writes = SelfWrites::new(Duration::from_millis(50));
+        let copied = PathBuf::from("/inbox/processed/a.pdf");
+        writes.record(std::slice::from_ref(&copied));
+
+        let created = Event::new(EventKind::FileCreated { path: copied.clone() }, "test");
+        let renamed = Event::new(EventKind::FileRenamed { from: "/inbox/a.pdf".into(), to: copied.clone() }, "test");
+        let other = Event::new(EventKind::FileCreated { path: "/inbox/b.pdf".into() }, "test");
+        assert!(writes.suppresses(&created));
+        assert!(writes.suppresses(&renamed));
+        assert!(!writes.suppresses(&other));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!writes.suppresses(&created));
+
+        let disabled = SelfWrites::new(Duration::ZERO);
+        disabled.record(&[copied]);
+        assert!(!disabled.suppresses(&created));
+    }
+
+    #[test]
+    fn test_later_changes_not_suppressed() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.txt");
+        std::fs::write(&file, b"ours").unwrap();
+        let writes = SelfWrites::new(Duration::from_secs(5));
+        writes.record(std::slice::from_ref(&file));
+
+        let modified = Event::new(EventKind::FileModified { path: file.clone() }, "test");
+        assert!(writes.suppresses(&modified));
+        std::fs::write(&file, b"someone else's").unwrap();
+        assert!(!writes.suppresses(&modified));
+    }
+}
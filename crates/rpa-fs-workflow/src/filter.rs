@@ -0,0 +1,142 @@
+// SPDX-License-Identifier: MIT OR AGPL-3.0-or-later
+// SPDX-FileCopyrightText: 2024 Hyperpolymath <hyperpolymath@proton.me>
+
+//! Per-watch path filters
+//!
+//! Paths are matched relative to the watch root. A glob without a `/`
+//! matches the file name at any depth; a glob with a `/` matches the
+//! whole relative path. Excludes win over includes, and an empty include
+//! list admits everything.
+
+use glob::{MatchOptions, Pattern};
+use rpa_core::{Error, Result};
+use std::path::{Component, Path};
+
+/// Include/exclude globs, depth limit and symlink policy for one watch
+#[derive(Debug, Clone, Default)]
+pub struct WatchFilter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    max_depth: Option<usize>,
+    follow_symlinks: bool,
+}
+
+impl WatchFilter {
+    /// Compile a filter; fails on an invalid glob
+    pub fn new(include: &[String], exclude: &[String], max_depth: Option<usize>, follow_symlinks: bool) -> Result<Self> {
+        Ok(Self {
+            include: compile(include)?,
+            exclude: compile(exclude)?,
+            max_depth,
+            follow_symlinks,
+        })
+    }
+
+    /// Whether events for `path` under `root` should be delivered
+    pub fn allows(&self, root: &Path, path: &Path) -> bool {
+        let Ok(relative) = path.strip_prefix(root) else {
+            return true;
+        };
+
+        if self.max_depth.is_some_and(|max| relative.components().count() > max) {
+            return false;
+        }
+        if !self.follow_symlinks && through_symlink(root, relative) {
+            return false;
+        }
+
+        if self.exclude.iter().any(|p| matches(p, relative)) {
+            return false;
+        }
+        self.include.is_empty() || self.include.iter().any(|p| matches(p, relative))
+    }
+}
+
+fn compile(globs: &[String]) -> Result<Vec<Pattern>> {
+    globs
+        .iter()
+        .map(|glob| Pattern::new(glob).map_err(|e| Error::InvalidPattern(format!("{}: {}", glob, e))))
+        .collect()
+}
+
+fn matches(pattern: &Pattern, relative: &Path) -> bool {
+    let options = MatchOptions {
+        require_literal_separator: true,
+        ..MatchOptions::new()
+    };
+    if pattern.as_str().contains('/') {
+        pattern.matches_path_with(relative, options)
+    } else {
+        relative
+            .file_name()
+            .is_some_and(|name| pattern.matches_with(&name.to_string_lossy(), options))
+    }
+}
+
+/// Whether any directory between `root` and the file is a symlink
+fn through_symlink(root: &Path, relative: &Path) -> bool {
+    let mut current = root.to_path_buf();
+    let Some(parent) = relative.parent() else {
+        return false;
+    };
+    parent.components().any(|component| {
+        if let Component::Normal(name) = component {
+            current.push(name);
+        }
+        current.symlink_metadata().is_ok_and(|m| m.file_type().is_symlink())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(include: &[&str], exclude: &[&str], max_depth: Option<usize>) -> WatchFilter {
+        let strings = |globs: &[&str]| globs.iter().map(|g| g.to_string()).collect::<Vec<_>>();
+        WatchFilter::new(&strings(include), &strings(exclude), max_depth, true).unwrap()
+    }
+
+    #[test]
+    fn test_include_exclude() {
+        let root = Path::new("/inbox");
+        let f = filter(&["*.pdf", "scans/*.tiff"], &["*.tmp.pdf", "archive/**"], None);
+
+        assert!(f.allows(root, Path::new("/inbox/a.pdf")));
+        assert!(f.allows(root, Path::new("/inbox/deep/er/a.pdf")));
+        assert!(f.allows(root, Path::new("/inbox/scans/b.tiff")));
+        assert!(!f.allows(root, Path::new("/inbox/other/b.tiff")));
+        assert!(!f.allows(root, Path::new("/inbox/c.tmp.pdf")));
+        assert!(!f.allows(root, Path::new("/inbox/archive/old.pdf")));
+        assert!(!f.allows(root, Path::new("/inbox/notes.txt")));
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let root = Path::new("/inbox");
+        let f = filter(&[], &[], Some(2));
+        assert!(f.allows(root, Path::new("/inbox/a.txt")));
+        assert!(f.allows(root, Path::new("/inbox/x/a.txt")));
+        assert!(!f.allows(root, Path::new("/inbox/x/y/a.txt")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinked_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(target.path(), dir.path().join("linked")).unwrap();
+        std::fs::create_dir(dir.path().join("real")).unwrap();
+
+        let strict = WatchFilter::new(&[], &[], None, false).unwrap();
+        assert!(strict.allows(dir.path(), &dir.path().join("real/a.txt")));
+        assert!(!strict.allows(dir.path(), &dir.path().join("linked/a.txt")));
+
+        let following = WatchFilter::new(&[], &[], None, true).unwrap();
+        assert!(following.allows(dir.path(), &dir.path().join("linked/a.txt")));
+    }
+
+    #[test]
+    fn test_invalid_glob() {
+        assert!(WatchFilter::new(&["[".to_string()], &[], None, false).is_err());
+    }
+}
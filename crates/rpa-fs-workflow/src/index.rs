@@ -0,0 +1,266 @@
+// SPDX-License-Identifier: MIT OR AGPL-3.0-or-later
+// SPDX-FileCopyrightText: 2024 Hyperpolymath <hyperpolymath@proton.me>
+
+//! Persistent index of processed files
+//!
+//! For every file the runner has handled, the index records its size,
+//! modification time and SHA-256, plus the rules and actions applied. It
+//! is used to:
+//!
+//! - skip created/modified events for files whose content is unchanged,
+//!   which also absorbs duplicate notifications
+//! - reconcile on startup: indexed files that were modified or deleted
+//!   while the runner was down produce modified/deleted events, and files
+//!   it does not know produce created events
+
+use chrono::{DateTime, Utc};
+use rpa_core::{Event, EventKind, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::config::WatchConfig;
+use crate::scan::{self, InitialScanConfig};
+use std::collections::{BTreeMap, HashSet};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tracing::debug;
+
+/// Identity of a file's content
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fingerprint {
+    pub size: u64,
+    pub mtime: DateTime<Utc>,
+    pub sha256: String,
+}
+
+impl Fingerprint {
+    /// Fingerprint the file at `path`
+    ///
+    /// If `known` has the same size and mtime its hash is reused instead of
+    /// reading the file again.
+    pub fn of(path: &Path, known: Option<&Fingerprint>) -> std::io::Result<Self> {
+        let metadata = std::fs::metadata(path)?;
+        let size = metadata.len();
+        let mtime = DateTime::<Utc>::from(metadata.modified()?);
+
+        if let Some(known) = known.filter(|k| k.size == size && k.mtime == mtime) {
+            return Ok(known.clone());
+        }
+
+        let mut file = std::fs::File::open(path)?;
+        let mut hasher = Sha256::new();
+        let mut buffer = [0u8; 64 * 1024];
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+        }
+        let sha256 = hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect();
+
+        Ok(Self { size, mtime, sha256 })
+    }
+
+    /// Whether both fingerprints describe the same content
+    pub fn same_content(&self, other: &Fingerprint) -> bool {
+        self.size == other.size && self.sha256 == other.sha256
+    }
+}
+
+/// What the runner did with a file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexEntry {
+    #[serde(flatten)]
+    pub fingerprint: Fingerprint,
+    /// Rules whose actions ran
+    pub rules: Vec<String>,
+    /// Actions that succeeded
+    pub actions: Vec<String>,
+    pub processed_at: DateTime<Utc>,
+}
+
+/// Processed files by path
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProcessedIndex {
+    files: BTreeMap<PathBuf, IndexEntry>,
+}
+
+impl ProcessedIndex {
+    /// Load an index; a missing file gives an empty index
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = std::fs::read(path)?;
+        Ok(serde_json::from_slice(&content)?)
+    }
+
+    /// Save the index, replacing the file atomically
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let temp = path.with_extension("tmp");
+        std::fs::write(&temp, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(&temp, path)?;
+        Ok(())
+    }
+
+    /// Entry for a path
+    pub fn get(&self, path: &Path) -> Option<&IndexEntry> {
+        self.files.get(path)
+    }
+
+    /// Number of indexed files
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Whether no files are indexed
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Paths of all indexed files
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.files.keys().map(PathBuf::as_path)
+    }
+
+    /// Record that a file was processed
+    pub fn record(&mut self, path: &Path, fingerprint: Fingerprint, rules: Vec<String>, actions: Vec<String>) {
+        self.files.insert(path.to_path_buf(), IndexEntry {
+            fingerprint,
+            rules,
+            actions,
+            processed_at: Utc::now(),
+        });
+    }
+
+    /// Update the fingerprint of a file whose content did not change
+    pub fn touch(&mut self, path: &Path, fingerprint: Fingerprint) {
+        if let Some(entry) = self.files.get_mut(path) {
+            entry.fingerprint = fingerprint;
+        }
+    }
+
+    /// Drop a deleted file
+    pub fn forget(&mut self, path: &Path) -> Option<IndexEntry> {
+        self.files.remove(path)
+    }
+
+    /// Carry an entry over to a renamed file
+    pub fn rename(&mut self, from: &Path, to: &Path) {
+        if let Some(entry) = self.files.remove(from) {
+            self.files.insert(to.to_path_buf(), entry);
+        }
+    }
+
+    /// Events for files under `watches` that changed while offline
+    ///
+    /// Files the index does not know are reported as created, except under
+    /// watches with an initial scan, which reports them itself, and those in
+    /// `skip`.
+    pub fn reconcile(&self, watches: &[WatchConfig], skip: &HashSet<PathBuf>) -> Vec<Event> {
+        let changed = self
+            .files
+            .iter()
+            .filter(|(path, _)| watches.iter().any(|watch| path.starts_with(&watch.path)))
+            .filter_map(|(path, entry)| {
+                let kind = match std::fs::metadata(path) {
+                    Err(_) => EventKind::FileDeleted { path: path.clone() },
+                    Ok(metadata) => {
+                        let mtime = metadata.modified().ok().map(DateTime::<Utc>::from);
+                        if metadata.len() == entry.fingerprint.size && mtime == Some(entry.fingerprint.mtime) {
+                            return None;
+                        }
+                        EventKind::FileModified { path: path.clone() }
+                    }
+                };
+                Some((path.clone(), kind))
+            });
+
+        let now = SystemTime::now();
+        let created = watches
+            .iter()
+            .filter(|watch| watch.initial_scan.is_none())
+            .flat_map(|watch| scan::collect(watch, &InitialScanConfig::default(), now, skip))
+            .filter(|path| !self.files.contains_key(path))
+            .map(|path| (path.clone(), EventKind::FileCreated { path }));
+
+        changed
+            .chain(created)
+            .map(|(path, kind)| {
+                debug!("Changed while offline: {:?}", kind);
+                let source = crate::rename::parent_of(&path);
+                Event::new(kind, source).with_metadata(serde_json::json!({ "offline_change": true }))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::WorkflowConfig;
+
+    #[test]
+    fn test_fingerprint_reuses_hash_until_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.txt");
+        std::fs::write(&file, b"hello").unwrap();
+
+        let first = Fingerprint::of(&file, None).unwrap();
+        assert_eq!(first.sha256, "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824");
+
+        let stale = Fingerprint { sha256: "stale".to_string(), ..first.clone() };
+        assert_eq!(Fingerprint::of(&file, Some(&stale)).unwrap().sha256, "stale");
+
+        std::fs::write(&file, b"hello, world").unwrap();
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(5);
+        std::fs::File::options().write(true).open(&file).unwrap().set_modified(later).unwrap();
+        assert!(!Fingerprint::of(&file, Some(&first)).unwrap().same_content(&first));
+    }
+
+    #[test]
+    fn test_reconcile_and_persist() {
+        let dir = tempfile::tempdir().unwrap();
+        let kept = dir.path().join("kept.txt");
+        let edited = dir.path().join("edited.txt");
+        let removed = dir.path().join("removed.txt");
+        let mut index = ProcessedIndex::default();
+        for path in [&kept, &edited, &removed] {
+            std::fs::write(path, b"v1").unwrap();
+            let fingerprint = Fingerprint::of(path, None).unwrap();
+            index.record(path, fingerprint, vec!["rule".to_string()], vec!["copy".to_string()]);
+        }
+
+        let file = dir.path().join("state/index.json");
+        index.save(&file).unwrap();
+        let index = ProcessedIndex::load(&file).unwrap();
+        assert_eq!(index.len(), 3);
+        assert_eq!(index.get(&kept).unwrap().actions, vec!["copy"]);
+
+        std::fs::write(&edited, b"version two").unwrap();
+        std::fs::remove_file(&removed).unwrap();
+        let added = dir.path().join("added.txt");
+        std::fs::write(&added, b"new").unwrap();
+        let mut watch = WorkflowConfig::example().watch.remove(0);
+        watch.path = dir.path().to_path_buf();
+        let skip = HashSet::from([file.clone()]);
+        let mut events: Vec<_> = index.reconcile(std::slice::from_ref(&watch), &skip).into_iter().map(|e| e.kind).collect();
+        events.sort_by_key(|kind| format!("{:?}", kind));
+        assert_eq!(events, vec![
+            EventKind::FileCreated { path: added.clone() },
+            EventKind::FileDeleted { path: removed },
+            EventKind::FileModified { path: edited.clone() },
+        ]);
+
+        // An initial scan reports new files itself
+        watch.initial_scan = Some(InitialScanConfig::default());
+        let events = index.reconcile(std::slice::from_ref(&watch), &skip);
+        assert!(!events.iter().any(|e| e.kind == EventKind::FileCreated { path: added.clone() }));
+        watch.path = PathBuf::from("/elsewhere");
+        assert!(index.reconcile(&[watch], &skip).is_empty());
+    }
+}
@@ -7,12 +7,33 @@
 //! - Watch directories for file changes
 //! - Execute actions based on file events (create, modify, delete, rename)
 //! - Supported actions: copy, move, archive, delete, rename patterns
+//! - Plugin-defined event sources feeding the same rules
+//! - Many workflows in one process sharing a watcher, plugins and workers
 
 pub mod actions;
+pub mod attributes;
+pub mod conditions;
 pub mod config;
+pub mod content;
+pub mod daemon;
+pub mod deadletter;
+pub mod debounce;
+pub mod feedback;
+pub mod filter;
+pub mod index;
 pub mod watcher;
+pub mod patterns;
+pub mod pipeline;
+pub mod reload;
+pub mod rename;
+pub mod retry;
 pub mod runner;
+pub mod scan;
+pub mod sources;
+pub mod stability;
+pub mod template;
 
 pub use config::WorkflowConfig;
-pub use runner::WorkflowRunner;
+pub use daemon::Daemon;
+pub use runner::{StopHandle, WorkflowRunner};
 pub use watcher::FsWatcher;
@@ -7,9 +7,15 @@
 //!
 //! # Examples
 //!
-//! Run a workflow from a config file:
+//! Run a workflow from a config file, reloading it when it changes:
 //! ```bash
 //! rpa-fs run workflow.json
+//! rpa-fs run --no-reload workflow.json
+//! ```
+//!
+//! Run every workflow in a directory, sharing one watcher and worker pool:
+//! ```bash
+//! rpa-fs daemon /etc/rpa-fs/workflows
 //! ```
 //!
 //! Generate an example configuration:
@@ -21,11 +27,32 @@
 //! ```bash
 //! rpa-fs validate workflow.json
 //! ```
+//!
+//! Manage the local plugin repository:
+//! ```bash
+//! rpa-fs plugin install tagger.wasm
+//! rpa-fs plugin list
+//! rpa-fs plugin inspect tagger@1.2.0
+//! rpa-fs plugin remove tagger --version 1.2.0
+//! rpa-fs plugin test tagger.wasm cases.json
+//! ```
+//!
+//! Inspect and replay events whose actions failed after all retries:
+//! ```bash
+//! rpa-fs dead-letter list workflow.json
+//! rpa-fs dead-letter replay workflow.json
+//! ```
 
 use clap::{Parser, Subcommand};
-use rpa_fs_workflow::{WorkflowConfig, WorkflowRunner};
+use rpa_fs_workflow::deadletter;
+use rpa_fs_workflow::config::RunnerConfig;
+use rpa_fs_workflow::{Daemon, WorkflowConfig, WorkflowRunner};
+use rpa_plugin::harness::{load_cases, PluginTestHarness};
+use rpa_plugin::repository::{IndexEntry, PluginInspection};
+use rpa_plugin::{PluginMetadata, PluginRepository};
+use semver::Version;
 use std::path::PathBuf;
-use tracing::{error, info, Level};
+use tracing::{error, info, warn, Level};
 use tracing_subscriber::FmtSubscriber;
 
 #[derive(Parser)]
@@ -50,6 +77,18 @@ enum Commands {
     Run {
         /// Path to the workflow configuration file (.json or .ncl)
         config: PathBuf,
+        /// Do not reload the configuration when the file changes
+        #[arg(long)]
+        no_reload: bool,
+    },
+
+    /// Run every workflow in a directory, starting and stopping them as files come and go
+    Daemon {
+        /// Directory of workflow configuration files (.json or .ncl)
+        dir: PathBuf,
+        /// Number of workers shared by all workflows (default: available CPUs)
+        #[arg(long)]
+        workers: Option<usize>,
     },
 
     /// Generate an example configuration file
@@ -64,6 +103,76 @@ enum Commands {
         /// Path to the configuration file to validate
         config: PathBuf,
     },
+
+    /// Manage the local plugin repository
+    Plugin {
+        /// Repository directory (default: ~/.local/share/rpa-elysium/plugins)
+        #[arg(long, global = true)]
+        repo: Option<PathBuf>,
+
+        #[command(subcommand)]
+        command: PluginCommands,
+    },
+
+    /// Inspect and replay dead-lettered events
+    DeadLetter {
+        #[command(subcommand)]
+        command: DeadLetterCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum DeadLetterCommands {
+    /// List dead letters in the workflow's dead-letter directory
+    List {
+        /// Path to the workflow configuration file
+        config: PathBuf,
+    },
+
+    /// Move dead-lettered files back and run the workflow's rules on them again
+    ///
+    /// Refused while the workflow is running, which would handle them too.
+    Replay {
+        /// Path to the workflow configuration file
+        config: PathBuf,
+        /// Sidecar or stored file names to replay (default: all)
+        entries: Vec<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum PluginCommands {
+    /// Install a plugin file into the repository
+    Install {
+        /// Path to the plugin WASM file
+        file: PathBuf,
+    },
+
+    /// List installed plugins
+    List,
+
+    /// Remove an installed plugin
+    Remove {
+        /// Plugin ID
+        id: String,
+        /// Only remove this version (default: all versions)
+        #[arg(long)]
+        version: Option<Version>,
+    },
+
+    /// Show exports, metadata, permissions and config schemas of a plugin
+    Inspect {
+        /// Installed plugin (`id` or `id@version`) or path to a WASM file
+        target: String,
+    },
+
+    /// Run plugin actions against test cases
+    Test {
+        /// Path to the plugin WASM file
+        plugin: PathBuf,
+        /// Path to a JSON file with an array of test cases
+        cases: PathBuf,
+    },
 }
 
 fn main() {
@@ -84,9 +193,12 @@ fn main() {
         .init();
 
     let result = match cli.command {
-        Commands::Run { config } => run_workflow(config),
+        Commands::Run { config, no_reload } => run_workflow(config, !no_reload),
+        Commands::Daemon { dir, workers } => run_daemon(dir, workers),
         Commands::Init { output } => init_workflow(output),
         Commands::Validate { config } => validate_workflow(config),
+        Commands::Plugin { repo, command } => plugin_command(repo, command),
+        Commands::DeadLetter { command } => dead_letter_command(command),
     };
 
     if let Err(e) = result {
@@ -95,7 +207,7 @@ fn main() {
     }
 }
 
-fn run_workflow(config_path: PathBuf) -> anyhow::Result<()> {
+fn run_workflow(config_path: PathBuf, reload: bool) -> anyhow::Result<()> {
     info!("Loading workflow from: {}", config_path.display());
 
     let config = WorkflowConfig::load(&config_path)?;
@@ -107,13 +219,15 @@ fn run_workflow(config_path: PathBuf) -> anyhow::Result<()> {
     );
 
     let mut runner = WorkflowRunner::new(config);
+    if reload {
+        runner = runner.with_config_file(&config_path);
+    }
     let stop_handle = runner.stop_handle();
 
     // Set up Ctrl+C handler
-    let stop_handle_clone = stop_handle.clone();
     ctrlc::set_handler(move || {
         info!("Received interrupt signal, stopping...");
-        stop_handle_clone.store(false, std::sync::atomic::Ordering::SeqCst);
+        stop_handle.stop();
     })
     .expect("Error setting Ctrl-C handler");
 
@@ -121,6 +235,28 @@ fn run_workflow(config_path: PathBuf) -> anyhow::Result<()> {
     Ok(())
 }
 
+fn run_daemon(dir: PathBuf, workers: Option<usize>) -> anyhow::Result<()> {
+    if !dir.is_dir() {
+        anyhow::bail!("Not a directory: {}", dir.display());
+    }
+
+    let mut settings = RunnerConfig::default();
+    if let Some(workers) = workers {
+        settings.workers = workers;
+    }
+    let mut daemon = Daemon::new(dir).with_settings(settings);
+    let stop_handle = daemon.stop_handle();
+
+    ctrlc::set_handler(move || {
+        info!("Received interrupt signal, stopping...");
+        stop_handle.stop();
+    })
+    .expect("Error setting Ctrl-C handler");
+
+    daemon.run()?;
+    Ok(())
+}
+
 fn init_workflow(output_path: PathBuf) -> anyhow::Result<()> {
     if output_path.exists() {
         anyhow::bail!(
@@ -145,6 +281,19 @@ fn validate_workflow(config_path: PathBuf) -> anyhow::Result<()> {
 
     let config = WorkflowConfig::load(&config_path)?;
 
+    let host = config.plugin_host()?;
+    let problems = config.check_plugin_actions(&host);
+    if !problems.is_empty() {
+        for problem in &problems {
+            error!("  {}", problem);
+        }
+        anyhow::bail!("{} plugin action(s) could not be resolved", problems.len());
+    }
+
+    for warning in config.warnings() {
+        warn!("  {}", warning);
+    }
+
     info!("Configuration is valid!");
     info!("  Workflow: {}", config.workflow.name);
     if let Some(desc) = &config.workflow.description {
@@ -152,6 +301,8 @@ fn validate_workflow(config_path: PathBuf) -> anyhow::Result<()> {
     }
     info!("  Watch paths: {}", config.watch.len());
     info!("  Rules: {}", config.rules.len());
+    info!("  Plugins: {}", config.plugins.len());
+    info!("  Sources: {}", config.sources.len());
 
     for rule in &config.rules {
         info!(
@@ -164,3 +315,205 @@ fn validate_workflow(config_path: PathBuf) -> anyhow::Result<()> {
 
     Ok(())
 }
+
+fn plugin_command(repo: Option<PathBuf>, command: PluginCommands) -> anyhow::Result<()> {
+    let root = repo.unwrap_or_else(PluginRepository::default_root);
+
+    match command {
+        PluginCommands::Install { file } => {
+            let mut repository = PluginRepository::open(&root)?;
+            let entry = repository.install(&file)?;
+            info!(
+                "Installed {}@{} ({:?}) into {}",
+                entry.id,
+                entry.version,
+                entry.signature,
+                root.display()
+            );
+        }
+        PluginCommands::List => {
+            let repository = PluginRepository::open(&root)?;
+            if repository.entries().next().is_none() {
+                info!("No plugins installed in {}", root.display());
+            }
+            for entry in repository.entries() {
+                info!(
+                    "{}@{}  {}  {:?}  {} action(s)",
+                    entry.id,
+                    entry.version,
+                    entry.sha256.get(..12).unwrap_or(&entry.sha256),
+                    entry.signature,
+                    entry.exports.len()
+                );
+            }
+        }
+        PluginCommands::Remove { id, version } => {
+            let mut repository = PluginRepository::open(&root)?;
+            let removed = repository.remove(&id, version.as_ref())?;
+            for entry in removed {
+                info!("Removed {}@{}", entry.id, entry.version);
+            }
+        }
+        PluginCommands::Inspect { target } => {
+            let path = PathBuf::from(&target);
+            if path.is_file() {
+                let inspection = PluginInspection::from_file(&path)?;
+                if !inspection.has_manifest {
+                    info!("(no rpa-plugin-metadata section; metadata derived from file name)");
+                }
+                print_plugin_details(
+                    &inspection.metadata,
+                    &inspection.exports,
+                    &inspection.sha256,
+                    inspection.size,
+                );
+            } else {
+                let repository = PluginRepository::open(&root)?;
+                let (id, version) = match target.split_once('@') {
+                    Some((id, version)) => (id, Some(Version::parse(version)?)),
+                    None => (target.as_str(), None),
+                };
+                let entry: &IndexEntry = repository
+                    .get(id, version.as_ref())
+                    .ok_or_else(|| anyhow::anyhow!("Plugin not installed: {}", target))?;
+                print_plugin_details(&entry.manifest, &entry.exports, &entry.sha256, entry.size);
+                info!("  Signature: {:?}", entry.signature);
+                info!("  Installed: {}", entry.installed_at.to_rfc3339());
+                info!("  File: {}", repository.entry_path(entry).display());
+            }
+        }
+        PluginCommands::Test { plugin, cases } => test_plugin(plugin, cases)?,
+    }
+
+    Ok(())
+}
+
+fn dead_letter_command(command: DeadLetterCommands) -> anyhow::Result<()> {
+    let config_path = match &command {
+        DeadLetterCommands::List { config } | DeadLetterCommands::Replay { config, .. } => config,
+    };
+    let config = WorkflowConfig::load(config_path)?;
+    let dir = config
+        .runner
+        .dead_letter_dir
+        .clone()
+        .ok_or_else(|| anyhow::anyhow!("Workflow has no runner.dead_letter_dir configured"))?;
+    let letters = deadletter::list(&dir)?;
+
+    match command {
+        DeadLetterCommands::List { .. } => {
+            if letters.is_empty() {
+                info!("No dead letters in {}", dir.display());
+            }
+            for (sidecar, letter) in &letters {
+                let name = sidecar.file_name().unwrap_or_default().to_string_lossy();
+                info!(
+                    "{}  rule '{}'  {} attempt(s)  {}",
+                    name.trim_end_matches(deadletter::SIDECAR_SUFFIX),
+                    letter.rule,
+                    letter.attempts,
+                    letter.failed_at.to_rfc3339()
+                );
+                if let Some(chain) = letter.errors.last() {
+                    info!("  {}", chain.join(": "));
+                }
+            }
+        }
+        DeadLetterCommands::Replay { entries, .. } => {
+            // The runner would also see the restored files and handle them a second time
+            if let Some(pid) = deadletter::live_owner(&dir) {
+                anyhow::bail!(
+                    "Workflow '{}' is running (pid {}); stop it before replaying dead letters",
+                    config.workflow.name,
+                    pid
+                );
+            }
+            let mut events = Vec::new();
+            for (sidecar, letter) in letters {
+                let name = sidecar.file_name().unwrap_or_default().to_string_lossy().to_string();
+                let stored = name.trim_end_matches(deadletter::SIDECAR_SUFFIX);
+                if !entries.is_empty() && !entries.iter().any(|e| e == &name || e == stored) {
+                    continue;
+                }
+                match letter.restore(&sidecar) {
+                    Ok(event) => events.push(event),
+                    Err(e) => warn!("Skipping {}: {}", name, e),
+                }
+            }
+            info!("Replaying {} dead-lettered event(s)", events.len());
+            WorkflowRunner::new(config).process(events)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn test_plugin(plugin: PathBuf, cases_path: PathBuf) -> anyhow::Result<()> {
+    let harness = PluginTestHarness::from_file(&plugin)?;
+    let cases = load_cases(&cases_path)?;
+    info!("Running {} case(s) against {}", cases.len(), plugin.display());
+
+    let reports = harness.run_cases(&cases);
+    let failed = reports.iter().filter(|r| !r.passed()).count();
+
+    for report in &reports {
+        if report.passed() {
+            info!("  PASS {}", report.name);
+        } else {
+            error!("  FAIL {}", report.name);
+            for failure in &report.failures {
+                error!("       {}", failure);
+            }
+        }
+    }
+
+    if failed > 0 {
+        anyhow::bail!("{} of {} case(s) failed", failed, reports.len());
+    }
+    info!("All {} case(s) passed", reports.len());
+    Ok(())
+}
+
+fn print_plugin_details(metadata: &PluginMetadata, exports: &[String], sha256: &str, size: u64) {
+    info!("Plugin: {} ({})", metadata.id, metadata.name);
+    info!("  Version: {}", metadata.version);
+    info!("  API version: {}", metadata.api_version);
+    if let Some(desc) = &metadata.description {
+        info!("  Description: {}", desc);
+    }
+    if let Some(author) = &metadata.author {
+        info!("  Author: {}", author);
+    }
+    if let Some(license) = &metadata.license {
+        info!("  License: {}", license);
+    }
+    info!("  SHA-256: {}", sha256);
+    info!("  Size: {} bytes", size);
+
+    info!("  Exports:");
+    for export in exports {
+        info!("    - {}", export);
+    }
+
+    info!("  Required permissions:");
+    if metadata.required_permissions.is_empty() {
+        info!("    (none)");
+    }
+    for permission in metadata.required_permissions.iter() {
+        info!("    - {}", permission.description());
+    }
+
+    info!("  Config schemas:");
+    if metadata.config_schemas.is_empty() {
+        info!("    (none)");
+    }
+    let mut schemas: Vec<_> = metadata.config_schemas.iter().collect();
+    schemas.sort_by(|a, b| a.0.cmp(b.0));
+    for (action, schema) in schemas {
+        info!(
+            "    {}: {}",
+            action,
+            serde_json::to_string_pretty(schema).unwrap_or_default()
+        );
+    }
+}
@@ -0,0 +1,160 @@
+// SPDX-License-Identifier: MIT OR AGPL-3.0-or-later
+// SPDX-FileCopyrightText: 2024 Hyperpolymath <hyperpolymath@proton.me>
+
+//! Rule pattern matching
+//!
+//! A rule's patterns are compiled once into a [`PatternSet`]:
+//!
+//! - `*.pdf`: a glob without `/` matches the file name
+//! - `invoices/**/2024-*.pdf`: a glob with `/` matches the path relative
+//!   to the watch root; `*` stays within one directory, `**` spans any
+//! - `regex:^(?P<client>[^/]+)/.*\.pdf$`: a regex over the relative path;
+//!   named captures become variables
+//! - `!*.tmp`: a negation; a path matching any negation never matches
+//!
+//! A set with only negations matches everything they do not exclude.
+
+use glob::{MatchOptions, Pattern};
+use regex::Regex;
+use rpa_core::{Error, Result};
+use serde_json::{Map, Value};
+use std::path::{Component, Path};
+
+/// Prefix marking a regex pattern
+const REGEX_PREFIX: &str = "regex:";
+
+/// One compiled pattern
+#[derive(Debug, Clone)]
+enum RulePattern {
+    /// Glob over the file name
+    Name(Pattern),
+    /// Glob over the relative path
+    Path(Pattern),
+    /// Regex over the relative path
+    Regex(Regex),
+}
+
+/// Compiled patterns of a rule
+#[derive(Debug, Clone, Default)]
+pub struct PatternSet {
+    include: Vec<RulePattern>,
+    exclude: Vec<RulePattern>,
+}
+
+impl PatternSet {
+    /// Compile patterns; fails on the first invalid one
+    pub fn compile(patterns: &[String]) -> Result<Self> {
+        let mut set = Self::default();
+        for source in patterns {
+            match source.strip_prefix('!') {
+                Some(negated) => set.exclude.push(compile_one(negated)?),
+                None => set.include.push(compile_one(source)?),
+            }
+        }
+        Ok(set)
+    }
+
+    /// Match a path relative to its watch root; returns regex captures
+    pub fn matches(&self, relative: &Path) -> Option<Map<String, Value>> {
+        let path = slash_path(relative);
+        let name = relative
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        if self.exclude.iter().any(|p| matches_one(p, &path, &name).is_some()) {
+            return None;
+        }
+        if self.include.is_empty() {
+            return Some(Map::new());
+        }
+        self.include.iter().find_map(|p| matches_one(p, &path, &name))
+    }
+}
+
+fn compile_one(source: &str) -> Result<RulePattern> {
+    let invalid = |e: &dyn std::fmt::Display| Error::InvalidPattern(format!("'{}': {}", source, e));
+
+    if let Some(regex) = source.strip_prefix(REGEX_PREFIX) {
+        return Regex::new(regex).map(RulePattern::Regex).map_err(|e| invalid(&e));
+    }
+    let glob = Pattern::new(source).map_err(|e| invalid(&e))?;
+    Ok(if source.contains('/') {
+        RulePattern::Path(glob)
+    } else {
+        RulePattern::Name(glob)
+    })
+}
+
+fn matches_one(pattern: &RulePattern, path: &str, name: &str) -> Option<Map<String, Value>> {
+    let options = MatchOptions {
+        require_literal_separator: true,
+        ..MatchOptions::new()
+    };
+
+    match pattern {
+        RulePattern::Name(glob) => glob.matches_with(name, options).then(Map::new),
+        RulePattern::Path(glob) => glob.matches_with(path, options).then(Map::new),
+        RulePattern::Regex(regex) => crate::content::captures(regex, path),
+    }
+}
+
+/// Relative path with `/` separators on every platform
+fn slash_path(relative: &Path) -> String {
+    relative
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(part) => Some(part.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(patterns: &[&str]) -> PatternSet {
+        PatternSet::compile(&patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>()).unwrap()
+    }
+
+    #[test]
+    fn test_names_and_relative_paths() {
+        let patterns = set(&["*.txt", "invoices/**/2024-*.pdf"]);
+        assert!(patterns.matches(Path::new("notes.txt")).is_some());
+        assert!(patterns.matches(Path::new("a/b/notes.txt")).is_some());
+        assert!(patterns.matches(Path::new("invoices/acme/2024-03.pdf")).is_some());
+        assert!(patterns.matches(Path::new("invoices/2024-03.pdf")).is_some());
+        assert!(patterns.matches(Path::new("invoices/acme/2023-03.pdf")).is_none());
+        assert!(patterns.matches(Path::new("receipts/2024-03.pdf")).is_none());
+    }
+
+    #[test]
+    fn test_regex_captures() {
+        let patterns = set(&[r"regex:^(?P<client>[^/]+)/(?P<year>\d{4})-\d{2}\.pdf$"]);
+        let captures = patterns.matches(Path::new("acme/2024-03.pdf")).unwrap();
+        assert_eq!(captures["client"], "acme");
+        assert_eq!(captures["year"], "2024");
+        assert!(patterns.matches(Path::new("acme/march.pdf")).is_none());
+    }
+
+    #[test]
+    fn test_negation() {
+        let patterns = set(&["*.pdf", "!drafts/**"]);
+        assert!(patterns.matches(Path::new("final/a.pdf")).is_some());
+        assert!(patterns.matches(Path::new("drafts/a.pdf")).is_none());
+
+        let only_negations = set(&["!*.tmp"]);
+        assert!(only_negations.matches(Path::new("a.pdf")).is_some());
+        assert!(only_negations.matches(Path::new("a.tmp")).is_none());
+        assert!(set(&[]).matches(Path::new("anything")).is_some());
+    }
+
+    #[test]
+    fn test_invalid_patterns_rejected() {
+        assert!(PatternSet::compile(&["[".to_string()]).is_err());
+        assert!(PatternSet::compile(&["regex:(".to_string()]).is_err());
+        assert!(PatternSet::compile(&["!regex:(".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,255 @@
+// SPDX-License-Identifier: MIT OR AGPL-3.0-or-later
+// SPDX-FileCopyrightText: 2024 Hyperpolymath <hyperpolymath@proton.me>
+
+//! Data flow between the actions of a rule
+//!
+//! A [`PipelineContext`] follows the file through a rule's actions:
+//!
+//! - `path`: the file's current path. When a step moves the file away (its
+//!   old path no longer exists), the path follows the step's first affected
+//!   path, so a `copy` after a `move` reads the moved file.
+//! - `steps`: each step's `status`, `message`, `output`, affected `paths`
+//!   and, for failed steps, `errors`, keyed by step ID
+//! - `variables`: pattern captures, condition variables and the
+//!   `variables` object of any step output
+//!
+//! Each action receives the event rewritten to the current path, with the
+//! context under `metadata.pipeline` and the variables under
+//! `metadata.variables`, from where templates in action fields read them
+//! (see [`crate::template`]).
+//!
+//! `if` steps branch on a [`BranchCondition`]; steps in the branch not
+//! taken are recorded as skipped.
+
+use crate::actions::ActionStep;
+use crate::conditions::{event_path, ConditionConfig};
+use rpa_core::action::ActionResult;
+use rpa_core::{Event, EventKind};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::path::{Path, PathBuf};
+
+/// Condition of an `if` step
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BranchCondition {
+    /// An earlier step ended with `status`, and its output at `pointer` equals `equals`
+    Step {
+        step: String,
+        #[serde(default)]
+        status: StepStatus,
+        /// JSON pointer into the step's output
+        #[serde(default)]
+        pointer: Option<String>,
+        #[serde(default)]
+        equals: Option<Value>,
+    },
+    /// Rule conditions, checked against the file's current path
+    File {
+        conditions: Vec<ConditionConfig>,
+    },
+}
+
+/// How a step ended
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StepStatus {
+    #[default]
+    Succeeded,
+    Failed,
+    /// In a branch that was not taken
+    Skipped,
+}
+
+/// State carried from one action of a rule to the next
+#[derive(Debug, Clone, Default)]
+pub struct PipelineContext {
+    /// Current path of the file
+    pub path: Option<PathBuf>,
+    /// Results of finished steps by step ID
+    pub steps: Map<String, Value>,
+    /// Variables visible to later steps
+    pub variables: Map<String, Value>,
+}
+
+impl PipelineContext {
+    /// Start a pipeline for an event carrying rule variables in its metadata
+    pub fn new(event: &Event) -> Self {
+        let variables = event
+            .metadata
+            .get("variables")
+            .and_then(Value::as_object)
+            .cloned()
+            .unwrap_or_default();
+        Self {
+            path: event_path(event).map(Path::to_path_buf),
+            steps: Map::new(),
+            variables,
+        }
+    }
+
+    /// Record a successful step and move the current path if the file moved
+    pub fn record(&mut self, id: &str, result: &ActionResult) {
+        if let Some(variables) = result.output.get("variables").and_then(Value::as_object) {
+            self.variables.extend(variables.clone());
+        }
+
+        let moved_away = self.path.as_deref().is_some_and(|path| !path.exists());
+        if let (true, Some(first)) = (moved_away, result.affected_paths.first()) {
+            self.path = Some(first.clone());
+        }
+
+        self.steps.insert(id.to_string(), json!({
+            "status": StepStatus::Succeeded,
+            "message": result.message,
+            "output": result.output,
+            "paths": result.affected_paths,
+        }));
+    }
+
+    /// Record a step that failed with the error chain of each attempt
+    pub fn record_failure(&mut self, id: &str, errors: &[Vec<String>]) {
+        let message = errors.last().map(|chain| chain.join(": ")).unwrap_or_default();
+        self.steps.insert(id.to_string(), json!({
+            "status": StepStatus::Failed,
+            "message": message,
+            "output": Value::Null,
+            "paths": [],
+            "errors": errors,
+        }));
+    }
+
+    /// Record steps that will not run, including their nested steps
+    pub fn skip(&mut self, steps: &[ActionStep]) {
+        for step in steps.iter().flat_map(ActionStep::flatten) {
+            self.steps.insert(step.id().to_string(), json!({
+                "status": StepStatus::Skipped,
+                "message": "",
+                "output": Value::Null,
+                "paths": [],
+            }));
+        }
+    }
+
+    /// Whether an earlier step ended as a `step` branch condition requires
+    pub fn step_matches(&self, step: &str, status: StepStatus, pointer: Option<&str>, equals: Option<&Value>) -> bool {
+        let Some(result) = self.steps.get(step) else {
+            return false;
+        };
+        if result.get("status") != Some(&json!(status)) {
+            return false;
+        }
+        match (pointer, equals) {
+            (Some(pointer), expected) => {
+                let actual = result["output"].pointer(pointer);
+                match expected {
+                    Some(expected) => actual == Some(expected),
+                    None => actual.is_some_and(|v| !v.is_null()),
+                }
+            }
+            (None, Some(expected)) => &result["output"] == expected,
+            (None, None) => true,
+        }
+    }
+
+    /// Take over what concurrently run copies of this context recorded
+    ///
+    /// The path follows the branch that moved the file, if any; a config
+    /// only lets a branch move or delete it when no other branch uses it.
+    pub fn merge(&mut self, branches: impl IntoIterator<Item = PipelineContext>) {
+        let before = self.path.clone();
+        for branch in branches {
+            if branch.path != before {
+                self.path = branch.path;
+            }
+            self.steps.extend(branch.steps);
+            self.variables.extend(branch.variables);
+        }
+    }
+
+    /// The event as the next action sees it
+    pub fn event(&self, event: &Event) -> Event {
+        let mut event = event.clone();
+        if let Some(current) = &self.path {
+            match &mut event.kind {
+                EventKind::FileCreated { path } | EventKind::FileModified { path } => *path = current.clone(),
+                EventKind::FileRenamed { to, .. } => *to = current.clone(),
+                _ => {}
+            }
+        }
+
+        if !event.metadata.is_object() {
+            event.metadata = Value::Object(Map::new());
+        }
+        let metadata = event.metadata.as_object_mut().expect("metadata is an object");
+        metadata.insert("variables".to_string(), Value::Object(self.variables.clone()));
+        metadata.insert("pipeline".to_string(), json!({
+            "path": self.path,
+            "steps": self.steps,
+        }));
+        event
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_follows_moved_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let original = dir.path().join("a.txt");
+        std::fs::write(&original, b"data").unwrap();
+        let event = crate::conditions::with_variables(
+            &Event::new(EventKind::FileCreated { path: original.clone() }, "test"),
+            Map::from_iter([("client".to_string(), json!("acme"))]),
+        );
+        let mut context = PipelineContext::new(&event);
+
+        // A copy leaves the file where it is
+        let copy = dir.path().join("backup/a.txt");
+        context.record("backup", &ActionResult::success("copied").with_paths(vec![copy.clone()]));
+        assert_eq!(context.path.as_deref(), Some(original.as_path()));
+
+        // A move takes it along
+        let moved = dir.path().join("b.txt");
+        std::fs::rename(&original, &moved).unwrap();
+        let result = ActionResult::success("moved")
+            .with_paths(vec![moved.clone()])
+            .with_output(json!({ "variables": { "year": "2024" } }));
+        context.record("file", &result);
+        assert_eq!(context.path.as_deref(), Some(moved.as_path()));
+
+        let next = context.event(&event);
+        assert_eq!(next.kind, EventKind::FileCreated { path: moved.clone() });
+        assert_eq!(next.metadata["variables"]["year"], "2024");
+        assert_eq!(next.metadata["pipeline"]["steps"]["backup"]["paths"][0], json!(copy));
+
+        let rendered = crate::template::render(
+            "{filename} {client} {steps.file.message}",
+            &crate::template::TemplateContext::new(&next),
+        );
+        assert_eq!(rendered.unwrap(), "b.txt acme moved");
+    }
+
+    #[test]
+    fn test_merge_follows_the_branch_that_moved() {
+        let dir = tempfile::tempdir().unwrap();
+        let original = dir.path().join("a.txt");
+        std::fs::write(&original, b"data").unwrap();
+        let event = Event::new(EventKind::FileCreated { path: original.clone() }, "test");
+        let mut context = PipelineContext::new(&event);
+
+        let moved = dir.path().join("b.txt");
+        let mut mover = context.clone();
+        std::fs::rename(&original, &moved).unwrap();
+        mover.record("move", &ActionResult::success("moved").with_paths(vec![moved.clone()]));
+        let mut checker = context.clone();
+        checker.steps.insert("check".to_string(), json!({ "status": StepStatus::Succeeded }));
+
+        // A later branch that left the path alone does not undo the move
+        context.merge([mover, checker]);
+        assert_eq!(context.path.as_deref(), Some(moved.as_path()));
+        assert!(context.steps.contains_key("move") && context.steps.contains_key("check"));
+    }
+}
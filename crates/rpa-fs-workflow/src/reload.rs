@@ -0,0 +1,207 @@
+// SPDX-License-Identifier: MIT OR AGPL-3.0-or-later
+// SPDX-FileCopyrightText: 2024 Hyperpolymath <hyperpolymath@proton.me>
+
+//! Hot reload of the workflow configuration
+//!
+//! A background thread polls the config file the runner was started from.
+//! When its content changes, the file is loaded and validated there; a
+//! valid config is handed to the event loop, which swaps rules, watches and
+//! plugins between events. Watches whose settings did not change keep
+//! their watcher, and the plugin host is only reloaded when the `plugins`
+//! list changed. An invalid edit is logged and the running config stays.
+//!
+//! Runner settings and sources take effect on the next start. Files a
+//! Nickel config imports are not polled; save the main file to reload.
+
+use crate::config::WorkflowConfig;
+use crate::watcher::EventInjector;
+use serde::Serialize;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tracing::{debug, error, info};
+
+/// How often the config file is checked for changes
+pub const RELOAD_INTERVAL: Duration = Duration::from_millis(500);
+
+/// What changed between two configurations
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ConfigDiff {
+    pub rules_added: Vec<String>,
+    pub rules_removed: Vec<String>,
+    pub rules_changed: Vec<String>,
+    pub watches_added: Vec<PathBuf>,
+    pub watches_removed: Vec<PathBuf>,
+    /// Watches at the same path with different settings
+    pub watches_changed: Vec<PathBuf>,
+    pub plugins_changed: bool,
+    pub match_mode_changed: bool,
+    /// Only applied on the next start
+    pub sources_changed: bool,
+    /// Only applied on the next start
+    pub runner_changed: bool,
+}
+
+impl ConfigDiff {
+    /// Compare the running config with a new one
+    pub fn between(old: &WorkflowConfig, new: &WorkflowConfig) -> Self {
+        let rule = |config: &WorkflowConfig, name: &str| config.rules.iter().find(|r| r.name == name).map(json);
+        let watch = |config: &WorkflowConfig, path: &Path| config.watch.iter().find(|w| w.path == path).map(json);
+
+        let mut diff = Self::default();
+        for added in &new.rules {
+            match rule(old, &added.name) {
+                None => diff.rules_added.push(added.name.clone()),
+                Some(before) if before != json(added) => diff.rules_changed.push(added.name.clone()),
+                Some(_) => {}
+            }
+        }
+        diff.rules_removed = old.rules.iter()
+            .filter(|r| rule(new, &r.name).is_none())
+            .map(|r| r.name.clone())
+            .collect();
+
+        for added in &new.watch {
+            match watch(old, &added.path) {
+                None => diff.watches_added.push(added.path.clone()),
+                Some(before) if before != json(added) => diff.watches_changed.push(added.path.clone()),
+                Some(_) => {}
+            }
+        }
+        diff.watches_removed = old.watch.iter()
+            .filter(|w| watch(new, &w.path).is_none())
+            .map(|w| w.path.clone())
+            .collect();
+
+        diff.plugins_changed = json(&old.plugins) != json(&new.plugins);
+        diff.match_mode_changed = old.match_mode != new.match_mode;
+        diff.sources_changed = json(&old.sources) != json(&new.sources);
+        diff.runner_changed = json(&old.runner) != json(&new.runner);
+        diff
+    }
+
+    /// Whether the configs are equivalent
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl fmt::Display for ConfigDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let paths = |paths: &[PathBuf]| paths.iter().map(|p| p.display().to_string()).collect::<Vec<_>>();
+        let mut parts = Vec::new();
+        for (label, items) in [
+            ("rules added", self.rules_added.clone()),
+            ("rules removed", self.rules_removed.clone()),
+            ("rules changed", self.rules_changed.clone()),
+            ("watches added", paths(&self.watches_added)),
+            ("watches removed", paths(&self.watches_removed)),
+            ("watches changed", paths(&self.watches_changed)),
+        ] {
+            if !items.is_empty() {
+                parts.push(format!("{}: {}", label, items.join(", ")));
+            }
+        }
+        for (label, changed) in [
+            ("plugins changed", self.plugins_changed),
+            ("match mode changed", self.match_mode_changed),
+            ("sources changed (applied on restart)", self.sources_changed),
+            ("runner settings changed (applied on restart)", self.runner_changed),
+        ] {
+            if changed {
+                parts.push(label.to_string());
+            }
+        }
+
+        if parts.is_empty() {
+            write!(f, "no changes")
+        } else {
+            write!(f, "{}", parts.join("; "))
+        }
+    }
+}
+
+pub(crate) fn json(value: &impl Serialize) -> serde_json::Value {
+    serde_json::to_value(value).unwrap_or_default()
+}
+
+/// Poll `path` and deliver each valid new version of the config
+///
+/// The event loop is woken through `injector` after each delivery.
+pub fn spawn_reloader(
+    path: PathBuf,
+    injector: EventInjector,
+    running: Arc<AtomicBool>,
+) -> (Receiver<WorkflowConfig>, JoinHandle<()>) {
+    let (tx, rx) = channel();
+    let thread = std::thread::spawn(move || {
+        let mut last = std::fs::read(&path).ok();
+        while running.load(Ordering::SeqCst) {
+            std::thread::sleep(RELOAD_INTERVAL);
+            let Ok(content) = std::fs::read(&path) else {
+                // Editors may replace the file; try again next round
+                continue;
+            };
+            if last.as_ref() == Some(&content) {
+                continue;
+            }
+            last = Some(content);
+
+            debug!("Config file changed: {}", path.display());
+            match WorkflowConfig::load(&path) {
+                Ok(config) => {
+                    if tx.send(config).is_err() {
+                        return;
+                    }
+                    injector.wake();
+                }
+                Err(e) => error!("Keeping the running config; {} is invalid: {}", path.display(), e),
+            }
+        }
+        info!("Stopped watching config file {}", path.display());
+    });
+    (rx, thread)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_summarises_changes() {
+        let mut old = WorkflowConfig::example();
+        let mut rule = old.rules[0].clone();
+        rule.name = "second".to_string();
+        old.rules.push(rule);
+        let mut watch = old.watch[0].clone();
+        watch.path = PathBuf::from("/tmp/second");
+        old.watch.push(watch);
+        assert!(ConfigDiff::between(&old, &old).is_empty());
+
+        let mut new = old.clone();
+        new.rules[0].patterns.push("*.odt".to_string());
+        new.rules[1].name = "added".to_string();
+        new.watch[0].recursive = !new.watch[0].recursive;
+        new.watch[1].path = PathBuf::from("/elsewhere");
+        new.runner.workers += 1;
+
+        let diff = ConfigDiff::between(&old, &new);
+        assert_eq!(diff.rules_added, ["added"]);
+        assert_eq!(diff.rules_removed, [old.rules[1].name.clone()]);
+        assert_eq!(diff.rules_changed, [old.rules[0].name.clone()]);
+        assert_eq!(diff.watches_changed, [old.watch[0].path.clone()]);
+        assert_eq!(diff.watches_added, [PathBuf::from("/elsewhere")]);
+        assert_eq!(diff.watches_removed, [old.watch[1].path.clone()]);
+        assert!(!diff.plugins_changed);
+        assert!(diff.runner_changed);
+
+        let summary = diff.to_string();
+        assert!(summary.contains("rules added: added"));
+        assert!(summary.contains("watches added: /elsewhere"));
+        assert!(summary.contains("runner settings changed (applied on restart)"));
+    }
+}
@@ -0,0 +1,166 @@
+// SPDX-License-Identifier: MIT OR AGPL-3.0-or-later
+// SPDX-FileCopyrightText: 2024 Hyperpolymath <hyperpolymath@proton.me>
+
+//! Pairing of rename notifications into `FileRenamed` events
+//!
+//! Backends report a move as a `From` half and a `To` half sharing a
+//! tracker (the inotify cookie). Halves are paired within a short window:
+//!
+//! - `From` + `To` with the same tracker: `FileRenamed { from, to }`
+//! - `To` without a `From`: the file moved in from outside the watched
+//!   tree, reported as `FileCreated` with `moved_from_outside: true`
+//! - `From` without a `To` once the window elapses: the file moved out,
+//!   reported as `FileDeleted` with `moved_to_outside: true`
+//!
+//! A combined `Both` notification for a pair that was already matched is
+//! ignored; otherwise it becomes a `FileRenamed` directly.
+
+use rpa_core::{Event, EventKind};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How long a `From` half waits for its `To` half
+pub const RENAME_WINDOW: Duration = Duration::from_millis(250);
+
+/// Pairs rename halves by tracker
+#[derive(Debug, Default)]
+pub struct RenameTracker {
+    /// Unmatched `From` halves: tracker, path and when they arrived
+    pending: Vec<(Option<usize>, PathBuf, Instant)>,
+    /// Trackers already paired, so a trailing `Both` can be ignored
+    paired: HashMap<usize, Instant>,
+}
+
+impl RenameTracker {
+    /// Create an empty tracker
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the `From` half of a move
+    pub fn moved_from(&mut self, tracker: Option<usize>, path: PathBuf, now: Instant) {
+        self.pending.push((tracker, path, now));
+    }
+
+    /// Record the `To` half of a move
+    pub fn moved_to(&mut self, tracker: Option<usize>, path: PathBuf, source: &str) -> Event {
+        let matched = self
+            .pending
+            .iter()
+            .position(|(t, _, _)| tracker.is_some() && *t == tracker);
+
+        match matched {
+            Some(index) => {
+                let (_, from, now) = self.pending.remove(index);
+                if let Some(tracker) = tracker {
+                    self.paired.insert(tracker, now);
+                }
+                Event::new(EventKind::FileRenamed { from, to: path }, source)
+            }
+            None => Event::new(EventKind::FileCreated { path }, source)
+                .with_metadata(serde_json::json!({ "moved_from_outside": true })),
+        }
+    }
+
+    /// Handle a combined notification carrying both paths
+    pub fn moved_both(&mut self, tracker: Option<usize>, from: PathBuf, to: PathBuf, source: &str) -> Option<Event> {
+        if let Some(tracker) = tracker {
+            if self.paired.remove(&tracker).is_some() {
+                return None;
+            }
+            self.pending.retain(|(t, _, _)| *t != Some(tracker));
+        }
+        Some(Event::new(EventKind::FileRenamed { from, to }, source))
+    }
+
+    /// Report `From` halves whose window elapsed as moves out of the tree
+    pub fn expire(&mut self, now: Instant) -> Vec<Event> {
+        self.paired.retain(|_, at| now.duration_since(*at) < RENAME_WINDOW);
+
+        let (expired, pending): (Vec<_>, Vec<_>) = self
+            .pending
+            .drain(..)
+            .partition(|(_, _, at)| now.duration_since(*at) >= RENAME_WINDOW);
+        self.pending = pending;
+
+        expired
+            .into_iter()
+            .map(|(_, path, _)| {
+                let source = parent_of(&path);
+                Event::new(EventKind::FileDeleted { path }, source)
+                    .with_metadata(serde_json::json!({ "moved_to_outside": true }))
+            })
+            .collect()
+    }
+
+    /// When the oldest unmatched `From` half expires
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.iter().map(|(_, _, at)| *at + RENAME_WINDOW).min()
+    }
+}
+
+/// Event source for a path: its parent directory
+pub fn parent_of(path: &Path) -> String {
+    path.parent().map(|p| p.to_string_lossy().to_string()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pairs_by_tracker() {
+        let mut tracker = RenameTracker::new();
+        let now = Instant::now();
+        tracker.moved_from(Some(7), PathBuf::from("/in/a.tmp"), now);
+        tracker.moved_from(Some(8), PathBuf::from("/in/b.tmp"), now);
+
+        let event = tracker.moved_to(Some(8), PathBuf::from("/in/b.pdf"), "/in");
+        assert_eq!(event.kind, EventKind::FileRenamed {
+            from: PathBuf::from("/in/b.tmp"),
+            to: PathBuf::from("/in/b.pdf"),
+        });
+
+        // The combined notification for the same pair is dropped
+        let both = tracker.moved_both(Some(8), PathBuf::from("/in/b.tmp"), PathBuf::from("/in/b.pdf"), "/in");
+        assert!(both.is_none());
+        assert_eq!(tracker.expire(now).len(), 0);
+    }
+
+    #[test]
+    fn test_moves_across_the_tree_boundary() {
+        let mut tracker = RenameTracker::new();
+        let now = Instant::now();
+
+        let moved_in = tracker.moved_to(Some(1), PathBuf::from("/in/new.pdf"), "/in");
+        assert_eq!(moved_in.kind, EventKind::FileCreated { path: PathBuf::from("/in/new.pdf") });
+        assert_eq!(moved_in.metadata["moved_from_outside"], true);
+
+        tracker.moved_from(Some(2), PathBuf::from("/in/old.pdf"), now);
+        assert_eq!(tracker.next_deadline(), Some(now + RENAME_WINDOW));
+        assert!(tracker.expire(now + Duration::from_millis(10)).is_empty());
+
+        let moved_out = tracker.expire(now + RENAME_WINDOW);
+        assert_eq!(moved_out.len(), 1);
+        assert_eq!(moved_out[0].kind, EventKind::FileDeleted { path: PathBuf::from("/in/old.pdf") });
+        assert_eq!(moved_out[0].metadata["moved_to_outside"], true);
+    }
+
+    #[test]
+    fn test_halves_without_tracker_not_paired() {
+        let mut tracker = RenameTracker::new();
+        let now = Instant::now();
+        tracker.moved_from(None, PathBuf::from("/in/old.pdf"), now);
+        let event = tracker.moved_to(None, PathBuf::from("/in/new.pdf"), "/in");
+        assert_eq!(event.kind, EventKind::FileCreated { path: PathBuf::from("/in/new.pdf") });
+        assert_eq!(tracker.expire(now + RENAME_WINDOW).len(), 1);
+    }
+
+    #[test]
+    fn test_both_without_halves() {
+        let mut tracker = RenameTracker::new();
+        let event = tracker.moved_both(None, PathBuf::from("/in/a"), PathBuf::from("/in/b"), "/in");
+        assert!(matches!(event.unwrap().kind, EventKind::FileRenamed { .. }));
+    }
+}
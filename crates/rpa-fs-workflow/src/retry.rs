@@ -0,0 +1,191 @@
+// SPDX-License-Identifier: MIT OR AGPL-3.0-or-later
+// SPDX-FileCopyrightText: 2024 Hyperpolymath <hyperpolymath@proton.me>
+
+//! Retry policies for actions
+//!
+//! A failed attempt is retried if its error class is listed in the
+//! policy's `retry_on`. The delay before retry `n` is
+//! `initial_backoff_ms * multiplier^(n-1)`, capped at `max_backoff_ms` and
+//! spread by up to `jitter` (a fraction of the delay) in either direction.
+//! The spread comes from a hash of the file and the attempt, so files that
+//! failed together retry at different times.
+
+use rpa_core::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::Duration;
+
+/// How often and when to retry a failed action
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first (default: 3)
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// Delay before the first retry (default: 1s)
+    #[serde(default = "default_initial_backoff")]
+    pub initial_backoff_ms: u64,
+    /// Upper bound on any delay (default: 60s)
+    #[serde(default = "default_max_backoff")]
+    pub max_backoff_ms: u64,
+    /// Growth factor between delays (default: 2)
+    #[serde(default = "default_multiplier")]
+    pub multiplier: f64,
+    /// Random spread as a fraction of the delay (default: 0.2)
+    #[serde(default = "default_jitter")]
+    pub jitter: f64,
+    /// Error classes worth retrying (default: io)
+    #[serde(default = "default_retry_on")]
+    pub retry_on: Vec<ErrorClass>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_max_attempts(),
+            initial_backoff_ms: default_initial_backoff(),
+            max_backoff_ms: default_max_backoff(),
+            multiplier: default_multiplier(),
+            jitter: default_jitter(),
+            retry_on: default_retry_on(),
+        }
+    }
+}
+
+fn default_max_attempts() -> u32 {
+    3
+}
+
+fn default_initial_backoff() -> u64 {
+    1_000 // 1 second
+}
+
+fn default_max_backoff() -> u64 {
+    60_000 // 1 minute
+}
+
+fn default_multiplier() -> f64 {
+    2.0
+}
+
+fn default_jitter() -> f64 {
+    0.2
+}
+
+fn default_retry_on() -> Vec<ErrorClass> {
+    vec![ErrorClass::Io]
+}
+
+/// Kinds of action failure
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorClass {
+    /// Filesystem or other I/O error
+    Io,
+    /// The action ran and reported failure
+    Failed,
+    /// Any other error (plugins, configuration, ...)
+    Other,
+    /// Every failure
+    Any,
+}
+
+impl ErrorClass {
+    /// Class of an error returned by an action
+    pub fn of(error: &Error) -> Self {
+        match error {
+            Error::Io(_) => ErrorClass::Io,
+            Error::ActionFailed { .. } => ErrorClass::Failed,
+            _ => ErrorClass::Other,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Single attempt, no retries
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    /// Check the policy's values
+    pub fn validate(&self) -> Result<()> {
+        if self.max_attempts == 0 {
+            return Err(Error::Config("Retry policy needs at least one attempt".into()));
+        }
+        if self.multiplier.is_nan() || self.multiplier < 1.0 {
+            return Err(Error::Config("Retry multiplier must be at least 1".into()));
+        }
+        if !(0.0..=1.0).contains(&self.jitter) {
+            return Err(Error::Config("Retry jitter must be between 0 and 1".into()));
+        }
+        Ok(())
+    }
+
+    /// Whether a failure of `class` after `attempt` attempts is retried
+    pub fn should_retry(&self, class: ErrorClass, attempt: u32) -> bool {
+        attempt < self.max_attempts
+            && self.retry_on.iter().any(|c| *c == ErrorClass::Any || *c == class)
+    }
+
+    /// Delay before the retry following attempt `attempt` (1-based) of
+    /// the action on `key`, usually the event's path
+    pub fn backoff(&self, attempt: u32, key: impl Hash) -> Duration {
+        let base = self.initial_backoff_ms as f64 * self.multiplier.powi(attempt.saturating_sub(1) as i32);
+        let capped = base.min(self.max_backoff_ms as f64);
+        let spread = capped * self.jitter.clamp(0.0, 1.0) * (2.0 * unit_hash(key, attempt) - 1.0);
+        Duration::from_millis((capped + spread).max(0.0) as u64)
+    }
+}
+
+/// Value in `[0, 1)` spread evenly over keys and attempts
+fn unit_hash(key: impl Hash, attempt: u32) -> f64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    attempt.hash(&mut hasher);
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_grows_and_caps() {
+        let policy = RetryPolicy {
+            initial_backoff_ms: 100,
+            max_backoff_ms: 1_000,
+            jitter: 0.0,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(policy.backoff(1, "a.txt"), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2, "a.txt"), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3, "a.txt"), Duration::from_millis(400));
+        assert_eq!(policy.backoff(10, "a.txt"), Duration::from_millis(1_000));
+
+        let jittery = RetryPolicy { jitter: 0.5, ..policy };
+        let delays: Vec<_> = (0..20).map(|i| jittery.backoff(2, format!("{}.txt", i)).as_millis()).collect();
+        assert!(delays.iter().all(|delay| (100..=300).contains(delay)));
+        // Files failing together do not retry in lockstep
+        assert!(delays.iter().any(|&delay| delay != delays[0]));
+        assert_eq!(jittery.backoff(2, "a.txt"), jittery.backoff(2, "a.txt"));
+    }
+
+    #[test]
+    fn test_retry_classes() {
+        let policy = RetryPolicy::default();
+        assert!(policy.should_retry(ErrorClass::Io, 1));
+        assert!(policy.should_retry(ErrorClass::Io, 2));
+        assert!(!policy.should_retry(ErrorClass::Io, 3));
+        assert!(!policy.should_retry(ErrorClass::Failed, 1));
+
+        let any = RetryPolicy { retry_on: vec![ErrorClass::Any], ..RetryPolicy::default() };
+        assert!(any.should_retry(ErrorClass::Other, 1));
+        assert!(!RetryPolicy::none().should_retry(ErrorClass::Io, 1));
+
+        let io = std::io::Error::new(std::io::ErrorKind::TimedOut, "stale NFS handle");
+        assert_eq!(ErrorClass::of(&io.into()), ErrorClass::Io);
+    }
+}
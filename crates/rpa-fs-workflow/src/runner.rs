@@ -2,21 +2,223 @@
 // SPDX-FileCopyrightText: 2024 Hyperpolymath <hyperpolymath@proton.me>
 
 //! Workflow runner that orchestrates watching and action execution
+//!
+//! Events are queued on one of `workers` bounded shards, chosen by the
+//! event's path, and each shard is drained in order by its own task on a
+//! shared multi-threaded tokio runtime. Events for the same path are
+//! therefore handled in order while different paths run concurrently.
+//! When a shard is full the event loop waits, which applies backpressure
+//! to the watcher. An event held by a stability policy, or waiting to
+//! retry an action, waits in a task of its own; later events for its path
+//! queue behind it and go back to the shard once the wait is over.
+//!
+//! Stopping wakes the event loop immediately. Events still queued are
+//! cancelled (and saved to `runner.pending_file` if configured, to be
+//! replayed on the next start), while in-flight actions get up to
+//! `runner.shutdown_grace_ms` to finish.
 
-use crate::actions::DynamicAction;
-use crate::config::{EventType, RuleConfig, WorkflowConfig};
-use crate::watcher::FsWatcher;
-use glob::Pattern;
-use rpa_core::{Action, Event, EventKind, Result, WorkflowState};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use crate::actions::{ActionConfig, ActionStep, DynamicAction};
+use crate::conditions::{self, ConditionEvaluator};
+use crate::config::{EventType, MatchMode, RuleConfig, RunnerConfig, WatchConfig, WorkflowConfig};
+use crate::deadletter::{self, DeadLetter};
+use crate::feedback::SelfWrites;
+use crate::filter::WatchFilter;
+use crate::index::{Fingerprint, ProcessedIndex};
+use crate::patterns::PatternSet;
+use crate::pipeline::{BranchCondition, PipelineContext};
+use crate::reload::{self, ConfigDiff};
+use crate::retry::{ErrorClass, RetryPolicy};
+use crate::scan::{self, ScanDedup};
+use crate::sources;
+use crate::stability::{self, CloseWriteTracker, Stability, StabilityConfig};
+use crate::template::TemplateContext;
+use crate::watcher::{EventInjector, FsWatcher};
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use rpa_core::action::ActionResult;
+use rpa_core::{Event, EventKind, Result, WorkflowState};
+use rpa_plugin::PluginHost;
+use std::collections::hash_map::DefaultHasher;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::oneshot;
 use tracing::{debug, error, info, warn};
 
+/// How long the event loop waits before retrying a full shard
+const BACKPRESSURE_RETRY: Duration = Duration::from_millis(10);
+
 /// Runner that executes a workflow configuration
 pub struct WorkflowRunner {
     config: WorkflowConfig,
-    state: WorkflowState,
-    running: Arc<AtomicBool>,
+    state: Arc<Mutex<WorkflowState>>,
+    stop: StopHandle,
+    plugins: Option<Arc<PluginHost>>,
+    /// File the config was loaded from, reloaded when it changes
+    config_file: Option<PathBuf>,
+}
+
+/// Handle for stopping a running workflow from another thread
+///
+/// A stop requested before the workflow runs makes `run` return at once.
+#[derive(Clone)]
+pub struct StopHandle {
+    pub(crate) running: Arc<AtomicBool>,
+    /// Wakes the event loop blocked on the watcher
+    pub(crate) waker: Arc<Mutex<Option<EventInjector>>>,
+}
+
+impl StopHandle {
+    pub(crate) fn new() -> Self {
+        Self {
+            running: Arc::new(AtomicBool::new(true)),
+            waker: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Request a stop and wake the event loop
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(waker) = self.waker.lock().unwrap().as_ref() {
+            waker.wake();
+        }
+    }
+
+    /// Whether no stop was requested yet
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+}
+
+/// Result of running a rule's actions
+#[derive(Debug, Default)]
+struct RuleOutcome {
+    /// Names of the actions that succeeded
+    applied: Vec<String>,
+    /// An action failed for good and the event was dead-lettered
+    dead_lettered: bool,
+}
+
+/// An action that failed after all its attempts
+#[derive(Debug)]
+struct StepFailure {
+    action: ActionConfig,
+    attempts: u32,
+    /// Error chain of each attempt
+    errors: Vec<Vec<String>>,
+}
+
+/// How often the processed index is saved while running
+const INDEX_AUTOSAVE: Duration = Duration::from_secs(30);
+
+/// Everything a worker needs to handle an event
+pub(crate) struct Shared {
+    /// Rules and plugins in use, swapped on reload
+    pub(crate) active: RwLock<Arc<Active>>,
+    /// Runner settings as of the start
+    runner: RunnerConfig,
+    pub(crate) state: Arc<Mutex<WorkflowState>>,
+    close_writes: CloseWriteTracker,
+    /// Set on stop; queued events are then moved to `pending`
+    pub(crate) cancelled: AtomicBool,
+    /// Events cancelled before they were processed
+    pending: Mutex<Vec<Event>>,
+    /// Processed files, when `runner.index_file` is set
+    index: Option<Mutex<ProcessedIndex>>,
+    index_saved: Mutex<Instant>,
+    /// Paths recently written by actions, whose events are ignored
+    self_writes: SelfWrites,
+    /// Files reported by both the initial scan and the watcher
+    pub(crate) scan_dedup: Arc<Mutex<ScanDedup>>,
+    /// Events waiting for their file to become stable, by path
+    held: Mutex<HashMap<PathBuf, Held>>,
+    /// Jobs queued or running in a worker pool, see [`Flight`]
+    in_flight: AtomicUsize,
+}
+
+/// Events for a path held while an earlier one is waited for
+#[derive(Debug, Default)]
+struct Held {
+    /// The waiting event if held for stability, then later events for the same path
+    events: VecDeque<Event>,
+    state: HeldState,
+}
+
+/// What the events held for a path wait for
+#[derive(Debug, Default)]
+enum HeldState {
+    /// The first event waits for its file to become stable
+    Waiting,
+    /// The wait is over; the first event skips rules needing these policies
+    Settled(Vec<StabilityConfig>),
+    /// An event handled outside the shard, e.g. one retrying an action
+    #[default]
+    Queued,
+}
+
+/// A stability wait to run outside the shard
+#[derive(Debug)]
+pub(crate) struct Hold {
+    path: PathBuf,
+    since: DateTime<Utc>,
+    /// Policy of the event's watch, which every rule needs
+    watch_policy: Option<StabilityConfig>,
+    /// Further policies of individual rules
+    rule_policies: Vec<StabilityConfig>,
+}
+
+/// A configuration prepared for handling events
+///
+/// Each event is handled entirely with the `Active` current when it starts.
+pub(crate) struct Active {
+    pub(crate) config: WorkflowConfig,
+    pub(crate) plugins: Option<Arc<PluginHost>>,
+    conditions: Arc<ConditionEvaluator>,
+    /// Compiled patterns, one set per rule
+    patterns: Vec<PatternSet>,
+    /// Indices of enabled rules in priority order
+    order: Vec<usize>,
+    /// Compiled filters, one per watch
+    filters: Vec<WatchFilter>,
+}
+
+impl Active {
+    pub(crate) fn new(mut config: WorkflowConfig, plugins: Option<Arc<PluginHost>>) -> Result<Self> {
+        config.number_steps();
+        Ok(Self {
+            patterns: config.rules.iter().map(RuleConfig::pattern_set).collect::<Result<_>>()?,
+            filters: config.watch.iter().map(|w| Ok(w.options()?.filter)).collect::<Result<_>>()?,
+            order: config.rule_order(),
+            conditions: Arc::new(ConditionEvaluator::new(plugins.clone())),
+            plugins,
+            config,
+        })
+    }
+
+    /// Whether one of the watches delivers events for `path`
+    ///
+    /// A non-recursive watch only delivers its direct children, as the
+    /// daemon may register its path recursively for another workflow.
+    pub(crate) fn watches(&self, path: &Path) -> bool {
+        let Some(watch) = self.config.watch_for(path) else {
+            return false;
+        };
+        let nested = path.strip_prefix(&watch.path).is_ok_and(|r| r.components().count() > 1);
+        if nested && !watch.recursive {
+            return false;
+        }
+        self.config.watch.iter()
+            .zip(&self.filters)
+            .find(|(w, _)| std::ptr::eq(*w, watch))
+            .is_some_and(|(_, filter)| filter.allows(&watch.path, path))
+    }
 }
 
 impl WorkflowRunner {
@@ -25,173 +227,1826 @@ pub fn new(config: WorkflowConfig) -> Self {
         let state = WorkflowState::new(&config.workflow.name);
         Self {
             config,
-            state,
-            running: Arc::new(AtomicBool::new(false)),
+            state: Arc::new(Mutex::new(state)),
+            stop: StopHandle::new(),
+            plugins: None,
+            config_file: None,
         }
     }
 
-    /// Get the current workflow state
-    pub fn state(&self) -> &WorkflowState {
-        &self.state
+    /// Reload the config whenever `path` changes while running
+    pub fn with_config_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config_file = Some(path.into());
+        self
+    }
+
+    /// Get a snapshot of the current workflow state
+    pub fn state(&self) -> WorkflowState {
+        self.state.lock().unwrap().clone()
     }
 
     /// Get a handle to stop the runner
-    pub fn stop_handle(&self) -> Arc<AtomicBool> {
-        self.running.clone()
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop.clone()
+    }
+
+    /// Handle events once without watching, e.g. to replay dead letters (blocking)
+    pub fn process(&mut self, events: Vec<Event>) -> Result<()> {
+        self.load_plugins()?;
+        let index = load_index(&self.config)?;
+        let shared = self.shared(CloseWriteTracker::new(), index)?;
+
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        runtime.block_on(async {
+            for event in &events {
+                self.state.lock().unwrap().record_event();
+                shared.handle_in_place(event).await;
+            }
+        });
+        shared.save_index(true);
+        Ok(())
+    }
+
+    fn load_plugins(&mut self) -> Result<()> {
+        if self.plugins.is_none() && !self.config.plugins.is_empty() {
+            let host = self.config.plugin_host()?;
+            info!("Loaded {} plugin(s)", host.plugin_count());
+            self.plugins = Some(Arc::new(host));
+        }
+        Ok(())
+    }
+
+    fn shared(&self, close_writes: CloseWriteTracker, index: Option<ProcessedIndex>) -> Result<Shared> {
+        Shared::new(&self.config, self.plugins.clone(), self.state.clone(), close_writes, index)
     }
 
     /// Run the workflow (blocking)
     pub fn run(&mut self) -> Result<()> {
+        if !self.stop.is_running() {
+            info!("Workflow '{}' was stopped before it started", self.config.workflow.name);
+            return Ok(());
+        }
         info!("Starting workflow: {}", self.config.workflow.name);
-        self.state.start();
-        self.running.store(true, Ordering::SeqCst);
+        self.state.lock().unwrap().start();
+
+        self.load_plugins()?;
+        let _dead_letters = self.config.runner.dead_letter_dir.as_deref().map(deadletter::Owner::claim).transpose()?;
 
-        // Determine if any watch config wants recursive
-        let recursive = self.config.watch.iter().any(|w| w.recursive);
-        let mut watcher = FsWatcher::new(recursive)?;
+        let pool = WorkerPool::new(&self.config.runner)?;
+
+        // Files modified from here on are reported by the watcher
+        let started = SystemTime::now();
+
+        // Recursion is set per watch in `watch_with`
+        let mut watcher = FsWatcher::new(false)?;
 
         // Set up watches
         for watch_config in &self.config.watch {
-            if !watch_config.path.exists() {
-                warn!(
-                    "Watch path does not exist, creating: {}",
-                    watch_config.path.display()
-                );
-                std::fs::create_dir_all(&watch_config.path)?;
-            }
-            watcher.watch(&watch_config.path)?;
+            watch(&mut watcher, watch_config)?;
         }
 
+        let close_writes = CloseWriteTracker::new();
+        if self.config.uses_close_write() {
+            watcher.track_close_writes(close_writes.clone());
+        }
+
+        let source_threads = match &self.plugins {
+            Some(host) if !self.config.sources.is_empty() => sources::spawn_sources(
+                &self.config.sources,
+                host.clone(),
+                watcher.injector(),
+                self.stop.running.clone(),
+            ),
+            _ => {
+                if !self.config.sources.is_empty() {
+                    warn!("Sources are configured but no plugins are loaded");
+                }
+                Vec::new()
+            }
+        };
+
+        let index = load_index(&self.config)?;
+        let (catch_up, skip) = catch_up(&self.config, index.as_ref())?;
+        let shared = Arc::new(self.shared(close_writes.clone(), index)?);
+
         info!(
-            "Workflow '{}' is running. Watching {} paths.",
+            "Workflow '{}' is running. Watching {} paths with {} worker(s).",
             self.config.workflow.name,
-            watcher.watched_paths().len()
+            watcher.watched_paths().len(),
+            pool.workers()
         );
 
+        *self.stop.waker.lock().unwrap() = Some(watcher.injector());
+
+        let (reloads, reload_thread) = match self.config_file.clone() {
+            Some(path) => {
+                info!("Reloading the config when {} changes", path.display());
+                let (reloads, thread) = reload::spawn_reloader(path, watcher.injector(), self.stop.running.clone());
+                (Some(reloads), Some(thread))
+            }
+            None => (None, None),
+        };
+
+        for event in catch_up {
+            pool.enqueue(&shared, event, &self.stop);
+        }
+
+        let mut scan_threads: Vec<_> = self.config.watch.iter().any(|w| w.initial_scan.is_some()).then(|| {
+            scan::spawn_scan(
+                &self.config.watch,
+                started,
+                skip,
+                watcher.injector(),
+                self.stop.running.clone(),
+                shared.scan_dedup.clone(),
+            )
+        }).into_iter().collect();
+
         // Main event loop
-        while self.running.load(Ordering::SeqCst) {
+        while self.stop.is_running() {
             if let Some(event) = watcher.next_event() {
-                self.state.record_event();
-                self.handle_event(&event);
+                self.state.lock().unwrap().record_event();
+                pool.enqueue(&shared, event, &self.stop);
+            }
+            for config in reloads.iter().flat_map(|reloads| reloads.try_iter()) {
+                scan_threads.extend(self.reload(config, &mut watcher, &shared, &close_writes));
             }
         }
 
-        self.state.stop();
-        info!(
-            "Workflow '{}' stopped. Events: {}, Actions: {}, Errors: {}",
-            self.config.workflow.name,
-            self.state.events_processed,
-            self.state.actions_executed,
-            self.state.error_count
+        info!("Stopping workflow: {}", self.config.workflow.name);
+        *self.stop.waker.lock().unwrap() = None;
+
+        // Cancel queued events and give in-flight actions the grace period
+        shared.cancelled.store(true, Ordering::SeqCst);
+        let clean = pool.shut_down(Duration::from_millis(self.config.runner.shutdown_grace_ms));
+
+        for thread in source_threads.into_iter().chain(scan_threads).chain(reload_thread) {
+            let _ = thread.join();
+        }
+
+        shared.finish(clean)
+    }
+
+    /// Swap in a reloaded config, leaving unchanged watches and plugins alone
+    ///
+    /// Returns the initial scan of added watches that have one.
+    fn reload(
+        &mut self,
+        mut config: WorkflowConfig,
+        watcher: &mut FsWatcher,
+        shared: &Shared,
+        close_writes: &CloseWriteTracker,
+    ) -> Option<JoinHandle<()>> {
+        let diff = ConfigDiff::between(&self.config, &config);
+        if diff.is_empty() {
+            debug!("Config file saved without changes");
+            return None;
+        }
+
+        let plugins = if diff.plugins_changed {
+            match (!config.plugins.is_empty()).then(|| config.plugin_host()).transpose() {
+                Ok(host) => host.map(Arc::new),
+                Err(e) => {
+                    error!("Keeping the running config: {}", e);
+                    return None;
+                }
+            }
+        } else {
+            self.plugins.clone()
+        };
+        if let Some(host) = &plugins {
+            let problems = config.check_plugin_actions(host);
+            if !problems.is_empty() {
+                error!("Keeping the running config: {}", problems.join("; "));
+                return None;
+            }
+        }
+
+        // Runner settings and sources stay as started
+        config.runner = self.config.runner.clone();
+        config.sources = self.config.sources.clone();
+        let active = match Active::new(config.clone(), plugins.clone()) {
+            Ok(active) => active,
+            Err(e) => {
+                error!("Keeping the running config: {}", e);
+                return None;
+            }
+        };
+
+        let changes: Vec<_> = diff.watches_added.iter()
+            .chain(&diff.watches_changed)
+            .chain(&diff.watches_removed)
+            .map(|path| (self.config.watch.iter().find(|w| &w.path == path), config.watch.iter().find(|w| &w.path == path)))
+            .collect();
+        if let Err(e) = swap_watches(watcher, &changes) {
+            error!("Keeping the running config: {}", e);
+            return None;
+        }
+        let added: Vec<_> = config.watch.iter().filter(|w| diff.watches_added.contains(&w.path)).cloned().collect();
+        if config.uses_close_write() {
+            watcher.track_close_writes(close_writes.clone());
+        }
+
+        *shared.active.write().unwrap() = Arc::new(active);
+        self.plugins = plugins;
+        self.config = config;
+        info!("Reloaded config: {}", diff);
+
+        added.iter().any(|w| w.initial_scan.is_some()).then(|| {
+            let dedup = shared.scan_dedup.clone();
+            scan::spawn_scan(&added, SystemTime::now(), HashSet::new(), watcher.injector(), self.stop.running.clone(), dedup)
+        })
+    }
+
+    /// Stop the workflow
+    pub fn stop(&self) {
+        self.stop.stop();
+    }
+}
+
+/// Load the processed index if `runner.index_file` is set
+pub(crate) fn load_index(config: &WorkflowConfig) -> Result<Option<ProcessedIndex>> {
+    let Some(path) = &config.runner.index_file else {
+        return Ok(None);
+    };
+    let index = ProcessedIndex::load(path)?;
+    info!("Loaded index of {} processed file(s) from {}", index.len(), path.display());
+    Ok(Some(index))
+}
+
+/// Events to handle before new ones: those cancelled by the last stop,
+/// then files that changed while stopped
+///
+/// Also returns the paths the initial scan can skip.
+pub(crate) fn catch_up(config: &WorkflowConfig, index: Option<&ProcessedIndex>) -> Result<(Vec<Event>, HashSet<PathBuf>)> {
+    // Indexed files are reconciled here; the initial scan only adds new ones
+    let mut skip: HashSet<PathBuf> = index.iter().flat_map(|i| i.paths().map(Path::to_path_buf)).collect();
+    let mut events = Vec::new();
+
+    if let Some(path) = &config.runner.pending_file {
+        let replay = load_pending(path)?;
+        if !replay.is_empty() {
+            info!("Replaying {} pending event(s) from {}", replay.len(), path.display());
+        }
+        skip.extend(replay.iter().filter_map(conditions::event_path).map(Path::to_path_buf));
+        events.extend(replay);
+    }
+
+    if let Some(index) = index {
+        let own_files = [&config.runner.index_file, &config.runner.pending_file];
+        let mut queued = skip.clone();
+        queued.extend(own_files.into_iter().flatten().cloned());
+        let offline_changes = index.reconcile(&config.watch, &queued);
+        if !offline_changes.is_empty() {
+            info!("{} file(s) changed while stopped", offline_changes.len());
+        }
+        events.extend(offline_changes);
+    }
+    Ok((events, skip))
+}
+
+/// Watch a configured directory, creating it if missing
+pub(crate) fn watch(watcher: &mut FsWatcher, watch_config: &WatchConfig) -> Result<()> {
+    if !watch_config.path.exists() {
+        warn!(
+            "Watch path does not exist, creating: {}",
+            watch_config.path.display()
         );
+        std::fs::create_dir_all(&watch_config.path)?;
+    }
+    watcher.watch_with(&watch_config.path, watch_config.options()?)
+}
 
-        Ok(())
+/// Change watch registrations, each given as its settings before and after
+///
+/// New and changed watches are registered before any removed one is
+/// unwatched. If a registration fails, the changes made so far are undone
+/// and the error is returned.
+pub(crate) fn swap_watches(watcher: &mut FsWatcher, changes: &[(Option<&WatchConfig>, Option<&WatchConfig>)]) -> Result<()> {
+    let mut done = Vec::new();
+    let mut failure = None;
+    for (before, after) in changes {
+        let Some(after) = after else {
+            continue;
+        };
+        if let Some(before) = before {
+            if let Err(e) = watcher.unwatch(&before.path) {
+                failure = Some(e);
+                break;
+            }
+        }
+        if let Err(e) = watch(watcher, after) {
+            if let Some(before) = before {
+                rewatch(watcher, before);
+            }
+            failure = Some(e);
+            break;
+        }
+        done.push((*before, after));
+    }
+
+    if let Some(e) = failure {
+        for (before, after) in done.into_iter().rev() {
+            if let Err(e) = watcher.unwatch(&after.path) {
+                error!("{}", e);
+            }
+            if let Some(before) = before {
+                rewatch(watcher, before);
+            }
+        }
+        return Err(e);
+    }
+
+    for (before, _) in changes.iter().filter(|(_, after)| after.is_none()) {
+        if let Some(before) = before {
+            if let Err(e) = watcher.unwatch(&before.path) {
+                error!("{}", e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Restore a registration while undoing `swap_watches`
+fn rewatch(watcher: &mut FsWatcher, watch_config: &WatchConfig) {
+    if let Err(e) = watch(watcher, watch_config) {
+        error!("Failed to watch {} again: {}", watch_config.path.display(), e);
+    }
+}
+
+/// Work queued on a shard
+enum Task {
+    Event(Event),
+    /// The wait for a path is over; handle the first of its held events
+    Settled(PathBuf),
+}
+
+impl Task {
+    fn path(&self) -> Option<&Path> {
+        match self {
+            Task::Event(event) => conditions::event_path(event),
+            Task::Settled(path) => Some(path),
+        }
+    }
+}
+
+tokio::task_local! {
+    /// Tells the shard that the event of this task waits to retry an action
+    static PARK: Cell<Option<oneshot::Sender<()>>>;
+}
+
+/// Hand the shard back to other paths before waiting to retry
+fn park() {
+    let _ = PARK.try_with(|park| park.take().map(|tx| tx.send(())));
+}
+
+/// A task and the workflow that handles it
+type Job = (Flight, Task);
+
+/// A job of a workflow, counted in `Shared::in_flight` until dropped
+///
+/// It travels with the job through stability waits, retries and requeues.
+struct Flight(Arc<Shared>);
+
+impl Flight {
+    fn new(shared: &Arc<Shared>) -> Self {
+        shared.in_flight.fetch_add(1, Ordering::SeqCst);
+        Self(shared.clone())
+    }
+}
+
+impl Drop for Flight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Worker tasks on a multi-threaded runtime, each draining its own shard
+pub(crate) struct WorkerPool {
+    runtime: tokio::runtime::Runtime,
+    shards: Vec<mpsc::Sender<Job>>,
+    tasks: Vec<tokio::task::JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    /// Start `settings.workers` workers sharing `settings.queue_capacity`
+    pub(crate) fn new(settings: &RunnerConfig) -> Result<Self> {
+        let workers = settings.workers.max(1);
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(workers)
+            .enable_all()
+            .build()?;
+
+        let shard_capacity = (settings.queue_capacity / workers).max(1);
+        let (shards, tasks) = (0..workers)
+            .map(|_| {
+                let (tx, rx) = mpsc::channel::<Job>(shard_capacity);
+                let requeue = tx.downgrade();
+                (tx, runtime.spawn(work(rx, requeue)))
+            })
+            .unzip();
+        Ok(Self { runtime, shards, tasks })
+    }
+
+    pub(crate) fn workers(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Queue an event on its shard, waiting while the shard is full
+    ///
+    /// If `stop` is requested while waiting, the event is cancelled.
+    pub(crate) fn enqueue(&self, shared: &Arc<Shared>, event: Event, stop: &StopHandle) {
+        let shard = &self.shards[shard_for(&event, self.shards.len())];
+        let mut job = (Flight::new(shared), Task::Event(event));
+        loop {
+            match shard.try_send(job) {
+                Ok(()) => return,
+                Err(TrySendError::Full(returned)) => {
+                    if !stop.is_running() {
+                        shared.cancel(returned.1);
+                        return;
+                    }
+                    job = returned;
+                    std::thread::sleep(BACKPRESSURE_RETRY);
+                }
+                Err(TrySendError::Closed(_)) => {
+                    error!("Worker stopped unexpectedly; dropping event");
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Close the queues and give in-flight actions `grace` to finish
+    ///
+    /// Returns false if actions were abandoned.
+    pub(crate) fn shut_down(self, grace: Duration) -> bool {
+        drop(self.shards);
+        let clean = self.runtime.block_on(async {
+            tokio::time::timeout(grace, futures::future::join_all(self.tasks)).await.is_ok()
+        });
+        if !clean {
+            warn!("Actions still running after the {:?} grace period were abandoned", grace);
+        }
+        self.runtime.shutdown_background();
+        clean
+    }
+}
+
+/// Drain one shard, handling events in order until it is closed
+///
+/// Stability waits run in tasks of their own, which report back through
+/// `requeue` so the held events are handled on this shard. An event that
+/// has to retry an action is left to finish in its task, with later events
+/// for its path held until it is done.
+async fn work(mut rx: mpsc::Receiver<Job>, requeue: mpsc::WeakSender<Job>) {
+    let mut retrying: Vec<tokio::task::JoinHandle<()>> = Vec::new();
+    while let Some((flight, task)) = rx.recv().await {
+        let shared = &flight.0;
+        if shared.cancelled.load(Ordering::SeqCst) {
+            shared.cancel(task);
+            continue;
+        }
+        let path = task.path().map(Path::to_path_buf);
+        // An event sent back to the shard may have others queued behind it
+        let resumes = matches!(task, Task::Settled(_));
+        let (tx, mut parked) = oneshot::channel();
+        let handler = shared.clone();
+        let mut handling = tokio::spawn(PARK.scope(Cell::new(Some(tx)), async move {
+            match task {
+                Task::Event(event) => handler.handle_event(&event).await,
+                Task::Settled(path) => handler.handle_settled(&path).await,
+            }
+        }));
+
+        tokio::select! {
+            hold = &mut handling => follow_up(flight, path.filter(|_| resumes), hold.ok().flatten(), &requeue),
+            Ok(()) = &mut parked => {
+                if let Some(path) = &path {
+                    shared.held.lock().unwrap().entry(path.clone()).or_default();
+                }
+                let requeue = requeue.clone();
+                retrying.retain(|task| !task.is_finished());
+                retrying.push(tokio::spawn(async move {
+                    let hold = handling.await.ok().flatten();
+                    follow_up(flight, path, hold, &requeue);
+                }));
+            }
+        }
+    }
+    // Retries in flight count against the shutdown grace period
+    futures::future::join_all(retrying).await;
+}
+
+/// Go on after an event: wait for stability in a task of its own, or send
+/// the next event queued for `path` back to the shard
+fn follow_up(flight: Flight, path: Option<PathBuf>, hold: Option<Hold>, requeue: &mpsc::WeakSender<Job>) {
+    let requeue = requeue.clone();
+    if let Some(hold) = hold {
+        tokio::spawn(async move {
+            let path = hold.path.clone();
+            flight.0.wait_for(hold).await;
+            settled(requeue, flight, path).await;
+        });
+    } else if let Some(path) = path.filter(|path| flight.0.has_queued(path)) {
+        tokio::spawn(settled(requeue, flight, path));
     }
+}
+
+/// Send `Task::Settled` for a path back to its shard
+async fn settled(requeue: mpsc::WeakSender<Job>, flight: Flight, path: PathBuf) {
+    // Without a sender the pool is shutting down; `finish` saves the held events
+    if let Some(tx) = requeue.upgrade() {
+        let _ = tx.send((flight, Task::Settled(path))).await;
+    }
+}
+
+/// Load events saved by a previous stop
+///
+/// The file stays until the next stop rewrites it, so events are replayed
+/// again if the runner dies before handling them.
+fn load_pending(path: &Path) -> Result<Vec<Event>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+}
 
-    /// Handle a single event
-    fn handle_event(&mut self, event: &Event) {
+/// Save cancelled events for the next start, removing the file if none
+fn save_pending(path: &Path, events: &[Event]) -> Result<()> {
+    if events.is_empty() {
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, serde_json::to_vec_pretty(events)?)?;
+    Ok(())
+}
+
+/// Pick the shard for an event so that events for one path share a shard
+///
+/// A rename goes to the shard of the path it moves away from, behind the
+/// events still queued for that path.
+fn shard_for(event: &Event, shards: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    let path = match &event.kind {
+        EventKind::FileRenamed { from, .. } => Some(from.as_path()),
+        _ => conditions::event_path(event),
+    };
+    match path {
+        Some(path) => path.hash(&mut hasher),
+        None => event.source.hash(&mut hasher),
+    }
+    (hasher.finish() % shards as u64) as usize
+}
+
+impl Shared {
+    pub(crate) fn new(
+        config: &WorkflowConfig,
+        plugins: Option<Arc<PluginHost>>,
+        state: Arc<Mutex<WorkflowState>>,
+        close_writes: CloseWriteTracker,
+        index: Option<ProcessedIndex>,
+    ) -> Result<Self> {
+        Ok(Self {
+            active: RwLock::new(Arc::new(Active::new(config.clone(), plugins)?)),
+            runner: config.runner.clone(),
+            state,
+            close_writes,
+            cancelled: AtomicBool::new(false),
+            pending: Mutex::new(Vec::new()),
+            index: index.map(Mutex::new),
+            index_saved: Mutex::new(Instant::now()),
+            self_writes: SelfWrites::new(Duration::from_millis(config.runner.self_write_window_ms)),
+            scan_dedup: Arc::new(Mutex::new(ScanDedup::new(SystemTime::now()))),
+            held: Mutex::new(HashMap::new()),
+            in_flight: AtomicUsize::new(0),
+        })
+    }
+
+    /// The config events are currently handled with
+    pub(crate) fn active(&self) -> Arc<Active> {
+        self.active.read().unwrap().clone()
+    }
+
+    /// Whether no worker pool holds jobs of this workflow
+    pub(crate) fn is_idle(&self) -> bool {
+        self.in_flight.load(Ordering::SeqCst) == 0
+    }
+
+    /// Handle a single event, or queue it behind an event held for its path
+    ///
+    /// Returns the stability wait to run if the event has to be held.
+    pub(crate) async fn handle_event(&self, event: &Event) -> Option<Hold> {
+        if self.scan_dedup.lock().unwrap().is_duplicate(event) {
+            debug!("Already reported by the scan or the watcher: {:?}", event.kind);
+            return None;
+        }
+        if let Some(path) = conditions::event_path(event) {
+            if let Some(held) = self.held.lock().unwrap().get_mut(path) {
+                debug!("Queueing event behind the one held for {}", path.display());
+                held.events.push_back(event.clone());
+                return None;
+            }
+        }
+        self.handle(event, None).await
+    }
+
+    /// Handle the first event held for `path` once the wait is over
+    ///
+    /// Any later events stay queued; see `has_queued`.
+    async fn handle_settled(&self, path: &Path) -> Option<Hold> {
+        let (event, settled) = {
+            let mut held = self.held.lock().unwrap();
+            let entry = held.get_mut(path)?;
+            if matches!(entry.state, HeldState::Waiting) {
+                return None;
+            }
+            let settled = match std::mem::take(&mut entry.state) {
+                HeldState::Settled(failed) => Some(failed),
+                _ => None,
+            };
+            let event = entry.events.pop_front();
+            if entry.events.is_empty() {
+                held.remove(path);
+            }
+            (event?, settled)
+        };
+        self.handle(&event, settled).await
+    }
+
+    /// Whether events for `path` are queued with nothing ahead of them
+    fn has_queued(&self, path: &Path) -> bool {
+        let mut held = self.held.lock().unwrap();
+        match held.get(path) {
+            Some(entry) if matches!(entry.state, HeldState::Queued) => {
+                if entry.events.is_empty() {
+                    held.remove(path);
+                    return false;
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Handle an event, waiting in place if its file has to become stable
+    async fn handle_in_place(&self, event: &Event) {
+        let mut hold = self.handle_event(event).await;
+        let Some(path) = conditions::event_path(event) else {
+            return;
+        };
+        loop {
+            match hold {
+                Some(waiting) => self.wait_for(waiting).await,
+                None if self.has_queued(path) => {}
+                None => return,
+            }
+            hold = self.handle_settled(path).await;
+        }
+    }
+
+    /// Wait for the file of a held event, noting the policies it did not satisfy
+    async fn wait_for(&self, hold: Hold) {
+        let mut failed = Vec::new();
+        if let Some(policy) = hold.watch_policy {
+            if !self.ensure_stable(&hold.path, &policy, hold.since).await {
+                failed.push(policy);
+            }
+        }
+        if failed.is_empty() {
+            for policy in hold.rule_policies {
+                if !self.ensure_stable(&hold.path, &policy, hold.since).await {
+                    failed.push(policy);
+                }
+            }
+        }
+        if let Some(held) = self.held.lock().unwrap().get_mut(&hold.path) {
+            held.state = HeldState::Settled(failed);
+        }
+    }
+
+    /// Set a task aside on stop, to be saved with the pending events
+    fn cancel(&self, task: Task) {
+        let events = match task {
+            Task::Event(event) => vec![event],
+            Task::Settled(path) => self.held.lock().unwrap().remove(&path).map(|held| held.events.into()).unwrap_or_default(),
+        };
+        self.pending.lock().unwrap().extend(events);
+    }
+
+    /// Handle an event; `settled` lists the stability policies a held
+    /// event did not satisfy, and is `None` for an event not yet held
+    async fn handle(&self, event: &Event, settled: Option<Vec<StabilityConfig>>) -> Option<Hold> {
         debug!("Handling event: {:?}", event.kind);
 
-        // Clone rules to avoid borrow conflict with mutable self
-        let rules: Vec<_> = self.config.rules.iter()
-            .filter(|r| r.enabled && Self::rule_matches_static(r, event))
-            .cloned()
+        if settled.is_none() {
+            if let Some(index) = &self.index {
+                match &event.kind {
+                    EventKind::FileDeleted { path } => {
+                        index.lock().unwrap().forget(path);
+                    }
+                    EventKind::FileRenamed { from, to } => index.lock().unwrap().rename(from, to),
+                    _ => {}
+                }
+            }
+
+            if self.self_writes.suppresses(event) {
+                return None;
+            }
+        }
+
+        let active = self.active();
+        let watch = conditions::event_path(event).and_then(|path| active.config.watch_for(path));
+        let rules: Vec<_> = active.order.iter()
+            .map(|&i| (&active.config.rules[i], &active.patterns[i]))
+            .filter(|(r, _)| in_scope(r, watch))
+            .filter_map(|(r, patterns)| Some((r, rule_matches(r, patterns, event, watch)?)))
             .collect();
+        if rules.is_empty() {
+            return None;
+        }
 
-        for rule in rules {
+        // Files that still exist must be completely written first
+        let written = match &event.kind {
+            EventKind::FileDeleted { .. } => None,
+            _ => conditions::event_path(event),
+        };
+        let watch_policy = written
+            .and(watch)
+            .and_then(|watch| watch.stability.as_ref());
+        let failed = match (settled, written) {
+            (Some(failed), _) => failed,
+            (None, Some(path)) => {
+                let mut rule_policies: Vec<StabilityConfig> = Vec::new();
+                for policy in rules.iter().filter_map(|(r, _)| r.stability.as_ref()) {
+                    if Some(policy) != watch_policy && !rule_policies.contains(policy) {
+                        rule_policies.push(policy.clone());
+                    }
+                }
+                if watch_policy.is_some() || !rule_policies.is_empty() {
+                    // Ahead of any events still queued for the path
+                    let mut held = self.held.lock().unwrap();
+                    let entry = held.entry(path.to_path_buf()).or_default();
+                    entry.events.push_front(event.clone());
+                    entry.state = HeldState::Waiting;
+                    return Some(Hold {
+                        path: path.to_path_buf(),
+                        since: event.timestamp,
+                        watch_policy: watch_policy.cloned(),
+                        rule_policies,
+                    });
+                }
+                Vec::new()
+            }
+            (None, None) => Vec::new(),
+        };
+        if watch_policy.is_some_and(|policy| failed.contains(policy)) {
+            return None;
+        }
+
+        // Skip files whose content was already processed; held events get
+        // here only after their wait, so this is the finished content
+        let fingerprint = match &event.kind {
+            EventKind::FileCreated { path } | EventKind::FileModified { path } => match self.unprocessed(path) {
+                Some(fingerprint) => fingerprint,
+                None => return None,
+            },
+            _ => None,
+        };
+
+        let mut applied_rules = Vec::new();
+        let mut applied_actions = Vec::new();
+        for (rule, captures) in rules {
+            if rule.stability.as_ref().is_some_and(|policy| failed.contains(policy)) {
+                continue;
+            }
+
+            let Some(variables) = active.conditions.evaluate_blocking(&rule.name, &rule.conditions, event).await else {
+                continue;
+            };
+            let variables = captures.into_iter().chain(variables).collect();
             info!("Rule '{}' matched event", rule.name);
-            self.execute_rule_actions(&rule, event);
+            let outcome = self.execute_rule_actions(&active, rule, &conditions::with_variables(event, variables)).await;
+            if outcome.dead_lettered {
+                // The file has been moved out of the way; nothing else can run on it
+                return None;
+            }
+            applied_rules.push(rule.name.clone());
+            applied_actions.extend(outcome.applied);
+
+            if rule.stop || active.config.match_mode == MatchMode::First {
+                debug!("Rule '{}' is final for this event", rule.name);
+                break;
+            }
+        }
+
+        if let Some(path) = written {
+            self.close_writes.forget(path);
+        }
+
+        if let (Some(index), Some(path), Some(fingerprint)) = (&self.index, written, fingerprint) {
+            if !applied_rules.is_empty() {
+                index.lock().unwrap().record(path, fingerprint, applied_rules, applied_actions);
+                self.save_index(false);
+            }
+        }
+        None
+    }
+
+    /// Save the index and cancelled events after a stop, and log the totals
+    pub(crate) fn finish(&self, clean: bool) -> Result<()> {
+        self.save_index(true);
+
+        let held = std::mem::take(&mut *self.held.lock().unwrap());
+        self.pending.lock().unwrap().extend(held.into_values().flat_map(|held| held.events));
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        match &self.runner.pending_file {
+            Some(path) => {
+                save_pending(path, &pending)?;
+                if !pending.is_empty() {
+                    info!("Saved {} pending event(s) to {}", pending.len(), path.display());
+                }
+            }
+            None if !pending.is_empty() => {
+                warn!("Cancelled {} queued event(s)", pending.len());
+            }
+            None => {}
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.stop(clean);
+        info!(
+            "Workflow '{}' stopped{}. Events: {}, Actions: {}, Errors: {}",
+            self.active().config.workflow.name,
+            if clean { "" } else { " (unclean)" },
+            state.events_processed,
+            state.actions_executed,
+            state.error_count
+        );
+        Ok(())
+    }
+
+    /// Fingerprint a file unless the index shows its content was processed
+    ///
+    /// Returns `Some(None)` without an index and `None` to skip the event.
+    fn unprocessed(&self, path: &Path) -> Option<Option<Fingerprint>> {
+        let Some(index) = &self.index else {
+            return Some(None);
+        };
+
+        let known = index.lock().unwrap().get(path).map(|e| e.fingerprint.clone());
+        let fingerprint = match Fingerprint::of(path, known.as_ref()) {
+            Ok(fingerprint) => fingerprint,
+            Err(e) => {
+                debug!("Cannot fingerprint {}: {}", path.display(), e);
+                return None;
+            }
+        };
+
+        if known.as_ref().is_some_and(|known| known.same_content(&fingerprint)) {
+            debug!("Skipping already processed file: {}", path.display());
+            index.lock().unwrap().touch(path, fingerprint);
+            return None;
         }
+        Some(Some(fingerprint))
     }
 
-    /// Check if a rule matches an event (static version to avoid borrow issues)
-    fn rule_matches_static(rule: &RuleConfig, event: &Event) -> bool {
-        // Check event type
-        let event_type = match &event.kind {
-            EventKind::FileCreated { .. } => EventType::Created,
-            EventKind::FileModified { .. } => EventType::Modified,
-            EventKind::FileDeleted { .. } => EventType::Deleted,
-            EventKind::FileRenamed { .. } => EventType::Renamed,
-            _ => return false,
+    /// Save the index on stop, or when the last save is older than the autosave interval
+    fn save_index(&self, force: bool) {
+        let (Some(index), Some(path)) = (&self.index, &self.runner.index_file) else {
+            return;
         };
+        {
+            let mut saved = self.index_saved.lock().unwrap();
+            if !force && saved.elapsed() < INDEX_AUTOSAVE {
+                return;
+            }
+            *saved = Instant::now();
+        }
 
-        if !rule.events.contains(&event_type) {
-            return false;
+        let snapshot = index.lock().unwrap().clone();
+        if let Err(e) = snapshot.save(path) {
+            error!("Failed to save index {}: {}", path.display(), e);
         }
+    }
 
-        // Check file patterns
-        if rule.patterns.is_empty() {
-            return true; // No patterns = match all
+    /// Wait for `path` to satisfy a stability policy; false if it never does
+    async fn ensure_stable(&self, path: &Path, policy: &StabilityConfig, since: DateTime<Utc>) -> bool {
+        match stability::wait_until_stable(path, policy, since, &self.close_writes).await {
+            Stability::Stable => true,
+            Stability::Vanished => {
+                debug!("File vanished before it was completely written: {}", path.display());
+                false
+            }
+            Stability::TimedOut => {
+                error!(
+                    "File not completely written within {}ms: {}",
+                    policy.timeout_ms,
+                    path.display()
+                );
+                self.state.lock().unwrap().record_error();
+                false
+            }
         }
+    }
+
+    /// Execute all actions for a matched rule
+    ///
+    /// Each action sees the event at the file's current path, with the
+    /// results of earlier actions in its pipeline context. If a failure
+    /// stops the rule, its `on_error` steps run; if there are none or they
+    /// fail too, the event is dead-lettered.
+    async fn execute_rule_actions(&self, active: &Active, rule: &RuleConfig, event: &Event) -> RuleOutcome {
+        let mut outcome = RuleOutcome::default();
+        let mut context = PipelineContext::new(event);
 
-        let path = match &event.kind {
-            EventKind::FileCreated { path }
-            | EventKind::FileModified { path }
-            | EventKind::FileDeleted { path } => path,
-            EventKind::FileRenamed { to, .. } => to,
-            _ => return false,
+        let Err(failure) = self.run_steps(active, rule, &rule.actions, event, &mut context, &mut outcome.applied).await else {
+            return outcome;
         };
+        if !rule.on_error.is_empty() {
+            info!("Running error handlers of rule '{}'", rule.name);
+            if self.run_steps(active, rule, &rule.on_error, event, &mut context, &mut outcome.applied).await.is_ok() {
+                return outcome;
+            }
+        }
 
-        let filename = path
-            .file_name()
-            .map(|n| n.to_string_lossy().to_string())
-            .unwrap_or_default();
+        if let Some(dir) = &self.runner.dead_letter_dir {
+            let letter = DeadLetter {
+                event: context.event(event),
+                rule: rule.name.clone(),
+                action: failure.action,
+                attempts: failure.attempts,
+                errors: failure.errors,
+                original_path: context.path.clone(),
+                stored_as: None,
+                failed_at: chrono::Utc::now(),
+            };
+            match letter.store(dir) {
+                Ok(sidecar) => {
+                    warn!("Moved event to the dead-letter directory: {}", sidecar.display());
+                    outcome.dead_lettered = true;
+                }
+                Err(e) => error!("Failed to write dead letter to {}: {}", dir.display(), e),
+            }
+        }
+        outcome
+    }
 
-        for pattern_str in &rule.patterns {
-            match Pattern::new(pattern_str) {
-                Ok(pattern) => {
-                    if pattern.matches(&filename) {
-                        return true;
+    /// Run steps in order until one fails without being handled
+    fn run_steps<'a>(
+        &'a self,
+        active: &'a Active,
+        rule: &'a RuleConfig,
+        steps: &'a [ActionStep],
+        event: &'a Event,
+        context: &'a mut PipelineContext,
+        applied: &'a mut Vec<String>,
+    ) -> BoxFuture<'a, std::result::Result<(), StepFailure>> {
+        Box::pin(async move {
+            for step in steps {
+                let Err(failure) = self.run_step(active, rule, step, event, context, applied).await else {
+                    continue;
+                };
+                if !step.on_error.is_empty() {
+                    info!("Running error handlers of step '{}'", step.id());
+                    if self.run_steps(active, rule, &step.on_error, event, context, applied).await.is_ok() {
+                        continue;
                     }
                 }
-                Err(e) => {
-                    warn!("Invalid glob pattern '{}': {}", pattern_str, e);
+                if step.continue_on_error {
+                    debug!("Continuing after failed step '{}'", step.id());
+                    continue;
                 }
+                return Err(failure);
             }
-        }
+            Ok(())
+        })
+    }
 
-        false
+    /// Run one step: a branch, a parallel group or an action
+    async fn run_step(
+        &self,
+        active: &Active,
+        rule: &RuleConfig,
+        step: &ActionStep,
+        event: &Event,
+        context: &mut PipelineContext,
+        applied: &mut Vec<String>,
+    ) -> std::result::Result<(), StepFailure> {
+        match &step.action {
+            ActionConfig::If { condition, then, otherwise } => {
+                let holds = self.branch_holds(active, rule, step, condition, event, context).await;
+                let (taken, skipped) = if holds { (then, otherwise) } else { (otherwise, then) };
+                debug!("Step '{}' takes the {} branch", step.id(), if holds { "then" } else { "else" });
+                context.skip(skipped);
+                let result = ActionResult::success(if holds { "then" } else { "else" });
+                context.record(step.id(), &result);
+                self.run_steps(active, rule, taken, event, context, applied).await
+            }
+            ActionConfig::Parallel { steps } => {
+                let branches = steps.iter().map(|branch| {
+                    let mut context = context.clone();
+                    async move {
+                        let mut applied = Vec::new();
+                        let result = self
+                            .run_steps(active, rule, std::slice::from_ref(branch), event, &mut context, &mut applied)
+                            .await;
+                        (context, applied, result)
+                    }
+                });
+
+                let mut failure = None;
+                let mut contexts = Vec::new();
+                for (branch, branch_applied, result) in futures::future::join_all(branches).await {
+                    contexts.push(branch);
+                    applied.extend(branch_applied);
+                    if let Err(e) = result {
+                        failure.get_or_insert(e);
+                    }
+                }
+                context.merge(contexts);
+                match failure {
+                    Some(failure) => Err(failure),
+                    None => {
+                        context.record(step.id(), &ActionResult::success(format!("{} step(s) finished", steps.len())));
+                        Ok(())
+                    }
+                }
+            }
+            _ => {
+                self.run_action(active, rule, step, event, context).await?;
+                applied.push(step.action.name().to_string());
+                Ok(())
+            }
+        }
     }
 
-    /// Execute all actions for a matched rule
-    fn execute_rule_actions(&mut self, rule: &RuleConfig, event: &Event) {
-        let runtime = tokio::runtime::Builder::new_current_thread()
-            .enable_all()
-            .build()
-            .expect("Failed to create tokio runtime");
+    /// Whether the condition of an `if` step holds
+    async fn branch_holds(
+        &self,
+        active: &Active,
+        rule: &RuleConfig,
+        step: &ActionStep,
+        condition: &BranchCondition,
+        event: &Event,
+        context: &PipelineContext,
+    ) -> bool {
+        match condition {
+            BranchCondition::Step { step: target, status, pointer, equals } => {
+                context.step_matches(target, *status, pointer.as_deref(), equals.as_ref())
+            }
+            BranchCondition::File { conditions } => {
+                // Keyed apart from the rule's own conditions in the predicate cache
+                let key = format!("{}/{}", rule.name, step.id());
+                active.conditions.evaluate_blocking(&key, conditions, &context.event(event)).await.is_some()
+            }
+        }
+    }
 
-        for action_config in &rule.actions {
-            let action = DynamicAction::from_config(action_config.clone());
+    /// Run an action, retrying failures per the step's or rule's retry policy
+    ///
+    /// The action counts as executed once, however many attempts it takes.
+    async fn run_action(
+        &self,
+        active: &Active,
+        rule: &RuleConfig,
+        step: &ActionStep,
+        event: &Event,
+        context: &mut PipelineContext,
+    ) -> std::result::Result<(), StepFailure> {
+        let name = step.action.name();
+        let step_event = context.event(event);
+        let policy = step.retry.as_ref().or(rule.retry.as_ref()).cloned().unwrap_or_else(RetryPolicy::none);
+        let mut errors = Vec::new();
+        let mut attempt = 0;
+        let mut ran = false;
 
-            match runtime.block_on(action.execute(event)) {
-                Ok(result) => {
-                    self.state.record_action();
-                    if result.success {
-                        info!(
-                            "Action '{}' succeeded: {}",
-                            action.name(),
-                            result.message
-                        );
-                    } else {
-                        warn!(
-                            "Action '{}' failed: {}",
-                            action.name(),
-                            result.message
-                        );
-                        self.state.record_error();
+        loop {
+            attempt += 1;
+            let rendered = step.action.render(&TemplateContext::new(&step_event));
+            let result = match rendered {
+                Ok(config) => {
+                    DynamicAction::from_config(config, active.plugins.as_ref()).execute_blocking(step_event.clone()).await
+                }
+                Err(e) => Err(e),
+            };
+            let (class, chain) = match result {
+                Ok(result) if result.success => {
+                    self.state.lock().unwrap().record_action();
+                    info!("Action '{}' succeeded: {}", name, result.message);
+                    if !rule.allow_retrigger {
+                        self.self_writes.record(&result.affected_paths);
                     }
+                    context.record(step.id(), &result);
+                    return Ok(());
                 }
-                Err(e) => {
-                    error!("Action '{}' error: {}", action.name(), e);
-                    self.state.record_error();
+                Ok(result) => {
+                    ran = true;
+                    (ErrorClass::Failed, vec![result.message])
+                }
+                Err(e) => (ErrorClass::of(&e), deadletter::error_chain(&e)),
+            };
+            let message = chain.join(": ");
+            errors.push(chain);
+
+            if !policy.should_retry(class, attempt) {
+                match class {
+                    ErrorClass::Failed => warn!("Action '{}' failed: {}", name, message),
+                    _ => error!("Action '{}' error: {}", name, message),
                 }
+                break;
+            }
+            let delay = policy.backoff(attempt, conditions::event_path(&step_event));
+            warn!(
+                "Action '{}' failed (attempt {} of {}), retrying in {:?}: {}",
+                name,
+                attempt,
+                policy.max_attempts,
+                delay,
+                message
+            );
+            park();
+            tokio::time::sleep(delay).await;
+        }
+
+        {
+            let mut state = self.state.lock().unwrap();
+            if ran {
+                state.record_action();
             }
+            state.record_error();
         }
+        context.record_failure(step.id(), &errors);
+        Err(StepFailure {
+            action: step.action.clone(),
+            attempts: attempt,
+            errors,
+        })
     }
+}
 
-    /// Stop the workflow
-    pub fn stop(&self) {
-        info!("Stopping workflow: {}", self.config.workflow.name);
-        self.running.store(false, Ordering::SeqCst);
+/// Whether a rule applies to events from `watch`
+fn in_scope(rule: &RuleConfig, watch: Option<&WatchConfig>) -> bool {
+    rule.watches.is_empty()
+        || watch
+            .and_then(|w| w.name.as_ref())
+            .is_some_and(|name| rule.watches.contains(name))
+}
+
+/// Match a rule's event types and patterns; returns the pattern captures
+fn rule_matches(
+    rule: &RuleConfig,
+    patterns: &PatternSet,
+    event: &Event,
+    watch: Option<&WatchConfig>,
+) -> Option<serde_json::Map<String, serde_json::Value>> {
+    // Check event type
+    let event_type = match &event.kind {
+        EventKind::FileCreated { .. } => EventType::Created,
+        EventKind::FileModified { .. } => EventType::Modified,
+        EventKind::FileDeleted { .. } => EventType::Deleted,
+        EventKind::FileRenamed { .. } => EventType::Renamed,
+        EventKind::Custom { .. } => EventType::Custom,
+        _ => return None,
+    };
+
+    if !rule.events.contains(&event_type) {
+        return None;
+    }
+
+    // Custom events match patterns against their name, file events
+    // against the path relative to their watch root
+    let relative = match &event.kind {
+        EventKind::Custom { name } => PathBuf::from(name),
+        _ => {
+            let path = conditions::event_path(event)?;
+            let Some(watch) = watch else {
+                debug!("No watch covers {}; no rule applies", path.display());
+                return None;
+            };
+            relative_to(path, &watch.path)
+        }
+    };
+
+    patterns.matches(&relative)
+}
+
+/// Path relative to a watch root given in either its configured or canonical form
+fn relative_to(path: &Path, root: &Path) -> PathBuf {
+    if let Ok(relative) = path.strip_prefix(root) {
+        return relative.to_path_buf();
+    }
+    root.canonicalize()
+        .ok()
+        .and_then(|root| path.strip_prefix(root).ok().map(Path::to_path_buf))
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn created(path: &str) -> Event {
+        Event::new(EventKind::FileCreated { path: PathBuf::from(path) }, "test")
+    }
+
+    fn shared(config: WorkflowConfig) -> Shared {
+        Shared {
+            runner: config.runner.clone(),
+            active: RwLock::new(Arc::new(Active::new(config, None).unwrap())),
+            state: Arc::new(Mutex::new(WorkflowState::new("test"))),
+            close_writes: CloseWriteTracker::new(),
+            cancelled: AtomicBool::new(false),
+            pending: Mutex::new(Vec::new()),
+            index: None,
+            index_saved: Mutex::new(Instant::now()),
+            self_writes: SelfWrites::new(Duration::from_secs(5)),
+            scan_dedup: Arc::new(Mutex::new(ScanDedup::new(SystemTime::now()))),
+            held: Mutex::new(HashMap::new()),
+            in_flight: AtomicUsize::new(0),
+        }
+    }
+
+    #[test]
+    fn test_cancelled_events_are_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = Arc::new(shared(WorkflowConfig::example()));
+        shared.cancelled.store(true, Ordering::SeqCst);
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let (tx, rx) = mpsc::channel(8);
+        for i in 0..3 {
+            tx.try_send((Flight::new(&shared), Task::Event(created(&format!("/in/{}.pdf", i))))).unwrap();
+        }
+        let requeue = tx.downgrade();
+        drop(tx);
+        runtime.block_on(work(rx, requeue));
+
+        assert!(shared.is_idle());
+        let pending = std::mem::take(&mut *shared.pending.lock().unwrap());
+        assert_eq!(pending.len(), 3);
+        assert_eq!(shared.state.lock().unwrap().actions_executed, 0);
+
+        let file = dir.path().join("pending.json");
+        save_pending(&file, &pending).unwrap();
+        let replayed = load_pending(&file).unwrap();
+        assert_eq!(replayed.len(), 3);
+        assert_eq!(replayed[0].id, pending[0].id);
+
+        // Only a stop after the replay was handled removes the file
+        assert!(file.exists());
+        save_pending(&file, &[]).unwrap();
+        assert!(!file.exists());
+    }
+
+    #[test]
+    fn test_stop_before_run() {
+        let mut runner = WorkflowRunner::new(WorkflowConfig::example());
+        runner.stop_handle().stop();
+        runner.run().unwrap();
+        assert!(runner.state().started_at.is_none());
+    }
+
+    #[test]
+    fn test_rules_scoped_to_watches() {
+        let mut config = WorkflowConfig::example();
+        config.rules[0].watches = vec!["inbox".to_string()];
+        let rule = &config.rules[0];
+
+        assert!(in_scope(rule, config.watch.first()));
+        assert!(!in_scope(rule, None));
+
+        let mut other = config.watch[0].clone();
+        other.name = Some("outbox".to_string());
+        assert!(!in_scope(rule, Some(&other)));
+    }
+
+    #[test]
+    fn test_patterns_match_relative_to_watch_root() {
+        let mut config = WorkflowConfig::example();
+        config.watch[0].path = PathBuf::from("/srv/inbox");
+        config.rules[0].patterns = vec![r"regex:^invoices/(?P<client>[^/]+)/.*\.pdf$".to_string()];
+        let rule = &config.rules[0];
+        let patterns = rule.pattern_set().unwrap();
+        let watch = config.watch.first();
+
+        let captures = rule_matches(rule, &patterns, &created("/srv/inbox/invoices/acme/march.pdf"), watch).unwrap();
+        assert_eq!(captures["client"], "acme");
+        assert!(rule_matches(rule, &patterns, &created("/srv/inbox/acme/march.pdf"), watch).is_none());
+
+        // Without a watch there is no root to match against
+        config.rules[0].patterns = vec!["*.pdf".to_string()];
+        let patterns = config.rules[0].pattern_set().unwrap();
+        assert!(rule_matches(&config.rules[0], &patterns, &created("/elsewhere/march.pdf"), None).is_none());
+    }
+
+    #[test]
+    fn test_index_skips_unchanged_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = WorkflowConfig::example();
+        config.watch[0].path = dir.path().to_path_buf();
+        config.rules[0].patterns = vec!["*.txt".to_string()];
+        config.rules[0].actions = vec![crate::actions::ActionConfig::Copy {
+            destination: dir.path().join("out"),
+            overwrite: true,
+            preserve_structure: false,
+        }.into()];
+        let mut shared = shared(config);
+        shared.index = Some(Mutex::new(ProcessedIndex::default()));
+
+        let file = dir.path().join("a.txt");
+        std::fs::write(&file, b"v1").unwrap();
+        let event = Event::new(EventKind::FileCreated { path: file.clone() }, "test");
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+
+        // A duplicate notification does not run the actions again
+        runtime.block_on(shared.handle_event(&event));
+        runtime.block_on(shared.handle_event(&event));
+        assert_eq!(shared.state.lock().unwrap().actions_executed, 1);
+        let entry = shared.index.as_ref().unwrap().lock().unwrap().get(&file).cloned().unwrap();
+        assert_eq!(entry.rules, vec!["backup-pdfs"]);
+
+        std::fs::write(&file, b"v2, longer").unwrap();
+        runtime.block_on(shared.handle_event(&event));
+        assert_eq!(shared.state.lock().unwrap().actions_executed, 2);
+
+        std::fs::remove_file(&file).unwrap();
+        runtime.block_on(shared.handle_event(&Event::new(EventKind::FileDeleted { path: file.clone() }, "test")));
+        assert!(shared.index.as_ref().unwrap().lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_first_match_wins() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = WorkflowConfig::example();
+        config.watch[0].path = dir.path().to_path_buf();
+        config.rules[0].patterns = vec!["*.txt".to_string()];
+        config.rules[0].actions = vec![crate::actions::ActionConfig::Copy {
+            destination: dir.path().join("archive"),
+            overwrite: true,
+            preserve_structure: false,
+        }.into()];
+        let mut moved = config.rules[0].clone();
+        moved.name = "move-texts".to_string();
+        moved.priority = 5;
+        moved.actions = vec![crate::actions::ActionConfig::Copy {
+            destination: dir.path().join("texts"),
+            overwrite: true,
+            preserve_structure: false,
+        }.into()];
+        config.rules.push(moved);
+        config.match_mode = MatchMode::First;
+        let shared = shared(config);
+
+        let file = dir.path().join("a.txt");
+        std::fs::write(&file, b"data").unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(shared.handle_event(&Event::new(EventKind::FileCreated { path: file }, "test")));
+
+        assert!(dir.path().join("texts/a.txt").exists());
+        assert!(!dir.path().join("archive").exists());
+    }
+
+    #[test]
+    fn test_actions_follow_moved_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = WorkflowConfig::example();
+        config.watch[0].path = dir.path().to_path_buf();
+        config.rules[0].patterns = vec![r"regex:^(?P<kind>[a-z]+)-.*\.txt$".to_string()];
+        config.rules[0].actions = vec![
+            crate::actions::ActionConfig::Move {
+                destination: dir.path().join("sorted"),
+                overwrite: false,
+            }.into(),
+            crate::actions::ActionConfig::Copy {
+                destination: dir.path().join("backup/{kind}"),
+                overwrite: false,
+                preserve_structure: false,
+            }.into(),
+        ];
+        let shared = shared(config);
+
+        let file = dir.path().join("invoice-7.txt");
+        std::fs::write(&file, b"data").unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(shared.handle_event(&Event::new(EventKind::FileCreated { path: file.clone() }, "test")));
+
+        assert!(!file.exists());
+        assert!(dir.path().join("sorted/invoice-7.txt").exists());
+        assert!(dir.path().join("backup/invoice/invoice-7.txt").exists());
+        assert_eq!(shared.state.lock().unwrap().error_count, 0);
+    }
+
+    #[test]
+    fn test_branches_parallel_and_error_handlers() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("blocked"), b"not a directory").unwrap();
+        let out = |name: &str| dir.path().join(name).to_string_lossy().to_string();
+        let mut config = WorkflowConfig::example();
+        config.watch[0].path = dir.path().to_path_buf();
+        config.rules[0].patterns = vec!["*.txt".to_string()];
+        config.rules[0].actions = serde_json::from_value(serde_json::json!([
+            { "type": "copy", "id": "nfs", "destination": out("blocked/nfs"), "continue_on_error": true },
+            { "type": "if", "condition": { "type": "step", "step": "nfs", "status": "failed" },
+              "then": [{ "type": "parallel", "steps": [
+                  { "type": "copy", "destination": out("local") },
+                  { "type": "copy", "destination": out("mirror") },
+              ] }],
+              "else": [{ "type": "copy", "id": "unused", "destination": out("unused") }] },
+            { "type": "copy", "destination": out("blocked/again") },
+            { "type": "copy", "destination": out("never") },
+        ])).unwrap();
+        config.rules[0].on_error = vec![crate::actions::ActionConfig::Move {
+            destination: dir.path().join("failed"),
+            overwrite: false,
+        }.into()];
+        // The outputs land next to the file, inside the watch
+        config.rules[0].allow_retrigger = true;
+        config.validate().unwrap();
+        let shared = shared(config);
+
+        let file = dir.path().join("a.txt");
+        std::fs::write(&file, b"data").unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(shared.handle_event(&Event::new(EventKind::FileCreated { path: file.clone() }, "test")));
+
+        assert!(dir.path().join("local/a.txt").exists());
+        assert!(dir.path().join("mirror/a.txt").exists());
+        assert!(!dir.path().join("unused").exists());
+        assert!(!dir.path().join("never").exists());
+        // The second failure stopped the rule and its handler moved the file
+        assert!(dir.path().join("failed/a.txt").exists());
+        assert_eq!(shared.state.lock().unwrap().error_count, 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_parallel_branches_overlap() {
+        let dir = tempfile::tempdir().unwrap();
+        // Each branch copies into a FIFO, whose open blocks until it is read.
+        // The FIFOs are read second branch first, so running the branches
+        // one after the other would never finish.
+        let fifo = |name: &str| {
+            std::fs::create_dir_all(dir.path().join(name)).unwrap();
+            let path = dir.path().join(name).join("a.txt");
+            assert!(std::process::Command::new("mkfifo").arg(&path).status().unwrap().success());
+            path
+        };
+        let (first, second) = (fifo("first"), fifo("second"));
+        let copy = |name: &str| serde_json::json!({
+            "type": "copy", "destination": dir.path().join(name), "overwrite": true,
+        });
+        let mut config = WorkflowConfig::example();
+        config.watch[0].path = dir.path().to_path_buf();
+        config.rules[0].patterns = vec!["*.txt".to_string()];
+        config.rules[0].allow_retrigger = true;
+        config.rules[0].actions = serde_json::from_value(serde_json::json!([
+            { "type": "parallel", "steps": [copy("first"), copy("second")] },
+        ])).unwrap();
+        let shared = Arc::new(shared(config));
+
+        let file = dir.path().join("a.txt");
+        std::fs::write(&file, b"data").unwrap();
+        let handler = shared.clone();
+        let handling = std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+            runtime.block_on(handler.handle_event(&Event::new(EventKind::FileCreated { path: file }, "test")));
+        });
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let read = |path: &Path| std::fs::read(path).unwrap();
+            let _ = tx.send((read(&second), read(&first)));
+        });
+        let (second, first) = rx.recv_timeout(Duration::from_secs(10)).expect("branches ran one after the other");
+        assert_eq!((first.as_slice(), second.as_slice()), (&b"data"[..], &b"data"[..]));
+        handling.join().unwrap();
+        assert_eq!(shared.state.lock().unwrap().error_count, 0);
+    }
+
+    #[test]
+    fn test_own_writes_do_not_retrigger() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = WorkflowConfig::example();
+        config.watch[0].path = dir.path().to_path_buf();
+        config.rules[0].patterns = vec!["*.txt".to_string()];
+        config.rules[0].actions = vec![crate::actions::ActionConfig::Rename {
+            pattern: "{name}-done.txt".to_string(),
+        }.into()];
+        let shared = shared(config);
+
+        let file = dir.path().join("a.txt");
+        std::fs::write(&file, b"data").unwrap();
+        let renamed = dir.path().join("a-done.txt");
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(shared.handle_event(&Event::new(EventKind::FileCreated { path: file.clone() }, "test")));
+        assert!(renamed.exists());
+
+        // The watcher reporting the rename does not rename it again
+        let echo = Event::new(EventKind::FileRenamed { from: file, to: renamed.clone() }, "test");
+        runtime.block_on(shared.handle_event(&echo));
+        assert!(renamed.exists());
+        assert_eq!(shared.state.lock().unwrap().actions_executed, 1);
+    }
+
+    #[test]
+    fn test_exhausted_retries_dead_letter_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("blocked"), b"not a directory").unwrap();
+        let mut config = WorkflowConfig::example();
+        config.watch[0].path = dir.path().to_path_buf();
+        config.rules[0].patterns = vec!["*.txt".to_string()];
+        config.rules[0].actions = vec![crate::actions::ActionConfig::Copy {
+            destination: dir.path().join("blocked/out"),
+            overwrite: true,
+            preserve_structure: false,
+        }.into()];
+        config.rules[0].retry = Some(RetryPolicy {
+            initial_backoff_ms: 1,
+            jitter: 0.0,
+            ..RetryPolicy::default()
+        });
+        config.runner.dead_letter_dir = Some(dir.path().join("dead"));
+        let shared = shared(config);
+
+        let file = dir.path().join("a.txt");
+        std::fs::write(&file, b"data").unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+        runtime.block_on(shared.handle_event(&Event::new(EventKind::FileCreated { path: file.clone() }, "test")));
+
+        assert!(!file.exists());
+        assert_eq!(shared.state.lock().unwrap().error_count, 1);
+        let letters = deadletter::list(&dir.path().join("dead")).unwrap();
+        assert_eq!(letters.len(), 1);
+        let letter = &letters[0].1;
+        assert_eq!(letter.rule, "backup-pdfs");
+        assert_eq!(letter.attempts, 3);
+        assert_eq!(letter.errors.len(), 3);
+        assert_eq!(letter.original_path.as_deref(), Some(file.as_path()));
+    }
+
+    #[test]
+    fn test_retries_do_not_block_their_shard() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = tempfile::tempdir().unwrap();
+        std::fs::write(out.path().join("blocked"), b"not a directory yet").unwrap();
+        let mut config = WorkflowConfig::example();
+        config.watch[0].path = dir.path().to_path_buf();
+        config.rules[0].patterns = vec!["slow.txt".to_string()];
+        config.rules[0].events = vec![EventType::Created, EventType::Modified];
+        config.rules[0].actions = vec![crate::actions::ActionConfig::Copy {
+            destination: out.path().join("blocked/out"),
+            overwrite: true,
+            preserve_structure: false,
+        }.into()];
+        config.rules[0].retry = Some(RetryPolicy {
+            initial_backoff_ms: 1_000,
+            jitter: 0.0,
+            ..RetryPolicy::default()
+        });
+        let mut fast = config.rules[0].clone();
+        fast.name = "fast".to_string();
+        fast.patterns = vec!["fast.txt".to_string()];
+        fast.retry = None;
+        fast.actions = vec![crate::actions::ActionConfig::Copy {
+            destination: out.path().to_path_buf(),
+            overwrite: true,
+            preserve_structure: false,
+        }.into()];
+        config.rules.push(fast);
+        let shared = Arc::new(shared(config));
+        let pool = WorkerPool::new(&RunnerConfig { workers: 1, ..Default::default() }).unwrap();
+        let stop = StopHandle::new();
+
+        let slow = dir.path().join("slow.txt");
+        std::fs::write(&slow, b"data").unwrap();
+        std::fs::write(dir.path().join("fast.txt"), b"data").unwrap();
+        pool.enqueue(&shared, Event::new(EventKind::FileCreated { path: slow.clone() }, "test"), &stop);
+        pool.enqueue(&shared, Event::new(EventKind::FileModified { path: slow.clone() }, "test"), &stop);
+        pool.enqueue(&shared, Event::new(EventKind::FileCreated { path: dir.path().join("fast.txt") }, "test"), &stop);
+
+        let wait_for = |what: &dyn Fn() -> bool| {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while !what() && Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(20));
+            }
+            what()
+        };
+        // The fast file is copied while the slow one waits for its retry
+        assert!(wait_for(&|| out.path().join("fast.txt").exists()));
+        assert_eq!(shared.held.lock().unwrap()[&slow].events.len(), 1);
+
+        std::fs::remove_file(out.path().join("blocked")).unwrap();
+        std::fs::create_dir(out.path().join("blocked")).unwrap();
+        assert!(wait_for(&|| shared.state.lock().unwrap().actions_executed == 3));
+        assert!(pool.shut_down(Duration::from_secs(5)));
+        assert!(out.path().join("blocked/out/slow.txt").exists());
+        assert_eq!(shared.state.lock().unwrap().error_count, 0);
+        assert!(shared.held.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_reload_swaps_rules_and_watches() {
+        let dir = tempfile::tempdir().unwrap();
+        let kept = dir.path().join("kept");
+        let mut config = WorkflowConfig::example();
+        config.watch[0].path = kept.clone();
+        config.rules[0].actions = vec![ActionConfig::Copy {
+            destination: dir.path().join("pdf"),
+            overwrite: true,
+            preserve_structure: false,
+        }.into()];
+        let mut runner = WorkflowRunner::new(config.clone());
+        let shared = runner.shared(CloseWriteTracker::new(), None).unwrap();
+        let mut watcher = FsWatcher::new(false).unwrap();
+        watch(&mut watcher, &config.watch[0]).unwrap();
+
+        // A plugin that cannot load keeps the running config
+        let mut broken = config.clone();
+        broken.plugins.push(serde_json::from_value(serde_json::json!({ "path": "/missing.wasm" })).unwrap());
+        assert!(runner.reload(broken, &mut watcher, &shared, &CloseWriteTracker::new()).is_none());
+        assert!(shared.active().config.plugins.is_empty());
+
+        let added = dir.path().join("added");
+        let mut new = config.clone();
+        new.rules[0].patterns = vec!["*.txt".to_string()];
+        new.watch.push(WatchConfig { name: None, path: added.clone(), ..config.watch[0].clone() });
+        runner.reload(new.clone(), &mut watcher, &shared, &CloseWriteTracker::new());
+        assert_eq!(watcher.watched_paths(), [kept.clone(), added.clone()]);
+
+        // A watch that cannot be registered keeps the running config and watches
+        std::fs::write(dir.path().join("file"), b"").unwrap();
+        let mut unwatchable = new.clone();
+        unwatchable.watch[1].recursive = !unwatchable.watch[1].recursive;
+        for path in [dir.path().join("extra"), dir.path().join("file/sub")] {
+            unwatchable.watch.push(WatchConfig { name: None, path, ..config.watch[0].clone() });
+        }
+        unwatchable.watch.remove(0);
+        assert!(runner.reload(unwatchable, &mut watcher, &shared, &CloseWriteTracker::new()).is_none());
+        assert_eq!(shared.active().config.watch.len(), 2);
+        let mut watched = watcher.watched_paths().to_vec();
+        watched.sort();
+        assert_eq!(watched, [added, kept.clone()]);
+
+        let file = kept.join("a.txt");
+        std::fs::write(&file, b"data").unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(shared.handle_event(&Event::new(EventKind::FileCreated { path: file }, "test")));
+        assert!(dir.path().join("pdf/a.txt").exists());
+    }
+
+    #[test]
+    fn test_same_path_same_shard() {
+        let first = shard_for(&created("/in/a.pdf"), 8);
+        let modified = Event::new(EventKind::FileModified { path: PathBuf::from("/in/a.pdf") }, "other");
+        assert_eq!(first, shard_for(&modified, 8));
+        assert!((0..64).map(|i| shard_for(&created(&format!("/in/{}.pdf", i)), 8)).all(|s| s < 8));
+    }
+
+    #[test]
+    fn test_rename_follows_modify_of_its_source() {
+        let modified = Event::new(EventKind::FileModified { path: PathBuf::from("/in/a.pdf") }, "test");
+        for i in 0..64 {
+            let to = PathBuf::from(format!("/out/{}.pdf", i));
+            let renamed = Event::new(EventKind::FileRenamed { from: PathBuf::from("/in/a.pdf"), to }, "test");
+            assert_eq!(shard_for(&modified, 8), shard_for(&renamed, 8));
+        }
+    }
+
+    #[test]
+    fn test_workers_process_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = WorkflowConfig::example();
+        config.watch[0].path = dir.path().to_path_buf();
+        config.rules[0].patterns = vec!["*.txt".to_string()];
+        config.rules[0].actions = vec![crate::actions::ActionConfig::Copy {
+            destination: dir.path().join("out"),
+            overwrite: true,
+            preserve_structure: false,
+        }.into()];
+        let shared = shared(config);
+
+        let files: Vec<_> = (0..20)
+            .map(|i| {
+                let path = dir.path().join(format!("{}.txt", i));
+                std::fs::write(&path, b"data").unwrap();
+                path
+            })
+            .collect();
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(4)
+            .build()
+            .unwrap();
+        let shared = Arc::new(shared);
+        runtime.block_on(async {
+            let tasks: Vec<_> = files
+                .into_iter()
+                .map(|path| {
+                    let shared = shared.clone();
+                    tokio::spawn(async move {
+                        shared.handle_event(&Event::new(EventKind::FileCreated { path }, "test")).await
+                    })
+                })
+                .collect();
+            for task in tasks {
+                task.await.unwrap();
+            }
+        });
+
+        assert_eq!(shared.state.lock().unwrap().actions_executed, 20);
+        assert_eq!(std::fs::read_dir(dir.path().join("out")).unwrap().count(), 20);
+    }
+
+    #[test]
+    fn test_held_events_do_not_block_their_shard() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = tempfile::tempdir().unwrap();
+        let mut config = WorkflowConfig::example();
+        config.watch[0].path = dir.path().to_path_buf();
+        config.watch[0].stability = Some(StabilityConfig {
+            policy: stability::StabilityPolicy::Sidecar { suffix: ".done".to_string() },
+            timeout_ms: 10_000,
+        });
+        config.rules[0].patterns = vec!["*.txt".to_string()];
+        config.rules[0].events = vec![EventType::Created, EventType::Modified];
+        config.rules[0].actions = vec![crate::actions::ActionConfig::Copy {
+            destination: out.path().to_path_buf(),
+            overwrite: true,
+            preserve_structure: false,
+        }.into()];
+        let shared = Arc::new(shared(config));
+        let pool = WorkerPool::new(&RunnerConfig { workers: 1, ..Default::default() }).unwrap();
+        let stop = StopHandle::new();
+
+        let slow = dir.path().join("slow.txt");
+        let fast = dir.path().join("fast.txt");
+        std::fs::write(&slow, b"data").unwrap();
+        std::fs::write(&fast, b"data").unwrap();
+        std::fs::write(dir.path().join("fast.txt.done"), b"").unwrap();
+        pool.enqueue(&shared, Event::new(EventKind::FileCreated { path: slow.clone() }, "test"), &stop);
+        pool.enqueue(&shared, Event::new(EventKind::FileModified { path: slow.clone() }, "test"), &stop);
+        pool.enqueue(&shared, Event::new(EventKind::FileCreated { path: fast }, "test"), &stop);
+
+        let wait_for = |path: PathBuf| {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while !path.exists() && Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(20));
+            }
+            path.exists()
+        };
+        assert!(wait_for(out.path().join("fast.txt")));
+        assert!(!out.path().join("slow.txt").exists());
+
+        std::fs::write(dir.path().join("slow.txt.done"), b"").unwrap();
+        assert!(wait_for(out.path().join("slow.txt")));
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while shared.state.lock().unwrap().actions_executed < 3 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert!(pool.shut_down(Duration::from_secs(5)));
+        // The event queued behind the held one ran after it
+        assert_eq!(shared.state.lock().unwrap().actions_executed, 3);
+        assert!(shared.held.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_fingerprint_taken_after_stability_wait() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = WorkflowConfig::example();
+        config.watch[0].path = dir.path().to_path_buf();
+        config.watch[0].stability = Some(StabilityConfig {
+            policy: stability::StabilityPolicy::Sidecar { suffix: ".done".to_string() },
+            timeout_ms: 10_000,
+        });
+        config.rules[0].patterns = vec!["*.txt".to_string()];
+        config.rules[0].actions = vec![crate::actions::ActionConfig::Copy {
+            destination: dir.path().join("out"),
+            overwrite: true,
+            preserve_structure: false,
+        }.into()];
+        let mut shared = shared(config);
+        shared.index = Some(Mutex::new(ProcessedIndex::default()));
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+
+        let file = dir.path().join("a.txt");
+        std::fs::write(&file, b"partial").unwrap();
+        let hold = runtime.block_on(shared.handle_event(&created(file.to_str().unwrap()))).unwrap();
+
+        // The writer finishes while the event is held
+        std::fs::write(&file, b"partial, then the rest").unwrap();
+        std::fs::write(dir.path().join("a.txt.done"), b"").unwrap();
+        runtime.block_on(shared.wait_for(hold));
+        assert!(runtime.block_on(shared.handle_settled(&file)).is_none());
+
+        let entry = shared.index.as_ref().unwrap().lock().unwrap().get(&file).cloned().unwrap();
+        let finished = Fingerprint::of(&file, None).unwrap();
+        assert!(entry.fingerprint.same_content(&finished));
     }
 }
@@ -0,0 +1,297 @@
+// SPDX-License-Identifier: MIT OR AGPL-3.0-or-later
+// SPDX-FileCopyrightText: 2024 Hyperpolymath <hyperpolymath@proton.me>
+
+//! Catch-up scan of files that arrived while the runner was down
+//!
+//! Once the watches are in place, each watch with `initial_scan` set is
+//! walked and a synthetic `FileCreated` event (metadata
+//! `initial_scan: true`) is injected for every file its filter admits.
+//! Files modified after the watcher started are skipped, as the watcher
+//! reports those itself, as are files already queued for replay and files
+//! in the processed index (see [`crate::index`]), which a watch with an
+//! initial scan requires.
+//!
+//! A file copied in with its old modification time is reported by both the
+//! watcher and the scan; [`ScanDedup`] drops whichever event comes second.
+
+use crate::config::WatchConfig;
+use crate::rename::parent_of;
+use crate::watcher::EventInjector;
+use rpa_core::{Event, EventKind};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+use tracing::{debug, info};
+use walkdir::WalkDir;
+
+/// Catch-up scan settings for a watch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InitialScanConfig {
+    /// Order in which files are emitted
+    #[serde(default)]
+    pub order: ScanOrder,
+    /// Maximum events per second (0 = unlimited, default: 100)
+    #[serde(default = "default_rate")]
+    pub rate_per_sec: u32,
+}
+
+impl Default for InitialScanConfig {
+    fn default() -> Self {
+        Self {
+            order: ScanOrder::default(),
+            rate_per_sec: default_rate(),
+        }
+    }
+}
+
+fn default_rate() -> u32 {
+    100
+}
+
+/// Order of catch-up events
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanOrder {
+    /// Oldest modification time first
+    #[default]
+    Oldest,
+    /// Newest modification time first
+    Newest,
+    /// Lexicographic path order
+    Path,
+}
+
+/// Files under a watch to emit, in the configured order
+///
+/// `started` is when the watcher started; `skip` holds paths already queued or indexed.
+pub fn collect(watch: &WatchConfig, scan: &InitialScanConfig, started: SystemTime, skip: &HashSet<PathBuf>) -> Vec<PathBuf> {
+    let Ok(options) = watch.options() else {
+        return Vec::new();
+    };
+    let depth = match (watch.recursive, watch.max_depth) {
+        (false, _) => 1,
+        (true, Some(max)) => max,
+        (true, None) => usize::MAX,
+    };
+
+    let mut files: Vec<(SystemTime, PathBuf)> = WalkDir::new(&watch.path)
+        .min_depth(1)
+        .max_depth(depth)
+        .follow_links(watch.follow_symlinks)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .filter(|entry| options.filter.allows(&watch.path, entry.path()))
+        .filter(|entry| !skip.contains(entry.path()))
+        .filter_map(|entry| {
+            let modified = entry.metadata().ok()?.modified().ok()?;
+            (modified < started).then(|| (modified, entry.into_path()))
+        })
+        .collect();
+
+    match scan.order {
+        ScanOrder::Oldest => files.sort(),
+        ScanOrder::Newest => files.sort_by(|a, b| b.cmp(a)),
+        ScanOrder::Path => files.sort_by(|a, b| a.1.cmp(&b.1)),
+    }
+    files.into_iter().map(|(_, path)| path).collect()
+}
+
+/// Drops repeated reports of files last written before the runner started
+///
+/// Only such files can be reported by both the scan and the watcher, so
+/// only they are tracked, and only while a scan runs. A path is forgotten
+/// once both have reported it.
+#[derive(Debug)]
+pub struct ScanDedup {
+    started: SystemTime,
+    /// Scans still running
+    scans: usize,
+    /// Paths reported by one side so far, and whether it was the scan
+    seen: HashMap<PathBuf, bool>,
+}
+
+impl ScanDedup {
+    /// Track files last written before `started`
+    pub fn new(started: SystemTime) -> Self {
+        Self { started, scans: 0, seen: HashMap::new() }
+    }
+
+    /// Track reports until a matching [`ScanDedup::scan_finished`]
+    pub fn scan_started(&mut self) {
+        self.scans += 1;
+    }
+
+    /// Forget every report once no scan runs any more
+    pub fn scan_finished(&mut self) {
+        self.scans = self.scans.saturating_sub(1);
+        if self.scans == 0 {
+            self.seen = HashMap::new();
+        }
+    }
+
+    /// Whether `event` repeats a scan event, or is a scan event for a path
+    /// the watcher already reported
+    pub fn is_duplicate(&mut self, event: &Event) -> bool {
+        let (EventKind::FileCreated { path } | EventKind::FileModified { path }) = &event.kind else {
+            return false;
+        };
+        if self.scans == 0 || !written_before(path, self.started) {
+            return false;
+        }
+        let from_scan = event.metadata.get("initial_scan").and_then(|v| v.as_bool()) == Some(true);
+        match self.seen.entry(path.clone()) {
+            // Later watcher events for the path are changes of their own
+            Entry::Occupied(reported) if *reported.get() != from_scan => {
+                reported.remove();
+                true
+            }
+            Entry::Occupied(_) => from_scan,
+            Entry::Vacant(entry) => {
+                entry.insert(from_scan);
+                false
+            }
+        }
+    }
+}
+
+fn written_before(path: &Path, time: SystemTime) -> bool {
+    std::fs::metadata(path)
+        .and_then(|m| m.modified())
+        .is_ok_and(|modified| modified < time)
+}
+
+/// Scan every watch with `initial_scan` set on a background thread
+pub fn spawn_scan(
+    watches: &[WatchConfig],
+    started: SystemTime,
+    skip: HashSet<PathBuf>,
+    injector: EventInjector,
+    running: Arc<AtomicBool>,
+    dedup: Arc<Mutex<ScanDedup>>,
+) -> JoinHandle<()> {
+    let watches: Vec<_> = watches
+        .iter()
+        .filter_map(|w| Some((w.clone(), w.initial_scan?)))
+        .collect();
+
+    dedup.lock().unwrap().scan_started();
+    std::thread::spawn(move || {
+        scan_watches(&watches, started, &skip, &injector, &running);
+        dedup.lock().unwrap().scan_finished();
+    })
+}
+
+/// Emit the catch-up events of `watches` until stopped
+fn scan_watches(
+    watches: &[(WatchConfig, InitialScanConfig)],
+    started: SystemTime,
+    skip: &HashSet<PathBuf>,
+    injector: &EventInjector,
+    running: &AtomicBool,
+) {
+    for (watch, scan) in watches {
+        let files = collect(watch, scan, started, skip);
+        info!("Initial scan of {}: {} file(s) to process", watch.path.display(), files.len());
+
+        let pause = match scan.rate_per_sec {
+            0 => Duration::ZERO,
+            rate => Duration::from_secs(1) / rate,
+        };
+        for path in files {
+            if !running.load(Ordering::SeqCst) {
+                debug!("Initial scan stopped");
+                return;
+            }
+            let source = parent_of(&path);
+            let event = Event::new(EventKind::FileCreated { path }, source)
+                .with_metadata(serde_json::json!({ "initial_scan": true }));
+            if !injector.inject(event) {
+                return;
+            }
+            std::thread::sleep(pause);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scan(order: ScanOrder) -> InitialScanConfig {
+        InitialScanConfig { order, rate_per_sec: 0 }
+    }
+
+    #[test]
+    fn test_collect_orders_and_filters() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        let now = SystemTime::now();
+        for (name, age) in [("b.pdf", 10), ("sub/a.pdf", 30), ("c.pdf", 20), ("skip.txt", 40)] {
+            let path = dir.path().join(name);
+            std::fs::write(&path, b"x").unwrap();
+            let file = std::fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(now - Duration::from_secs(age)).unwrap();
+        }
+        let mut watch = crate::WorkflowConfig::example().watch.remove(0);
+        watch.path = dir.path().to_path_buf();
+        watch.include = vec!["*.pdf".to_string()];
+
+        let oldest = collect(&watch, &scan(ScanOrder::Oldest), now, &HashSet::new());
+        assert_eq!(oldest, vec![dir.path().join("sub/a.pdf"), dir.path().join("c.pdf"), dir.path().join("b.pdf")]);
+
+        let skip = HashSet::from([dir.path().join("c.pdf")]);
+        let by_path = collect(&watch, &scan(ScanOrder::Path), now, &skip);
+        assert_eq!(by_path, vec![dir.path().join("b.pdf"), dir.path().join("sub/a.pdf")]);
+
+        // Files written after the watcher started are left to the watcher
+        let earlier = now - Duration::from_secs(15);
+        let before = collect(&watch, &scan(ScanOrder::Newest), earlier, &HashSet::new());
+        assert_eq!(before, vec![dir.path().join("c.pdf"), dir.path().join("sub/a.pdf")]);
+    }
+
+    #[test]
+    fn test_dedup_between_scan_and_watcher() {
+        let dir = tempfile::tempdir().unwrap();
+        let (copied, fresh) = (dir.path().join("copied.pdf"), dir.path().join("fresh.pdf"));
+        std::fs::write(&copied, b"x").unwrap();
+        std::fs::write(&fresh, b"x").unwrap();
+        let started = SystemTime::now();
+        std::fs::File::options().write(true).open(&copied).unwrap()
+            .set_modified(started - Duration::from_secs(3600)).unwrap();
+
+        let watcher = |path: &Path| Event::new(EventKind::FileCreated { path: path.to_path_buf() }, "test");
+        let scanned = |path: &Path| watcher(path).with_metadata(serde_json::json!({ "initial_scan": true }));
+
+        let mut dedup = ScanDedup::new(started);
+        dedup.scan_started();
+        assert!(!dedup.is_duplicate(&scanned(&copied)));
+        assert!(dedup.is_duplicate(&watcher(&copied)));
+        assert!(dedup.seen.is_empty());
+        assert!(!dedup.is_duplicate(&watcher(&copied)));
+
+        let mut dedup = ScanDedup::new(started);
+        dedup.scan_started();
+        assert!(!dedup.is_duplicate(&watcher(&copied)));
+        assert!(!dedup.is_duplicate(&watcher(&copied)));
+        assert!(dedup.is_duplicate(&scanned(&copied)));
+        assert!(!dedup.is_duplicate(&watcher(&copied)));
+
+        // Reports are forgotten once the scan is done, and no longer tracked
+        dedup.scan_finished();
+        assert!(dedup.seen.is_empty());
+        assert!(!dedup.is_duplicate(&scanned(&copied)));
+        assert!(!dedup.is_duplicate(&scanned(&copied)));
+
+        // Files written since the start are never emitted by the scan
+        let mut dedup = ScanDedup::new(started - Duration::from_secs(60));
+        dedup.scan_started();
+        assert!(!dedup.is_duplicate(&watcher(&fresh)));
+        assert!(!dedup.is_duplicate(&watcher(&fresh)));
+    }
+}
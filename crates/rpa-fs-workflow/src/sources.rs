@@ -0,0 +1,363 @@
+// SPDX-License-Identifier: MIT OR AGPL-3.0-or-later
+// SPDX-FileCopyrightText: 2024 Hyperpolymath <hyperpolymath@proton.me>
+
+//! Plugin-defined event sources
+//!
+//! A source plugin produces `rpa_core::Event`s that are fed through the
+//! same rule matching as filesystem events. Two entry points are supported:
+//!
+//! - `poll`: called every `interval_ms`; events are returned as the action
+//!   output (an array, or an object with an `events` array) or emitted
+//!   with `emit_event` host requests
+//! - `source`: a long-running entry point whose `emit_event` requests are
+//!   delivered as they happen; it is restarted after `interval_ms` when it
+//!   returns or exhausts its budget
+//!
+//! Events omitting `id`, `timestamp` or `source` get defaults, with the
+//! source set to `plugin:<name>`. File events are only accepted for paths
+//! the plugin was granted read or write access to.
+
+use crate::watcher::EventInjector;
+use rpa_core::{Error, Event, EventKind, Result};
+use rpa_plugin::{Permission, PermissionSet, PluginContext, PluginHost, PluginInstance};
+use semver::VersionReq;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// How often a stopping source thread checks the running flag
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Configuration for a plugin event source
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SourceConfig {
+    /// Name of this source
+    pub name: String,
+    /// Plugin providing the source
+    pub plugin: String,
+    /// Semver requirement for the plugin version
+    #[serde(default)]
+    pub version: Option<String>,
+    /// Entry point style
+    #[serde(default)]
+    pub mode: SourceMode,
+    /// Exported function to call (default: `poll` or `source`)
+    #[serde(default)]
+    pub action: Option<String>,
+    /// Polling interval, or restart delay for long-running sources
+    #[serde(default = "default_interval")]
+    pub interval_ms: u64,
+    /// Configuration passed to the plugin
+    #[serde(default)]
+    pub config: HashMap<String, Value>,
+    /// Whether this source is enabled
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+/// Source entry point styles
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceMode {
+    /// Called at an interval, returns new events
+    #[default]
+    Poll,
+    /// Long-running, emits events while it runs
+    Source,
+}
+
+fn default_interval() -> u64 {
+    60_000 // 1 minute
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl SourceConfig {
+    /// Name of the exported function to call
+    pub fn entry_point(&self) -> &str {
+        match (&self.action, self.mode) {
+            (Some(action), _) => action,
+            (None, SourceMode::Poll) => "poll",
+            (None, SourceMode::Source) => "source",
+        }
+    }
+
+    /// Parse the version requirement
+    pub fn version_req(&self) -> Result<Option<VersionReq>> {
+        self.version
+            .as_deref()
+            .map(VersionReq::parse)
+            .transpose()
+            .map_err(|e| Error::Config(format!(
+                "Source '{}': invalid version requirement for plugin '{}': {}",
+                self.name, self.plugin, e
+            )))
+    }
+
+    fn resolve<'a>(&self, host: &'a PluginHost) -> Result<&'a PluginInstance> {
+        host.resolve(&self.plugin, self.version_req()?.as_ref())
+            .map_err(|e| Error::Config(format!("Source '{}': {}", self.name, e)))
+    }
+
+    fn context(&self) -> PluginContext {
+        let trigger = Event::new(
+            EventKind::Scheduled {
+                schedule: format!("every {}ms", self.interval_ms),
+            },
+            format!("plugin:{}", self.name),
+        );
+        let mut ctx = PluginContext::new(trigger);
+        for (key, value) in &self.config {
+            ctx = ctx.with_config(key.clone(), value.clone());
+        }
+        ctx
+    }
+
+    /// Convert an emitted JSON value to an event
+    fn to_event(&self, value: Value, granted: &PermissionSet) -> Option<Event> {
+        match serde_json::from_value::<Event>(value) {
+            Ok(mut event) => {
+                let paths: Vec<&Path> = match &event.kind {
+                    EventKind::FileCreated { path }
+                    | EventKind::FileModified { path }
+                    | EventKind::FileDeleted { path } => vec![path],
+                    EventKind::FileRenamed { from, to } => vec![from, to],
+                    _ => Vec::new(),
+                };
+                if let Some(path) = paths.into_iter().find(|path| !may_access(granted, path)) {
+                    warn!(
+                        "Source '{}' emitted an event for {}, which it has no access to",
+                        self.name,
+                        path.display()
+                    );
+                    return None;
+                }
+                if event.source.is_empty() {
+                    event.source = format!("plugin:{}", self.name);
+                }
+                Some(event)
+            }
+            Err(e) => {
+                warn!("Source '{}' emitted an invalid event: {}", self.name, e);
+                None
+            }
+        }
+    }
+}
+
+/// Whether `granted` lets a plugin read or write `path`
+fn may_access(granted: &PermissionSet, path: &Path) -> bool {
+    path.is_absolute()
+        && path.components().all(|c| c != std::path::Component::ParentDir)
+        && (granted.check(&Permission::read_path(path)) || granted.check(&Permission::write_path(path)))
+}
+
+/// Call a poll source once and collect the events it produced
+pub fn poll_once(source: &SourceConfig, host: &PluginHost) -> Result<Vec<Event>> {
+    let instance = source.resolve(host)?;
+    let result = instance
+        .execute(source.entry_point(), &source.context())
+        .map_err(|e| Error::ActionFailed {
+            action: source.name.clone(),
+            reason: e.to_string(),
+        })?;
+
+    if !result.success {
+        return Err(Error::ActionFailed {
+            action: source.name.clone(),
+            reason: result.message,
+        });
+    }
+
+    let returned = match result.output {
+        Value::Array(events) => events,
+        Value::Object(mut obj) => match obj.remove("events") {
+            Some(Value::Array(events)) => events,
+            _ => Vec::new(),
+        },
+        _ => Vec::new(),
+    };
+
+    Ok(result
+        .events
+        .into_iter()
+        .chain(returned)
+        .filter_map(|value| source.to_event(value, instance.permissions()))
+        .collect())
+}
+
+/// Run a long-running source until it returns, injecting events as they arrive
+pub fn run_stream(source: &SourceConfig, host: &PluginHost, injector: &EventInjector) -> Result<()> {
+    let instance = source.resolve(host)?;
+    let (tx, rx) = channel();
+
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            for value in rx {
+                if let Some(event) = source.to_event(value, instance.permissions()) {
+                    injector.inject(event);
+                }
+            }
+        });
+
+        let result = instance.execute_streaming(source.entry_point(), &source.context(), tx);
+        match result {
+            Ok(result) if result.success => Ok(()),
+            Ok(result) => Err(Error::ActionFailed {
+                action: source.name.clone(),
+                reason: result.message,
+            }),
+            Err(e) => Err(Error::ActionFailed {
+                action: source.name.clone(),
+                reason: e.to_string(),
+            }),
+        }
+    })
+}
+
+/// Start a thread per enabled source; threads exit once `running` is cleared
+pub fn spawn_sources(
+    sources: &[SourceConfig],
+    host: Arc<PluginHost>,
+    injector: EventInjector,
+    running: Arc<AtomicBool>,
+) -> Vec<JoinHandle<()>> {
+    sources
+        .iter()
+        .filter(|s| s.enabled)
+        .cloned()
+        .map(|source| {
+            let host = host.clone();
+            let injector = injector.clone();
+            let running = running.clone();
+            std::thread::spawn(move || run_source(&source, &host, &injector, &running))
+        })
+        .collect()
+}
+
+fn run_source(source: &SourceConfig, host: &PluginHost, injector: &EventInjector, running: &AtomicBool) {
+    info!("Starting {:?} source '{}'", source.mode, source.name);
+
+    while running.load(Ordering::SeqCst) {
+        match source.mode {
+            SourceMode::Poll => match poll_once(source, host) {
+                Ok(events) => {
+                    debug!("Source '{}' produced {} event(s)", source.name, events.len());
+                    for event in events {
+                        injector.inject(event);
+                    }
+                }
+                Err(e) => warn!("Source '{}' failed: {}", source.name, e),
+            },
+            SourceMode::Source => {
+                if let Err(e) = run_stream(source, host, injector) {
+                    warn!("Source '{}' failed: {}", source.name, e);
+                }
+            }
+        }
+
+        let deadline = Instant::now() + Duration::from_millis(source.interval_ms);
+        while running.load(Ordering::SeqCst) && Instant::now() < deadline {
+            std::thread::sleep(STOP_CHECK_INTERVAL.min(deadline - Instant::now()));
+        }
+    }
+
+    debug!("Source '{}' stopped", source.name);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watcher::FsWatcher;
+    use rpa_plugin::host::PluginConfig;
+
+    /// `poll` returns one event; `source` emits one through a host request
+    const SOURCE_PLUGIN: &str = r#"
+        (module
+          (import "host" "request" (func $request (param i32 i32) (result i32)))
+          (import "host" "set_output" (func $set_output (param i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 0) "[{\22kind\22:{\22type\22:\22custom\22,\22name\22:\22row_added\22},\22metadata\22:{\22row\22:7}}]")
+          (data (i32.const 100) "{\22type\22:\22emit_event\22,\22event\22:{\22kind\22:{\22type\22:\22manual\22}}}")
+          (func (export "poll") (result i32)
+            (call $set_output (i32.const 0) (i32.const 68)))
+          (func (export "source") (result i32)
+            (drop (call $request (i32.const 100) (i32.const 56)))
+            i32.const 0))
+    "#;
+
+    fn host() -> (tempfile::TempDir, PluginHost) {
+        let dir = tempfile::tempdir().unwrap();
+        let wasm = dir.path().join("csv-feed.wasm");
+        std::fs::write(&wasm, wat::parse_str(SOURCE_PLUGIN).unwrap()).unwrap();
+        let mut host = PluginHost::new().unwrap();
+        host.load_plugin(PluginConfig::new(&wasm)).unwrap();
+        (dir, host)
+    }
+
+    fn source(mode: SourceMode) -> SourceConfig {
+        serde_json::from_value(serde_json::json!({
+            "name": "exports",
+            "plugin": "csv-feed",
+            "mode": mode,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_poll_source_events() {
+        let (_dir, host) = host();
+        let events = poll_once(&source(SourceMode::Poll), &host).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, EventKind::Custom { name: "row_added".to_string() });
+        assert_eq!(events[0].metadata["row"], 7);
+        assert_eq!(events[0].source, "plugin:exports");
+    }
+
+    #[test]
+    fn test_stream_source_injects_events() {
+        let (_dir, host) = host();
+        let mut watcher = FsWatcher::new(false).unwrap();
+        run_stream(&source(SourceMode::Source), &host, &watcher.injector()).unwrap();
+        let event = watcher.try_next_event().unwrap();
+        assert_eq!(event.kind, EventKind::Manual);
+    }
+
+    #[test]
+    fn test_file_events_need_path_access() {
+        let plugin = r#"
+            (module
+              (import "host" "set_output" (func $set_output (param i32 i32) (result i32)))
+              (memory (export "memory") 1)
+              (data (i32.const 0) "[{\22kind\22:{\22type\22:\22file_created\22,\22path\22:\22/etc/passwd\22}}]")
+              (func (export "poll") (result i32)
+                (call $set_output (i32.const 0) (i32.const 55))))
+        "#;
+        let dir = tempfile::tempdir().unwrap();
+        let wasm = dir.path().join("csv-feed.wasm");
+        std::fs::write(&wasm, wat::parse_str(plugin).unwrap()).unwrap();
+        let poll = |config: PluginConfig| {
+            let mut host = PluginHost::new().unwrap();
+            host.load_plugin(config).unwrap();
+            poll_once(&source(SourceMode::Poll), &host).unwrap()
+        };
+
+        assert!(poll(PluginConfig::new(&wasm)).is_empty());
+        let events = poll(PluginConfig::new(&wasm).with_permission(Permission::read_path("/etc")));
+        assert_eq!(events.len(), 1);
+
+        let granted = PermissionSet::new([Permission::read_path("/srv/feed")]);
+        assert!(may_access(&granted, Path::new("/srv/feed/rows.csv")));
+        assert!(!may_access(&granted, Path::new("/srv/feed/../../etc/passwd")));
+        assert!(!may_access(&granted, Path::new("rows.csv")));
+    }
+}
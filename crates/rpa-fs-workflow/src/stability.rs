@@ -0,0 +1,275 @@
+// SPDX-License-Identifier: MIT OR AGPL-3.0-or-later
+// SPDX-FileCopyrightText: 2024 Hyperpolymath <hyperpolymath@proton.me>
+
+//! Write-completion detection
+//!
+//! A stability policy holds an event until the file it refers to has
+//! been completely written. If the policy is not satisfied within its
+//! timeout the event is reported as an error and its rules do not run.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often a held file is re-checked
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often open handles are re-checked; each check scans `/proc`
+const OPEN_HANDLES_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Stability policy with its timeout
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StabilityConfig {
+    /// Condition that marks the file as completely written
+    #[serde(flatten)]
+    pub policy: StabilityPolicy,
+    /// Give up after this long (default: 5 minutes)
+    #[serde(default = "default_timeout")]
+    pub timeout_ms: u64,
+}
+
+/// Conditions that mark a file as completely written
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StabilityPolicy {
+    /// Size and modification time unchanged for `quiet_ms`
+    SizeStable {
+        #[serde(default = "default_quiet")]
+        quiet_ms: u64,
+    },
+    /// The writer closed the file (inotify `IN_CLOSE_WRITE`)
+    CloseWrite,
+    /// No process holds the file open (Linux only)
+    NoOpenHandles,
+    /// A marker file named `<file><suffix>` exists
+    Sidecar {
+        #[serde(default = "default_suffix")]
+        suffix: String,
+    },
+}
+
+fn default_timeout() -> u64 {
+    300_000 // 5 minutes
+}
+
+fn default_quiet() -> u64 {
+    2_000 // 2 seconds
+}
+
+fn default_suffix() -> String {
+    ".done".to_string()
+}
+
+/// Result of waiting for a file to become stable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stability {
+    /// The policy was satisfied
+    Stable,
+    /// The file disappeared while waiting
+    Vanished,
+    /// The timeout elapsed first
+    TimedOut,
+}
+
+/// Close-write notifications recorded by the watcher
+#[derive(Debug, Clone, Default)]
+pub struct CloseWriteTracker {
+    closed: Arc<Mutex<HashMap<PathBuf, DateTime<Utc>>>>,
+}
+
+impl CloseWriteTracker {
+    /// Create an empty tracker
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record that a writer closed `path`
+    pub fn record(&self, path: impl Into<PathBuf>) {
+        self.closed.lock().unwrap().insert(path.into(), Utc::now());
+    }
+
+    /// Whether `path` was closed after being written at `since`
+    pub fn closed_since(&self, path: &Path, since: DateTime<Utc>) -> bool {
+        self.closed
+            .lock()
+            .unwrap()
+            .get(path)
+            .is_some_and(|closed| *closed >= since)
+    }
+
+    /// Forget a path once its event has been handled
+    pub fn forget(&self, path: &Path) {
+        self.closed.lock().unwrap().remove(path);
+    }
+}
+
+/// Wait until `path` satisfies `config`
+///
+/// `since` is when the file was last written, used by `CloseWrite`. The
+/// file is checked on a blocking thread.
+pub async fn wait_until_stable(
+    path: &Path,
+    config: &StabilityConfig,
+    since: DateTime<Utc>,
+    close_writes: &CloseWriteTracker,
+) -> Stability {
+    let deadline = Instant::now() + Duration::from_millis(config.timeout_ms);
+    let mut last_seen: Option<(u64, SystemTime)> = None;
+    let mut unchanged_since = Instant::now();
+
+    loop {
+        let (owned, policy) = (path.to_path_buf(), config.policy.clone());
+        let probe = tokio::task::spawn_blocking(move || probe(&owned, &policy)).await.unwrap_or_else(|e| {
+            tracing::warn!("Stability check panicked: {}", e);
+            None
+        });
+        let Some((current, ready)) = probe else {
+            return Stability::Vanished;
+        };
+
+        let stable = match &config.policy {
+            StabilityPolicy::SizeStable { quiet_ms } => {
+                if last_seen != Some(current) {
+                    last_seen = Some(current);
+                    unchanged_since = Instant::now();
+                }
+                unchanged_since.elapsed() >= Duration::from_millis(*quiet_ms)
+            }
+            StabilityPolicy::CloseWrite => close_writes.closed_since(path, since),
+            StabilityPolicy::NoOpenHandles | StabilityPolicy::Sidecar { .. } => ready,
+        };
+
+        if stable {
+            return Stability::Stable;
+        }
+        if Instant::now() >= deadline {
+            return Stability::TimedOut;
+        }
+
+        let interval = match config.policy {
+            StabilityPolicy::NoOpenHandles => OPEN_HANDLES_POLL_INTERVAL,
+            _ => POLL_INTERVAL,
+        };
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        tokio::time::sleep(interval.min(remaining)).await;
+    }
+}
+
+/// Size and modification time of `path`, and whether the policy's own check passes
+///
+/// Returns `None` if the file is gone. Size and close-write policies are
+/// judged by the caller.
+fn probe(path: &Path, policy: &StabilityPolicy) -> Option<((u64, SystemTime), bool)> {
+    let metadata = std::fs::metadata(path).ok()?;
+    let ready = match policy {
+        StabilityPolicy::NoOpenHandles => !is_open(path),
+        StabilityPolicy::Sidecar { suffix } => sidecar_path(path, suffix).exists(),
+        StabilityPolicy::SizeStable { .. } | StabilityPolicy::CloseWrite => false,
+    };
+    Some(((metadata.len(), metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH)), ready))
+}
+
+/// Path of the marker file for `path`
+pub fn sidecar_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Whether any process has `path` open, by scanning `/proc/*/fd`
+#[cfg(target_os = "linux")]
+fn is_open(path: &Path) -> bool {
+    let Ok(target) = path.canonicalize() else {
+        return false;
+    };
+    let Ok(processes) = std::fs::read_dir("/proc") else {
+        return false;
+    };
+
+    processes
+        .flatten()
+        .filter_map(|process| std::fs::read_dir(process.path().join("fd")).ok())
+        .flat_map(|fds| fds.flatten())
+        .any(|fd| std::fs::read_link(fd.path()).is_ok_and(|link| link == target))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_open(_path: &Path) -> bool {
+    tracing::warn!("The no_open_handles stability policy is only supported on Linux");
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn config(policy: StabilityPolicy, timeout_ms: u64) -> StabilityConfig {
+        StabilityConfig { policy, timeout_ms }
+    }
+
+    #[test]
+    fn test_size_stable() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("scan.pdf");
+        std::fs::write(&file, b"%PDF").unwrap();
+
+        let policy = config(StabilityPolicy::SizeStable { quiet_ms: 150 }, 2_000);
+        let result = block_on(wait_until_stable(&file, &policy, Utc::now(), &CloseWriteTracker::new()));
+        assert_eq!(result, Stability::Stable);
+
+        let missing = dir.path().join("missing.pdf");
+        let result = block_on(wait_until_stable(&missing, &policy, Utc::now(), &CloseWriteTracker::new()));
+        assert_eq!(result, Stability::Vanished);
+    }
+
+    #[test]
+    fn test_sidecar_and_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("batch.csv");
+        std::fs::write(&file, b"a,b").unwrap();
+
+        let policy = config(StabilityPolicy::Sidecar { suffix: ".done".to_string() }, 150);
+        let tracker = CloseWriteTracker::new();
+        assert_eq!(block_on(wait_until_stable(&file, &policy, Utc::now(), &tracker)), Stability::TimedOut);
+
+        std::fs::write(dir.path().join("batch.csv.done"), b"").unwrap();
+        assert_eq!(block_on(wait_until_stable(&file, &policy, Utc::now(), &tracker)), Stability::Stable);
+    }
+
+    #[test]
+    fn test_close_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("download.zip");
+        std::fs::write(&file, b"PK").unwrap();
+
+        let policy = config(StabilityPolicy::CloseWrite, 150);
+        let tracker = CloseWriteTracker::new();
+        let written = Utc::now();
+        assert_eq!(block_on(wait_until_stable(&file, &policy, written, &tracker)), Stability::TimedOut);
+
+        tracker.record(&file);
+        assert_eq!(block_on(wait_until_stable(&file, &policy, written, &tracker)), Stability::Stable);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_open_handles() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("copying.bin");
+        let handle = std::fs::File::create(&file).unwrap();
+        assert!(is_open(&file));
+        drop(handle);
+        assert!(!is_open(&file));
+    }
+}
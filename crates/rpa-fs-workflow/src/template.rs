@@ -0,0 +1,447 @@
+// SPDX-License-Identifier: MIT OR AGPL-3.0-or-later
+// SPDX-FileCopyrightText: 2024 Hyperpolymath <hyperpolymath@proton.me>
+
+//! Templates in action fields
+//!
+//! Destinations, rename patterns and, for plugin actions with
+//! `template_config` set, plugin config strings may contain placeholders
+//! of the form `{name}`, `{name:format}` or `{name|filter|filter:arg}`;
+//! `{{` and `}}` stand for literal braces.
+//!
+//! Names:
+//!
+//! - file: `path`, `filename`, `name` (without extension), `ext`, `dir`,
+//!   `parent` (name of the containing directory)
+//! - event: `event` (created, modified, ...), `source`, `id`, `timestamp`
+//! - attributes, read when used: `mtime`, `ctime`, `size`, `mime`
+//! - clock: `now`, `date` (YYYY-MM-DD), `time` (HH-MM-SS), `datetime`
+//! - `env.NAME`: an environment variable
+//! - `steps.ID...`: results of earlier steps (see [`crate::pipeline`])
+//! - anything else: a pattern capture or condition variable; dotted names
+//!   walk into objects and arrays
+//!
+//! Times take a strftime format: `/archive/{mtime:%Y}/{mtime:%m}/`. They
+//! are in UTC and default to `%Y%m%d_%H%M%S`.
+//!
+//! Filters: `lower`, `upper`, `slug`, `pad:N` (zero-pad to N characters)
+//! and `default:TEXT` (used when the name is unknown or empty).
+//!
+//! In destinations and rename patterns, values other than `path`, `dir`,
+//! `mime`, `env.*` and times must be plain file names: a variable such as
+//! `../../etc` is an error rather than a way out of the destination.
+
+use crate::attributes::{self, TimeField};
+use crate::content::value_text;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Utc};
+use rpa_core::{Error, Event, EventKind, Result};
+use serde_json::{Map, Value};
+use std::cell::OnceCell;
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::path::{Component, Path};
+
+/// Format of times without an explicit one
+const DEFAULT_TIME_FORMAT: &str = "%Y%m%d_%H%M%S";
+
+/// One parsed piece of a template
+#[derive(Debug, PartialEq)]
+enum Piece<'a> {
+    Text(&'a str),
+    Placeholder(Placeholder<'a>),
+}
+
+/// A `{...}` placeholder
+#[derive(Debug, PartialEq)]
+struct Placeholder<'a> {
+    name: &'a str,
+    format: Option<&'a str>,
+    filters: Vec<(&'a str, Option<&'a str>)>,
+}
+
+/// A resolved value, before formatting
+enum Resolved {
+    Text(String),
+    Time(DateTime<Utc>),
+}
+
+/// Values a template can refer to
+pub struct TemplateContext<'a> {
+    event: &'a Event,
+    extra: Map<String, Value>,
+    /// Variables for `env.NAME` in place of the process environment
+    env: Option<HashMap<String, String>>,
+    metadata: OnceCell<Option<Metadata>>,
+}
+
+impl<'a> TemplateContext<'a> {
+    /// Context of an event; variables and step results come from its metadata
+    pub fn new(event: &'a Event) -> Self {
+        Self {
+            event,
+            extra: Map::new(),
+            env: None,
+            metadata: OnceCell::new(),
+        }
+    }
+
+    /// Add a value that takes precedence over everything else
+    pub fn with_value(mut self, name: impl Into<String>, value: impl Into<Value>) -> Self {
+        self.extra.insert(name.into(), value.into());
+        self
+    }
+
+    /// Read `env.NAME` from `env` instead of the process environment
+    pub fn with_env(mut self, env: HashMap<String, String>) -> Self {
+        self.env = Some(env);
+        self
+    }
+
+    fn path(&self) -> Option<&Path> {
+        match &self.event.kind {
+            EventKind::FileCreated { path } | EventKind::FileModified { path } | EventKind::FileDeleted { path } => {
+                Some(path)
+            }
+            EventKind::FileRenamed { to, .. } => Some(to),
+            _ => None,
+        }
+    }
+
+    fn metadata(&self) -> Option<&Metadata> {
+        self.metadata
+            .get_or_init(|| self.path().and_then(|p| std::fs::metadata(p).ok()))
+            .as_ref()
+    }
+
+    fn resolve(&self, name: &str) -> Option<Resolved> {
+        if let Some(value) = lookup(&self.extra, name) {
+            return Some(Resolved::Text(value_text(value)));
+        }
+
+        let text = |s: Option<&std::ffi::OsStr>| s.map(|s| Resolved::Text(s.to_string_lossy().to_string()));
+        let now = Utc::now();
+        match name {
+            "path" => text(self.path().map(Path::as_os_str)),
+            "filename" => text(self.path()?.file_name()),
+            "name" => text(self.path()?.file_stem()),
+            "ext" => Some(text(self.path()?.extension()).unwrap_or(Resolved::Text(String::new()))),
+            "dir" => text(self.path()?.parent().map(Path::as_os_str)),
+            "parent" => text(self.path()?.parent()?.file_name()),
+            "event" => Some(Resolved::Text(event_name(&self.event.kind))),
+            "source" => Some(Resolved::Text(self.event.source.clone())),
+            "id" => Some(Resolved::Text(self.event.id.clone())),
+            "timestamp" => Some(Resolved::Time(self.event.timestamp)),
+            "now" => Some(Resolved::Time(now)),
+            "date" => Some(Resolved::Text(now.format("%Y-%m-%d").to_string())),
+            "time" => Some(Resolved::Text(now.format("%H-%M-%S").to_string())),
+            "datetime" => Some(Resolved::Text(now.format("%Y%m%d_%H%M%S").to_string())),
+            "mtime" => attributes::file_time(self.metadata()?, TimeField::Mtime).map(|t| Resolved::Time(t.into())),
+            "ctime" => attributes::file_time(self.metadata()?, TimeField::Ctime).map(|t| Resolved::Time(t.into())),
+            "size" => Some(Resolved::Text(self.metadata()?.len().to_string())),
+            "mime" => attributes::detect_mime(self.path()?).map(|m| Resolved::Text(m.to_string())),
+            _ => {
+                if let Some(var) = name.strip_prefix("env.") {
+                    return match &self.env {
+                        Some(env) => env.get(var).cloned().map(Resolved::Text),
+                        None => std::env::var(var).ok().map(Resolved::Text),
+                    };
+                }
+                if let Some(step) = name.strip_prefix("steps.") {
+                    let steps = self.event.metadata.pointer("/pipeline/steps")?.as_object()?;
+                    return lookup(steps, step).map(|v| Resolved::Text(value_text(v)));
+                }
+                let variables = self.event.metadata.get("variables")?.as_object()?;
+                lookup(variables, name).map(|v| Resolved::Text(value_text(v)))
+            }
+        }
+    }
+}
+
+/// Replace every placeholder in `template` with its value
+pub fn render(template: &str, context: &TemplateContext) -> Result<String> {
+    render_as(template, context, false)
+}
+
+/// Like [`render`], for a destination or file name
+///
+/// Values that do not come from the file itself, the environment or a
+/// time must be plain file names; see the module docs.
+pub fn render_path(template: &str, context: &TemplateContext) -> Result<String> {
+    render_as(template, context, true)
+}
+
+fn render_as(template: &str, context: &TemplateContext, path: bool) -> Result<String> {
+    let mut rendered = String::with_capacity(template.len());
+    for piece in parse(template)? {
+        match piece {
+            Piece::Text(text) => rendered.push_str(text),
+            Piece::Placeholder(placeholder) => rendered.push_str(&expand(template, &placeholder, context, path)?),
+        }
+    }
+    Ok(rendered)
+}
+
+/// Check a template's syntax, time formats and filters
+pub fn check(template: &str) -> Result<()> {
+    for piece in parse(template)? {
+        let Piece::Placeholder(placeholder) = piece else {
+            continue;
+        };
+        if let Some(format) = placeholder.format {
+            check_time_format(template, format)?;
+        }
+        for (filter, arg) in &placeholder.filters {
+            let valid = match *filter {
+                "lower" | "upper" | "slug" => arg.is_none(),
+                "pad" => arg.is_some_and(|n| n.parse::<usize>().is_ok()),
+                "default" => true,
+                _ => false,
+            };
+            if !valid {
+                return Err(Error::Config(format!("Invalid filter '{}' in template '{}'", filter, template)));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Names referenced by the placeholders in `template`
+pub fn references(template: &str) -> Result<Vec<&str>> {
+    Ok(parse(template)?
+        .into_iter()
+        .filter_map(|piece| match piece {
+            Piece::Placeholder(placeholder) => Some(placeholder.name),
+            Piece::Text(_) => None,
+        })
+        .collect())
+}
+
+fn expand(template: &str, placeholder: &Placeholder, context: &TemplateContext, path: bool) -> Result<String> {
+    let resolved = context.resolve(placeholder.name);
+    if let (true, Some(Resolved::Text(text))) = (path, &resolved) {
+        let trusted = matches!(placeholder.name, "path" | "dir" | "mime") || placeholder.name.starts_with("env.");
+        if !trusted && !is_file_name(text) {
+            return Err(Error::Workflow(format!(
+                "'{}' in template '{}' is '{}', which is not a plain file name",
+                placeholder.name, template, text
+            )));
+        }
+    }
+    let mut value = match (resolved, placeholder.format) {
+        (Some(Resolved::Time(time)), format) => {
+            let format = format.unwrap_or(DEFAULT_TIME_FORMAT);
+            check_time_format(template, format)?;
+            Some(time.format(format).to_string())
+        }
+        (Some(Resolved::Text(_)), Some(_)) => {
+            return Err(Error::Workflow(format!(
+                "'{}' in template '{}' is not a time and takes no format",
+                placeholder.name, template
+            )));
+        }
+        (Some(Resolved::Text(text)), None) => Some(text),
+        (None, _) => None,
+    };
+
+    for (filter, arg) in &placeholder.filters {
+        value = match (*filter, value) {
+            ("default", None) => Some(arg.unwrap_or_default().to_string()),
+            ("default", Some(text)) if text.is_empty() => Some(arg.unwrap_or_default().to_string()),
+            ("default", Some(text)) => Some(text),
+            (_, None) => None,
+            ("lower", Some(text)) => Some(text.to_lowercase()),
+            ("upper", Some(text)) => Some(text.to_uppercase()),
+            ("slug", Some(text)) => Some(slug(&text)),
+            ("pad", Some(text)) => {
+                let width = arg.and_then(|n| n.parse().ok()).unwrap_or(0);
+                Some(format!("{:0>width$}", text, width = width))
+            }
+            (other, _) => {
+                return Err(Error::Workflow(format!("Unknown filter '{}' in template '{}'", other, template)));
+            }
+        };
+    }
+
+    value.ok_or_else(|| Error::Workflow(format!("Undefined template value '{}' in '{}'", placeholder.name, template)))
+}
+
+/// Split a template into text and placeholders
+fn parse(template: &str) -> Result<Vec<Piece<'_>>> {
+    let mut pieces = Vec::new();
+    let mut rest = template;
+    while !rest.is_empty() {
+        let Some(start) = rest.find(['{', '}']) else {
+            pieces.push(Piece::Text(rest));
+            break;
+        };
+        if start > 0 {
+            pieces.push(Piece::Text(&rest[..start]));
+        }
+        rest = &rest[start..];
+
+        if let Some(after) = rest.strip_prefix("{{") {
+            pieces.push(Piece::Text("{"));
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("}}") {
+            pieces.push(Piece::Text("}"));
+            rest = after;
+        } else if rest.starts_with('}') {
+            return Err(Error::Config(format!("Unmatched '}}' in template '{}'", template)));
+        } else {
+            let end = rest
+                .find('}')
+                .ok_or_else(|| Error::Config(format!("Unclosed '{{' in template '{}'", template)))?;
+            pieces.push(Piece::Placeholder(parse_placeholder(template, &rest[1..end])?));
+            rest = &rest[end + 1..];
+        }
+    }
+    Ok(pieces)
+}
+
+fn parse_placeholder<'a>(template: &str, inner: &'a str) -> Result<Placeholder<'a>> {
+    let mut parts = inner.split('|');
+    let head = parts.next().unwrap_or_default().trim();
+    let (name, format) = match head.split_once(':') {
+        Some((name, format)) => (name.trim(), Some(format)),
+        None => (head, None),
+    };
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err(Error::Config(format!("Invalid placeholder '{{{}}}' in template '{}'", inner, template)));
+    }
+
+    let filters = parts
+        .map(|filter| match filter.trim().split_once(':') {
+            Some((filter, arg)) => (filter, Some(arg)),
+            None => (filter.trim(), None),
+        })
+        .collect();
+    Ok(Placeholder { name, format, filters })
+}
+
+fn check_time_format(template: &str, format: &str) -> Result<()> {
+    if StrftimeItems::new(format).any(|item| matches!(item, Item::Error)) {
+        return Err(Error::Config(format!("Invalid time format '{}' in template '{}'", format, template)));
+    }
+    Ok(())
+}
+
+/// Value at a dotted name
+fn lookup<'v>(values: &'v Map<String, Value>, name: &str) -> Option<&'v Value> {
+    let mut segments = name.split('.');
+    let first = values.get(segments.next()?)?;
+    segments.try_fold(first, |value, segment| match value {
+        Value::Object(map) => map.get(segment),
+        Value::Array(items) => items.get(segment.parse::<usize>().ok()?),
+        _ => None,
+    })
+}
+
+/// Whether `text` names a file without leaving its directory
+fn is_file_name(text: &str) -> bool {
+    !text.contains(['/', '\\'])
+        && Path::new(text).components().all(|c| matches!(c, Component::Normal(_)))
+}
+
+/// Lowercase ASCII letters and digits, with runs of anything else as `-`
+fn slug(text: &str) -> String {
+    let mut slug = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_string()
+}
+
+fn event_name(kind: &EventKind) -> String {
+    match kind {
+        EventKind::FileCreated { .. } => "created".to_string(),
+        EventKind::FileModified { .. } => "modified".to_string(),
+        EventKind::FileDeleted { .. } => "deleted".to_string(),
+        EventKind::FileRenamed { .. } => "renamed".to_string(),
+        EventKind::Manual => "manual".to_string(),
+        EventKind::Scheduled { .. } => "scheduled".to_string(),
+        EventKind::Custom { name } => name.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_file_attributes_and_times() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("Scan 01.PDF");
+        std::fs::write(&file, b"%PDF-1.7").unwrap();
+        let mtime = DateTime::parse_from_rfc3339("2023-07-14T09:30:00Z").unwrap();
+        std::fs::File::options().write(true).open(&file).unwrap().set_modified(mtime.into()).unwrap();
+        let event = Event::new(EventKind::FileCreated { path: file }, "test");
+        let context = TemplateContext::new(&event);
+
+        assert_eq!(render("/archive/{mtime:%Y}/{mtime:%m}/", &context).unwrap(), "/archive/2023/07/");
+        assert_eq!(render("{name|slug}.{ext|lower} {size}B {mime}", &context).unwrap(), "scan-01.pdf 8B application/pdf");
+        assert_eq!(render("{event}:{filename|upper}", &context).unwrap(), "created:SCAN 01.PDF");
+        assert_eq!(render("{{literal}} {mtime}", &context).unwrap(), "{literal} 20230714_093000");
+    }
+
+    #[test]
+    fn test_variables_filters_and_env() {
+        let event = crate::conditions::with_variables(
+            &Event::new(EventKind::FileCreated { path: "/in/a.pdf".into() }, "test"),
+            Map::from_iter([
+                ("client".to_string(), json!("Acme Corp")),
+                ("invoice".to_string(), json!({ "number": 42 })),
+            ]),
+        );
+        let env = HashMap::from([("ROOT".to_string(), "/srv".to_string())]);
+        let context = TemplateContext::new(&event).with_value("counter", 7).with_env(env);
+
+        assert_eq!(
+            render("{env.ROOT}/{client|slug}/{invoice.number|pad:5}-{counter}", &context).unwrap(),
+            "/srv/acme-corp/00042-7"
+        );
+        assert!(render("{env.HOME}", &context).is_err());
+        assert_eq!(render("{region|default:eu}/{client|default:none}", &context).unwrap(), "eu/Acme Corp");
+        assert!(render("{region}", &context).is_err());
+        assert!(render("{client:%Y}", &context).is_err());
+    }
+
+    #[test]
+    fn test_path_values_stay_in_place() {
+        let event = crate::conditions::with_variables(
+            &Event::new(EventKind::FileCreated { path: "/in/a.pdf".into() }, "test"),
+            Map::from_iter([
+                ("client".to_string(), json!("acme")),
+                ("up".to_string(), json!("..")),
+                ("escape".to_string(), json!("../../etc")),
+                ("root".to_string(), json!("/etc")),
+                ("windows".to_string(), json!("..\\etc")),
+            ]),
+        );
+        let env = HashMap::from([("ROOT".to_string(), "/srv".to_string())]);
+        let context = TemplateContext::new(&event).with_env(env);
+
+        assert_eq!(render_path("{env.ROOT}/{client}/{dir}/{name}", &context).unwrap(), "/srv/acme//in/a");
+        for name in ["up", "escape", "root", "windows"] {
+            let template = format!("/out/{{{}}}/", name);
+            assert!(render_path(&template, &context).is_err(), "{}", name);
+            assert!(render(&template, &context).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_check_syntax() {
+        assert!(check("/archive/{mtime:%Y}/{name|lower|pad:3}").is_ok());
+        assert!(check("{name").is_err());
+        assert!(check("name}").is_err());
+        assert!(check("{}").is_err());
+        assert!(check("{name|shout}").is_err());
+        assert!(check("{name|pad:x}").is_err());
+        assert!(check("{mtime:%Q}").is_err());
+        assert_eq!(references("{a}/{b.c|upper}").unwrap(), vec!["a", "b.c"]);
+    }
+}
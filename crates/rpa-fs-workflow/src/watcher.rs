@@ -2,33 +2,105 @@
 // SPDX-FileCopyrightText: 2024 Hyperpolymath <hyperpolymath@proton.me>
 
 //! Filesystem watcher implementation using notify
+//!
+//! Filesystem events are filtered and debounced per watch root (see
+//! [`crate::filter`] and [`crate::debounce`]) after rename halves have been
+//! paired (see [`crate::rename`]); injected events are delivered immediately.
 
 use notify::{
-    event::{CreateKind, ModifyKind, RemoveKind, RenameMode},
+    event::{AccessKind, AccessMode, CreateKind, ModifyKind, RemoveKind, RenameMode},
     Config, Event as NotifyEvent, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
 };
+use crate::debounce::{DebounceConfig, Debouncer};
+use crate::filter::WatchFilter;
+use crate::rename::{parent_of, RenameTracker};
+use crate::stability::CloseWriteTracker;
 use rpa_core::{Event, EventKind as RpaEventKind};
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::{channel, Receiver};
-use std::time::Duration;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
 use tracing::{debug, error, info, warn};
 
+/// Message delivered to the watcher's event channel
+enum WatchMessage {
+    /// Raw notification from notify
+    Notify(Result<NotifyEvent, notify::Error>),
+    /// Event injected by another producer
+    Injected(Event),
+    /// Wake a blocked `next_event` without an event
+    Wake,
+}
+
+/// Handle for feeding events from other producers into a watcher
+#[derive(Clone)]
+pub struct EventInjector {
+    sender: Sender<WatchMessage>,
+    /// Recorded as `metadata.workflow` of injected events
+    workflow: Option<String>,
+}
+
+impl EventInjector {
+    /// Inject an event; returns false if the watcher has been dropped
+    pub fn inject(&self, event: Event) -> bool {
+        let mut event = event;
+        if let Some(workflow) = &self.workflow {
+            if !event.metadata.is_object() {
+                event.metadata = serde_json::Value::Object(Default::default());
+            }
+            event.metadata["workflow"] = workflow.clone().into();
+        }
+        self.sender.send(WatchMessage::Injected(event)).is_ok()
+    }
+
+    /// Injector marking its events as belonging to one workflow
+    pub fn for_workflow(&self, workflow: impl Into<String>) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            workflow: Some(workflow.into()),
+        }
+    }
+
+    /// Wake the watcher's consumer; `next_event` returns `None`
+    pub fn wake(&self) {
+        let _ = self.sender.send(WatchMessage::Wake);
+    }
+}
+
+/// Settings for one watched root
+#[derive(Debug, Clone, Default)]
+pub struct WatchOptions {
+    /// Watch subdirectories too
+    pub recursive: bool,
+    /// Debounce and coalescing settings
+    pub debounce: DebounceConfig,
+    /// Which paths under the root are delivered
+    pub filter: WatchFilter,
+}
+
 /// Filesystem watcher that converts notify events to RPA events
 pub struct FsWatcher {
     watcher: RecommendedWatcher,
-    receiver: Receiver<Result<NotifyEvent, notify::Error>>,
+    sender: Sender<WatchMessage>,
+    receiver: Receiver<WatchMessage>,
     watched_paths: Vec<PathBuf>,
     recursive: bool,
+    /// Settings by watch root
+    roots: Vec<(PathBuf, WatchOptions)>,
+    debouncer: Debouncer,
+    renames: RenameTracker,
+    /// Receives close-write notifications when set
+    close_writes: Option<CloseWriteTracker>,
 }
 
 impl FsWatcher {
     /// Create a new filesystem watcher
     pub fn new(recursive: bool) -> rpa_core::Result<Self> {
         let (tx, rx) = channel();
+        let notify_tx = tx.clone();
 
         let watcher = RecommendedWatcher::new(
             move |res| {
-                if let Err(e) = tx.send(res) {
+                if let Err(e) = notify_tx.send(WatchMessage::Notify(res)) {
                     error!("Failed to send watch event: {}", e);
                 }
             },
@@ -38,16 +110,25 @@ pub fn new(recursive: bool) -> rpa_core::Result<Self> {
 
         Ok(Self {
             watcher,
+            sender: tx,
             receiver: rx,
             watched_paths: Vec::new(),
             recursive,
+            roots: Vec::new(),
+            debouncer: Debouncer::new(),
+            renames: RenameTracker::new(),
+            close_writes: None,
         })
     }
 
     /// Add a path to watch
     pub fn watch(&mut self, path: impl AsRef<Path>) -> rpa_core::Result<()> {
-        let path = path.as_ref().to_path_buf();
-        let mode = if self.recursive {
+        self.add_watch(path.as_ref(), self.recursive)
+    }
+
+    fn add_watch(&mut self, path: &Path, recursive: bool) -> rpa_core::Result<()> {
+        let path = path.to_path_buf();
+        let mode = if recursive {
             RecursiveMode::Recursive
         } else {
             RecursiveMode::NonRecursive
@@ -62,6 +143,52 @@ pub fn watch(&mut self, path: impl AsRef<Path>) -> rpa_core::Result<()> {
         Ok(())
     }
 
+    /// Add a path to watch with its own recursion, debounce and filter settings
+    pub fn watch_with(&mut self, path: impl AsRef<Path>, options: WatchOptions) -> rpa_core::Result<()> {
+        let path = path.as_ref().to_path_buf();
+        self.add_watch(&path, options.recursive)?;
+        // Backends may report events under either form of the root
+        if let Ok(canonical) = path.canonicalize() {
+            if canonical != path {
+                self.roots.push((canonical, options.clone()));
+            }
+        }
+        self.roots.push((path, options));
+        Ok(())
+    }
+
+    /// Record close-write notifications in `tracker`
+    pub fn track_close_writes(&mut self, tracker: CloseWriteTracker) {
+        self.close_writes = Some(tracker);
+    }
+
+    /// Watch root containing a path: the longest matching root wins
+    fn root_for(&self, path: &Path) -> Option<&(PathBuf, WatchOptions)> {
+        self.roots
+            .iter()
+            .filter(|(root, _)| path.starts_with(root))
+            .max_by_key(|(root, _)| root.as_os_str().len())
+    }
+
+    /// Debounce settings for an event's path
+    fn debounce_for(&self, event: &Event) -> DebounceConfig {
+        crate::conditions::event_path(event)
+            .and_then(|path| self.root_for(path))
+            .map(|(_, options)| options.debounce)
+            .unwrap_or_else(DebounceConfig::disabled)
+    }
+
+    /// Whether the filter of the event's watch admits it
+    fn admits(&self, event: &Event) -> bool {
+        let Some(path) = crate::conditions::event_path(event) else {
+            return true;
+        };
+        match self.root_for(path) {
+            Some((root, options)) => options.filter.allows(root, path),
+            None => true,
+        }
+    }
+
     /// Stop watching a path
     pub fn unwatch(&mut self, path: impl AsRef<Path>) -> rpa_core::Result<()> {
         let path = path.as_ref();
@@ -70,40 +197,116 @@ pub fn unwatch(&mut self, path: impl AsRef<Path>) -> rpa_core::Result<()> {
             .map_err(|e| rpa_core::Error::Watch(format!("Failed to unwatch {}: {}", path.display(), e)))?;
 
         self.watched_paths.retain(|p| p != path);
+        let canonical = path.canonicalize().ok();
+        self.roots.retain(|(root, _)| root != path && Some(root) != canonical.as_ref());
         info!("Stopped watching: {}", path.display());
         Ok(())
     }
 
+    /// Get a handle for injecting events from other producers
+    pub fn injector(&self) -> EventInjector {
+        EventInjector {
+            sender: self.sender.clone(),
+            workflow: None,
+        }
+    }
+
     /// Get the next event, blocking until one is available
-    pub fn next_event(&self) -> Option<Event> {
-        match self.receiver.recv() {
-            Ok(Ok(event)) => self.convert_event(event),
-            Ok(Err(e)) => {
-                warn!("Watch error: {}", e);
-                None
+    ///
+    /// Returns `None` when woken or when the channel is closed.
+    pub fn next_event(&mut self) -> Option<Event> {
+        loop {
+            if let Some(event) = self.pop_ready() {
+                return Some(event);
+            }
+
+            let deadline = match (self.debouncer.next_deadline(), self.renames.next_deadline()) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            let message = match deadline {
+                Some(deadline) => {
+                    match self.receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                        Ok(message) => message,
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        Err(RecvTimeoutError::Disconnected) => return None,
+                    }
+                }
+                None => self.receiver.recv().ok()?,
+            };
+
+            match message {
+                WatchMessage::Wake => return None,
+                message => {
+                    if let Some(event) = self.handle_message(message) {
+                        return Some(event);
+                    }
+                }
             }
-            Err(_) => None, // Channel closed
         }
     }
 
     /// Try to get the next event without blocking
-    pub fn try_next_event(&self) -> Option<Event> {
-        match self.receiver.try_recv() {
-            Ok(Ok(event)) => self.convert_event(event),
-            Ok(Err(e)) => {
+    pub fn try_next_event(&mut self) -> Option<Event> {
+        while let Ok(message) = self.receiver.try_recv() {
+            if let Some(event) = self.handle_message(message) {
+                return Some(event);
+            }
+        }
+        self.pop_ready()
+    }
+
+    /// Settle expired rename halves, then take a debounced event
+    fn pop_ready(&mut self) -> Option<Event> {
+        let now = Instant::now();
+        for event in self.renames.expire(now) {
+            self.debounce(event, now);
+        }
+        self.debouncer.pop_ready(now)
+    }
+
+    fn debounce(&mut self, event: Event, now: Instant) {
+        if !self.admits(&event) {
+            debug!("Filtered out event: {:?}", event.kind);
+            return;
+        }
+        let config = self.debounce_for(&event);
+        self.debouncer.push(event, config, now);
+    }
+
+    /// Deliver injected events, debounce filesystem events
+    fn handle_message(&mut self, message: WatchMessage) -> Option<Event> {
+        match message {
+            WatchMessage::Notify(Ok(event)) => {
+                if let (Some(tracker), EventKind::Access(AccessKind::Close(AccessMode::Write))) =
+                    (&self.close_writes, event.kind)
+                {
+                    for path in &event.paths {
+                        tracker.record(path);
+                    }
+                }
+                let now = Instant::now();
+                let event = self.convert_event(event, now)?;
+                self.debounce(event, now);
+                None
+            }
+            WatchMessage::Notify(Err(e)) => {
                 warn!("Watch error: {}", e);
                 None
             }
-            Err(_) => None,
+            WatchMessage::Injected(event) => Some(event),
+            WatchMessage::Wake => None,
         }
     }
 
     /// Convert a notify event to an RPA event
-    fn convert_event(&self, event: NotifyEvent) -> Option<Event> {
+    fn convert_event(&mut self, event: NotifyEvent, now: Instant) -> Option<Event> {
+        let tracker = event.attrs.tracker();
         let paths = event.paths;
         if paths.is_empty() {
             return None;
         }
+        let source = parent_of(&paths[0]);
 
         let kind = match event.kind {
             EventKind::Create(CreateKind::File) | EventKind::Create(CreateKind::Any) => {
@@ -122,20 +325,14 @@ fn convert_event(&self, event: NotifyEvent) -> Option<Event> {
                 }
             }
             EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if paths.len() >= 2 => {
-                RpaEventKind::FileRenamed {
-                    from: paths[0].clone(),
-                    to: paths[1].clone(),
-                }
+                return self.renames.moved_both(tracker, paths[0].clone(), paths[1].clone(), &source);
             }
             EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
-                RpaEventKind::FileDeleted {
-                    path: paths[0].clone(),
-                }
+                self.renames.moved_from(tracker, paths[0].clone(), now);
+                return None;
             }
             EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
-                RpaEventKind::FileCreated {
-                    path: paths[0].clone(),
-                }
+                return Some(self.renames.moved_to(tracker, paths[0].clone(), &source));
             }
             _ => {
                 debug!("Ignoring event kind: {:?}", event.kind);
@@ -143,8 +340,6 @@ fn convert_event(&self, event: NotifyEvent) -> Option<Event> {
             }
         };
 
-        let source = paths[0].parent().map(|p| p.to_string_lossy().to_string()).unwrap_or_default();
-
         Some(Event::new(kind, source))
     }
 
@@ -174,4 +369,27 @@ fn test_watch_directory() {
         assert!(result.is_ok());
         assert_eq!(watcher.watched_paths().len(), 1);
     }
+
+    #[test]
+    fn test_filtered_roots() {
+        let dir = tempdir().unwrap();
+        let mut watcher = FsWatcher::new(false).unwrap();
+        let filter = WatchFilter::new(&["*.pdf".to_string()], &[], Some(1), false).unwrap();
+        watcher
+            .watch_with(dir.path(), WatchOptions { recursive: true, debounce: DebounceConfig::disabled(), filter })
+            .unwrap();
+
+        let event = |path: PathBuf| Event::new(RpaEventKind::FileCreated { path }, "test");
+        assert!(watcher.admits(&event(dir.path().join("a.pdf"))));
+        assert!(!watcher.admits(&event(dir.path().join("a.txt"))));
+        assert!(!watcher.admits(&event(dir.path().join("sub/a.pdf"))));
+    }
+
+    #[test]
+    fn test_injected_event() {
+        let mut watcher = FsWatcher::new(false).unwrap();
+        assert!(watcher.injector().inject(Event::new(RpaEventKind::Manual, "test")));
+        let event = watcher.try_next_event().unwrap();
+        assert_eq!(event.kind, RpaEventKind::Manual);
+    }
 }
@@ -5,16 +5,22 @@
 //!
 //! This module defines the interface that plugins must implement.
 
+use crate::error::PluginError;
 use crate::permissions::PermissionSet;
 use crate::Result;
 use async_trait::async_trait;
 use rpa_core::{action::ActionResult, Event};
+use semver::Version;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::path::PathBuf;
 
 /// Current plugin API version
 pub const API_VERSION: &str = "0.1.0";
 
+/// Name of the WASM custom section that carries plugin metadata as JSON
+pub const METADATA_SECTION: &str = "rpa-plugin-metadata";
+
 /// Metadata about a plugin
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PluginMetadata {
@@ -31,9 +37,14 @@ pub struct PluginMetadata {
     /// License (SPDX identifier)
     pub license: Option<String>,
     /// Plugin API version this plugin was built for
+    #[serde(default = "default_api_version")]
     pub api_version: String,
     /// Permissions this plugin requires
+    #[serde(default)]
     pub required_permissions: PermissionSet,
+    /// JSON Schema for each action's configuration, keyed by action name
+    #[serde(default)]
+    pub config_schemas: HashMap<String, serde_json::Value>,
     /// Custom metadata
     #[serde(default)]
     pub extra: HashMap<String, serde_json::Value>,
@@ -51,6 +62,7 @@ pub fn new(id: impl Into<String>, name: impl Into<String>, version: impl Into<St
             license: None,
             api_version: API_VERSION.to_string(),
             required_permissions: PermissionSet::empty(),
+            config_schemas: HashMap::new(),
             extra: HashMap::new(),
         }
     }
@@ -78,6 +90,72 @@ pub fn with_permissions(mut self, perms: PermissionSet) -> Self {
         self.required_permissions = perms;
         self
     }
+
+    /// Parse the plugin version as semver
+    pub fn semver(&self) -> Result<Version> {
+        Version::parse(&self.version).map_err(|e| {
+            PluginError::InvalidFormat(format!(
+                "Plugin '{}' has invalid version '{}': {}",
+                self.id, self.version, e
+            ))
+        })
+    }
+
+    /// Read metadata from the `rpa-plugin-metadata` custom section of a WASM binary
+    ///
+    /// Returns `Ok(None)` if the module does not carry a metadata section.
+    pub fn from_wasm(wasm: &[u8]) -> Result<Option<Self>> {
+        match find_custom_section(wasm, METADATA_SECTION) {
+            Some(data) => Ok(Some(serde_json::from_slice(data)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn default_api_version() -> String {
+    API_VERSION.to_string()
+}
+
+/// Locate a custom section by name in a WASM binary
+fn find_custom_section<'a>(wasm: &'a [u8], name: &str) -> Option<&'a [u8]> {
+    if wasm.len() < 8 || &wasm[0..4] != b"\0asm" {
+        return None;
+    }
+
+    let mut pos = 8;
+    while pos < wasm.len() {
+        let id = wasm[pos];
+        pos += 1;
+        let size = read_leb128(wasm, &mut pos)? as usize;
+        let end = pos.checked_add(size).filter(|&end| end <= wasm.len())?;
+
+        if id == 0 {
+            let mut name_pos = pos;
+            let name_len = read_leb128(wasm, &mut name_pos)? as usize;
+            let name_end = name_pos.checked_add(name_len).filter(|&e| e <= end)?;
+            if &wasm[name_pos..name_end] == name.as_bytes() {
+                return Some(&wasm[name_end..end]);
+            }
+        }
+
+        pos = end;
+    }
+
+    None
+}
+
+/// Decode an unsigned LEB128 u32
+fn read_leb128(bytes: &[u8], pos: &mut usize) -> Option<u32> {
+    let mut result = 0u32;
+    for shift in (0..35).step_by(7) {
+        let byte = *bytes.get(*pos)?;
+        *pos += 1;
+        result |= u32::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(result);
+        }
+    }
+    None
 }
 
 /// Context passed to plugin during execution
@@ -132,6 +210,15 @@ pub struct PluginActionResult {
     /// Logs produced during execution
     #[serde(default)]
     pub logs: Vec<PluginLog>,
+    /// Files written through host requests
+    #[serde(default)]
+    pub files_written: Vec<PathBuf>,
+    /// Permission checks that were denied during execution
+    #[serde(default)]
+    pub permission_denials: Vec<String>,
+    /// Events emitted through host requests and not streamed to a sink
+    #[serde(default)]
+    pub events: Vec<serde_json::Value>,
 }
 
 impl PluginActionResult {
@@ -142,6 +229,9 @@ pub fn success(message: impl Into<String>) -> Self {
             message: message.into(),
             output: serde_json::Value::Null,
             logs: Vec::new(),
+            files_written: Vec::new(),
+            permission_denials: Vec::new(),
+            events: Vec::new(),
         }
     }
 
@@ -152,6 +242,9 @@ pub fn failure(message: impl Into<String>) -> Self {
             message: message.into(),
             output: serde_json::Value::Null,
             logs: Vec::new(),
+            files_written: Vec::new(),
+            permission_denials: Vec::new(),
+            events: Vec::new(),
         }
     }
 
@@ -167,7 +260,7 @@ pub fn into_action_result(self) -> ActionResult {
             success: self.success,
             message: self.message,
             output: self.output,
-            affected_paths: Vec::new(),
+            affected_paths: self.files_written,
         }
     }
 }
@@ -250,6 +343,8 @@ pub enum HostRequest {
     CurrentTime,
     /// Generate UUID
     GenerateUuid,
+    /// Emit an `rpa_core::Event` (used by event source plugins)
+    EmitEvent { event: serde_json::Value },
 }
 
 /// Response from host to plugin
@@ -44,6 +44,16 @@ pub enum PluginError {
     #[error("Plugin API version mismatch: expected {expected}, got {got}")]
     VersionMismatch { expected: String, got: String },
 
+    #[error("Plugin version conflict: '{id}' version {version} is already loaded")]
+    VersionConflict { id: String, version: String },
+
+    #[error("No version of plugin '{id}' matches {requirement} (available: {available})")]
+    NoMatchingVersion {
+        id: String,
+        requirement: String,
+        available: String,
+    },
+
     #[error("{0}")]
     Other(String),
 }
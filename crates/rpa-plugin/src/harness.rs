@@ -0,0 +1,423 @@
+// SPDX-License-Identifier: MIT OR AGPL-3.0-or-later
+// SPDX-FileCopyrightText: 2024 Hyperpolymath <hyperpolymath@proton.me>
+
+//! Test harness for running plugin actions against fixture contexts
+//!
+//! A test case supplies a [`PluginContext`], a sandbox configuration and an
+//! optional fixture directory, and states what the resulting
+//! [`PluginActionResult`] must look like. The fixture is copied into a
+//! scratch directory for every case; the string `$FIXTURE` anywhere in the
+//! context, sandbox configuration or expectations is replaced with that
+//! directory's path, and the sandbox working directory defaults to it.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use rpa_plugin::harness::{load_cases, PluginTestHarness};
+//!
+//! let harness = PluginTestHarness::from_file("target/wasm32-unknown-unknown/release/tagger.wasm")?;
+//! for case in load_cases("tests/cases.json")? {
+//!     harness.run_case(&case).assert_passed();
+//! }
+//! ```
+
+use crate::api::{PluginActionResult, PluginContext};
+use crate::error::{PluginError, Result};
+use crate::sandbox::{Sandbox, SandboxConfig};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Placeholder replaced with the scratch fixture directory
+pub const FIXTURE_PLACEHOLDER: &str = "$FIXTURE";
+
+/// A single plugin test case
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TestCase {
+    /// Name of the case
+    pub name: String,
+    /// Action to execute
+    pub action: String,
+    /// Context passed to the action
+    pub context: PluginContext,
+    /// Sandbox configuration
+    #[serde(default)]
+    pub sandbox: SandboxConfig,
+    /// Fixture directory copied into the scratch directory
+    #[serde(default)]
+    pub fixture: Option<PathBuf>,
+    /// Expected outcome
+    #[serde(default)]
+    pub expect: Expectations,
+}
+
+/// Expected outcome of a test case
+///
+/// Fields left unset are not checked.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Expectations {
+    /// Expected success flag
+    #[serde(default)]
+    pub success: Option<bool>,
+    /// Expected output JSON (exact match)
+    #[serde(default)]
+    pub output: Option<serde_json::Value>,
+    /// Substrings that must each appear in some log message
+    #[serde(default)]
+    pub logs: Vec<String>,
+    /// Files the action must have written, relative to the scratch directory
+    #[serde(default)]
+    pub files_written: Option<Vec<PathBuf>>,
+    /// Permission denials the action must have hit, in order
+    #[serde(default)]
+    pub permission_denials: Option<Vec<String>>,
+    /// Substring of the error the execution must fail with
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/// Outcome of running a test case
+#[derive(Debug, Clone)]
+pub struct CaseReport {
+    /// Name of the case
+    pub name: String,
+    /// Result of the action, if it ran to completion
+    pub result: Option<PluginActionResult>,
+    /// Expectation mismatches; empty if the case passed
+    pub failures: Vec<String>,
+}
+
+impl CaseReport {
+    /// Whether every expectation was met
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    /// Panic with the list of mismatches if the case failed
+    pub fn assert_passed(&self) {
+        if !self.passed() {
+            panic!(
+                "plugin test case '{}' failed:\n  {}",
+                self.name,
+                self.failures.join("\n  ")
+            );
+        }
+    }
+}
+
+/// Load test cases from a JSON file containing an array of cases
+///
+/// Relative fixture paths are resolved against the file's directory.
+pub fn load_cases(path: impl AsRef<Path>) -> Result<Vec<TestCase>> {
+    let path = path.as_ref();
+    let mut cases: Vec<TestCase> = serde_json::from_slice(&std::fs::read(path)?)?;
+    let base = path.parent().unwrap_or(Path::new("."));
+    for case in &mut cases {
+        if let Some(fixture) = &case.fixture {
+            if fixture.is_relative() {
+                case.fixture = Some(base.join(fixture));
+            }
+        }
+    }
+    Ok(cases)
+}
+
+/// Runs plugin actions against test cases
+pub struct PluginTestHarness {
+    wasm: Vec<u8>,
+}
+
+impl PluginTestHarness {
+    /// Create a harness for a plugin binary (or WAT text)
+    pub fn new(wasm: impl Into<Vec<u8>>) -> Self {
+        Self { wasm: wasm.into() }
+    }
+
+    /// Create a harness for a plugin file
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self::new(std::fs::read(path)?))
+    }
+
+    /// Run every case, returning one report per case
+    pub fn run_cases(&self, cases: &[TestCase]) -> Vec<CaseReport> {
+        cases.iter().map(|case| self.run_case(case)).collect()
+    }
+
+    /// Run a single case
+    pub fn run_case(&self, case: &TestCase) -> CaseReport {
+        let mut report = CaseReport {
+            name: case.name.clone(),
+            result: None,
+            failures: Vec::new(),
+        };
+
+        let scratch = match ScratchDir::create(case.fixture.as_deref()) {
+            Ok(scratch) => scratch,
+            Err(e) => {
+                report.failures.push(format!("failed to prepare fixture: {}", e));
+                return report;
+            }
+        };
+
+        let outcome = self.execute(case, scratch.path());
+        let expect = match substitute(&case.expect, scratch.path()) {
+            Ok(expect) => expect,
+            Err(e) => {
+                report.failures.push(format!("invalid expectations: {}", e));
+                return report;
+            }
+        };
+
+        match outcome {
+            Ok(result) => {
+                if let Some(error) = &expect.error {
+                    report
+                        .failures
+                        .push(format!("expected error containing '{}', but action ran", error));
+                }
+                check_result(&expect, &result, scratch.path(), &mut report.failures);
+                report.result = Some(result);
+            }
+            Err(e) => {
+                let message = e.to_string();
+                match &expect.error {
+                    Some(expected) if message.contains(expected.as_str()) => {}
+                    Some(expected) => report.failures.push(format!(
+                        "expected error containing '{}', got '{}'",
+                        expected, message
+                    )),
+                    None => report.failures.push(format!("execution failed: {}", message)),
+                }
+            }
+        }
+
+        report
+    }
+
+    fn execute(&self, case: &TestCase, scratch: &Path) -> Result<PluginActionResult> {
+        let context: PluginContext = substitute(&case.context, scratch)?;
+        let mut sandbox_config: SandboxConfig = substitute(&case.sandbox, scratch)?;
+        if sandbox_config.work_dir.is_none() {
+            sandbox_config.work_dir = Some(scratch.to_path_buf());
+        }
+
+        let sandbox = Sandbox::new(sandbox_config)?;
+        let module = sandbox.load_module(&self.wasm)?;
+        sandbox.execute(&module, &case.action, &context)
+    }
+}
+
+fn check_result(
+    expect: &Expectations,
+    result: &PluginActionResult,
+    scratch: &Path,
+    failures: &mut Vec<String>,
+) {
+    if let Some(success) = expect.success {
+        if result.success != success {
+            failures.push(format!(
+                "expected success={}, got success={} ({})",
+                success, result.success, result.message
+            ));
+        }
+    }
+
+    if let Some(output) = &expect.output {
+        if &result.output != output {
+            failures.push(format!("expected output {}, got {}", output, result.output));
+        }
+    }
+
+    for expected in &expect.logs {
+        if !result.logs.iter().any(|log| log.message.contains(expected.as_str())) {
+            failures.push(format!("no log message contains '{}'", expected));
+        }
+    }
+
+    if let Some(expected) = &expect.files_written {
+        let written: Vec<PathBuf> = result
+            .files_written
+            .iter()
+            .map(|p| p.strip_prefix(scratch).unwrap_or(p).to_path_buf())
+            .collect();
+        let expected: Vec<PathBuf> = expected
+            .iter()
+            .map(|p| p.strip_prefix(scratch).unwrap_or(p).to_path_buf())
+            .collect();
+        if written != expected {
+            failures.push(format!(
+                "expected files written {:?}, got {:?}",
+                expected, written
+            ));
+        }
+    }
+
+    if let Some(expected) = &expect.permission_denials {
+        if &result.permission_denials != expected {
+            failures.push(format!(
+                "expected permission denials {:?}, got {:?}",
+                expected, result.permission_denials
+            ));
+        }
+    }
+}
+
+/// Replace `$FIXTURE` in every string of a serializable value
+fn substitute<T: Serialize + DeserializeOwned>(value: &T, scratch: &Path) -> Result<T> {
+    let mut json = serde_json::to_value(value)?;
+    replace_placeholder(&mut json, &scratch.to_string_lossy());
+    Ok(serde_json::from_value(json)?)
+}
+
+fn replace_placeholder(value: &mut serde_json::Value, replacement: &str) {
+    match value {
+        serde_json::Value::String(s) if s.contains(FIXTURE_PLACEHOLDER) => {
+            *s = s.replace(FIXTURE_PLACEHOLDER, replacement);
+        }
+        serde_json::Value::Array(items) => {
+            items.iter_mut().for_each(|v| replace_placeholder(v, replacement));
+        }
+        serde_json::Value::Object(map) => {
+            map.values_mut().for_each(|v| replace_placeholder(v, replacement));
+        }
+        _ => {}
+    }
+}
+
+/// Scratch directory removed on drop
+struct ScratchDir {
+    path: PathBuf,
+}
+
+impl ScratchDir {
+    fn create(fixture: Option<&Path>) -> Result<Self> {
+        let path = std::env::temp_dir().join(format!("rpa-plugin-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&path)?;
+        let scratch = Self { path };
+
+        if let Some(fixture) = fixture {
+            if !fixture.is_dir() {
+                return Err(PluginError::NotFound(format!(
+                    "fixture directory {}",
+                    fixture.display()
+                )));
+            }
+            copy_dir(fixture, &scratch.path)?;
+        }
+
+        Ok(scratch)
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            std::fs::create_dir_all(&target)?;
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            std::fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::permissions::Permission;
+    use rpa_core::{Event, EventKind};
+
+    /// Plugin that logs, writes `out.txt`, tries to read `/etc/shadow` and
+    /// sets `{"ok":true}` as output.
+    const PLUGIN: &str = r#"
+        (module
+          (import "host" "request" (func $request (param i32 i32) (result i32)))
+          (import "host" "set_output" (func $set_output (param i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 0) "{\"type\":\"log\",\"level\":\"info\",\"message\":\"tagging\"}")
+          (data (i32.const 100) "{\"type\":\"write_file\",\"path\":\"out.txt\",\"content\":[104,105]}")
+          (data (i32.const 200) "{\"type\":\"read_file\",\"path\":\"/etc/shadow\"}")
+          (data (i32.const 300) "{\"ok\":true}")
+          (func (export "tag") (result i32)
+            (drop (call $request (i32.const 0) (i32.const 49)))
+            (drop (call $request (i32.const 100) (i32.const 58)))
+            (drop (call $request (i32.const 200) (i32.const 41)))
+            (drop (call $set_output (i32.const 300) (i32.const 11)))
+            i32.const 0)
+          (func (export "fail") (result i32) i32.const 3))
+    "#;
+
+    fn case(action: &str, expect: Expectations) -> TestCase {
+        let event = Event::new(
+            EventKind::FileCreated {
+                path: PathBuf::from("$FIXTURE/input.txt"),
+            },
+            "$FIXTURE",
+        );
+        TestCase {
+            name: action.to_string(),
+            action: action.to_string(),
+            context: PluginContext::new(event),
+            sandbox: SandboxConfig::default().with_permission(Permission::write_path("$FIXTURE")),
+            fixture: None,
+            expect,
+        }
+    }
+
+    #[test]
+    fn test_case_passes() {
+        let harness = PluginTestHarness::new(PLUGIN);
+        let report = harness.run_case(&case(
+            "tag",
+            Expectations {
+                success: Some(true),
+                output: Some(serde_json::json!({ "ok": true })),
+                logs: vec!["tagging".to_string()],
+                files_written: Some(vec![PathBuf::from("out.txt")]),
+                permission_denials: Some(vec!["read /etc/shadow".to_string()]),
+                error: None,
+            },
+        ));
+        report.assert_passed();
+    }
+
+    #[test]
+    fn test_case_reports_mismatches() {
+        let harness = PluginTestHarness::new(PLUGIN);
+        let report = harness.run_case(&case(
+            "fail",
+            Expectations {
+                success: Some(true),
+                logs: vec!["never logged".to_string()],
+                ..Default::default()
+            },
+        ));
+        assert!(!report.passed());
+        assert_eq!(report.failures.len(), 2);
+    }
+
+    #[test]
+    fn test_expected_error() {
+        let harness = PluginTestHarness::new(PLUGIN);
+        let report = harness.run_case(&case(
+            "missing",
+            Expectations {
+                error: Some("not found".to_string()),
+                ..Default::default()
+            },
+        ));
+        report.assert_passed();
+    }
+}
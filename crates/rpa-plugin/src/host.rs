@@ -5,11 +5,14 @@
 
 use crate::api::{PluginContext, PluginMetadata, PluginActionResult};
 use crate::error::{PluginError, Result};
-use crate::permissions::Permission;
+use crate::permissions::{Permission, PermissionSet};
+use crate::repository::PluginRepository;
 use crate::sandbox::{Sandbox, SandboxConfig};
+use semver::{Version, VersionReq};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::path::PathBuf;
+use std::sync::mpsc::Sender;
 use tracing::{debug, info, warn};
 use wasmtime::Module;
 
@@ -18,7 +21,7 @@
 pub struct PluginConfig {
     /// Path to the plugin WASM file
     pub path: PathBuf,
-    /// Plugin ID (derived from path if not specified)
+    /// Plugin ID (taken from the module metadata, or the file name, if not specified)
     pub id: Option<String>,
     /// Whether the plugin is enabled
     #[serde(default = "default_true")]
@@ -38,14 +41,9 @@ fn default_true() -> bool {
 impl PluginConfig {
     /// Create a new plugin configuration
     pub fn new(path: impl Into<PathBuf>) -> Self {
-        let path = path.into();
-        let id = path
-            .file_stem()
-            .map(|s| s.to_string_lossy().to_string());
-
         Self {
-            path,
-            id,
+            path: path.into(),
+            id: None,
             enabled: true,
             sandbox: SandboxConfig::default(),
             config: HashMap::new(),
@@ -101,12 +99,57 @@ pub fn get_id(&self) -> String {
     }
 }
 
+/// Compile the plugin `config` points at, with its id
+fn instantiate(config: PluginConfig) -> Result<(String, PluginInstance)> {
+    info!("Loading plugin from {}", config.path.display());
+
+    // Create sandbox
+    let sandbox = Sandbox::new(config.sandbox.clone())?;
+
+    // Load WASM module
+    let bytes = std::fs::read(&config.path)?;
+    let module = sandbox.load_module(&bytes)?;
+    let (plugin_id, metadata, version) = identify(&config, &bytes)?;
+    let actions = exported_actions(&module);
+
+    debug!("Plugin '{}@{}' exports actions: {:?}", plugin_id, version, actions);
+
+    let instance = PluginInstance {
+        config,
+        metadata,
+        version,
+        module,
+        sandbox,
+        actions,
+    };
+    Ok((plugin_id, instance))
+}
+
+/// Id, metadata and version of a plugin module
+///
+/// The id comes from the configuration, then the module's metadata, then
+/// the file name, so versions in differently named files match up.
+fn identify(config: &PluginConfig, wasm: &[u8]) -> Result<(String, PluginMetadata, Version)> {
+    let embedded = PluginMetadata::from_wasm(wasm)?;
+    let plugin_id = match (&config.id, &embedded) {
+        (Some(id), _) => id.clone(),
+        (None, Some(metadata)) if !metadata.id.is_empty() => metadata.id.clone(),
+        _ => config.get_id(),
+    };
+    let mut metadata = embedded.unwrap_or_else(|| PluginMetadata::new(&plugin_id, &plugin_id, "0.1.0"));
+    metadata.id = plugin_id.clone();
+    let version = metadata.semver()?;
+    Ok((plugin_id, metadata, version))
+}
+
 /// A loaded plugin instance
 pub struct PluginInstance {
     /// Plugin configuration
     config: PluginConfig,
     /// Plugin metadata (loaded from WASM)
     metadata: PluginMetadata,
+    /// Parsed plugin version
+    version: Version,
     /// Compiled WASM module
     module: Module,
     /// Sandbox for execution
@@ -121,11 +164,21 @@ pub fn id(&self) -> &str {
         &self.metadata.id
     }
 
+    /// Get plugin version
+    pub fn version(&self) -> &Version {
+        &self.version
+    }
+
     /// Get plugin metadata
     pub fn metadata(&self) -> &PluginMetadata {
         &self.metadata
     }
 
+    /// Get the permissions granted to the plugin
+    pub fn permissions(&self) -> &PermissionSet {
+        &self.sandbox.config().permissions
+    }
+
     /// Get available actions
     pub fn actions(&self) -> &[String] {
         &self.actions
@@ -136,8 +189,7 @@ pub fn has_action(&self, action: &str) -> bool {
         self.actions.iter().any(|a| a == action)
     }
 
-    /// Execute an action
-    pub fn execute(&self, action: &str, ctx: &PluginContext) -> Result<PluginActionResult> {
+    fn ensure_action(&self, action: &str) -> Result<()> {
         if !self.has_action(action) {
             return Err(PluginError::ExecutionFailed(format!(
                 "Plugin '{}' does not have action '{}'",
@@ -145,15 +197,60 @@ pub fn execute(&self, action: &str, ctx: &PluginContext) -> Result<PluginActionR
                 action
             )));
         }
+        Ok(())
+    }
 
+    /// Execute an action
+    pub fn execute(&self, action: &str, ctx: &PluginContext) -> Result<PluginActionResult> {
+        self.ensure_action(action)?;
         self.sandbox.execute(&self.module, action, ctx)
     }
+
+    /// Execute an action with a fuel limit overriding the sandbox's own
+    pub fn execute_with_fuel(
+        &self,
+        action: &str,
+        ctx: &PluginContext,
+        fuel_limit: u64,
+    ) -> Result<PluginActionResult> {
+        self.ensure_action(action)?;
+        self.sandbox.execute_with_fuel(&self.module, action, ctx, Some(fuel_limit))
+    }
+
+    /// Execute an action, sending emitted events to `events` as they occur
+    pub fn execute_streaming(
+        &self,
+        action: &str,
+        ctx: &PluginContext,
+        events: Sender<serde_json::Value>,
+    ) -> Result<PluginActionResult> {
+        self.ensure_action(action)?;
+        self.sandbox.execute_streaming(&self.module, action, ctx, events)
+    }
+}
+
+/// Get exported functions of a module as available actions
+pub(crate) fn exported_actions(module: &Module) -> Vec<String> {
+    module
+        .exports()
+        .filter_map(|e| {
+            if e.ty().func().is_some() {
+                Some(e.name().to_string())
+            } else {
+                None
+            }
+        })
+        .filter(|name| !name.starts_with('_')) // Skip internal functions
+        .collect()
 }
 
 /// Plugin host that manages plugin lifecycle
+///
+/// Several versions of the same plugin ID can be loaded side by side; they
+/// are keyed by the semver version in their metadata.
 pub struct PluginHost {
-    /// Loaded plugins by ID
-    plugins: HashMap<String, PluginInstance>,
+    /// Loaded plugins by ID, then by version
+    plugins: HashMap<String, BTreeMap<Version, PluginInstance>>,
     /// Default sandbox configuration
     default_sandbox_config: SandboxConfig,
     /// Plugin search paths
@@ -186,58 +283,42 @@ pub fn load_plugin(&mut self, config: PluginConfig) -> Result<String> {
             return Err(PluginError::LoadFailed("Plugin is disabled".to_string()));
         }
 
-        let plugin_id = config.get_id();
-        info!("Loading plugin: {} from {}", plugin_id, config.path.display());
-
-        // Create sandbox
-        let sandbox = Sandbox::new(config.sandbox.clone())?;
-
-        // Load WASM module
-        let module = sandbox.load_module_from_file(&config.path)?;
-
-        // Extract metadata from module (in a real impl, this would parse custom sections)
-        let metadata = PluginMetadata::new(
-            &plugin_id,
-            &plugin_id,
-            "0.1.0",
-        );
-
-        // Get exported functions as available actions
-        let actions: Vec<String> = module
-            .exports()
-            .filter_map(|e| {
-                if e.ty().func().is_some() {
-                    Some(e.name().to_string())
-                } else {
-                    None
-                }
-            })
-            .filter(|name| !name.starts_with('_')) // Skip internal functions
-            .collect();
-
-        debug!("Plugin '{}' exports actions: {:?}", plugin_id, actions);
-
-        let instance = PluginInstance {
-            config,
-            metadata,
-            module,
-            sandbox,
-            actions,
-        };
+        let (plugin_id, instance) = instantiate(config)?;
+        let version = instance.version.clone();
+        if self
+            .plugins
+            .get(&plugin_id)
+            .is_some_and(|versions| versions.contains_key(&version))
+        {
+            return Err(PluginError::VersionConflict {
+                id: plugin_id,
+                version: version.to_string(),
+            });
+        }
 
-        self.plugins.insert(plugin_id.clone(), instance);
-        info!("Plugin '{}' loaded successfully", plugin_id);
+        self.plugins
+            .entry(plugin_id.clone())
+            .or_default()
+            .insert(version.clone(), instance);
+        info!("Plugin '{}@{}' loaded successfully", plugin_id, version);
 
         Ok(plugin_id)
     }
 
+    /// The id and version a plugin would be loaded as, without loading it
+    pub fn identify(config: &PluginConfig) -> Result<(String, Version)> {
+        let bytes = std::fs::read(&config.path)?;
+        let (id, _, version) = identify(config, &bytes)?;
+        Ok((id, version))
+    }
+
     /// Load a plugin from a path with default configuration
     pub fn load_plugin_from_path(&mut self, path: impl Into<PathBuf>) -> Result<String> {
         let config = PluginConfig::new(path);
         self.load_plugin(config)
     }
 
-    /// Unload a plugin
+    /// Unload all versions of a plugin
     pub fn unload_plugin(&mut self, id: &str) -> Result<()> {
         if self.plugins.remove(id).is_some() {
             info!("Plugin '{}' unloaded", id);
@@ -247,63 +328,126 @@ pub fn unload_plugin(&mut self, id: &str) -> Result<()> {
         }
     }
 
-    /// Get a plugin by ID
+    /// Unload a single version of a plugin
+    pub fn unload_plugin_version(&mut self, id: &str, version: &Version) -> Result<()> {
+        let versions = self
+            .plugins
+            .get_mut(id)
+            .ok_or_else(|| PluginError::NotFound(id.to_string()))?;
+
+        if versions.remove(version).is_none() {
+            return Err(PluginError::NotFound(format!("{}@{}", id, version)));
+        }
+        if versions.is_empty() {
+            self.plugins.remove(id);
+        }
+
+        info!("Plugin '{}@{}' unloaded", id, version);
+        Ok(())
+    }
+
+    /// Get the newest loaded version of a plugin by ID
     pub fn get_plugin(&self, id: &str) -> Option<&PluginInstance> {
-        self.plugins.get(id)
+        self.plugins
+            .get(id)
+            .and_then(|versions| versions.values().next_back())
+    }
+
+    /// Get a specific version of a plugin
+    pub fn get_plugin_version(&self, id: &str, version: &Version) -> Option<&PluginInstance> {
+        self.plugins.get(id).and_then(|versions| versions.get(version))
+    }
+
+    /// Get the loaded versions of a plugin, oldest first
+    pub fn versions(&self, id: &str) -> Vec<&Version> {
+        self.plugins
+            .get(id)
+            .map(|versions| versions.keys().collect())
+            .unwrap_or_default()
     }
 
-    /// Get all loaded plugins
+    /// Resolve a plugin ID and optional version requirement to a loaded instance
+    ///
+    /// The newest version satisfying the requirement is chosen. Without a
+    /// requirement the newest loaded version is used.
+    pub fn resolve(&self, id: &str, requirement: Option<&VersionReq>) -> Result<&PluginInstance> {
+        let versions = self
+            .plugins
+            .get(id)
+            .ok_or_else(|| PluginError::NotFound(id.to_string()))?;
+
+        let found = match requirement {
+            Some(req) => versions.iter().rev().find(|(v, _)| req.matches(v)),
+            None => versions.iter().next_back(),
+        };
+
+        found.map(|(_, instance)| instance).ok_or_else(|| {
+            PluginError::NoMatchingVersion {
+                id: id.to_string(),
+                requirement: requirement.map(|r| r.to_string()).unwrap_or_else(|| "*".to_string()),
+                available: versions
+                    .keys()
+                    .map(|v| v.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            }
+        })
+    }
+
+    /// Get all loaded plugins (every version)
     pub fn plugins(&self) -> impl Iterator<Item = &PluginInstance> {
-        self.plugins.values()
+        self.plugins.values().flat_map(|versions| versions.values())
     }
 
-    /// Get plugin count
+    /// Get plugin count (every version counts separately)
     pub fn plugin_count(&self) -> usize {
-        self.plugins.len()
+        self.plugins.values().map(|versions| versions.len()).sum()
     }
 
-    /// Execute an action on a plugin
+    /// Execute an action on the plugin version matching `requirement`
     pub fn execute_action(
         &self,
         plugin_id: &str,
+        requirement: Option<&VersionReq>,
         action: &str,
         ctx: &PluginContext,
     ) -> Result<PluginActionResult> {
-        let plugin = self
-            .get_plugin(plugin_id)
-            .ok_or_else(|| PluginError::NotFound(plugin_id.to_string()))?;
-
-        plugin.execute(action, ctx)
+        self.resolve(plugin_id, requirement)?.execute(action, ctx)
     }
 
     /// Find plugins that provide a specific action
     pub fn find_plugins_with_action(&self, action: &str) -> Vec<&PluginInstance> {
-        self.plugins
-            .values()
-            .filter(|p| p.has_action(action))
-            .collect()
+        self.plugins().filter(|p| p.has_action(action)).collect()
     }
 
     /// Discover and load plugins from search paths
+    ///
+    /// Each search path is a plugin repository; plugins listed in its
+    /// index are loaded after their hash has been verified. Paths without
+    /// an index are skipped.
     pub fn discover_plugins(&mut self) -> Result<Vec<String>> {
         let mut loaded = Vec::new();
 
         for search_path in self.search_paths.clone() {
-            if !search_path.exists() {
-                debug!("Plugin search path does not exist: {}", search_path.display());
+            let Some(repository) = PluginRepository::open_existing(&search_path)? else {
+                debug!("No plugin index in search path: {}", search_path.display());
                 continue;
-            }
-
-            let entries = std::fs::read_dir(&search_path)?;
+            };
 
-            for entry in entries.flatten() {
-                let path = entry.path();
+            for entry in repository.entries() {
+                if let Err(e) = repository.verify(entry) {
+                    warn!("Skipping plugin '{}@{}': {}", entry.id, entry.version, e);
+                    continue;
+                }
 
-                if path.extension().map(|e| e == "wasm").unwrap_or(false) {
-                    match self.load_plugin_from_path(&path) {
-                        Ok(id) => loaded.push(id),
-                        Err(e) => warn!("Failed to load plugin {}: {}", path.display(), e),
-                    }
+                let mut config = repository.plugin_config(entry);
+                config.sandbox = self.default_sandbox_config.clone();
+                match self.load_plugin(config) {
+                    Ok(id) => loaded.push(id),
+                    Err(e) => warn!(
+                        "Failed to load plugin '{}@{}': {}",
+                        entry.id, entry.version, e
+                    ),
                 }
             }
         }
@@ -311,18 +455,35 @@ pub fn discover_plugins(&mut self) -> Result<Vec<String>> {
         Ok(loaded)
     }
 
-    /// Reload a plugin
+    /// Reload every loaded version of a plugin
+    ///
+    /// The loaded versions are only replaced once all of them reloaded.
     pub fn reload_plugin(&mut self, id: &str) -> Result<()> {
-        let config = self
+        let loaded = self
             .plugins
             .get(id)
-            .ok_or_else(|| PluginError::NotFound(id.to_string()))?
-            .config
-            .clone();
-
-        self.unload_plugin(id)?;
-        self.load_plugin(config)?;
+            .ok_or_else(|| PluginError::NotFound(id.to_string()))?;
+
+        let mut reloaded = BTreeMap::new();
+        for instance in loaded.values() {
+            let (plugin_id, instance) = instantiate(instance.config.clone())?;
+            if plugin_id != id {
+                return Err(PluginError::LoadFailed(format!(
+                    "Plugin '{}' now identifies as '{}'",
+                    id, plugin_id
+                )));
+            }
+            if reloaded.contains_key(&instance.version) {
+                return Err(PluginError::VersionConflict {
+                    id: plugin_id,
+                    version: instance.version.to_string(),
+                });
+            }
+            reloaded.insert(instance.version.clone(), instance);
+        }
 
+        self.plugins.insert(id.to_string(), reloaded);
+        info!("Plugin '{}' reloaded", id);
         Ok(())
     }
 }
@@ -382,4 +543,90 @@ fn test_plugin_host_creation() {
         let host = PluginHost::new();
         assert!(host.is_ok());
     }
+
+    fn write_versioned_plugin(dir: &std::path::Path, id: &str, version: &str) -> PathBuf {
+        let metadata = format!(
+            r#"{{"id":"{}","name":"{}","version":"{}"}}"#,
+            id, id, version
+        );
+        let wat = format!(
+            r#"(module (@custom "rpa-plugin-metadata" "{}")
+                 (func (export "run") (result i32) i32.const 0))"#,
+            metadata.replace('"', "\\\"")
+        );
+        let path = dir.join(format!("{}-{}.wasm", id, version));
+        std::fs::write(&path, wat::parse_str(&wat).unwrap()).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_side_by_side_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut host = PluginHost::new().unwrap();
+
+        for version in ["1.2.0", "1.4.1", "2.0.0"] {
+            let path = write_versioned_plugin(dir.path(), "tagger", version);
+            host.load_plugin(PluginConfig::new(path).with_id("tagger")).unwrap();
+        }
+
+        assert_eq!(host.plugin_count(), 3);
+        assert_eq!(host.get_plugin("tagger").unwrap().version().to_string(), "2.0.0");
+
+        let req = VersionReq::parse("^1.2").unwrap();
+        assert_eq!(host.resolve("tagger", Some(&req)).unwrap().version().to_string(), "1.4.1");
+
+        let req = VersionReq::parse("~1.2").unwrap();
+        assert_eq!(host.resolve("tagger", Some(&req)).unwrap().version().to_string(), "1.2.0");
+
+        let req = VersionReq::parse("^3").unwrap();
+        assert!(matches!(
+            host.resolve("tagger", Some(&req)),
+            Err(PluginError::NoMatchingVersion { .. })
+        ));
+
+        host.unload_plugin_version("tagger", &Version::new(2, 0, 0)).unwrap();
+        assert_eq!(host.get_plugin("tagger").unwrap().version().to_string(), "1.4.1");
+    }
+
+    #[test]
+    fn test_id_taken_from_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut host = PluginHost::new().unwrap();
+
+        for version in ["1.0.0", "2.0.0"] {
+            let path = write_versioned_plugin(dir.path(), "tagger", version);
+            assert_eq!(host.load_plugin(PluginConfig::new(path)).unwrap(), "tagger");
+        }
+        assert_eq!(host.versions("tagger").len(), 2);
+    }
+
+    #[test]
+    fn test_duplicate_version_conflicts() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut host = PluginHost::new().unwrap();
+        let path = write_versioned_plugin(dir.path(), "tagger", "1.0.0");
+
+        host.load_plugin(PluginConfig::new(&path).with_id("tagger")).unwrap();
+        let result = host.load_plugin(PluginConfig::new(&path).with_id("tagger"));
+        assert!(matches!(result, Err(PluginError::VersionConflict { .. })));
+    }
+
+    #[test]
+    fn test_failed_reload_keeps_every_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut host = PluginHost::new().unwrap();
+        let old = write_versioned_plugin(dir.path(), "tagger", "1.0.0");
+        write_versioned_plugin(dir.path(), "tagger", "2.0.0");
+        for version in ["1.0.0", "2.0.0"] {
+            let path = dir.path().join(format!("tagger-{}.wasm", version));
+            host.load_plugin(PluginConfig::new(path)).unwrap();
+        }
+
+        host.reload_plugin("tagger").unwrap();
+        assert_eq!(host.versions("tagger").len(), 2);
+
+        std::fs::write(&old, b"not wasm").unwrap();
+        assert!(host.reload_plugin("tagger").is_err());
+        assert_eq!(host.versions("tagger").len(), 2);
+    }
 }
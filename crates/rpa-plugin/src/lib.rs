@@ -9,6 +9,7 @@
 //! - **WASM Sandbox**: Secure execution environment using WebAssembly
 //! - **Permission System**: Fine-grained control over plugin capabilities
 //! - **Resource Limits**: Memory, CPU, and I/O constraints
+//! - **Test Harness**: Run plugin actions against fixture contexts
 //!
 //! # Security Model
 //!
@@ -36,12 +37,15 @@
 
 pub mod api;
 pub mod error;
+pub mod harness;
 pub mod host;
 pub mod permissions;
+pub mod repository;
 pub mod sandbox;
 
 pub use api::{Plugin, PluginAction, PluginContext, PluginMetadata};
 pub use error::{PluginError, Result};
 pub use host::{PluginHost, PluginInstance};
 pub use permissions::{Permission, PermissionSet};
+pub use repository::PluginRepository;
 pub use sandbox::{Sandbox, SandboxConfig};
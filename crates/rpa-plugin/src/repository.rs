@@ -0,0 +1,520 @@
+// SPDX-License-Identifier: MIT OR AGPL-3.0-or-later
+// SPDX-FileCopyrightText: 2024 Hyperpolymath <hyperpolymath@proton.me>
+
+//! Local plugin repository
+//!
+//! A managed on-disk store of installed plugins. Each installed version is
+//! copied to `<root>/<id>/<version>/plugin.wasm` and recorded in
+//! `<root>/index.json` together with its hash, signature status, exports
+//! and metadata manifest.
+
+use crate::api::PluginMetadata;
+use crate::error::{PluginError, Result};
+use crate::host::{exported_actions, PluginConfig};
+use crate::sandbox::Sandbox;
+use chrono::{DateTime, Utc};
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+use tracing::{debug, info};
+
+/// File name of the repository index
+pub const INDEX_FILE: &str = "index.json";
+
+/// Current repository index format version
+const INDEX_FORMAT: u32 = 1;
+
+/// Signature state of an installed plugin
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SignatureStatus {
+    /// No detached signature was supplied
+    Unsigned,
+    /// A detached `.sig` file was stored but has not been verified
+    Unverified,
+}
+
+/// Index entry for one installed plugin version
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexEntry {
+    /// Plugin ID
+    pub id: String,
+    /// Plugin version
+    pub version: Version,
+    /// WASM file, relative to the repository root
+    pub file: PathBuf,
+    /// SHA-256 of the WASM file (hex)
+    pub sha256: String,
+    /// Size of the WASM file in bytes
+    pub size: u64,
+    /// Signature status
+    pub signature: SignatureStatus,
+    /// Exported actions
+    #[serde(default)]
+    pub exports: Vec<String>,
+    /// Plugin metadata manifest
+    pub manifest: PluginMetadata,
+    /// When this version was installed
+    pub installed_at: DateTime<Utc>,
+}
+
+/// On-disk repository index
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RepositoryIndex {
+    /// Index format version
+    pub format: u32,
+    /// Installed plugins by ID, then by version
+    #[serde(default)]
+    pub plugins: BTreeMap<String, BTreeMap<Version, IndexEntry>>,
+}
+
+impl Default for RepositoryIndex {
+    fn default() -> Self {
+        Self {
+            format: INDEX_FORMAT,
+            plugins: BTreeMap::new(),
+        }
+    }
+}
+
+/// Inspection report for a plugin binary
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PluginInspection {
+    /// Plugin metadata (from the custom section, or derived from the file name)
+    pub metadata: PluginMetadata,
+    /// Whether the metadata came from the module's custom section
+    pub has_manifest: bool,
+    /// Exported actions
+    pub exports: Vec<String>,
+    /// SHA-256 of the module (hex)
+    pub sha256: String,
+    /// Size of the module in bytes
+    pub size: u64,
+}
+
+impl PluginInspection {
+    /// Inspect a WASM plugin file without installing it
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path)?;
+        let fallback_id = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        Self::from_bytes(&bytes, &fallback_id)
+    }
+
+    /// Inspect WASM plugin bytes
+    pub fn from_bytes(bytes: &[u8], fallback_id: &str) -> Result<Self> {
+        let module = Sandbox::with_defaults()?.load_module(bytes)?;
+        let manifest = PluginMetadata::from_wasm(bytes)?;
+        let has_manifest = manifest.is_some();
+        let metadata = manifest
+            .unwrap_or_else(|| PluginMetadata::new(fallback_id, fallback_id, "0.1.0"));
+        metadata.semver()?;
+
+        Ok(Self {
+            metadata,
+            has_manifest,
+            exports: exported_actions(&module),
+            sha256: sha256_hex(bytes),
+            size: bytes.len() as u64,
+        })
+    }
+}
+
+/// Managed local plugin store
+pub struct PluginRepository {
+    root: PathBuf,
+    index: RepositoryIndex,
+}
+
+impl PluginRepository {
+    /// Default repository location
+    ///
+    /// `$XDG_DATA_HOME/rpa-elysium/plugins`, falling back to
+    /// `~/.local/share/rpa-elysium/plugins`.
+    pub fn default_root() -> PathBuf {
+        let data_home = std::env::var_os("XDG_DATA_HOME")
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| {
+                std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share"))
+            })
+            .unwrap_or_else(|| PathBuf::from("."));
+        data_home.join("rpa-elysium").join("plugins")
+    }
+
+    /// Open (or create) a repository at `root`
+    pub fn open(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        std::fs::create_dir_all(&root)?;
+        let index = Self::read_index(&root)?.unwrap_or_default();
+        Ok(Self { root, index })
+    }
+
+    /// Open an existing repository, returning `None` if `root` has no index
+    pub fn open_existing(root: impl Into<PathBuf>) -> Result<Option<Self>> {
+        let root = root.into();
+        Ok(Self::read_index(&root)?.map(|index| Self { root, index }))
+    }
+
+    fn read_index(root: &Path) -> Result<Option<RepositoryIndex>> {
+        let path = root.join(INDEX_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let index: RepositoryIndex = serde_json::from_slice(&std::fs::read(&path)?)?;
+        if index.format != INDEX_FORMAT {
+            return Err(PluginError::InvalidFormat(format!(
+                "Unsupported plugin index format {} in {}",
+                index.format,
+                path.display()
+            )));
+        }
+        Ok(Some(index))
+    }
+
+    /// Repository root directory
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Repository index
+    pub fn index(&self) -> &RepositoryIndex {
+        &self.index
+    }
+
+    /// Iterate over every installed plugin version
+    pub fn entries(&self) -> impl Iterator<Item = &IndexEntry> {
+        self.index.plugins.values().flat_map(|versions| versions.values())
+    }
+
+    /// Get an installed plugin version (newest if `version` is `None`)
+    pub fn get(&self, id: &str, version: Option<&Version>) -> Option<&IndexEntry> {
+        let versions = self.index.plugins.get(id)?;
+        match version {
+            Some(v) => versions.get(v),
+            None => versions.values().next_back(),
+        }
+    }
+
+    /// Absolute path of an entry's WASM file
+    pub fn entry_path(&self, entry: &IndexEntry) -> PathBuf {
+        self.root.join(&entry.file)
+    }
+
+    /// Install a plugin file into the repository
+    ///
+    /// Reinstalling an identical file is a no-op; installing a different
+    /// file under an existing ID and version is a conflict.
+    pub fn install(&mut self, source: impl AsRef<Path>) -> Result<IndexEntry> {
+        let source = source.as_ref();
+        let bytes = std::fs::read(source)?;
+        let fallback_id = source
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        let inspection = PluginInspection::from_bytes(&bytes, &fallback_id)?;
+
+        let id = inspection.metadata.id.clone();
+        let version = inspection.metadata.semver()?;
+        check_name("id", &id)?;
+        check_name("version", &version.to_string())?;
+
+        if let Some(existing) = self.get(&id, Some(&version)) {
+            if existing.sha256 == inspection.sha256 {
+                debug!("Plugin '{}@{}' already installed", id, version);
+                return Ok(existing.clone());
+            }
+            return Err(PluginError::VersionConflict {
+                id,
+                version: version.to_string(),
+            });
+        }
+
+        let relative_dir = PathBuf::from(&id).join(version.to_string());
+        let target_dir = self.root.join(&relative_dir);
+        std::fs::create_dir_all(&target_dir)?;
+        std::fs::write(target_dir.join("plugin.wasm"), &bytes)?;
+
+        let signature_path = signature_path(source);
+        let signature = if signature_path.exists() {
+            std::fs::copy(&signature_path, target_dir.join("plugin.wasm.sig"))?;
+            SignatureStatus::Unverified
+        } else {
+            SignatureStatus::Unsigned
+        };
+
+        let entry = IndexEntry {
+            id: id.clone(),
+            version: version.clone(),
+            file: relative_dir.join("plugin.wasm"),
+            sha256: inspection.sha256,
+            size: inspection.size,
+            signature,
+            exports: inspection.exports,
+            manifest: inspection.metadata,
+            installed_at: Utc::now(),
+        };
+
+        self.index
+            .plugins
+            .entry(id.clone())
+            .or_default()
+            .insert(version.clone(), entry.clone());
+        self.save()?;
+
+        info!("Installed plugin '{}@{}'", id, version);
+        Ok(entry)
+    }
+
+    /// Remove an installed plugin (all versions if `version` is `None`)
+    ///
+    /// Every entry is checked before anything is deleted. Returns the
+    /// removed entries.
+    pub fn remove(&mut self, id: &str, version: Option<&Version>) -> Result<Vec<IndexEntry>> {
+        check_name("id", id)?;
+        let versions = self
+            .index
+            .plugins
+            .get(id)
+            .ok_or_else(|| PluginError::NotFound(id.to_string()))?;
+
+        let removed: Vec<IndexEntry> = match version {
+            Some(v) => vec![versions
+                .get(v)
+                .cloned()
+                .ok_or_else(|| PluginError::NotFound(format!("{}@{}", id, v)))?],
+            None => versions.values().cloned().collect(),
+        };
+        for entry in &removed {
+            check_entry_file(id, entry)?;
+        }
+
+        let mut deleted = Ok(());
+        for entry in &removed {
+            let dir = self.root.join(id).join(entry.version.to_string());
+            if dir.exists() {
+                if let Err(e) = std::fs::remove_dir_all(&dir) {
+                    deleted = Err(e.into());
+                    break;
+                }
+            }
+            if let Some(versions) = self.index.plugins.get_mut(id) {
+                versions.remove(&entry.version);
+            }
+            info!("Removed plugin '{}@{}'", entry.id, entry.version);
+        }
+
+        if self.index.plugins.get(id).is_some_and(BTreeMap::is_empty) {
+            self.index.plugins.remove(id);
+            let plugin_dir = self.root.join(id);
+            if deleted.is_ok() && plugin_dir.exists() {
+                deleted = std::fs::remove_dir_all(plugin_dir).map_err(Into::into);
+            }
+        }
+
+        // Entries whose files are gone leave the index even if a later one failed
+        self.save()?;
+        deleted.map(|()| removed)
+    }
+
+    /// Verify that an entry's file still matches its recorded hash
+    pub fn verify(&self, entry: &IndexEntry) -> Result<()> {
+        let bytes = std::fs::read(self.entry_path(entry))?;
+        let actual = sha256_hex(&bytes);
+        if actual != entry.sha256 {
+            return Err(PluginError::InvalidFormat(format!(
+                "Plugin '{}@{}' hash mismatch: index has {}, file has {}",
+                entry.id, entry.version, entry.sha256, actual
+            )));
+        }
+        Ok(())
+    }
+
+    /// Build a plugin load configuration for an entry
+    pub fn plugin_config(&self, entry: &IndexEntry) -> PluginConfig {
+        PluginConfig::new(self.entry_path(entry)).with_id(entry.id.clone())
+    }
+
+    /// Write the index to disk atomically
+    pub fn save(&self) -> Result<()> {
+        let path = self.root.join(INDEX_FILE);
+        let tmp = self.root.join(format!("{}.tmp", INDEX_FILE));
+        std::fs::write(&tmp, serde_json::to_vec_pretty(&self.index)?)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+}
+
+/// Reject plugin ids and versions that are not plain directory names
+///
+/// Both come from untrusted plugin metadata and become paths in the store.
+fn check_name(what: &str, name: &str) -> Result<()> {
+    let plain = name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+    if name.is_empty() || !plain || name == "." || name == ".." {
+        return Err(PluginError::InvalidFormat(format!(
+            "Invalid plugin {} '{}': only letters, digits, '.', '_' and '-' are allowed",
+            what, name
+        )));
+    }
+    Ok(())
+}
+
+/// Reject an index entry whose file is not `<id>/<version>/<file>`
+///
+/// The index is a file on disk; removing an entry deletes the directory
+/// of its file, which must be the entry's own.
+fn check_entry_file(id: &str, entry: &IndexEntry) -> Result<()> {
+    let version = entry.version.to_string();
+    let components: Vec<_> = entry.file.components().collect();
+    let own = entry.id == id
+        && matches!(
+            components.as_slice(),
+            [Component::Normal(i), Component::Normal(v), Component::Normal(_)] if *i == id && *v == version.as_str()
+        );
+    if !own {
+        return Err(PluginError::InvalidFormat(format!(
+            "Plugin '{}@{}' has a file outside its directory in the repository: {}",
+            entry.id,
+            entry.version,
+            entry.file.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Detached signature path for a plugin file (`<file>.sig`)
+fn signature_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".sig");
+    PathBuf::from(name)
+}
+
+/// Hex-encoded SHA-256 digest
+pub(crate) fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plugin_wasm(id: &str, version: &str) -> Vec<u8> {
+        let metadata = format!(
+            r#"{{"id":"{}","name":"{}","version":"{}","config_schemas":{{"run":{{"type":"object"}}}}}}"#,
+            id, id, version
+        );
+        let wat = format!(
+            r#"(module (@custom "rpa-plugin-metadata" "{}")
+                 (func (export "run") (result i32) i32.const 0))"#,
+            metadata.replace('"', "\\\"")
+        );
+        wat::parse_str(&wat).unwrap()
+    }
+
+    #[test]
+    fn test_install_list_remove() {
+        let source = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+
+        let v1 = source.path().join("tagger-1.wasm");
+        let v2 = source.path().join("tagger-2.wasm");
+        std::fs::write(&v1, plugin_wasm("tagger", "1.0.0")).unwrap();
+        std::fs::write(&v2, plugin_wasm("tagger", "2.0.0")).unwrap();
+        std::fs::write(source.path().join("tagger-2.wasm.sig"), b"sig").unwrap();
+
+        let mut repo = PluginRepository::open(root.path()).unwrap();
+        let entry = repo.install(&v1).unwrap();
+        assert_eq!(entry.signature, SignatureStatus::Unsigned);
+        assert_eq!(entry.exports, vec!["run".to_string()]);
+        assert!(entry.manifest.config_schemas.contains_key("run"));
+
+        let entry = repo.install(&v2).unwrap();
+        assert_eq!(entry.signature, SignatureStatus::Unverified);
+
+        // Reinstalling the same file is a no-op
+        repo.install(&v1).unwrap();
+
+        let reopened = PluginRepository::open_existing(root.path()).unwrap().unwrap();
+        assert_eq!(reopened.entries().count(), 2);
+        assert_eq!(reopened.get("tagger", None).unwrap().version, Version::new(2, 0, 0));
+        reopened.verify(reopened.get("tagger", None).unwrap()).unwrap();
+
+        let removed = repo.remove("tagger", Some(&Version::new(1, 0, 0))).unwrap();
+        assert_eq!(removed.len(), 1);
+        assert!(!root.path().join("tagger/1.0.0").exists());
+        assert_eq!(repo.entries().count(), 1);
+    }
+
+    #[test]
+    fn test_conflicting_install_rejected() {
+        let source = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+
+        let a = source.path().join("a.wasm");
+        std::fs::write(&a, plugin_wasm("tagger", "1.0.0")).unwrap();
+        let b = source.path().join("b.wasm");
+        let mut other = plugin_wasm("tagger", "1.0.0");
+        other.extend_from_slice(&[0, 3, 1, b'x', 0]); // extra custom section
+        std::fs::write(&b, other).unwrap();
+
+        let mut repo = PluginRepository::open(root.path()).unwrap();
+        repo.install(&a).unwrap();
+        assert!(matches!(
+            repo.install(&b),
+            Err(PluginError::VersionConflict { .. })
+        ));
+    }
+
+    #[test]
+    fn test_ids_escaping_the_store_rejected() {
+        let source = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let mut repo = PluginRepository::open(root.path().join("store")).unwrap();
+
+        for id in ["../escaped", "/tmp/escaped", "..", ""] {
+            let path = source.path().join("evil.wasm");
+            std::fs::write(&path, plugin_wasm(id, "1.0.0")).unwrap();
+            assert!(matches!(repo.install(&path), Err(PluginError::InvalidFormat(_))), "{:?}", id);
+        }
+        assert!(!root.path().join("escaped").exists());
+        assert!(repo.remove("../store", None).is_err());
+        assert!(root.path().join("store").exists());
+    }
+
+    #[test]
+    fn test_remove_checks_every_entry_first() {
+        let source = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let mut repo = PluginRepository::open(root.path()).unwrap();
+        for version in ["1.0.0", "2.0.0"] {
+            let path = source.path().join(format!("tagger-{}.wasm", version));
+            std::fs::write(&path, plugin_wasm("tagger", version)).unwrap();
+            repo.install(&path).unwrap();
+        }
+
+        // A hand-edited entry pointing at the repository root or the plugin's directory
+        for file in ["plugin.wasm", "tagger/plugin.wasm", "tagger/1.0.0/sub/plugin.wasm"] {
+            let versions = repo.index.plugins.get_mut("tagger").unwrap();
+            versions.get_mut(&Version::new(2, 0, 0)).unwrap().file = PathBuf::from(file);
+            assert!(matches!(repo.remove("tagger", None), Err(PluginError::InvalidFormat(_))), "{}", file);
+            assert!(root.path().join("tagger/1.0.0/plugin.wasm").exists());
+            assert!(root.path().join(INDEX_FILE).exists());
+            assert_eq!(repo.entries().count(), 2);
+        }
+
+        let versions = repo.index.plugins.get_mut("tagger").unwrap();
+        versions.get_mut(&Version::new(2, 0, 0)).unwrap().file = PathBuf::from("tagger/2.0.0/plugin.wasm");
+        assert_eq!(repo.remove("tagger", None).unwrap().len(), 2);
+        assert!(!root.path().join("tagger").exists());
+        assert_eq!(PluginRepository::open_existing(root.path()).unwrap().unwrap().entries().count(), 0);
+    }
+}
@@ -4,12 +4,40 @@
 //! WASM Sandbox for secure plugin execution
 //!
 //! Provides isolated execution environment using WebAssembly.
+//!
+//! # Guest ABI
+//!
+//! Plugins export a linear memory named `memory` and one function per
+//! action with the signature `() -> i32`; a non-zero return marks the
+//! action as failed. The host provides these imports in module `host`:
+//!
+//! - `context_len() -> i32` / `context(ptr) -> i32`: the JSON-encoded
+//!   [`PluginContext`], copied to `ptr`
+//! - `request(ptr, len) -> i32`: handle a JSON-encoded [`HostRequest`];
+//!   returns the length of the pending JSON [`HostResponse`], or -1
+//! - `response(ptr) -> i32`: copy the pending response to `ptr`
+//! - `set_output(ptr, len) -> i32`: set the action's JSON output
+//!
+//! An `emit_event` request hands an event to the host; with
+//! [`Sandbox::execute_streaming`] it is delivered while the action is
+//! still running, otherwise it is returned in the result's `events`.
+//!
+//! The WASI `clock_time_get` and `random_get` functions are also provided,
+//! subject to the `Time` and `Random` permissions.
+//!
+//! # Deterministic mode
+//!
+//! With [`SandboxConfig::deterministic`] set, time comes from a virtual
+//! clock and UUIDs and WASI random bytes from a seeded PRNG, so a run can
+//! be replayed byte for byte from a recorded [`PluginContext`].
 
-use crate::api::{HostRequest, HostResponse, LogLevel, PluginContext, PluginActionResult};
+use crate::api::{HostRequest, HostResponse, LogLevel, PluginContext, PluginActionResult, PluginLog};
 use crate::error::{PluginError, Result};
 use crate::permissions::{Permission, PermissionSet};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
+use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use tracing::{debug, info, warn};
@@ -23,6 +51,7 @@
 
 /// Sandbox configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct SandboxConfig {
     /// Maximum memory in bytes
     pub memory_limit: u64,
@@ -34,6 +63,30 @@ pub struct SandboxConfig {
     pub permissions: PermissionSet,
     /// Working directory for file operations
     pub work_dir: Option<PathBuf>,
+    /// Replace real time and entropy with a virtual clock and seeded PRNG
+    pub deterministic: Option<DeterministicConfig>,
+}
+
+/// Settings for deterministic sandbox execution
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeterministicConfig {
+    /// Virtual clock start (default: the triggering event's timestamp)
+    pub start_time: Option<DateTime<Utc>>,
+    /// Milliseconds the virtual clock advances on every read
+    pub tick_ms: u64,
+    /// Seed for UUID generation and WASI random
+    pub seed: u64,
+}
+
+impl DeterministicConfig {
+    /// Create a deterministic config with the given seed
+    pub fn with_seed(seed: u64) -> Self {
+        Self {
+            seed,
+            ..Self::default()
+        }
+    }
 }
 
 impl Default for SandboxConfig {
@@ -46,6 +99,7 @@ fn default() -> Self {
                 .with(Permission::Time)
                 .with(Permission::Random),
             work_dir: None,
+            deterministic: None,
         }
     }
 }
@@ -85,29 +139,149 @@ pub fn with_work_dir(mut self, dir: impl Into<PathBuf>) -> Self {
         self.work_dir = Some(dir.into());
         self
     }
+
+    /// Enable deterministic mode
+    pub fn with_deterministic(mut self, deterministic: DeterministicConfig) -> Self {
+        self.deterministic = Some(deterministic);
+        self
+    }
 }
 
 /// Sandbox state shared with WASM
 #[derive(Debug)]
 struct SandboxState {
     permissions: PermissionSet,
-    logs: Vec<(LogLevel, String)>,
+    logs: Vec<PluginLog>,
     work_dir: Option<PathBuf>,
     start_time: Instant,
     timeout_ms: u64,
+    /// JSON-encoded context handed to the guest
+    context: Vec<u8>,
+    /// JSON-encoded response to the last host request
+    pending_response: Vec<u8>,
+    /// Output set by the guest
+    output: serde_json::Value,
+    /// Files written through host requests
+    files_written: Vec<PathBuf>,
+    /// Descriptions of denied permission checks
+    permission_denials: Vec<String>,
+    /// Time source
+    clock: Clock,
+    /// Seeded PRNG in deterministic mode
+    rng: Option<SplitMix64>,
+    /// Events emitted by the guest
+    events: Vec<serde_json::Value>,
+    /// Receiver for events emitted while the action runs
+    event_sink: Option<Sender<serde_json::Value>>,
+}
+
+/// Time source for a sandbox run
+#[derive(Debug)]
+enum Clock {
+    Real,
+    Virtual { now: DateTime<Utc>, tick: chrono::Duration },
+}
+
+impl Clock {
+    /// Read the clock; a virtual clock advances by one tick per read
+    fn read(&mut self) -> DateTime<Utc> {
+        match self {
+            Clock::Real => Utc::now(),
+            Clock::Virtual { now, tick } => {
+                let current = *now;
+                *now += *tick;
+                current
+            }
+        }
+    }
+
+    /// Current time without advancing a virtual clock
+    fn peek(&self) -> DateTime<Utc> {
+        match self {
+            Clock::Real => Utc::now(),
+            Clock::Virtual { now, .. } => *now,
+        }
+    }
+}
+
+/// SplitMix64 PRNG used for deterministic randomness
+#[derive(Debug)]
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
 }
 
 impl SandboxState {
-    fn new(config: &SandboxConfig) -> Self {
+    fn new(config: &SandboxConfig, ctx: &PluginContext, context: Vec<u8>) -> Self {
+        let (clock, rng) = match &config.deterministic {
+            Some(det) => (
+                Clock::Virtual {
+                    now: det.start_time.unwrap_or(ctx.event.timestamp),
+                    tick: chrono::Duration::milliseconds(det.tick_ms as i64),
+                },
+                Some(SplitMix64(det.seed)),
+            ),
+            None => (Clock::Real, None),
+        };
+
         Self {
             permissions: config.permissions.clone(),
             logs: Vec::new(),
             work_dir: config.work_dir.clone(),
             start_time: Instant::now(),
             timeout_ms: config.timeout_ms,
+            context,
+            pending_response: Vec::new(),
+            output: serde_json::Value::Null,
+            files_written: Vec::new(),
+            permission_denials: Vec::new(),
+            clock,
+            rng,
+            events: Vec::new(),
+            event_sink: None,
+        }
+    }
+
+    /// Fill `buf` with random bytes from the seeded PRNG or system entropy
+    fn fill_random(&mut self, buf: &mut [u8]) -> Result<()> {
+        match &mut self.rng {
+            Some(rng) => {
+                rng.fill(buf);
+                Ok(())
+            }
+            None => getrandom::fill(buf)
+                .map_err(|e| PluginError::SandboxError(format!("Entropy unavailable: {}", e))),
+        }
+    }
+
+    /// Resolve a guest path against the working directory
+    fn resolve_path(&self, path: &str) -> PathBuf {
+        match &self.work_dir {
+            Some(dir) => dir.join(path),
+            None => PathBuf::from(path),
         }
     }
 
+    fn deny(&mut self, description: impl Into<String>) -> HostResponse {
+        let description = description.into();
+        self.permission_denials.push(description.clone());
+        HostResponse::permission_denied(description)
+    }
+
     fn check_timeout(&self) -> Result<()> {
         if self.start_time.elapsed() > Duration::from_millis(self.timeout_ms) {
             Err(PluginError::Timeout(self.timeout_ms))
@@ -132,12 +306,12 @@ fn handle_request(&mut self, request: HostRequest) -> HostResponse {
 
         match request {
             HostRequest::ReadFile { path } => {
-                let path_buf = PathBuf::from(&path);
-                if let Err(_) = self.check_permission(&Permission::read_path(&path_buf)) {
-                    return HostResponse::permission_denied(format!("read {}", path));
+                let path_buf = self.resolve_path(&path);
+                if self.check_permission(&Permission::read_path(&path_buf)).is_err() {
+                    return self.deny(format!("read {}", path_buf.display()));
                 }
 
-                match std::fs::read(&path) {
+                match std::fs::read(&path_buf) {
                     Ok(content) => {
                         let encoded = base64::Engine::encode(
                             &base64::engine::general_purpose::STANDARD,
@@ -153,26 +327,29 @@ fn handle_request(&mut self, request: HostRequest) -> HostResponse {
             }
 
             HostRequest::WriteFile { path, content } => {
-                let path_buf = PathBuf::from(&path);
-                if let Err(_) = self.check_permission(&Permission::write_path(&path_buf)) {
-                    return HostResponse::permission_denied(format!("write {}", path));
+                let path_buf = self.resolve_path(&path);
+                if self.check_permission(&Permission::write_path(&path_buf)).is_err() {
+                    return self.deny(format!("write {}", path_buf.display()));
                 }
 
-                match std::fs::write(&path, &content) {
-                    Ok(_) => HostResponse::success_with_data(serde_json::json!({
-                        "bytes_written": content.len()
-                    })),
+                match std::fs::write(&path_buf, &content) {
+                    Ok(_) => {
+                        self.files_written.push(path_buf);
+                        HostResponse::success_with_data(serde_json::json!({
+                            "bytes_written": content.len()
+                        }))
+                    }
                     Err(e) => HostResponse::error(format!("Failed to write file: {}", e)),
                 }
             }
 
             HostRequest::ListDir { path } => {
-                let path_buf = PathBuf::from(&path);
-                if let Err(_) = self.check_permission(&Permission::read_path(&path_buf)) {
-                    return HostResponse::permission_denied(format!("read {}", path));
+                let path_buf = self.resolve_path(&path);
+                if self.check_permission(&Permission::read_path(&path_buf)).is_err() {
+                    return self.deny(format!("read {}", path_buf.display()));
                 }
 
-                match std::fs::read_dir(&path) {
+                match std::fs::read_dir(&path_buf) {
                     Ok(entries) => {
                         let files: Vec<_> = entries
                             .filter_map(|e| e.ok())
@@ -190,8 +367,8 @@ fn handle_request(&mut self, request: HostRequest) -> HostResponse {
             }
 
             HostRequest::GetEnv { name } => {
-                if let Err(_) = self.check_permission(&Permission::env(&name)) {
-                    return HostResponse::permission_denied(format!("env ${}", name));
+                if self.check_permission(&Permission::env(&name)).is_err() {
+                    return self.deny(format!("env ${}", name));
                 }
 
                 match std::env::var(&name) {
@@ -201,7 +378,11 @@ fn handle_request(&mut self, request: HostRequest) -> HostResponse {
             }
 
             HostRequest::Log { level, message } => {
-                self.logs.push((level.clone(), message.clone()));
+                self.logs.push(PluginLog {
+                    level,
+                    message: message.clone(),
+                    timestamp: self.clock.peek(),
+                });
                 match level {
                     LogLevel::Debug => debug!(target: "plugin", "{}", message),
                     LogLevel::Info => info!(target: "plugin", "{}", message),
@@ -212,23 +393,39 @@ fn handle_request(&mut self, request: HostRequest) -> HostResponse {
             }
 
             HostRequest::CurrentTime => {
-                if let Err(_) = self.check_permission(&Permission::Time) {
-                    return HostResponse::permission_denied("time");
+                if self.check_permission(&Permission::Time).is_err() {
+                    return self.deny("time");
                 }
 
-                let now = chrono::Utc::now();
+                let now = self.clock.read();
                 HostResponse::success_with_data(serde_json::json!({
                     "timestamp": now.timestamp(),
                     "iso": now.to_rfc3339()
                 }))
             }
 
+            HostRequest::EmitEvent { event } => {
+                match &self.event_sink {
+                    Some(sink) => {
+                        if sink.send(event).is_err() {
+                            return HostResponse::error("Event receiver closed");
+                        }
+                    }
+                    None => self.events.push(event),
+                }
+                HostResponse::success()
+            }
+
             HostRequest::GenerateUuid => {
-                if let Err(_) = self.check_permission(&Permission::Random) {
-                    return HostResponse::permission_denied("random");
+                if self.check_permission(&Permission::Random).is_err() {
+                    return self.deny("random");
                 }
 
-                let uuid = uuid::Uuid::new_v4();
+                let mut bytes = [0u8; 16];
+                if let Err(e) = self.fill_random(&mut bytes) {
+                    return HostResponse::error(e.to_string());
+                }
+                let uuid = uuid::Builder::from_random_bytes(bytes).into_uuid();
                 HostResponse::success_with_data(serde_json::json!({ "uuid": uuid.to_string() }))
             }
         }
@@ -279,6 +476,150 @@ pub fn encode(_engine: &Engine, data: &[u8]) -> String {
     }
 }
 
+type HostCaller<'a> = Caller<'a, Arc<Mutex<SandboxState>>>;
+
+/// Get the guest's exported linear memory
+fn guest_memory(caller: &mut HostCaller<'_>) -> Option<Memory> {
+    caller.get_export("memory").and_then(|e| e.into_memory())
+}
+
+/// Read `len` bytes at `ptr` from guest memory
+fn read_guest(caller: &mut HostCaller<'_>, ptr: i32, len: i32) -> Option<Vec<u8>> {
+    let memory = guest_memory(caller)?;
+    let mut buf = vec![0u8; usize::try_from(len).ok()?];
+    memory
+        .read(&caller, usize::try_from(ptr).ok()?, &mut buf)
+        .ok()?;
+    Some(buf)
+}
+
+/// Write `data` at `ptr` into guest memory, returning the byte count or -1
+fn write_guest(caller: &mut HostCaller<'_>, ptr: i32, data: &[u8]) -> i32 {
+    let Some(memory) = guest_memory(caller) else {
+        return -1;
+    };
+    let Ok(offset) = usize::try_from(ptr) else {
+        return -1;
+    };
+    match memory.write(caller, offset, data) {
+        Ok(()) => data.len() as i32,
+        Err(_) => -1,
+    }
+}
+
+/// Register the `host` module imports described in the module docs
+fn register_host_functions(linker: &mut Linker<Arc<Mutex<SandboxState>>>) -> Result<()> {
+    linker.func_wrap("host", "context_len", |caller: HostCaller<'_>| -> i32 {
+        caller.data().lock().unwrap().context.len() as i32
+    })?;
+
+    linker.func_wrap("host", "context", |mut caller: HostCaller<'_>, ptr: i32| -> i32 {
+        let context = caller.data().lock().unwrap().context.clone();
+        write_guest(&mut caller, ptr, &context)
+    })?;
+
+    linker.func_wrap(
+        "host",
+        "request",
+        |mut caller: HostCaller<'_>, ptr: i32, len: i32| -> i32 {
+            let Some(bytes) = read_guest(&mut caller, ptr, len) else {
+                return -1;
+            };
+            let response = match serde_json::from_slice::<HostRequest>(&bytes) {
+                Ok(request) => caller.data().lock().unwrap().handle_request(request),
+                Err(e) => HostResponse::error(format!("Invalid host request: {}", e)),
+            };
+            let encoded = serde_json::to_vec(&response).unwrap_or_default();
+            let len = encoded.len() as i32;
+            caller.data().lock().unwrap().pending_response = encoded;
+            len
+        },
+    )?;
+
+    linker.func_wrap("host", "response", |mut caller: HostCaller<'_>, ptr: i32| -> i32 {
+        let response = std::mem::take(&mut caller.data().lock().unwrap().pending_response);
+        write_guest(&mut caller, ptr, &response)
+    })?;
+
+    register_wasi_functions(linker)?;
+
+    linker.func_wrap(
+        "host",
+        "set_output",
+        |mut caller: HostCaller<'_>, ptr: i32, len: i32| -> i32 {
+            let Some(bytes) = read_guest(&mut caller, ptr, len) else {
+                return -1;
+            };
+            match serde_json::from_slice(&bytes) {
+                Ok(output) => {
+                    caller.data().lock().unwrap().output = output;
+                    0
+                }
+                Err(_) => -1,
+            }
+        },
+    )?;
+
+    Ok(())
+}
+
+/// WASI errno values used by the provided WASI functions
+const WASI_ESUCCESS: i32 = 0;
+const WASI_EFAULT: i32 = 21;
+const WASI_EIO: i32 = 29;
+const WASI_ENOTCAPABLE: i32 = 76;
+
+/// Register the WASI clock and random functions, backed by the sandbox state
+fn register_wasi_functions(linker: &mut Linker<Arc<Mutex<SandboxState>>>) -> Result<()> {
+    linker.func_wrap(
+        "wasi_snapshot_preview1",
+        "clock_time_get",
+        |mut caller: HostCaller<'_>, _clock_id: i32, _precision: i64, time_ptr: i32| -> i32 {
+            let nanos = {
+                let mut state = caller.data().lock().unwrap();
+                if state.check_permission(&Permission::Time).is_err() {
+                    state.deny("time");
+                    return WASI_ENOTCAPABLE;
+                }
+                state.clock.read().timestamp_nanos_opt().unwrap_or(0) as u64
+            };
+            if write_guest(&mut caller, time_ptr, &nanos.to_le_bytes()) < 0 {
+                WASI_EFAULT
+            } else {
+                WASI_ESUCCESS
+            }
+        },
+    )?;
+
+    linker.func_wrap(
+        "wasi_snapshot_preview1",
+        "random_get",
+        |mut caller: HostCaller<'_>, buf: i32, len: i32| -> i32 {
+            let Ok(len) = usize::try_from(len) else {
+                return WASI_EFAULT;
+            };
+            let mut bytes = vec![0u8; len];
+            {
+                let mut state = caller.data().lock().unwrap();
+                if state.check_permission(&Permission::Random).is_err() {
+                    state.deny("random");
+                    return WASI_ENOTCAPABLE;
+                }
+                if state.fill_random(&mut bytes).is_err() {
+                    return WASI_EIO;
+                }
+            }
+            if write_guest(&mut caller, buf, &bytes) < 0 {
+                WASI_EFAULT
+            } else {
+                WASI_ESUCCESS
+            }
+        },
+    )?;
+
+    Ok(())
+}
+
 /// WASM Sandbox for executing plugins
 pub struct Sandbox {
     engine: Engine,
@@ -326,29 +667,58 @@ pub fn execute(
         action: &str,
         ctx: &PluginContext,
     ) -> Result<PluginActionResult> {
-        let state = Arc::new(Mutex::new(SandboxState::new(&self.config)));
+        self.execute_with_fuel(module, action, ctx, self.config.fuel_limit)
+    }
+
+    /// Execute a plugin module with a fuel limit overriding the configured one
+    ///
+    /// The override only applies if the sandbox was created with fuel
+    /// metering enabled.
+    pub fn execute_with_fuel(
+        &self,
+        module: &Module,
+        action: &str,
+        ctx: &PluginContext,
+        fuel_limit: Option<u64>,
+    ) -> Result<PluginActionResult> {
+        self.run(module, action, ctx, fuel_limit, None)
+    }
+
+    /// Execute a plugin module, sending emitted events to `events` as they occur
+    pub fn execute_streaming(
+        &self,
+        module: &Module,
+        action: &str,
+        ctx: &PluginContext,
+        events: Sender<serde_json::Value>,
+    ) -> Result<PluginActionResult> {
+        self.run(module, action, ctx, self.config.fuel_limit, Some(events))
+    }
+
+    fn run(
+        &self,
+        module: &Module,
+        action: &str,
+        ctx: &PluginContext,
+        fuel_limit: Option<u64>,
+        event_sink: Option<Sender<serde_json::Value>>,
+    ) -> Result<PluginActionResult> {
+        let fuel_limit = fuel_limit.filter(|_| self.config.fuel_limit.is_some());
+        let context = serde_json::to_vec(ctx)?;
+        let mut state = SandboxState::new(&self.config, ctx, context);
+        state.event_sink = event_sink;
+        let state = Arc::new(Mutex::new(state));
 
         // Create store with fuel limits
         let mut store = Store::new(&self.engine, state.clone());
 
-        if let Some(fuel) = self.config.fuel_limit {
+        if let Some(fuel) = fuel_limit {
             store.set_fuel(fuel)?;
         }
 
         // Create linker with host functions
         let mut linker = Linker::new(&self.engine);
-
-        // Register host function for handling requests
-        let state_clone = state.clone();
-        linker.func_wrap(
-            "host",
-            "request",
-            move |mut caller: Caller<'_, Arc<Mutex<SandboxState>>>, ptr: i32, len: i32| -> i32 {
-                // This is a simplified interface - in production you'd use proper memory access
-                // For now, we return 0 to indicate success
-                0i32
-            },
-        )?;
+        register_host_functions(&mut linker)?;
 
         // Instantiate module
         let instance = linker.instantiate(&mut store, module)?;
@@ -360,36 +730,35 @@ pub fn execute(
 
         // Call the function
         let start = Instant::now();
-        let mut results = vec![Val::I32(0)];
+        let mut results = vec![Val::I32(0); func.ty(&store).results().len()];
 
         match func.call(&mut store, &[], &mut results) {
             Ok(_) => {
-                let state = state.lock().unwrap();
+                let mut state = state.lock().unwrap();
                 let elapsed = start.elapsed();
 
                 debug!("Plugin action '{}' completed in {:?}", action, elapsed);
 
-                // Build result from state
-                let logs = state
-                    .logs
-                    .iter()
-                    .map(|(level, msg)| crate::api::PluginLog {
-                        level: level.clone(),
-                        message: msg.clone(),
-                        timestamp: chrono::Utc::now(),
-                    })
-                    .collect();
+                let status = results.first().and_then(|v| v.i32()).unwrap_or(0);
+                let message = if status == 0 {
+                    format!("Action '{}' completed", action)
+                } else {
+                    format!("Action '{}' failed with status {}", action, status)
+                };
 
                 Ok(PluginActionResult {
-                    success: true,
-                    message: format!("Action '{}' completed", action),
-                    output: serde_json::Value::Null,
-                    logs,
+                    success: status == 0,
+                    message,
+                    output: std::mem::take(&mut state.output),
+                    logs: std::mem::take(&mut state.logs),
+                    files_written: std::mem::take(&mut state.files_written),
+                    permission_denials: std::mem::take(&mut state.permission_denials),
+                    events: std::mem::take(&mut state.events),
                 })
             }
             Err(e) => {
                 // Check if it was a fuel exhaustion
-                if store.get_fuel().unwrap_or(0) == 0 {
+                if fuel_limit.is_some() && store.get_fuel().unwrap_or(0) == 0 {
                     Err(PluginError::ResourceLimitExceeded(
                         "Instruction limit exceeded".to_string(),
                     ))
@@ -455,6 +824,12 @@ pub fn work_dir(mut self, dir: impl Into<PathBuf>) -> Self {
         self
     }
 
+    /// Enable deterministic mode
+    pub fn deterministic(mut self, deterministic: DeterministicConfig) -> Self {
+        self.config.deterministic = Some(deterministic);
+        self
+    }
+
     /// Build the sandbox
     pub fn build(self) -> Result<Sandbox> {
         Sandbox::new(self.config)
@@ -494,4 +869,79 @@ fn test_sandbox_creation() {
         let sandbox = Sandbox::with_defaults();
         assert!(sandbox.is_ok());
     }
+
+    /// Collects a `current_time` and a `generate_uuid` response into a JSON array
+    const REPLAY_PLUGIN: &str = r#"
+        (module
+          (import "host" "request" (func $request (param i32 i32) (result i32)))
+          (import "host" "response" (func $response (param i32) (result i32)))
+          (import "host" "set_output" (func $set_output (param i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 0) "{\"type\":\"current_time\"}")
+          (data (i32.const 32) "{\"type\":\"generate_uuid\"}")
+          (func (export "run") (result i32)
+            (local $len i32) (local $pos i32)
+            (i32.store8 (i32.const 1024) (i32.const 91))
+            (local.set $pos (i32.const 1025))
+            (local.set $len (call $request (i32.const 0) (i32.const 23)))
+            (drop (call $response (local.get $pos)))
+            (local.set $pos (i32.add (local.get $pos) (local.get $len)))
+            (i32.store8 (local.get $pos) (i32.const 44))
+            (local.set $pos (i32.add (local.get $pos) (i32.const 1)))
+            (local.set $len (call $request (i32.const 32) (i32.const 24)))
+            (drop (call $response (local.get $pos)))
+            (local.set $pos (i32.add (local.get $pos) (local.get $len)))
+            (i32.store8 (local.get $pos) (i32.const 93))
+            (local.set $pos (i32.add (local.get $pos) (i32.const 1)))
+            (call $set_output (i32.const 1024) (i32.sub (local.get $pos) (i32.const 1024)))))
+    "#;
+
+    fn replay(config: SandboxConfig, ctx: &PluginContext) -> serde_json::Value {
+        let sandbox = Sandbox::new(config).unwrap();
+        let module = sandbox.load_module(REPLAY_PLUGIN.as_bytes()).unwrap();
+        let result = sandbox.execute(&module, "run", ctx).unwrap();
+        assert!(result.success, "{}", result.message);
+        result.output
+    }
+
+    #[test]
+    fn test_deterministic_runs_replay_identically() {
+        let event = rpa_core::Event::new(rpa_core::EventKind::Manual, "test");
+        let ctx = PluginContext::new(event.clone());
+        let config = SandboxConfig::default().with_deterministic(DeterministicConfig::with_seed(42));
+
+        let first = replay(config.clone(), &ctx);
+        let second = replay(config, &ctx);
+        assert_eq!(serde_json::to_vec(&first).unwrap(), serde_json::to_vec(&second).unwrap());
+
+        // The virtual clock starts at the event timestamp
+        assert_eq!(first[0]["data"]["iso"], event.timestamp.to_rfc3339());
+
+        let reseeded = replay(
+            SandboxConfig::default().with_deterministic(DeterministicConfig::with_seed(7)),
+            &ctx,
+        );
+        assert_ne!(first[1], reseeded[1]);
+    }
+
+    #[test]
+    fn test_virtual_clock_ticks_and_seeded_random() {
+        let ctx = PluginContext::new(rpa_core::Event::new(rpa_core::EventKind::Manual, "test"));
+        let start = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap().with_timezone(&Utc);
+        let config = SandboxConfig::default().with_deterministic(DeterministicConfig {
+            start_time: Some(start),
+            tick_ms: 1000,
+            seed: 1,
+        });
+
+        let mut state = SandboxState::new(&config, &ctx, Vec::new());
+        assert_eq!(state.clock.read(), start);
+        assert_eq!(state.clock.read(), start + chrono::Duration::seconds(1));
+
+        let mut a = [0u8; 20];
+        let mut b = [0u8; 20];
+        state.fill_random(&mut a).unwrap();
+        SandboxState::new(&config, &ctx, Vec::new()).fill_random(&mut b).unwrap();
+        assert_eq!(a, b);
+    }
 }